@@ -0,0 +1,100 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use crate::http::base::{common_log_date, HttpMethod, HttpVersion};
+
+/// What the dispatcher knows about a request once its response has been written, passed to the
+/// `HttpServer::on_request_complete` hooks.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestLog {
+    pub(crate) remote_addr: SocketAddr,
+    pub(crate) method: HttpMethod,
+    /// The path as sent, query string included.
+    pub(crate) path: String,
+    pub(crate) version: HttpVersion,
+    pub(crate) status: u32,
+    /// Body bytes sent, 0 for HEAD requests.
+    pub(crate) size: u64,
+    pub(crate) received_at: SystemTime,
+    /// From dispatching the request to the last byte of the response being written.
+    pub(crate) duration: Duration,
+    /// How long the connection waited for a worker once accepted, only counted for its first
+    /// request.
+    pub(crate) queued: Duration,
+    /// With `HttpServer::enable_request_ids`.
+    pub(crate) request_id: Option<String>,
+    /// The message of a handler or filter panic, the response is then the 500 of
+    /// `HttpServer::set_panic_handler`.
+    pub(crate) panic: Option<String>,
+    /// The response could not be written completely because the client closed the connection
+    /// or stopped reading; `size` is 0 then.
+    pub(crate) disconnected: bool,
+}
+
+/// Renders the entry as a common log format line, e.g.
+/// `127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET /index.html HTTP/1.1" 200 2326`, followed
+/// by the request ID if there is one and `client disconnected` if the response was cut short.
+impl Display for RequestLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} - - [{}] \"{:?} {} {}\" {} {}",
+               self.remote_addr.ip(), common_log_date(self.received_at),
+               self.method, self.path, self.version, self.status, self.size)?;
+        if let Some(id) = &self.request_id {
+            write!(f, " {}", id)?;
+        }
+        if self.disconnected {
+            write!(f, " client disconnected")?;
+        }
+        Ok(())
+    }
+}
+
+/// A ready-made hook for `HttpServer::on_request_complete` printing common log format lines.
+pub(crate) fn log_to_stderr(log: &RequestLog) {
+    eprintln!("{}", log);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use crate::http::base::{HttpConnection, HttpResponse, HttpStatus};
+    use crate::http::testing::TestServer;
+    use super::*;
+
+    #[test]
+    fn the_hook_fires_once_per_request() {
+        let (sender, logs) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let server = TestServer::start(|server| {
+            server.register_end_point("/ok", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("fine")));
+            server.register_end_point("/users", HttpMethod::POST, Arc::new(|_| HttpResponse::with_status(HttpStatus::CREATED)));
+            server.register_end_point("/secret", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("secret")));
+            server.do_before(Box::new(|c: &HttpConnection| {
+                let secret = c.request.path.starts_with("/secret");
+                secret.then(|| HttpResponse::with_status(HttpStatus::FORBIDDEN))
+            }));
+            server.on_request_complete(Box::new(move |log| {
+                let _ = sender.lock().unwrap().send(log.clone());
+            }));
+        });
+        let requests = [
+            (HttpMethod::GET, "/ok?x=1", 200, 4),
+            (HttpMethod::POST, "/users", 201, 0),
+            (HttpMethod::GET, "/secret", 403, 0),
+            (HttpMethod::GET, "/missing", 404, 0),
+            (HttpMethod::DELETE, "/ok", 405, 0),
+        ];
+        for (method, path, status, size) in requests {
+            assert_eq!(server.request(method, path, &[], b"").status, status);
+            let log = logs.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!((log.method, log.path.as_str(), log.status, log.size), (method, path, status, size));
+            assert!(log.remote_addr.ip().is_loopback());
+            assert!(!log.disconnected);
+            assert!(log.to_string().contains(&format!("\"{:?} {} HTTP/1.1\" {} {}", method, path, status, size)), "{log}");
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(logs.try_recv().is_err());
+    }
+}
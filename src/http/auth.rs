@@ -0,0 +1,172 @@
+use crate::http::base::{HttpConnection, HttpHeader, HttpRequest, HttpResponse, HttpStatus};
+use crate::http::http_core::BeforeFilter;
+
+/// A `do_before` filter letting through requests whose `Authorization: Basic` credentials
+/// `validator` accepts, with the user name as their principal, see `HttpContext::principal`.
+/// Any other request gets a 401 asking for credentials to `realm`.
+pub(crate) fn basic_auth<V>(realm: &str, validator: V) -> BeforeFilter
+where
+    V: Fn(&str, &str) -> bool + Send + Sync + 'static,
+{
+    let realm = realm.replace('\\', "\\\\").replace('"', "\\\"");
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm);
+    Box::new(move |connection: &HttpConnection| {
+        let request = &connection.request;
+        let credentials = credentials(request, "Basic")
+            .and_then(decode_base64)
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+        match credentials.as_deref().and_then(|credentials| credentials.split_once(':')) {
+            Some((user, password)) if validator(user, password) => {
+                request.set_principal(user);
+                None
+            }
+            _ => Some(unauthorized(&challenge)),
+        }
+    })
+}
+
+/// A `do_before` filter letting through requests with an `Authorization: Bearer` token that
+/// `validator` accepts, the token becoming their principal. Any other request gets a 401.
+pub(crate) fn bearer_auth<V>(validator: V) -> BeforeFilter
+where
+    V: Fn(&str) -> bool + Send + Sync + 'static,
+{
+    Box::new(move |connection: &HttpConnection| {
+        let request = &connection.request;
+        match credentials(request, "Bearer") {
+            Some(token) if validator(token) => {
+                request.set_principal(token);
+                None
+            }
+            // RFC 6750: a request without credentials gets no error code
+            Some(_) => Some(unauthorized("Bearer error=\"invalid_token\"")),
+            None => Some(unauthorized("Bearer")),
+        }
+    })
+}
+
+/// The credentials of the `Authorization` header if it uses `scheme`, compared case-insensitively.
+fn credentials<'r>(request: &'r HttpRequest, scheme: &str) -> Option<&'r str> {
+    let (name, credentials) = request.headers.get(HttpHeader::AUTHORIZATION)?.trim().split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| credentials.trim())
+}
+
+fn unauthorized(challenge: &str) -> HttpResponse {
+    HttpResponse::with_status(HttpStatus::UNAUTHORIZED).header(HttpHeader::WWW_AUTHENTICATE, challenge)
+}
+
+/// Decodes standard base64, padding optional; `None` on any other character or a dangling one.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    // a single character left over carries less than a byte
+    (bits < 6).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::http::base::{HttpContext, HttpMethod};
+    use crate::http::client::ClientResponse;
+    use crate::http::testing::TestServer;
+    use super::*;
+
+    fn auth_server(filter: BeforeFilter) -> TestServer {
+        TestServer::start(|server| {
+            server.register_end_point("/me", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(r.principal().unwrap_or("nobody"))
+            }));
+            server.do_before(filter);
+        })
+    }
+
+    fn get(server: &TestServer, authorization: Option<&str>) -> ClientResponse {
+        let headers: Vec<(&str, &str)> = authorization.map(|value| (HttpHeader::AUTHORIZATION, value)).into_iter().collect();
+        server.request(HttpMethod::GET, "/me", &headers, b"")
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let server = auth_server(basic_auth("staff \"only\"", |user, password| {
+            matches!((user, password), ("ann", "secret") | ("ann", "se:cret") | ("béa", "secret"))
+        }));
+        let accepted = |authorization| {
+            let response = get(&server, Some(authorization));
+            assert_eq!(response.status, 200, "{authorization}");
+            response.text().unwrap().to_string()
+        };
+        assert_eq!(accepted("Basic YW5uOnNlY3JldA=="), "ann");
+        // the scheme is case-insensitive, padding is optional and the password may hold colons
+        assert_eq!(accepted("basic YW5uOnNlY3JldA"), "ann");
+        assert_eq!(accepted("Basic YW5uOnNlOmNyZXQ="), "ann");
+        assert_eq!(accepted("Basic YsOpYTpzZWNyZXQ="), "béa");
+
+        let challenge = "Basic realm=\"staff \\\"only\\\"\", charset=\"UTF-8\"";
+        let refused = [
+            None,
+            Some("Basic YW5uOndyb25n"),  // ann:wrong
+            Some("Bearer YW5uOnNlY3JldA=="),
+            Some("Basic"),
+            Some("Basic !!!!"),
+            Some("Basic YW5uOnNlY3JldA=Q"),
+            Some("Basic Y"),
+            Some("Basic YW5u"),  // no colon
+            Some("Basic //46eA=="),  // not UTF-8
+        ];
+        for authorization in refused {
+            let response = get(&server, authorization);
+            assert_eq!(response.status, 401, "{authorization:?}");
+            assert_eq!(response.header(HttpHeader::WWW_AUTHENTICATE), Some(challenge), "{authorization:?}");
+        }
+    }
+
+    #[test]
+    fn bearer_tokens() {
+        let server = auth_server(bearer_auth(|token| token == "t0ken"));
+        let response = get(&server, Some("Bearer t0ken"));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text().unwrap(), "t0ken");
+        assert_eq!(get(&server, Some("bearer  t0ken ")).text().unwrap(), "t0ken");
+
+        let missing = get(&server, None);
+        assert_eq!(missing.status, 401);
+        assert_eq!(missing.header(HttpHeader::WWW_AUTHENTICATE), Some("Bearer"));
+        let wrong = get(&server, Some("Bearer wrong"));
+        assert_eq!(wrong.status, 401);
+        assert_eq!(wrong.header(HttpHeader::WWW_AUTHENTICATE), Some("Bearer error=\"invalid_token\""));
+        // without a bearer token at all there is no error code
+        for authorization in ["Bearer ", "Basic t0ken", "t0ken"] {
+            let response = get(&server, Some(authorization));
+            assert_eq!(response.status, 401, "{authorization}");
+            assert_eq!(response.header(HttpHeader::WWW_AUTHENTICATE), Some("Bearer"), "{authorization}");
+        }
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("").as_deref(), Some(&b""[..]));
+        assert_eq!(decode_base64("Zg==").as_deref(), Some(&b"f"[..]));
+        assert_eq!(decode_base64("Zm8").as_deref(), Some(&b"fo"[..]));
+        assert_eq!(decode_base64("Zm9v").as_deref(), Some(&b"foo"[..]));
+        assert_eq!(decode_base64("Zm9vY"), None);
+        assert_eq!(decode_base64("Zm9v-"), None);
+    }
+}
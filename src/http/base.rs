@@ -1,8 +1,21 @@
+use std::cell::OnceCell;
+use std::cmp::{Ordering, Reverse};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::str::FromStr;
-use crate::utils::json::{DataType, JsonParser};
+use std::fmt;
+use std::fmt::{Display, Formatter, Write as _};
+use std::io;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::str::{FromStr, Utf8Error};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::http::cookie::Cookie;
+use crate::http::etag;
+use crate::http::headers::HeaderMap;
+use crate::http::sse::SseSink;
+use crate::utils::json::{DataType, JsonDeserializable, JsonError, JsonMap, JsonParser, JsonSerializable, Serializer};
 
 pub(crate) struct MediaType;
 
@@ -22,6 +35,82 @@ impl<'a> MediaType {
     pub(crate) const IMAGE_PNG: &'a str = "image/png";
     pub(crate) const SERVER_SENT_EVENTS: &'a str = "text/event-stream";
     pub(crate) const APPLICATION_JSON_PATCH_JSON: &'a str = "application/json-patch+json";
+
+    /// Guesses the media type of a file from its extension, `application/octet-stream` if unknown.
+    pub(crate) fn from_extension(extension: &str) -> &'a str {
+        match extension.to_ascii_lowercase().as_str() {
+            "html" | "htm" => MediaType::TEXT_HTML,
+            "txt" => MediaType::TEXT_PLAIN,
+            "xml" => MediaType::APPLICATION_XML,
+            "json" => MediaType::APPLICATION_JSON,
+            "jpg" | "jpeg" => MediaType::IMAGE_JPEG,
+            "png" => MediaType::IMAGE_PNG,
+            "css" => "text/css",
+            "js" | "mjs" => "text/javascript",
+            "svg" => "image/svg+xml",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "ico" => "image/x-icon",
+            "pdf" => "application/pdf",
+            "wasm" => "application/wasm",
+            _ => MediaType::APPLICATION_OCTET_STREAM,
+        }
+    }
+}
+
+/// A parsed `Content-type` value, see `HttpRequest::content_type`. It compares equal to a bare
+/// media type whatever its parameters, so `application/json; charset=utf-8` equals
+/// `MediaType::APPLICATION_JSON`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ContentType {
+    /// Lowercased, e.g. `text/html`.
+    pub(crate) media_type: String,
+    /// Names lowercased and values unquoted, in the order they appear.
+    pub(crate) params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    /// `None` unless `value` starts with a `type/subtype`.
+    pub(crate) fn parse(value: &str) -> Option<ContentType> {
+        let mut parts = value.split(';');
+        let media_type = parts.next()?.trim().to_ascii_lowercase();
+        match media_type.split_once('/') {
+            Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() => {}
+            _ => return None,
+        }
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string()))
+            .collect();
+        Some(ContentType { media_type, params })
+    }
+
+    pub(crate) fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(param, _)| param.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// `application/json` or a `+json` type such as `application/problem+json`.
+    pub(crate) fn is_json(&self) -> bool {
+        self.media_type == MediaType::APPLICATION_JSON || self.media_type.ends_with("+json")
+    }
+}
+
+impl PartialEq<str> for ContentType {
+    fn eq(&self, media_type: &str) -> bool {
+        self.media_type.eq_ignore_ascii_case(media_type)
+    }
+}
+
+impl PartialEq<&str> for ContentType {
+    fn eq(&self, media_type: &&str) -> bool {
+        self == *media_type
+    }
 }
 
 pub(crate) struct HttpHeader;
@@ -31,9 +120,38 @@ impl<'a> HttpHeader {
     pub(crate) const ACCEPT: &'a str = "Accept";
     pub(crate) const CONTENT: &'a str = "Content";
     pub(crate) const USER_AGENT: &'a str = "User-Agent";
+    pub(crate) const CONNECTION: &'a str = "Connection";
+    pub(crate) const COOKIE: &'a str = "Cookie";
+    pub(crate) const SET_COOKIE: &'a str = "Set-Cookie";
+    pub(crate) const TRANSFER_ENCODING: &'a str = "Transfer-Encoding";
+    pub(crate) const ALLOW: &'a str = "Allow";
+    pub(crate) const CACHE_CONTROL: &'a str = "Cache-Control";
+    pub(crate) const AGE: &'a str = "Age";
+    pub(crate) const X_CACHE: &'a str = "X-Cache";
+    pub(crate) const ACCEPT_ENCODING: &'a str = "Accept-Encoding";
+    pub(crate) const CONTENT_ENCODING: &'a str = "Content-Encoding";
+    pub(crate) const VARY: &'a str = "Vary";
+    pub(crate) const RANGE: &'a str = "Range";
+    pub(crate) const ACCEPT_RANGES: &'a str = "Accept-Ranges";
+    pub(crate) const CONTENT_RANGE: &'a str = "Content-Range";
+    pub(crate) const ETAG: &'a str = "ETag";
+    pub(crate) const IF_NONE_MATCH: &'a str = "If-None-Match";
+    pub(crate) const IF_MATCH: &'a str = "If-Match";
+    pub(crate) const EXPIRES: &'a str = "Expires";
+    pub(crate) const CONTENT_LOCATION: &'a str = "Content-Location";
+    pub(crate) const LOCATION: &'a str = "Location";
+    pub(crate) const DATE: &'a str = "Date";
+    pub(crate) const SERVER: &'a str = "Server";
+    pub(crate) const X_FORWARDED_FOR: &'a str = "X-Forwarded-For";
+    pub(crate) const X_REAL_IP: &'a str = "X-Real-IP";
+    pub(crate) const AUTHORIZATION: &'a str = "Authorization";
+    pub(crate) const WWW_AUTHENTICATE: &'a str = "WWW-Authenticate";
+    pub(crate) const X_REQUEST_ID: &'a str = "X-Request-Id";
+    pub(crate) const HOST: &'a str = "Host";
+    pub(crate) const RETRY_AFTER: &'a str = "Retry-After";
 }
 
-#[derive(Debug, Default, Hash, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Hash, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) enum HttpMethod {
     #[default]
     GET,
@@ -41,6 +159,9 @@ pub(crate) enum HttpMethod {
     PUT,
     DELETE,
     PATCH,
+    HEAD,
+    OPTIONS,
+    TRACE,
 }
 
 impl FromStr for HttpMethod {
@@ -54,6 +175,9 @@ impl FromStr for HttpMethod {
             "put" => Ok(HttpMethod::PUT),
             "delete" => Ok(HttpMethod::DELETE),
             "patch" => Ok(HttpMethod::PATCH),
+            "head" => Ok(HttpMethod::HEAD),
+            "options" => Ok(HttpMethod::OPTIONS),
+            "trace" => Ok(HttpMethod::TRACE),
             _ => {
                 Err("Unknown method detected".to_string())
             }
@@ -61,135 +185,860 @@ impl FromStr for HttpMethod {
     }
 }
 
+/// The protocol versions this server speaks; requests with any other get a 505.
+#[derive(Debug, Default, Hash, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum HttpVersion {
+    Http10,
+    #[default]
+    Http11,
+}
+
+impl Display for HttpVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpVersion::Http10 => write!(f, "HTTP/1.0"),
+            HttpVersion::Http11 => write!(f, "HTTP/1.1"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct HttpStatus;
 
 impl HttpStatus {
     pub(crate) const OK: u32 = 200;
+    pub(crate) const CREATED: u32 = 201;
+    pub(crate) const ACCEPTED: u32 = 202;
+    pub(crate) const NO_CONTENT: u32 = 204;
+    pub(crate) const PARTIAL_CONTENT: u32 = 206;
+    pub(crate) const MOVED_PERMANENTLY: u32 = 301;
+    pub(crate) const FOUND: u32 = 302;
+    pub(crate) const NOT_MODIFIED: u32 = 304;
     pub(crate) const BAD_REQUEST: u32 = 400;
-    pub(crate) const FORBIDDEN: u32 = 401;
+    pub(crate) const UNAUTHORIZED: u32 = 401;
+    pub(crate) const FORBIDDEN: u32 = 403;
     pub(crate) const NOT_FOUND: u32 = 404;
     pub(crate) const NOT_ALLOWED: u32 = 405;
+    pub(crate) const PRECONDITION_FAILED: u32 = 412;
+    pub(crate) const PAYLOAD_TOO_LARGE: u32 = 413;
+    pub(crate) const RANGE_NOT_SATISFIABLE: u32 = 416;
+    pub(crate) const TOO_MANY_REQUESTS: u32 = 429;
+    pub(crate) const HEADER_FIELDS_TOO_LARGE: u32 = 431;
     pub(crate) const INTERNAL_ERROR: u32 = 500;
+    pub(crate) const NOT_IMPLEMENTED: u32 = 501;
+    pub(crate) const SERVICE_UNAVAILABLE: u32 = 503;
+    pub(crate) const VERSION_NOT_SUPPORTED: u32 = 505;
+
+    /// The standard reason phrase for `status`, or a generic one for its class when the code is unknown.
+    pub(crate) fn reason_phrase(status: u32) -> &'static str {
+        match status {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => match status / 100 {
+                1 => "Informational",
+                2 => "Success",
+                3 => "Redirection",
+                4 => "Client Error",
+                _ => "Server Error",
+            }
+        }
+    }
+}
+
+/// Decodes `%XX` escapes (and `+` as a space when `plus_as_space` is set, as in forms and
+/// query strings), `None` for truncated or non-hex escapes and for invalid UTF-8.
+pub(crate) fn percent_decode(input: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let high = (iter.next()? as char).to_digit(16)?;
+                let low = (iter.next()? as char).to_digit(16)?;
+                bytes.push((high * 16 + low) as u8);
+            }
+            b'+' if plus_as_space => bytes.push(b' '),
+            byte => bytes.push(byte)
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let t = CivilTime::from(time);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[(t.days % 7) as usize], t.day, MONTHS[(t.month - 1) as usize], t.year, t.hour, t.minute, t.second)
+}
+
+/// Formats `time` as in the common log format, e.g. `06/Nov/1994:08:49:37 +0000`.
+pub(crate) fn common_log_date(time: SystemTime) -> String {
+    let t = CivilTime::from(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            t.day, MONTHS[(t.month - 1) as usize], t.year, t.hour, t.minute, t.second)
+}
+
+/// A UTC time split into calendar fields, `days` counting from 1970-01-01.
+struct CivilTime {
+    days: u64,
+    year: u64,
+    month: u64,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+}
+
+impl From<SystemTime> for CivilTime {
+    fn from(time: SystemTime) -> Self {
+        let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let days = seconds / 86400;
+        let (hour, minute, second) = (seconds % 86400 / 3600, seconds % 3600 / 60, seconds % 60);
+
+        // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z / 146097;
+        let day_of_era = z % 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+        CivilTime { days, year, month, day, hour, minute, second }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum RequestParseError {
+    /// The client closed the connection (or the read timed out) before a full request arrived.
+    ConnectionClosed,
+    /// Not `METHOD target HTTP/x.y`.
+    BadRequestLine,
+    /// A well-formed method this server does not know, e.g. `BREW`.
+    UnsupportedMethod(String),
+    /// A well-formed version other than HTTP/1.0 and HTTP/1.1, e.g. `HTTP/2.0`.
+    UnsupportedVersion(String),
+    /// Non-UTF-8 bytes in the request line or headers, or a header line without a `:`.
+    InvalidHeader,
+    /// The request line and headers exceed `RequestLimits::max_header_size`.
+    HeadersTooLarge,
+    /// The declared or received body exceeds `RequestLimits::max_body_size`.
+    BodyTooLarge,
+    /// A truncated or non-hex `%` escape, or one decoding to invalid UTF-8, in the path or query.
+    InvalidEncoding,
+    InvalidBody(String),
+}
+
+impl RequestParseError {
+    /// The status to reject the request with.
+    pub(crate) fn status(&self) -> u32 {
+        match self {
+            RequestParseError::UnsupportedMethod(_) => HttpStatus::NOT_IMPLEMENTED,
+            RequestParseError::UnsupportedVersion(_) => HttpStatus::VERSION_NOT_SUPPORTED,
+            RequestParseError::HeadersTooLarge => HttpStatus::HEADER_FIELDS_TOO_LARGE,
+            RequestParseError::BodyTooLarge => HttpStatus::PAYLOAD_TOO_LARGE,
+            _ => HttpStatus::BAD_REQUEST,
+        }
+    }
+}
+
+impl Display for RequestParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestParseError::ConnectionClosed => write!(f, "connection closed before a full request was received"),
+            RequestParseError::BadRequestLine => write!(f, "malformed request line"),
+            RequestParseError::UnsupportedMethod(method) => write!(f, "unsupported method `{}`", method),
+            RequestParseError::UnsupportedVersion(version) => write!(f, "unsupported version `{}`", version),
+            RequestParseError::InvalidHeader => write!(f, "malformed header"),
+            RequestParseError::HeadersTooLarge => write!(f, "request headers too large"),
+            RequestParseError::BodyTooLarge => write!(f, "request body too large"),
+            RequestParseError::InvalidEncoding => write!(f, "invalid percent-encoding in the request target"),
+            RequestParseError::InvalidBody(reason) => write!(f, "invalid body: {}", reason),
+        }
+    }
 }
 
+impl std::error::Error for RequestParseError {}
+
 #[derive(Debug)]
 pub(crate) struct HttpRequest {
-    pub(crate) version: String,
+    pub(crate) version: HttpVersion,
+    /// The request target, still percent-encoded and with its query string. The dispatcher
+    /// normalizes its path before routing, see `TrailingSlash`; routes match against it and decode
+    /// the path parameters they capture.
     pub(crate) path: String,
     pub(crate) method: HttpMethod,
-    pub(crate) headers: HashMap<String, String>,
-    pub(crate) query_params: HashMap<String, String>,
-    pub(crate) body: HashMap<String, DataType>,
+    pub(crate) headers: HeaderMap,
+    /// Every value of each query parameter, in the order they appear.
+    pub(crate) query_params: HashMap<String, Vec<String>>,
+    pub(crate) body: Vec<u8>,
+    /// Set by the dispatcher once `HttpServer::enable_request_ids` is on.
+    pub(crate) request_id: Option<String>,
+    json: OnceCell<Result<JsonMap, JsonError>>,
+    form: OnceCell<Option<JsonMap>>,
+    /// Who a `do_before` filter authenticated the request as, see `auth::basic_auth`.
+    principal: OnceCell<String>,
 }
 
 impl HttpRequest {
-    fn new(stream: &TcpStream) -> Option<Self> {
-        let mut reader = BufReader::new(stream);
+    /// Reads the next request from `reader`, failing as soon as it exceeds `limits`.
+    fn new(reader: &mut BufReader<TcpStream>, limits: &RequestLimits) -> Result<Self, RequestParseError> {
         let mut buffer = String::new();
 
         loop {
-            reader.read_line(&mut buffer).ok()?;
+            if buffer.len() >= limits.max_header_size {
+                return Err(RequestParseError::HeadersTooLarge);
+            }
+            let limit = (limits.max_header_size - buffer.len()) as u64;
+            match reader.by_ref().take(limit).read_line(&mut buffer) {
+                Ok(0) => return Err(RequestParseError::ConnectionClosed),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(RequestParseError::InvalidHeader),
+                Err(_) => return Err(RequestParseError::ConnectionClosed),
+            }
+            // a stray empty line before the request line, e.g. after a previous body, is ignored
+            if buffer == "\r\n" {
+                buffer.clear();
+            }
             if buffer.ends_with("\r\n\r\n") {
                 break;
             }
         }
 
-        let (first_line, header) = buffer.split_once('\n')?;
+        let (request_line, header) = buffer.split_once("\r\n").ok_or(RequestParseError::BadRequestLine)?;
+        let (method, path, version) = Self::parse_request_line(request_line)?;
 
-        let first_line: Vec<&str> = first_line.split(" ").collect();
-        let method: HttpMethod = first_line[0].trim().parse().ok()?;
-        let path = first_line[1].trim();
-        let version = first_line[2].trim();
-
-        let query_params: HashMap<String, String> = Self::parse_query_params(path);
-        let headers: HashMap<String, String> = Self::parse_header(header);
+        let raw_path = path.split('?').next().unwrap_or_default();
+        if percent_decode(raw_path, false).is_none() {
+            return Err(RequestParseError::InvalidEncoding);
+        }
+        let query_params = Self::parse_query_params(path).ok_or(RequestParseError::InvalidEncoding)?;
+        let headers = Self::parse_header(header)?;
 
-        let body = Self::parse_body(&mut reader, &headers)?;
+        let body = Self::read_body(reader, &headers, limits)?;
 
-        Some(HttpRequest {
+        Ok(HttpRequest {
             method,
             path: path.to_string(),
-            query_params: query_params,
-            version: version.trim().to_string(),
+            query_params,
+            version,
             headers,
             body,
+            request_id: None,
+            json: OnceCell::new(),
+            form: OnceCell::new(),
+            principal: OnceCell::new(),
         })
     }
 
-    fn parse_body(reader: &mut BufReader<&TcpStream>, headers: &HashMap<String, String>) -> Option<HashMap<String, DataType>> {
-        let body = match headers.get(HttpHeader::CONTENT_LENGTH) {
-            Some(content_length) => {
-                let size: usize = content_length.parse().ok()?;
-                let mut buffer = vec![0u8; size];
-                reader.read_exact(&mut buffer).ok()?;
-                buffer
+    pub(crate) fn body_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub(crate) fn body_text(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    /// The body parsed as a JSON object on first use, whatever the `Content-type`. An empty body
+    /// gives an empty object.
+    pub(crate) fn body_json(&self) -> Result<&JsonMap, JsonError> {
+        self.json
+            .get_or_init(|| {
+                if self.body.is_empty() {
+                    return Ok(JsonMap::new());
+                }
+                JsonParser::from_slice(&self.body).parse_to_map()
+            })
+            .as_ref()
+            .map_err(Clone::clone)
+    }
+
+    /// The body deserialized into `T`. Unlike `body_json` it requires a JSON `Content-type`, such
+    /// as `application/json` or `application/problem+json`.
+    pub(crate) fn body_as<T: JsonDeserializable>(&self) -> Result<T, BodyError> {
+        if !self.content_type().is_some_and(|content_type| content_type.is_json()) {
+            let content_type = self.header(HttpHeader::CONTENT_TYPE);
+            return Err(BodyError::UnsupportedContentType(content_type.map(str::to_string)));
+        }
+        let value = JsonParser::from_slice(&self.body).parse_to_value().map_err(BodyError::InvalidJson)?;
+        T::from_json(&value).map_err(BodyError::InvalidField)
+    }
+
+    /// The first value of the header `name`, whatever its casing.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// `None` without a `Content-type` or with one that is not a `type/subtype`.
+    pub(crate) fn content_type(&self) -> Option<ContentType> {
+        self.header(HttpHeader::CONTENT_TYPE).and_then(ContentType::parse)
+    }
+
+    /// `None` without a `Content-length` or with one that is not a number.
+    pub(crate) fn content_length(&self) -> Option<usize> {
+        self.header(HttpHeader::CONTENT_LENGTH).and_then(|length| length.trim().parse().ok())
+    }
+
+    pub(crate) fn user_agent(&self) -> Option<&str> {
+        self.header(HttpHeader::USER_AGENT)
+    }
+
+    pub(crate) fn host(&self) -> Option<&str> {
+        self.header(HttpHeader::HOST)
+    }
+
+    /// Who the request was authenticated as, set by filters such as `auth::basic_auth`.
+    pub(crate) fn principal(&self) -> Option<&str> {
+        self.principal.get().map(String::as_str)
+    }
+
+    /// Records who the request was authenticated as, for the handler to read with `principal`.
+    /// Only the first call has an effect, a later filter cannot replace an established identity.
+    pub(crate) fn set_principal(&self, principal: &str) {
+        let _ = self.principal.set(principal.to_string());
+    }
+
+    /// The fields of an url-encoded form body, with repeated keys collected into arrays;
+    /// `None` if the body is not valid form data.
+    pub(crate) fn form(&self) -> Option<&JsonMap> {
+        self.form.get_or_init(|| Self::parse_form(&self.body)).as_ref()
+    }
+
+    /// Reads the body `headers` announce, chunked or of a `Content-length`, none without either.
+    pub(crate) fn read_body(reader: &mut BufReader<TcpStream>, headers: &HeaderMap, limits: &RequestLimits) -> Result<Vec<u8>, RequestParseError> {
+        let chunked = headers.get(HttpHeader::TRANSFER_ENCODING)
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+        if chunked {
+            return Self::read_chunked_body(reader, limits);
+        }
+        let size: u64 = match headers.get(HttpHeader::CONTENT_LENGTH) {
+            Some(content_length) => content_length.trim().parse()
+                .map_err(|_| RequestParseError::InvalidBody(format!("invalid content length `{content_length}`")))?,
+            None => 0
+        };
+        if size > limits.max_body_size {
+            return Err(RequestParseError::BodyTooLarge);
+        }
+        // grows with the data actually received rather than trusting the declared size up front
+        let mut body = Vec::new();
+        match reader.take(size).read_to_end(&mut body) {
+            Ok(read) if read as u64 == size => Ok(body),
+            _ => Err(RequestParseError::InvalidBody("truncated body".to_string()))
+        }
+    }
+
+    fn parse_form(body: &[u8]) -> Option<JsonMap> {
+        let body = std::str::from_utf8(body).ok()?;
+        let mut form = JsonMap::new();
+        for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_decode(key, true)?;
+            let value = DataType::String(percent_decode(value, true)?);
+            match form.get_mut(&key) {
+                None => {
+                    form.insert(key, value);
+                }
+                Some(DataType::Array(values)) => values.push(value),
+                Some(previous) => {
+                    let first = std::mem::replace(previous, DataType::Null);
+                    *previous = DataType::Array(vec![first, value]);
+                }
             }
-            None => {
-                vec![]
+        }
+        Some(form)
+    }
+
+    /// Decodes a `Transfer-Encoding: chunked` body: hex size lines (extensions after `;` are
+    /// ignored), each followed by that many bytes and a CRLF, up to the zero-sized last chunk
+    /// and the optional trailers, which are discarded.
+    fn read_chunked_body(reader: &mut BufReader<TcpStream>, limits: &RequestLimits) -> Result<Vec<u8>, RequestParseError> {
+        let truncated = || RequestParseError::InvalidBody("truncated chunked body".to_string());
+        let read_line = |reader: &mut BufReader<TcpStream>| {
+            let mut line = String::new();
+            // size lines and trailers are bounded like header lines
+            match reader.by_ref().take(limits.max_header_size as u64).read_line(&mut line) {
+                Ok(0) | Err(_) => Err(truncated()),
+                Ok(_) => Ok(line)
+            }
+        };
+
+        let mut body = Vec::new();
+        loop {
+            let line = read_line(reader)?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| RequestParseError::InvalidBody(format!("invalid chunk size `{size}`")))?;
+            if size == 0 {
+                break;
+            }
+            // against what is left of the limit, a sum could overflow for a huge size
+            if size > limits.max_body_size.saturating_sub(body.len() as u64) {
+                return Err(RequestParseError::BodyTooLarge);
             }
+            let read = reader.take(size).read_to_end(&mut body).map_err(|_| truncated())?;
+            if read as u64 != size || read_line(reader)?.trim_end() != "" {
+                return Err(truncated());
+            }
+        }
+        while !read_line(reader)?.trim_end().is_empty() {}
+        Ok(body)
+    }
+
+    /// The pairs of the `Cookie` headers, there may be several. Quotes around values are removed.
+    pub(crate) fn cookies(&self) -> HashMap<String, String> {
+        self.headers.get_all(HttpHeader::COOKIE).iter()
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| {
+                let value = value.trim();
+                let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                (name.trim().to_string(), value.to_string())
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect()
+    }
+
+    /// Whether the `Accept` header allows `media_type` with a non-zero quality. A request without
+    /// `Accept` takes anything.
+    pub(crate) fn accepts(&self, media_type: &str) -> bool {
+        self.quality(media_type).is_some_and(|(q, _)| q > 0.0)
+    }
+
+    /// The type from `offered` the client prefers: highest `q` first, then the one matched by the
+    /// most specific range (`text/html` over `text/*` over `*/*`), then the earlier offer.
+    pub(crate) fn preferred_type<'o>(&self, offered: &[&'o str]) -> Option<&'o str> {
+        offered.iter()
+            .enumerate()
+            .filter_map(|(i, media_type)| {
+                let (q, specificity) = self.quality(media_type).filter(|(q, _)| *q > 0.0)?;
+                Some((q, specificity, Reverse(i), *media_type))
+            })
+            .max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(_, _, _, media_type)| media_type)
+    }
+
+    /// The quality the `Accept` header gives `media_type` and the specificity of the range it
+    /// was taken from (0 for `*/*`, 1 for `type/*`, 2 for an exact match), `None` if no range matches.
+    fn quality(&self, media_type: &str) -> Option<(f32, u8)> {
+        let Some(accept) = self.headers.get(HttpHeader::ACCEPT) else {
+            return Some((1.0, 0));
         };
+        let media_type = media_type.to_ascii_lowercase();
+        let (main_type, _) = media_type.split_once('/')?;
+        accept.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let range = parts.next()?.trim().to_ascii_lowercase();
+                let specificity = match range.split_once('/')? {
+                    ("*", "*") => 0,
+                    (main, "*") if main == main_type => 1,
+                    _ if range == media_type => 2,
+                    _ => return None,
+                };
+                let q = parts
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                    .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+                Some((q, specificity))
+            })
+            // the most specific matching range decides, whatever its quality
+            .max_by_key(|(_, specificity)| *specificity)
+    }
 
-        let body = std::str::from_utf8(&body).unwrap();
+    /// HTTP/1.1 connections are persistent unless the client sends `Connection: close`,
+    /// HTTP/1.0 ones only when it asks for `Connection: keep-alive`.
+    fn wants_keep_alive(&self) -> bool {
+        // `Connection` is a list of options, e.g. `keep-alive, Upgrade`
+        let has_option = |option: &str| self.headers.get_all(HttpHeader::CONNECTION).iter()
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(option));
+        match self.version {
+            HttpVersion::Http10 => has_option("keep-alive"),
+            HttpVersion::Http11 => !has_option("close"),
+        }
+    }
 
-        let body = JsonParser::new(body).parse_to_map();
-        Some(body)
+    fn parse_request_line(request_line: &str) -> Result<(HttpMethod, &str, HttpVersion), RequestParseError> {
+        let [method, path, version] = request_line.split(' ').collect::<Vec<_>>()[..] else {
+            return Err(RequestParseError::BadRequestLine);
+        };
+        let method: HttpMethod = method.parse().map_err(|_| {
+            if !method.is_empty() && method.bytes().all(|b| b.is_ascii_alphabetic()) {
+                RequestParseError::UnsupportedMethod(method.to_string())
+            } else {
+                RequestParseError::BadRequestLine
+            }
+        })?;
+        if path.is_empty() {
+            return Err(RequestParseError::BadRequestLine);
+        }
+        match version.strip_prefix("HTTP/").map(|number| number.as_bytes()) {
+            Some(b"1.0") => Ok((method, path, HttpVersion::Http10)),
+            Some(b"1.1") => Ok((method, path, HttpVersion::Http11)),
+            Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                Err(RequestParseError::UnsupportedVersion(version.to_string()))
+            }
+            _ => Err(RequestParseError::BadRequestLine)
+        }
     }
 
-    fn parse_header(header_str: &str) -> HashMap<String, String> {
+    pub(crate) fn parse_header(header_str: &str) -> Result<HeaderMap, RequestParseError> {
         header_str
             .split("\r\n")
-            .filter_map(|x| x.split_once(':'))
-            .map(|(a, b)| (a.trim().to_string(), b.trim().to_string()))
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (a, b) = line.split_once(':').ok_or(RequestParseError::InvalidHeader)?;
+                Ok((a.trim().to_string(), b.trim().to_string()))
+            })
             .collect()
     }
-    fn parse_query_params(url_path: &str) -> HashMap<String, String> {
+    /// The decoded query parameters of `url_path`, a repeated name keeping all its values, e.g.
+    /// `["a", "b"]` for `?tag=a&tag=b`. `None` if one of them has an invalid escape.
+    pub(crate) fn parse_query_params(url_path: &str) -> Option<HashMap<String, Vec<String>>> {
+        let mut params: HashMap<String, Vec<String>> = HashMap::new();
         if let Some(tuple) = url_path.split_once("?") {
-            let query_params = tuple.1.split("&")
-                .filter_map(|query| query.split_once("="))
-                .map(|(a, b)| (a.trim().to_string(), b.trim().to_string()))
-                .collect();
-            return query_params;
+            for (a, b) in tuple.1.split("&").filter_map(|query| query.split_once("=")) {
+                params.entry(percent_decode(a.trim(), true)?)
+                    .or_default()
+                    .push(percent_decode(b.trim(), true)?);
+            }
         }
-        return HashMap::new();
+        Some(params)
     }
 }
 
+/// See `HttpContext::client_ip`.
+fn client_ip(request: &HttpRequest, remote_addr: SocketAddr, trust_proxy: bool) -> IpAddr {
+    let forwarded = || {
+        let headers = &request.headers;
+        let first_forwarded = headers.get_all(HttpHeader::X_FORWARDED_FOR).iter()
+            .flat_map(|value| value.split(','))
+            .next();
+        first_forwarded.or_else(|| headers.get(HttpHeader::X_REAL_IP))
+            .and_then(|ip| ip.trim().parse().ok())
+    };
+    trust_proxy.then(forwarded).flatten().unwrap_or(remote_addr.ip())
+}
+
+/// The connection a request arrived on, as handed to `HttpContext::new`.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) remote_addr: SocketAddr,
+    /// 1 for the first request on the connection, counting up on a persistent one.
+    pub(crate) request_number: usize,
+    /// Whether `HttpContext::client_ip` believes the forwarding headers, see `HttpServer::trust_proxy`.
+    pub(crate) trust_proxy: bool,
+}
+
 pub(crate) struct HttpContext<'a> {
     pub path_params: HashMap<String, String>,
-    pub query_params: HashMap<String, String>,
+    /// The request's query parameters, for every route kind alike.
+    pub query_params: &'a HashMap<String, Vec<String>>,
     pub request: &'a HttpRequest,
+    /// Only filled in for the method-not-allowed handler, sorted.
+    pub allowed_methods: Vec<HttpMethod>,
+    /// The peer of the connection, a proxy's address when behind one, see `client_ip`.
+    pub remote_addr: SocketAddr,
+    /// 1 for the first request on the connection, counting up on a persistent one.
+    pub request_number: usize,
+    trust_proxy: bool,
 }
 
 impl<'a> HttpContext<'a> {
-    pub fn new(path_params: HashMap<String, String>, query_params: HashMap<String, String>, request: &'a HttpRequest) -> Self {
+    pub fn new(path_params: HashMap<String, String>, request: &'a HttpRequest, connection: ConnectionInfo) -> Self {
         HttpContext {
             path_params,
-            query_params,
+            query_params: &request.query_params,
             request,
+            allowed_methods: Vec::new(),
+            remote_addr: connection.remote_addr,
+            request_number: connection.request_number,
+            trust_proxy: connection.trust_proxy,
         }
     }
 
+    /// The address of the client. When the server trusts its proxy, that is the first address of
+    /// `X-Forwarded-For`, or else `X-Real-IP`, since the proxy is the peer; otherwise, and when
+    /// neither header holds an address, the peer's. Only trust a proxy that overwrites these
+    /// headers, clients can send them too.
+    pub fn client_ip(&self) -> IpAddr {
+        client_ip(self.request, self.remote_addr, self.trust_proxy)
+    }
+
+    pub fn accepts(&self, media_type: &str) -> bool {
+        self.request.accepts(media_type)
+    }
+
+    pub fn preferred_type<'o>(&self, offered: &[&'o str]) -> Option<&'o str> {
+        self.request.preferred_type(offered)
+    }
+
     pub fn get_path_param(&self, path_variable: &str) -> Option<&String> {
         self.path_params.get(path_variable)
     }
 
+    /// The first value of the query parameter, see `get_query_params` for all of them.
     pub fn get_query_param(&self, query_variable: &str) -> Option<&String> {
+        self.query_params.get(query_variable).and_then(|values| values.first())
+    }
+
+    /// Every value of a repeated query parameter such as `?tag=a&tag=b`, empty if it is missing.
+    pub fn get_query_params(&self, query_variable: &str) -> Vec<&str> {
         self.query_params.get(query_variable)
+            .map_or_else(Vec::new, |values| values.iter().map(String::as_str).collect())
+    }
+
+    /// Like `get_query_params`, but also collects the values of `name[]`, as in
+    /// `?ids[]=1&ids[]=2`; those of `name` come first if a url mixes both conventions.
+    pub fn get_query_param_list(&self, name: &str) -> Vec<&str> {
+        let mut values = self.get_query_params(name);
+        values.extend(self.get_query_params(&format!("{}[]", name)));
+        values
+    }
+
+    /// Every value of `get_query_param_list` parsed as `T`, failing on the first that is not one.
+    /// A missing parameter is an empty list.
+    pub fn get_query_param_list_as<T: FromStr>(&self, name: &str) -> Result<Vec<T>, ParamError> {
+        self.get_query_param_list(name).into_iter()
+            .enumerate()
+            .map(|(index, value)| value.parse().map_err(|_| ParamError::InvalidElement {
+                name: name.to_string(),
+                index,
+                value: value.to_string(),
+                expected: std::any::type_name::<T>().rsplit("::").next().unwrap_or_default(),
+            }))
+            .collect()
+    }
+
+    /// The ID tracing this request, see `HttpServer::enable_request_ids`.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request.request_id.as_deref()
+    }
+
+    /// Who a `do_before` filter authenticated the request as, see `HttpRequest::principal`.
+    pub fn principal(&self) -> Option<&str> {
+        self.request.principal()
+    }
+
+    /// The JSON body deserialized into `T`, see `HttpRequest::body_as`. A failure converts into
+    /// a 400, so handlers can `return e.into()`.
+    pub fn body_as<T: JsonDeserializable>(&self) -> Result<T, BodyError> {
+        self.request.body_as()
+    }
+
+    pub fn get_path_param_as<T: FromStr>(&self, path_variable: &str) -> Result<T, ParamError> {
+        Self::parse_param(path_variable, self.get_path_param(path_variable))
+    }
+
+    pub fn get_query_param_as<T: FromStr>(&self, query_variable: &str) -> Result<T, ParamError> {
+        Self::parse_param(query_variable, self.get_query_param(query_variable))
+    }
+
+    /// `default` when the query parameter is missing or cannot be parsed.
+    pub fn get_query_param_or<T: FromStr>(&self, query_variable: &str, default: T) -> T {
+        self.get_query_param_as(query_variable).unwrap_or(default)
+    }
+
+    fn parse_param<T: FromStr>(name: &str, value: Option<&String>) -> Result<T, ParamError> {
+        let value = value.ok_or_else(|| ParamError::Missing { name: name.to_string() })?;
+        value.parse().map_err(|_| ParamError::Invalid {
+            name: name.to_string(),
+            value: value.clone(),
+            expected: std::any::type_name::<T>().rsplit("::").next().unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ParamError {
+    Missing { name: String },
+    Invalid { name: String, value: String, expected: &'static str },
+    /// The value at `index` of a list parameter, see `HttpContext::get_query_param_list_as`.
+    InvalidElement { name: String, index: usize, value: String, expected: &'static str },
+}
+
+impl Display for ParamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Missing { name } => write!(f, "missing parameter `{name}`"),
+            ParamError::Invalid { name, value, expected } => {
+                write!(f, "parameter `{name}` must be a valid {expected}, got `{value}`")
+            }
+            ParamError::InvalidElement { name, index, value, expected } => {
+                write!(f, "element {index} of parameter `{name}` must be a valid {expected}, got `{value}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// Why `HttpContext::body_as` could not produce a value.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BodyError {
+    /// The `Content-type` is not JSON, `None` without one.
+    UnsupportedContentType(Option<String>),
+    /// The body is not a JSON document.
+    InvalidJson(JsonError),
+    /// The document does not have the shape of the target type.
+    InvalidField(JsonError),
+}
+
+impl Display for BodyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::UnsupportedContentType(None) => write!(f, "expected a JSON body, got no content type"),
+            BodyError::UnsupportedContentType(Some(content_type)) => {
+                write!(f, "expected a JSON body, got `{content_type}`")
+            }
+            BodyError::InvalidJson(error) => write!(f, "malformed JSON body: {error}"),
+            BodyError::InvalidField(error) => write!(f, "invalid request body: {error}"),
+        }
     }
 }
 
+impl std::error::Error for BodyError {}
+
+/// A 400 whose body explains what was wrong with the request body.
+impl From<BodyError> for HttpResponse {
+    fn from(error: BodyError) -> Self {
+        HttpResponse::bad_request().text(&error.to_string())
+    }
+}
+
+/// A 400 whose body explains which parameter was wrong, so handlers can `return e.into()`.
+impl From<ParamError> for HttpResponse {
+    fn from(error: ParamError) -> Self {
+        HttpResponse::bad_request_with_data(error.to_string().into_bytes())
+    }
+}
+
+/// A 400 for a body that is not a JSON object, explaining why as `{"error", "line", "column"}`;
+/// the position is 1-based, in characters, and `null` for errors without one.
+pub(crate) fn invalid_json_response(body: &[u8], error: &JsonError) -> HttpResponse {
+    let position = match error {
+        JsonError::UnexpectedEof => Some(body.len()),
+        _ => error.position(),
+    };
+    let mut details = JsonMap::new();
+    details.insert("error".to_string(), DataType::String(error.to_string()));
+    let (line, column) = match position {
+        Some(position) => {
+            let before = String::from_utf8_lossy(&body[..position.min(body.len())]);
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            let line = before.matches('\n').count() + 1;
+            let column = before[line_start..].chars().count() + 1;
+            (DataType::Int(line as i32), DataType::Int(column as i32))
+        }
+        None => (DataType::Null, DataType::Null),
+    };
+    details.insert("line".to_string(), line);
+    details.insert("column".to_string(), column);
+    HttpResponse::with_status(HttpStatus::BAD_REQUEST).json(&details)
+}
+
+/// A response body, either in memory or read incrementally while it is written to the client.
+pub(crate) enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Sent with a `Content-length` when `length` is known, chunked otherwise.
+    Stream { reader: Box<dyn Read + Send>, length: Option<u64> },
+    /// `length` bytes from the file's current position, see `HttpResponse::file`.
+    File { file: File, length: u64 },
+    /// Server-sent events pushed by the callback, the connection closes once it returns.
+    Events(Box<dyn FnOnce(&mut SseSink<'_>) + Send>),
+}
+
 pub(crate) struct HttpResponse {
     pub(crate) status: u32,
-    headers: HashMap<String, String>,
-    pub(crate) data: Option<Vec<u8>>,
+    headers: HeaderMap,
+    /// Each cookie needs a `Set-Cookie` header of its own, so they are kept apart from `headers`.
+    cookies: Vec<Cookie>,
+    pub(crate) body: Body,
+    /// Set for HEAD requests: the headers describe the body, which is not sent.
+    omit_body: bool,
 }
 
 impl<'a> HttpResponse {
     const BREAK_LINE: &'a str = "\r\n";
+    const STREAM_BUFFER_SIZE: usize = 8192;
 
     pub(crate) fn set_header(&mut self, key: String, value:String) {
-        self.headers.insert(key, value);
+        self.headers.insert(&key, &value);
+    }
+
+    /// Sets `key` unless the handler already did.
+    fn set_default_header(&mut self, key: &str, value: &str) {
+        if !self.headers.contains(key) {
+            self.headers.insert(key, value);
+        }
+    }
+
+    /// Adds another `key` header line rather than replacing the existing value, e.g. for `Link`.
+    pub(crate) fn append_header(&mut self, key: &str, value: &str) {
+        self.headers.append(key, value);
+    }
+
+    pub(crate) fn add_cookie(&mut self, cookie: Cookie) {
+        self.cookies.push(cookie);
+    }
+
+    pub(crate) fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    /// A copy of the response if its body is in memory, `None` for streams, files and events.
+    pub(crate) fn try_clone(&self) -> Option<HttpResponse> {
+        let body = match &self.body {
+            Body::Empty => Body::Empty,
+            Body::Bytes(data) => Body::Bytes(data.clone()),
+            _ => return None,
+        };
+        Some(HttpResponse {
+            status: self.status,
+            headers: self.headers.clone(),
+            cookies: self.cookies.clone(),
+            body,
+            omit_body: self.omit_body,
+        })
     }
 
     pub(crate) fn ok() -> HttpResponse {
@@ -204,77 +1053,1102 @@ impl<'a> HttpResponse {
         HttpResponse::build_response(HttpStatus::BAD_REQUEST, None)
     }
 
+    pub(crate) fn no_content() -> HttpResponse {
+        HttpResponse::build_response(HttpStatus::NO_CONTENT, None)
+    }
+
+    pub(crate) fn not_found() -> HttpResponse {
+        HttpResponse::build_response(HttpStatus::NOT_FOUND, None)
+    }
+
+    pub(crate) fn internal_error() -> HttpResponse {
+        HttpResponse::build_response(HttpStatus::INTERNAL_ERROR, None)
+    }
+
+    /// An empty response to build on, e.g. `HttpResponse::with_status(201).json(&user)`.
+    pub(crate) fn with_status(status: u32) -> HttpResponse {
+        HttpResponse::build_response(status, None)
+    }
+
+    pub(crate) fn header(mut self, key: &str, value: &str) -> HttpResponse {
+        self.set_header(key.to_string(), value.to_string());
+        self
+    }
+
+    pub(crate) fn content_type(self, media_type: &str) -> HttpResponse {
+        self.header(HttpHeader::CONTENT_TYPE, media_type)
+    }
+
+    pub(crate) fn body(mut self, data: Vec<u8>) -> HttpResponse {
+        self.body = Body::Bytes(data);
+        self
+    }
+
+    /// A `text/plain; charset=utf-8` body.
+    pub(crate) fn text(self, text: &str) -> HttpResponse {
+        self.content_type(&format!("{}; charset=utf-8", MediaType::TEXT_PLAIN)).body(text.as_bytes().to_vec())
+    }
+
+    /// A `text/html; charset=utf-8` body, see `file` for HTML pages on disk.
+    pub(crate) fn html(self, html: &str) -> HttpResponse {
+        self.content_type(&format!("{}; charset=utf-8", MediaType::TEXT_HTML)).body(html.as_bytes().to_vec())
+    }
+
+    pub(crate) fn json<T: JsonSerializable + ?Sized>(self, value: &T) -> HttpResponse {
+        let json = Serializer::new().serialize(value);
+        self.content_type(MediaType::APPLICATION_JSON).body(json.into_bytes())
+    }
+
+    /// Sets the `ETag` header, quoting `tag` unless it already is an entity tag such as `W/"v2"`.
+    pub(crate) fn with_etag(self, tag: &str) -> HttpResponse {
+        self.header(HttpHeader::ETAG, &etag::quote(tag))
+    }
+
+    /// Sets a strong `ETag` hashed from the body, unless the response already has one or its body
+    /// is not buffered in memory.
+    pub(crate) fn auto_etag(self) -> HttpResponse {
+        match &self.body {
+            Body::Bytes(data) if self.get_header(HttpHeader::ETAG).is_none() => {
+                let tag = etag::body_etag(data);
+                self.header(HttpHeader::ETAG, &tag)
+            }
+            _ => self,
+        }
+    }
+
     pub(crate) fn bad_request_with_data(data: Vec<u8>) -> HttpResponse {
         HttpResponse {
             status: HttpStatus::BAD_REQUEST,
-            headers: HashMap::new(),
-            data: Some(data),
+            headers: HeaderMap::new(),
+            body: Body::Bytes(data),
+            cookies: Vec::new(),
+            omit_body: false,
+        }
+    }
+
+    /// A 200 response streaming `reader` with chunked encoding, as its length is unknown.
+    pub(crate) fn from_reader<R: Read + Send + 'static>(reader: R, content_type: &str) -> HttpResponse {
+        Self::stream(reader, None, content_type)
+    }
+
+    /// A 200 response streaming exactly `length` bytes of `reader`, e.g. a file of known size.
+    pub(crate) fn from_sized_reader<R: Read + Send + 'static>(reader: R, length: u64, content_type: &str) -> HttpResponse {
+        Self::stream(reader, Some(length), content_type)
+    }
+
+    /// A 200 `text/event-stream` response running `events` on the worker thread, which holds on
+    /// to the connection until `events` returns, e.g. after noticing a send failed because the
+    /// client went away.
+    pub(crate) fn sse<F: FnOnce(&mut SseSink<'_>) + Send + 'static>(events: F) -> HttpResponse {
+        HttpResponse::ok()
+            .content_type(MediaType::SERVER_SENT_EVENTS)
+            .header(HttpHeader::CACHE_CONTROL, "no-cache")
+            .with_body(Body::Events(Box::new(events)))
+    }
+
+    fn with_body(mut self, body: Body) -> HttpResponse {
+        self.body = body;
+        self
+    }
+
+    /// A 200 response with the file at `path`, its `Content-type` guessed from the extension.
+    /// It advertises `Accept-Ranges: bytes`, so a `Range` request gets just the requested part.
+    /// A missing file gives a 404, a directory or an unreadable file a 403.
+    pub(crate) fn file<P: AsRef<Path>>(path: P) -> HttpResponse {
+        let path = path.as_ref();
+        let opened = File::open(path).and_then(|file| Ok((file.metadata()?, file)));
+        let (metadata, file) = match opened {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return HttpResponse::not_found(),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return HttpResponse::with_status(HttpStatus::FORBIDDEN),
+            Err(_) => return HttpResponse::internal_error(),
+        };
+        if metadata.is_dir() {
+            return HttpResponse::with_status(HttpStatus::FORBIDDEN);
         }
+        let content_type = path.extension()
+            .and_then(|extension| extension.to_str())
+            .map_or(MediaType::APPLICATION_OCTET_STREAM, MediaType::from_extension);
+        HttpResponse::ok()
+            .content_type(content_type)
+            .header(HttpHeader::ACCEPT_RANGES, "bytes")
+            .with_body(Body::File { file, length: metadata.len() })
+    }
+
+    /// Narrows a 200 response advertising `Accept-Ranges: bytes` to the single range requested
+    /// by a `Range` header: a 206 with `Content-Range` when the range overlaps the body, a 416
+    /// otherwise. Multiple ranges and malformed headers are ignored, leaving the full response.
+    pub(crate) fn apply_range(&mut self, range: &str) {
+        let ranges_accepted = self.get_header(HttpHeader::ACCEPT_RANGES).is_some_and(|unit| unit.eq_ignore_ascii_case("bytes"));
+        if self.status != HttpStatus::OK || !ranges_accepted || !matches!(self.body, Body::Bytes(_) | Body::File { .. }) {
+            return;
+        }
+        let Some(length) = self.content_length() else {
+            return;
+        };
+        match ByteRange::parse(range, length) {
+            ByteRange::Full => {}
+            ByteRange::Unsatisfiable => {
+                self.status = HttpStatus::RANGE_NOT_SATISFIABLE;
+                self.set_header(HttpHeader::CONTENT_RANGE.to_string(), format!("bytes */{}", length));
+                self.body = Body::Empty;
+            }
+            ByteRange::Partial(first, last) => {
+                self.body = match std::mem::replace(&mut self.body, Body::Empty) {
+                    Body::Bytes(data) => Body::Bytes(data[first as usize..=last as usize].to_vec()),
+                    Body::File { mut file, .. } => match file.seek(SeekFrom::Current(first as i64)) {
+                        Ok(_) => Body::File { file, length: last - first + 1 },
+                        Err(_) => {
+                            *self = HttpResponse::internal_error();
+                            return;
+                        }
+                    },
+                    body => body,
+                };
+                self.status = HttpStatus::PARTIAL_CONTENT;
+                self.set_header(HttpHeader::CONTENT_RANGE.to_string(), format!("bytes {}-{}/{}", first, last, length));
+            }
+        }
+    }
+
+    fn stream<R: Read + Send + 'static>(reader: R, length: Option<u64>, content_type: &str) -> HttpResponse {
+        let mut response = HttpResponse {
+            status: HttpStatus::OK,
+            headers: HeaderMap::new(),
+            body: Body::Stream { reader: Box::new(reader), length },
+            cookies: Vec::new(),
+            omit_body: false,
+        };
+        response.set_header(HttpHeader::CONTENT_TYPE.to_string(), content_type.to_string());
+        response
+    }
+
+    pub(crate) fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+
+    pub(crate) fn status_text(&self) -> &'static str {
+        HttpStatus::reason_phrase(self.status)
     }
 
     pub(crate) fn build_response(status: u32, data: Option<Vec<u8>>) -> HttpResponse {
-        let mut headers = HashMap::new();
+        let headers = HeaderMap::new();
         HttpResponse {
             status,
             headers,
-            data
+            body: data.map_or(Body::Empty, Body::Bytes),
+            cookies: Vec::new(),
+            omit_body: false,
         }
     }
 
-    pub(crate) fn get_output_as_bytes(self, version: &str) -> Vec<u8> {
-        let status_line = format!("{} {} OK", version, self.status.to_string());
-        let mut response_detail = String::new();
-        let mut headers = &self.headers;
+    /// Keeps the headers, including the `Content-length` of the original body, but sends no body.
+    pub(crate) fn strip_body(&mut self) {
+        self.omit_body = true;
+    }
 
-        response_detail.push_str(status_line.as_str());
-        response_detail.push_str(Self::BREAK_LINE);
-        headers.iter().for_each(|(k, v)| {
-            response_detail.push_str(k.as_str());
-            response_detail.push_str(":");
-            response_detail.push_str(v.as_str());
-            response_detail.push_str(Self::BREAK_LINE);
-        });
+    /// The body length if known up front, `None` for streams sent chunked and event streams.
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        match &self.body {
+            Body::Empty => Some(0),
+            Body::Bytes(data) => Some(data.len() as u64),
+            Body::Stream { length, .. } => *length,
+            Body::File { length, .. } => Some(*length),
+            Body::Events(_) => None,
+        }
+    }
+
+    /// 1xx, 204 and 304 responses never have a body, nor a `Content-length` describing one.
+    fn is_bodiless(&self) -> bool {
+        self.status < 200 || self.status == HttpStatus::NO_CONTENT || self.status == HttpStatus::NOT_MODIFIED
+    }
+
+    /// Whether the client can tell where the body ends without the connection closing.
+    pub(crate) fn is_delimited(&self, version: HttpVersion) -> bool {
+        match self.body {
+            _ if self.is_bodiless() => true,
+            Body::Events(_) => false,
+            _ => self.content_length().is_some() || version != HttpVersion::Http10,
+        }
+    }
+
+    /// Writes the status line, headers and body, adding a `Date` unless the handler set one.
+    /// Streams of unknown length are chunked, except for HTTP/1.0 clients which cannot decode that:
+    /// their body simply ends with the connection, as does an event stream. Returns the number of
+    /// body bytes sent, chunk framing excluded.
+    pub(crate) fn write_to<W: Write>(mut self, version: HttpVersion, writer: &mut W) -> io::Result<u64> {
+        self.set_default_header(HttpHeader::DATE, &http_date(SystemTime::now()));
+        // the body decides the framing, whatever the handler set
+        self.headers.remove(HttpHeader::CONTENT_LENGTH);
+        self.headers.remove(HttpHeader::TRANSFER_ENCODING);
+        let (head, chunked) = self.render_head(version);
+        writer.write_all(head.as_bytes())?;
+
+        if self.omit_body || self.is_bodiless() {
+            writer.flush()?;
+            return Ok(0);
+        }
+        let sent = match self.body {
+            Body::Empty => 0,
+            Body::Bytes(data) => {
+                writer.write_all(&data)?;
+                data.len() as u64
+            }
+            Body::Stream { reader, length: Some(length) } => Self::copy_exactly(reader, length, writer)?,
+            Body::File { file, length } => Self::copy_exactly(file, length, writer)?,
+            Body::Stream { mut reader, length: None } => Self::copy_stream(&mut reader, writer, chunked)?,
+            Body::Events(events) => {
+                writer.flush()?;
+                // a client hanging up is how event streams usually end, not an error
+                let mut sink = SseSink::new(writer);
+                events(&mut sink);
+                return Ok(sink.bytes_sent());
+            }
+        };
+        writer.flush()?;
+        Ok(sent)
+    }
+
+    /// The status line and headers up to the blank line ending them, and whether the body is
+    /// sent chunked. The framing header is derived from the body, `write_to` drops any the
+    /// handler set.
+    fn render_head(&self, version: HttpVersion) -> (String, bool) {
+        let mut head = String::with_capacity(256);
+        // formatting into a String cannot fail
+        let _ = write!(head, "{} {} {}{}", version, self.status, self.status_text(), Self::BREAK_LINE);
+        for (name, value) in self.headers.iter() {
+            let _ = write!(head, "{}:{}{}", name, value, Self::BREAK_LINE);
+        }
+        for cookie in &self.cookies {
+            let _ = write!(head, "{}:{}{}", HttpHeader::SET_COOKIE, cookie, Self::BREAK_LINE);
+        }
+
+        let chunked = match self.content_length() {
+            _ if self.is_bodiless() => false,
+            Some(length) => {
+                let _ = write!(head, "{}:{}{}", HttpHeader::CONTENT_LENGTH, length, Self::BREAK_LINE);
+                false
+            }
+            None if !self.is_delimited(version) => false,
+            None => {
+                let _ = write!(head, "{}:chunked{}", HttpHeader::TRANSFER_ENCODING, Self::BREAK_LINE);
+                true
+            }
+        };
+        head.push_str(Self::BREAK_LINE);
+        (head, chunked)
+    }
+
+    fn copy_exactly<R: Read, W: Write>(reader: R, length: u64, writer: &mut W) -> io::Result<u64> {
+        let copied = Self::copy_stream(&mut reader.take(length), writer, false)?;
+        if copied != length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "response stream ended before its declared length"));
+        }
+        Ok(copied)
+    }
+
+    fn copy_stream<R: Read, W: Write>(reader: &mut R, writer: &mut W, chunked: bool) -> io::Result<u64> {
+        let mut buffer = [0; Self::STREAM_BUFFER_SIZE];
+        let mut copied = 0;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if chunked {
+                write!(writer, "{:x}\r\n", read)?;
+                writer.write_all(&buffer[..read])?;
+                writer.write_all(Self::BREAK_LINE.as_bytes())?;
+            } else {
+                writer.write_all(&buffer[..read])?;
+            }
+            copied += read as u64;
+        }
+        if chunked {
+            writer.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(copied)
+    }
+}
 
-        let content = self.data.unwrap_or(vec![]);
-        response_detail.push_str(HttpHeader::CONTENT_LENGTH);
-        response_detail.push_str(":");
-        response_detail.push_str(content.len().to_string().as_str());
-        response_detail.push_str(Self::BREAK_LINE);
-        response_detail.push_str(Self::BREAK_LINE);
-        let mut response_detail = response_detail.into_bytes();
-        response_detail.extend(content);
-        response_detail
+/// What a `Range` header asks of a body of a given length.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No usable range, the whole body is sent.
+    Full,
+    /// The first and last byte, inclusive and within the body.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parses `bytes=first-last`, `bytes=first-` and the suffix form `bytes=-count`.
+    fn parse(header: &str, length: u64) -> ByteRange {
+        let number = |s: &str| if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) { s.parse::<u64>().ok() } else { None };
+        let Some(spec) = header.trim().strip_prefix("bytes=") else {
+            return ByteRange::Full;
+        };
+        let Some((first, last)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+            return ByteRange::Full;
+        };
+        match (first.trim(), last.trim()) {
+            ("", count) => match number(count) {
+                None => ByteRange::Full,
+                Some(0) => ByteRange::Unsatisfiable,
+                Some(_) if length == 0 => ByteRange::Unsatisfiable,
+                Some(count) => ByteRange::Partial(length - count.min(length), length - 1),
+            },
+            (first, last) => {
+                let Some(first) = number(first) else {
+                    return ByteRange::Full;
+                };
+                let last = match last {
+                    "" => length.saturating_sub(1),
+                    last => match number(last) {
+                        Some(last) if last >= first => last.min(length.saturating_sub(1)),
+                        _ => return ByteRange::Full,
+                    },
+                };
+                if first >= length {
+                    ByteRange::Unsatisfiable
+                } else {
+                    ByteRange::Partial(first, last)
+                }
+            }
+        }
+    }
+}
+
+/// Limits on persistent connections: how long to wait for the next request and how many
+/// requests to serve before closing. The wait also bounds every read of a request, the first one
+/// of a connection included, so that idle clients cannot hold on to a worker.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct KeepAlive {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+/// Bounds on what a client may send, checked while reading so that an oversized request is
+/// rejected before it is buffered.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct RequestLimits {
+    /// The request line and headers together.
+    pub(crate) max_header_size: usize,
+    pub(crate) max_body_size: u64,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_header_size: 16 * 1024,
+            max_body_size: 1024 * 1024,
+        }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct HttpConnection {
     tcp_stream: TcpStream,
+    reader: BufReader<TcpStream>,
     pub(crate) socket_addr: SocketAddr,
     pub(crate) request: HttpRequest,
+    keep_alive: KeepAlive,
+    limits: RequestLimits,
+    /// Sent as the `Server` header unless the handler set one, left out when empty.
+    server_name: Arc<str>,
+    /// See `HttpServer::trust_proxy`.
+    trust_proxy: bool,
+    served: usize,
+    persistent: bool,
 }
 
 impl<'a> Drop for HttpConnection {
     fn drop(&mut self) {
-        self.tcp_stream.shutdown(Shutdown::Both).unwrap()
+        // the client may already have closed its side
+        let _ = self.tcp_stream.shutdown(Shutdown::Both);
     }
 }
 
 impl<'a> HttpConnection {
+    const LINGER_TIMEOUT: Duration = Duration::from_millis(200);
+    const LINGER_LIMIT: u64 = 64 * 1024;
 
-    pub(crate) fn new(connection: (TcpStream, SocketAddr)) -> Self {
-        HttpConnection {
-            request: HttpRequest::new(&connection.0).unwrap(),
+    /// Reads the first request of a connection, waiting up to the idle timeout for each read.
+    /// Malformed requests are answered with a 4xx or 5xx status before the connection is dropped,
+    /// see `RequestParseError::status`.
+    pub(crate) fn new(mut connection: (TcpStream, SocketAddr), keep_alive: KeepAlive, limits: RequestLimits, server_name: Arc<str>, trust_proxy: bool) -> Option<Self> {
+        connection.0.set_read_timeout(Some(keep_alive.idle_timeout)).ok()?;
+        let mut reader = BufReader::new(connection.0.try_clone().ok()?);
+        let request = match HttpRequest::new(&mut reader, &limits) {
+            Ok(request) => request,
+            Err(e) => {
+                Self::reject(&mut connection.0, e, &server_name);
+                return None;
+            }
+        };
+        Some(HttpConnection {
+            request,
+            reader,
             tcp_stream: connection.0,
             socket_addr: connection.1,
+            keep_alive,
+            limits,
+            server_name,
+            trust_proxy,
+            served: 0,
+            persistent: false,
+        })
+    }
+
+    /// Waits up to the idle timeout for another request on a persistent connection,
+    /// returning `false` when the connection should be closed instead.
+    pub(crate) fn next_request(&mut self) -> bool {
+        if !self.persistent {
+            return false;
+        }
+        match HttpRequest::new(&mut self.reader, &self.limits) {
+            Ok(request) => {
+                self.request = request;
+                true
+            }
+            Err(e) => {
+                Self::reject(&mut self.tcp_stream, e, &self.server_name);
+                false
+            }
         }
     }
 
-    pub(crate) fn response(&mut self, response: HttpResponse) {
-        self.tcp_stream.write_all(&response.get_output_as_bytes(self.request.version.as_str())).unwrap();
+    /// Answers a request that could not be parsed with the matching 4xx/5xx status, then closes.
+    fn reject(stream: &mut TcpStream, error: RequestParseError, server_name: &str) {
+        if let RequestParseError::ConnectionClosed = error {
+            return;
+        }
+        let mut response = HttpResponse::with_status(error.status())
+            .header(HttpHeader::CONNECTION, "close")
+            .text(&error.to_string());
+        if !server_name.is_empty() {
+            response.set_default_header(HttpHeader::SERVER, server_name);
+        }
+        if response.write_to(HttpVersion::Http11, stream).is_err() {
+            return;
+        }
+        // closing with unread input would reset the connection, possibly before the client read
+        // the response, so stop writing and briefly drain what it is still sending
+        let _ = stream.shutdown(Shutdown::Write);
+        if stream.set_read_timeout(Some(Self::LINGER_TIMEOUT)).is_ok() {
+            let _ = io::copy(&mut stream.take(Self::LINGER_LIMIT), &mut io::sink());
+        }
+    }
+
+    /// What handlers learn about this connection through `HttpContext`.
+    pub(crate) fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            remote_addr: self.socket_addr,
+            request_number: self.served + 1,
+            trust_proxy: self.trust_proxy,
+        }
+    }
+
+    /// The address of the client of the current request, as `HttpContext::client_ip` has it.
+    pub(crate) fn client_ip(&self) -> IpAddr {
+        client_ip(&self.request, self.socket_addr, self.trust_proxy)
+    }
+
+    /// Sends `response`, returning the number of body bytes written. An error means the client
+    /// went away or stopped reading, the connection is not kept alive then.
+    pub(crate) fn response(&mut self, mut response: HttpResponse) -> io::Result<u64> {
+        self.served += 1;
+        let version = self.request.version;
+        self.persistent = response.is_delimited(version) && self.request.wants_keep_alive() && self.served < self.keep_alive.max_requests;
+        let connection = if self.persistent { "keep-alive" } else { "close" };
+        response.set_header(HttpHeader::CONNECTION.to_string(), connection.to_string());
+        if !self.server_name.is_empty() {
+            response.set_default_header(HttpHeader::SERVER, &self.server_name);
+        }
+        let written = response.write_to(version, &mut BufWriter::new(&self.tcp_stream));
+        if written.is_err() {
+            self.persistent = false;
+        }
+        written
     }
 
     pub(crate) fn close(&self) {
-        self.tcp_stream.shutdown(Shutdown::Both).unwrap()
+        // the client may already have closed its side
+        let _ = self.tcp_stream.shutdown(Shutdown::Both);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::http::client::HttpClient;
+    use crate::http::cookie::SameSite;
+    use crate::http::http_core::{HttpServer, ServerError};
+    use crate::http::testing::{raw_status, TestServer};
+    use super::*;
+
+    fn write(response: HttpResponse, version: HttpVersion) -> String {
+        let mut out = Vec::new();
+        response.write_to(version, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn framing_headers(written: &str) -> Vec<&str> {
+        written.split("\r\n\r\n").next().unwrap()
+            .lines()
+            .filter(|line| {
+                let name = line.split(':').next().unwrap_or_default();
+                name.eq_ignore_ascii_case(HttpHeader::CONTENT_LENGTH) || name.eq_ignore_ascii_case(HttpHeader::TRANSFER_ENCODING)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn framing_headers_of_the_handler_are_replaced() {
+        let response = HttpResponse::ok()
+            .header("content-length", "999")
+            .header("TRANSFER-ENCODING", "gzip, chunked")
+            .text("hello");
+        let written = write(response, HttpVersion::Http11);
+        assert_eq!(framing_headers(&written), ["Content-length:5"]);
+        assert!(written.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn streams_of_unknown_length_are_chunked_once() {
+        let response = HttpResponse::from_reader(&b"hello"[..], "text/plain")
+            .header("Content-Length", "5");
+        let written = write(response, HttpVersion::Http11);
+        assert_eq!(framing_headers(&written), ["Transfer-Encoding:chunked"]);
+        assert!(written.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+
+        let response = HttpResponse::from_sized_reader(&b"hello"[..], 5, "text/plain")
+            .header("transfer-encoding", "chunked");
+        assert_eq!(framing_headers(&write(response, HttpVersion::Http11)), ["Content-length:5"]);
+    }
+
+    #[test]
+    fn bodiless_responses_have_no_framing() {
+        let response = HttpResponse::with_status(HttpStatus::NO_CONTENT).header("Content-Length", "3");
+        assert!(framing_headers(&write(response, HttpVersion::Http11)).is_empty());
+    }
+
+    fn echo_server() -> TestServer {
+        TestServer::start(|server| {
+            server.register_end_point("/echo", HttpMethod::POST, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().content_type(MediaType::APPLICATION_OCTET_STREAM).body(r.request.body_bytes().to_vec())
+            }));
+        })
+    }
+
+    fn chunked_post(chunks: &str) -> String {
+        format!("POST /echo HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{chunks}")
+    }
+
+    #[test]
+    fn chunked_bodies_are_reassembled() {
+        let server = echo_server();
+        let response = server.send_raw(chunked_post("5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n").as_bytes());
+        assert_eq!(raw_status(&response), 200);
+        assert!(response.ends_with("\r\n\r\nhello, world"), "{response}");
+    }
+
+    #[test]
+    fn invalid_chunk_sizes_are_rejected() {
+        let server = echo_server();
+        assert_eq!(raw_status(&server.send_raw(chunked_post("zz\r\nhello\r\n0\r\n\r\n").as_bytes())), 400);
+        assert_eq!(raw_status(&server.send_raw(chunked_post("5\r\nhi").as_bytes())), 400);
+        // the sum with what was read so far would overflow
+        let huge = chunked_post("1\r\na\r\nffffffffffffffff\r\n");
+        assert_eq!(raw_status(&server.send_raw(huge.as_bytes())), 413);
+    }
+
+    #[test]
+    fn status_lines_carry_the_reason_phrase() {
+        assert_eq!(write(HttpResponse::not_found(), HttpVersion::Http11).split("\r\n").next(), Some("HTTP/1.1 404 Not Found"));
+        assert!(write(HttpResponse::with_status(418), HttpVersion::Http10).starts_with("HTTP/1.0 418 Client Error\r\n"));
+        assert_eq!(HttpStatus::reason_phrase(299), "Success");
+        assert_eq!(HttpStatus::reason_phrase(599), "Server Error");
+    }
+
+    /// Parses `raw` as it arrives on a real socket, the client having closed its write half.
+    fn read_request(raw: &[u8]) -> Result<HttpRequest, RequestParseError> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(raw).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reader = BufReader::new(listener.accept().unwrap().0);
+        HttpRequest::new(&mut reader, &RequestLimits::default())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn requests_without_a_body_have_an_empty_json_body() {
+        let request = read_request(b"GET /users?page=2 HTTP/1.1\r\nHost: x\r\nAccept: application/json\r\n\r\n").unwrap();
+        assert_eq!(request.method, HttpMethod::GET);
+        assert!(request.body_bytes().is_empty());
+        assert_eq!(request.body_json(), Ok(&JsonMap::new()));
+
+        let request = read_request(b"POST /users HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n").unwrap();
+        assert_eq!(request.body_json(), Ok(&JsonMap::new()));
+        // a body made of whitespace only is not a document
+        let request = read_request(b"POST /users HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\n\r\n \n\t ").unwrap();
+        assert_eq!(request.body_json(), Err(JsonError::UnexpectedEof));
+    }
+
+    fn context<'a>(request: &'a HttpRequest, path_params: &[(&str, &str)]) -> HttpContext<'a> {
+        let path_params = path_params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let connection = ConnectionInfo { remote_addr: "127.0.0.1:1234".parse().unwrap(), request_number: 1, trust_proxy: false };
+        HttpContext::new(path_params, request, connection)
+    }
+
+    #[test]
+    fn typed_parameters() {
+        let request = read_request(b"GET /users/42?page=3&size=big HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let context = context(&request, &[("id", "42"), ("name", "ann")]);
+        assert_eq!(context.get_path_param_as::<u64>("id"), Ok(42));
+        assert_eq!(context.get_query_param_as::<u32>("page"), Ok(3));
+        assert_eq!(context.get_path_param_as::<String>("name"), Ok("ann".to_string()));
+
+        assert_eq!(context.get_path_param_as::<u64>("missing"), Err(ParamError::Missing { name: "missing".to_string() }));
+        assert_eq!(context.get_query_param_as::<u32>("missing"), Err(ParamError::Missing { name: "missing".to_string() }));
+        let malformed = context.get_path_param_as::<u8>("name").unwrap_err();
+        assert_eq!(malformed, ParamError::Invalid { name: "name".to_string(), value: "ann".to_string(), expected: "u8" });
+        let too_big = context.get_query_param_as::<u8>("size").unwrap_err();
+        assert_eq!(too_big.to_string(), "parameter `size` must be a valid u8, got `big`");
+        assert!(matches!(context.get_path_param_as::<i8>("id"), Ok(42)));
+        assert!(matches!(context.get_path_param_as::<bool>("id"), Err(ParamError::Invalid { .. })));
+
+        assert_eq!(context.get_query_param_or("page", 1u32), 3);
+        assert_eq!(context.get_query_param_or("size", 10u32), 10);
+        assert_eq!(context.get_query_param_or("missing", 10u32), 10);
+
+        let response = HttpResponse::from(malformed);
+        let written = write(response, HttpVersion::Http11);
+        assert!(written.starts_with("HTTP/1.1 400 "), "{written}");
+        assert!(written.ends_with("parameter `name` must be a valid u8, got `ann`"), "{written}");
+    }
+
+    fn post(content_type: &str, body: &[u8]) -> HttpRequest {
+        let head = format!("POST /submit HTTP/1.1\r\nHost: x\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n", body.len());
+        read_request(&[head.as_bytes(), body].concat()).unwrap()
+    }
+
+    #[test]
+    fn form_bodies_are_decoded() {
+        let request = post("application/x-www-form-urlencoded", b"email=ann%40example.com&name=Ann+Lee&tag=a&tag=b+c&tag=%2B&empty=&flag");
+        let form = request.form().unwrap();
+        let string = |s: &str| DataType::String(s.to_string());
+        assert_eq!(form["email"], string("ann@example.com"));
+        assert_eq!(form["name"], string("Ann Lee"));
+        // repeated keys collect their values in order
+        assert_eq!(form["tag"], DataType::Array(vec![string("a"), string("b c"), string("+")]));
+        assert_eq!(form["empty"], string(""));
+        assert_eq!(form["flag"], string(""));
+        assert_eq!(form.len(), 5);
+        assert_eq!(post("application/x-www-form-urlencoded", b"na%20me=%C3%A9").form().unwrap()["na me"], string("é"));
+
+        assert!(post("application/x-www-form-urlencoded", b"a=%zz").form().is_none());
+        assert!(post("application/x-www-form-urlencoded", b"a=%C3").form().is_none());
+        // the raw body stays available
+        assert_eq!(request.body_bytes(), b"email=ann%40example.com&name=Ann+Lee&tag=a&tag=b+c&tag=%2B&empty=&flag");
+    }
+
+    #[test]
+    fn binary_and_json_bodies_reach_the_handler() {
+        let binary: Vec<u8> = (0..=255).chain([0xff, 0xfe, 0, 0xc3]).collect();
+        let response = echo_server().request(HttpMethod::POST, "/echo", &[(HttpHeader::CONTENT_TYPE, "application/octet-stream")], &binary);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, binary);
+
+        let server = TestServer::start(|server| {
+            server.register_end_point("/json", HttpMethod::POST, Arc::new(|r: HttpContext| {
+                let text_ok = r.request.body_text().is_ok();
+                match r.request.body_json() {
+                    Ok(body) => HttpResponse::ok().text(&format!("{} {:?} {}", text_ok, body["name"], body.len())),
+                    Err(e) => HttpResponse::bad_request_with_data(e.to_string().into_bytes()),
+                }
+            }));
+        });
+        let json = [(HttpHeader::CONTENT_TYPE, "application/json")];
+        let response = server.request(HttpMethod::POST, "/json", &json, r#"{"name": "ann", "tags": [1]}"#.as_bytes());
+        assert_eq!(response.text().unwrap(), r#"true String("ann") 2"#);
+        assert_eq!(server.request(HttpMethod::POST, "/json", &json, &binary).status, 400);
+    }
+
+    #[test]
+    fn cookies_round_trip_through_the_rendered_bytes() {
+        let mut response = HttpResponse::ok().text("hi");
+        response.add_cookie(Cookie::new("session", "abc123").path("/").http_only(true).secure(true)
+            .same_site(SameSite::Lax).max_age(Duration::from_secs(3600)));
+        response.add_cookie(Cookie::new("theme", "dark").domain("example.com"));
+        let written = write(response, HttpVersion::Http11);
+
+        let set_cookies: Vec<&str> = written.split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.eq_ignore_ascii_case(HttpHeader::SET_COOKIE))
+            .map(|(_, value)| value.trim())
+            .collect();
+        assert_eq!(set_cookies, [
+            "session=abc123; Max-Age=3600; Path=/; Secure; HttpOnly; SameSite=Lax",
+            "theme=dark; Domain=example.com",
+        ]);
+
+        // a client sends back the name and value of each, the attributes stay with it
+        let sent: Vec<&str> = set_cookies.iter().map(|cookie| cookie.split(';').next().unwrap()).collect();
+        let raw = format!("GET / HTTP/1.1\r\nHost: x\r\nCookie: {}\r\n\r\n", sent.join("; "));
+        let cookies = read_request(raw.as_bytes()).unwrap().cookies();
+        assert_eq!(cookies, HashMap::from([("session".to_string(), "abc123".to_string()), ("theme".to_string(), "dark".to_string())]));
+
+        let raw = b"GET / HTTP/1.1\r\nHost: x\r\nCookie:  a = \"quoted value\" ;b=2;;junk\r\n\r\n";
+        let cookies = read_request(raw).unwrap().cookies();
+        assert_eq!(cookies, HashMap::from([("a".to_string(), "quoted value".to_string()), ("b".to_string(), "2".to_string())]));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("john%20doe", false).as_deref(), Some("john doe"));
+        assert_eq!(percent_decode("a+b", false).as_deref(), Some("a+b"));
+        assert_eq!(percent_decode("a+b%2B", true).as_deref(), Some("a b+"));
+        assert_eq!(percent_decode("a%26b%3Dc", true).as_deref(), Some("a&b=c"));
+        assert_eq!(percent_decode("caf%C3%A9%20%E2%82%AC%F0%9F%98%80", false).as_deref(), Some("café €😀"));
+        assert_eq!(percent_decode("%2f%2F", false).as_deref(), Some("//"));
+        for invalid in ["%ZZ", "%2", "%", "a%g0", "%C3", "%FF", "%C3%28"] {
+            assert_eq!(percent_decode(invalid, true), None, "{invalid}");
+        }
+
+        let server = TestServer::start(|server| {
+            server.register_end_point("/abc/{name}/{id}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                let q = r.get_query_param("q").cloned().unwrap_or_default();
+                HttpResponse::ok().text(&format!("{}|{}|{}|{}", r.path_params["name"], r.path_params["id"], q, r.request.path))
+            }));
+        });
+        assert_eq!(server.get("/abc/john%20doe/1?q=a%26b").text().unwrap(), "john doe|1|a&b|/abc/john%20doe/1?q=a%26b");
+        assert_eq!(server.get("/abc/a%2Fb/%C3%A9?q=x+y%3D").text().unwrap(), "a/b|é|x y=|/abc/a%2Fb/%C3%A9?q=x+y%3D");
+        for invalid in ["/abc/%ZZ/1", "/abc/x/1?q=%2", "/abc/%C3/1", "/abc/x/1?q%ZZ=1"] {
+            let raw = server.send_raw(format!("GET {invalid} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").as_bytes());
+            assert_eq!(raw_status(&raw), 400, "{invalid}");
+        }
+    }
+
+    fn with_accept(accept: Option<&str>) -> HttpRequest {
+        let accept = accept.map_or_else(String::new, |accept| format!("Accept: {accept}\r\n"));
+        read_request(format!("GET / HTTP/1.1\r\nHost: x\r\n{accept}\r\n").as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn preferred_type_with_browser_accept_headers() {
+        let offered = [MediaType::APPLICATION_JSON, MediaType::TEXT_HTML];
+        let cases = [
+            // Chrome and Firefox navigating to a page
+            (Some("text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,image/apng,*/*;q=0.8,application/signed-exchange;v=b3;q=0.7"), Some(MediaType::TEXT_HTML)),
+            (Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"), Some(MediaType::TEXT_HTML)),
+            // jQuery's getJSON, fetch and curl
+            (Some("application/json, text/javascript, */*; q=0.01"), Some(MediaType::APPLICATION_JSON)),
+            (Some("*/*"), Some(MediaType::APPLICATION_JSON)),
+            (None, Some(MediaType::APPLICATION_JSON)),
+            // equal quality and specificity keep the order of the offers
+            (Some("text/html;q=0.5, application/json;q=0.5"), Some(MediaType::APPLICATION_JSON)),
+            (Some("text/*, application/json;q=0.9"), Some(MediaType::TEXT_HTML)),
+            (Some("application/json;q=0, */*"), Some(MediaType::TEXT_HTML)),
+            (Some("TEXT/HTML"), Some(MediaType::TEXT_HTML)),
+            (Some("image/png, image/*;q=0.5"), None),
+        ];
+        for (accept, expected) in cases {
+            assert_eq!(with_accept(accept).preferred_type(&offered), expected, "{accept:?}");
+        }
+        let chrome = with_accept(Some("text/html,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(chrome.accepts(MediaType::APPLICATION_JSON));
+        assert!(!with_accept(Some("text/html, */*;q=0")).accepts(MediaType::APPLICATION_JSON));
+    }
+
+    #[test]
+    fn malformed_requests_get_an_error_status() {
+        let server = echo_server();
+        let cases: [(&[u8], u32); 9] = [
+            (b"GARBAGE\r\n\r\n", 400),
+            (b"GET /\r\n\r\n", 400),
+            (b"GET / HTTP/1.1 extra\r\n\r\n", 400),
+            (b"BREW /pot HTTP/1.1\r\nHost: x\r\n\r\n", 501),
+            (b"GET / HTTP/2.0\r\nHost: x\r\n\r\n", 505),
+            (b"GET / HTTP/1.1\r\nno colon here\r\n\r\n", 400),
+            (b"GET / HTTP/1.1\r\nX-Bad: \xff\xfe\r\n\r\n", 400),
+            (b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: abc\r\n\r\n", 400),
+            (b"POST /echo HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nshort", 400),
+        ];
+        for (raw, status) in cases {
+            let response = server.send_raw(raw);
+            assert_eq!(raw_status(&response), status, "{:?}", String::from_utf8_lossy(raw));
+            assert!(response.to_ascii_lowercase().contains("connection:close"), "{response}");
+        }
+        // a client closing without sending anything gets no response
+        assert_eq!(server.send_raw(b""), "");
+        assert_eq!(server.send_raw(b"GET / HTTP/1.1\r\nHost"), "");
+        assert_eq!(server.request(HttpMethod::POST, "/echo", &[], b"still up").text().unwrap(), "still up");
+    }
+
+    #[test]
+    fn header_casing_does_not_matter() {
+        let request = read_request(b"POST / HTTP/1.1\r\nhost: x\r\ncontent-TYPE: application/json\r\ncontent-length: 8\r\n\r\n{\"a\": 1}").unwrap();
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.header(HttpHeader::CONTENT_LENGTH), Some("8"));
+        assert!(request.content_type().is_some_and(|content_type| content_type.is_json()));
+        assert_eq!(request.body_json().unwrap()["a"], DataType::Int(1));
+
+        let mut response = HttpResponse::ok().header("content-TYPE", "text/plain").text("hi");
+        response.set_header("Content-Type".to_string(), "text/html".to_string());
+        let written = write(response, HttpVersion::Http11);
+        let content_types: Vec<&str> = written.lines().filter(|line| line.to_ascii_lowercase().starts_with("content-type")).collect();
+        assert_eq!(content_types, ["Content-Type:text/html"]);
+    }
+
+    #[test]
+    fn byte_ranges_are_parsed() {
+        assert_eq!(ByteRange::parse("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(ByteRange::parse("bytes=40-59", 100), ByteRange::Partial(40, 59));
+        assert_eq!(ByteRange::parse("bytes=-5", 100), ByteRange::Partial(95, 99));
+        assert_eq!(ByteRange::parse("bytes=-500", 100), ByteRange::Partial(0, 99));
+        assert_eq!(ByteRange::parse("bytes=90-", 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse("bytes=90-1000", 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=200-300", 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-0", 100), ByteRange::Unsatisfiable);
+        for ignored in ["bytes=9-0", "bytes=0-1,5-6", "items=0-9", "bytes=a-9", "bytes=-", "bytes=+1-2"] {
+            assert_eq!(ByteRange::parse(ignored, 100), ByteRange::Full, "{ignored}");
+        }
+    }
+
+    #[test]
+    fn range_requests_get_a_slice_of_the_file() {
+        let path = std::env::temp_dir().join(format!("range_{}.bin", std::process::id()));
+        let content: Vec<u8> = (0..100).collect();
+        std::fs::write(&path, &content).unwrap();
+        let file = path.clone();
+        let server = TestServer::start(move |server| {
+            server.register_end_point("/file", HttpMethod::GET, Arc::new(move |_| HttpResponse::file(&file)));
+        });
+        let get = |range: &str| server.request(HttpMethod::GET, "/file", &[(HttpHeader::RANGE, range)], b"");
+
+        let full = server.get("/file");
+        assert_eq!((full.status, full.header(HttpHeader::ACCEPT_RANGES)), (200, Some("bytes")));
+        assert_eq!(full.body, content);
+        for (range, first, last) in [("bytes=0-9", 0, 9), ("bytes=40-59", 40, 59), ("bytes=-5", 95, 99)] {
+            let partial = get(range);
+            assert_eq!(partial.status, 206, "{range}");
+            assert_eq!(partial.header(HttpHeader::CONTENT_RANGE), Some(format!("bytes {first}-{last}/100").as_str()));
+            assert_eq!(partial.header(HttpHeader::CONTENT_LENGTH), Some((last - first + 1).to_string().as_str()));
+            assert_eq!(partial.body, &content[first..=last], "{range}");
+        }
+        let out_of_bounds = get("bytes=100-200");
+        assert_eq!(out_of_bounds.status, 416);
+        assert_eq!(out_of_bounds.header(HttpHeader::CONTENT_RANGE), Some("bytes */100"));
+        assert!(out_of_bounds.body.is_empty());
+        // several ranges at once are answered with the whole file
+        let several = get("bytes=0-1,5-6");
+        assert_eq!((several.status, several.body.len()), (200, 100));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn repeated_names_keep_every_value() {
+        let request = read_request(b"GET /search?tag=a&q=x&tag=b&tag=c HTTP/1.1\r\nHost: x\r\n\
+            Cookie: session=abc\r\nX-Forwarded-For: 203.0.113.7\r\nCookie: theme=dark\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n").unwrap();
+        let context = context(&request, &[]);
+        assert_eq!(context.get_query_params("tag"), ["a", "b", "c"]);
+        assert_eq!(context.get_query_param("tag").map(String::as_str), Some("a"));
+        assert_eq!(context.get_query_params("q"), ["x"]);
+        assert!(context.get_query_params("missing").is_empty());
+
+        assert_eq!(request.headers.get_all(HttpHeader::COOKIE), ["session=abc", "theme=dark"]);
+        assert_eq!(request.headers.get(HttpHeader::COOKIE), Some("session=abc"));
+        assert_eq!(request.headers.get_all("x-forwarded-for"), ["203.0.113.7", "10.0.0.1"]);
+        let cookies = request.cookies();
+        assert_eq!(cookies, HashMap::from([("session".to_string(), "abc".to_string()), ("theme".to_string(), "dark".to_string())]));
+    }
+
+    #[test]
+    fn multi_valued_response_headers_get_one_line_each() {
+        let mut response = HttpResponse::ok().text("hi");
+        response.append_header(HttpHeader::SET_COOKIE, "session=abc; Path=/");
+        response.append_header(HttpHeader::SET_COOKIE, "theme=dark");
+        response.append_header("Link", "</a.css>; rel=preload");
+        response.append_header("Link", "</b.js>; rel=preload");
+        let written = write(response, HttpVersion::Http11);
+        let head: Vec<&str> = written.split("\r\n\r\n").next().unwrap().lines().skip(1).collect();
+        let values = |name: &str| -> Vec<&str> {
+            head.iter().filter_map(|line| line.split_once(':')).filter(|(key, _)| *key == name).map(|(_, value)| value).collect()
+        };
+        assert_eq!(values(HttpHeader::SET_COOKIE), ["session=abc; Path=/", "theme=dark"]);
+        assert_eq!(values("Link"), ["</a.css>; rel=preload", "</b.js>; rel=preload"]);
+    }
+
+    #[test]
+    fn http_dates_of_known_timestamps() {
+        let date = |seconds: u64| http_date(UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        // leap days, 2000 being a leap year although a century
+        assert_eq!(date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(date(1709164800), "Thu, 29 Feb 2024 00:00:00 GMT");
+        assert_eq!(date(4102444799), "Thu, 31 Dec 2099 23:59:59 GMT");
+        // sub-second parts are dropped
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_millis(784_111_777_999)), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn rendered_bytes_are_stable() {
+        let response = || {
+            let mut response = HttpResponse::with_status(HttpStatus::CREATED)
+                .header(HttpHeader::DATE, "Sun, 06 Nov 1994 08:49:37 GMT")
+                .header(HttpHeader::LOCATION, "/users/42")
+                .header("X-Trace", "a b")
+                .text("hello");
+            response.append_header("Vary", "Accept");
+            response.append_header("Vary", "Origin");
+            response.add_cookie(Cookie::new("session", "abc"));
+            response
+        };
+        let expected = "HTTP/1.1 201 Created\r\n\
+            Date:Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+            Location:/users/42\r\n\
+            X-Trace:a b\r\n\
+            Content-type:text/plain; charset=utf-8\r\n\
+            Vary:Accept\r\n\
+            Vary:Origin\r\n\
+            Set-Cookie:session=abc\r\n\
+            Content-length:5\r\n\
+            \r\n\
+            hello";
+        assert_eq!(write(response(), HttpVersion::Http11), expected);
+        // rendering the head only borrows the response
+        let response_once = response();
+        let (head, chunked) = response_once.render_head(HttpVersion::Http11);
+        assert!(!chunked);
+        assert_eq!(head, response_once.render_head(HttpVersion::Http11).0);
+        assert_eq!(head, expected.trim_end_matches("hello"));
+        assert_eq!(write(response(), HttpVersion::Http10), expected.replacen("HTTP/1.1", "HTTP/1.0", 1));
+    }
+
+    fn nodelay_of_accepted_streams(server: HttpServer) -> String {
+        let mut server = server.workers(1);
+        server.do_before(Box::new(|c: &HttpConnection| {
+            Some(HttpResponse::ok().text(&c.tcp_stream.nodelay().unwrap().to_string()))
+        }));
+        let handle = server.start_in_background().unwrap();
+        let response = HttpClient::new().get(&format!("http://{}/", handle.local_addr())).send().unwrap();
+        assert!(handle.shutdown(Duration::from_secs(5)));
+        response.text().unwrap().to_string()
+    }
+
+    #[test]
+    fn listener_options() {
+        let mut server = HttpServer::bind("127.0.0.1", 0);
+        assert_eq!(server.local_addr(), None);
+        let addr = server.listen().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(server.local_addr(), Some(addr));
+        assert_eq!(server.listen().unwrap(), addr);
+        // the port is taken as long as `server` listens on it
+        match HttpServer::bind("127.0.0.1", addr.port() as u32).listen() {
+            Err(ServerError::Bind(address, _)) => assert_eq!(address, addr.to_string()),
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(HttpServer::bind("127.0.0.1", 70000).listen(), Err(ServerError::InvalidAddress(_))));
+        drop(server);
+
+        assert_eq!(nodelay_of_accepted_streams(HttpServer::bind("127.0.0.1", 0).nodelay(true)), "true");
+        assert_eq!(nodelay_of_accepted_streams(HttpServer::bind("127.0.0.1", 0)), "false");
+    }
+
+    #[test]
+    fn content_type_parameters_and_casing() {
+        let parsed = ContentType::parse("Application/JSON; Charset=\"UTF-8\" ; boundary=x").unwrap();
+        assert_eq!(parsed.media_type, "application/json");
+        assert_eq!(parsed, MediaType::APPLICATION_JSON);
+        assert_eq!(parsed, "APPLICATION/json");
+        assert!(parsed.is_json());
+        assert_eq!(parsed.charset(), Some("UTF-8"));
+        assert_eq!(parsed.param("BOUNDARY"), Some("x"));
+        assert_eq!(parsed.param("missing"), None);
+
+        assert!(ContentType::parse("application/problem+json").unwrap().is_json());
+        assert!(!ContentType::parse("text/json-ish").unwrap().is_json());
+        assert_eq!(ContentType::parse("text/plain;charset").unwrap().params, []);
+        for invalid in ["", "json", "text/", "/plain", "; charset=utf-8"] {
+            assert_eq!(ContentType::parse(invalid), None, "{invalid}");
+        }
+
+        let request = read_request(b"POST /users HTTP/1.1\r\nhOsT: example.com\r\nuser-AGENT: curl/8.0\r\n\
+            CONTENT-TYPE: application/json;charset=utf-8\r\ncontent-LENGTH: 2\r\n\r\n{}").unwrap();
+        assert_eq!(request.content_type().unwrap(), MediaType::APPLICATION_JSON);
+        assert_eq!(request.content_length(), Some(2));
+        assert_eq!(request.host(), Some("example.com"));
+        assert_eq!(request.user_agent(), Some("curl/8.0"));
+        assert_eq!(request.header("content-type"), Some("application/json;charset=utf-8"));
+        assert_eq!(request.header("X-Missing"), None);
+        assert!(request.body_json().unwrap().is_empty());
+
+        let request = read_request(b"GET / HTTP/1.1\r\nHost: x\r\nContent-Type: nonsense\r\n\r\n").unwrap();
+        assert_eq!((request.content_type(), request.content_length(), request.user_agent()), (None, None, None));
+    }
+
+    #[test]
+    fn array_style_query_parameters() {
+        let request = read_request(b"GET /items?id=1&id[]=2&id=3&id%5B%5D=4&other=5 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let params = context(&request, &[]);
+        // the repeated plain name first, then the bracketed one
+        assert_eq!(params.get_query_param_list("id"), ["1", "3", "2", "4"]);
+        assert_eq!(params.get_query_param_list_as::<u32>("id"), Ok(vec![1, 3, 2, 4]));
+        assert_eq!(params.get_query_param_list_as::<u32>("missing"), Ok(vec![]));
+        assert_eq!(params.get_query_params("id[]"), ["2", "4"]);
+
+        let malformed_request = read_request(b"GET /items?id=1&id[]=two&id[]=3 HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let malformed = context(&malformed_request, &[]);
+        let error = malformed.get_query_param_list_as::<u32>("id").unwrap_err();
+        assert_eq!(error, ParamError::InvalidElement { name: "id".to_string(), index: 1, value: "two".to_string(), expected: "u32" });
+        assert_eq!(error.to_string(), "element 1 of parameter `id` must be a valid u32, got `two`");
+        assert_eq!(malformed.get_query_param_list_as::<String>("id"), Ok(vec!["1".to_string(), "two".to_string(), "3".to_string()]));
+    }
+
+    #[test]
+    fn text_html_and_file_responses() {
+        let rendered = |response: HttpResponse| -> (u32, Option<String>, Vec<u8>) {
+            let status = response.status;
+            let content_type = response.get_header(HttpHeader::CONTENT_TYPE).map(str::to_string);
+            let mut out = Vec::new();
+            response.write_to(HttpVersion::Http11, &mut out).unwrap();
+            let body_start = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            (status, content_type, out[body_start..].to_vec())
+        };
+        let text = rendered(HttpResponse::ok().text("héllo"));
+        assert_eq!(text, (200, Some("text/plain; charset=utf-8".to_string()), "héllo".as_bytes().to_vec()));
+        let html = rendered(HttpResponse::ok().html("<p>é</p>"));
+        assert_eq!(html, (200, Some("text/html; charset=utf-8".to_string()), "<p>é</p>".as_bytes().to_vec()));
+
+        let dir = std::env::temp_dir().join(format!("file_responses_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: [(&str, &[u8], &str); 5] = [
+            ("index.html", b"<h1>hi</h1>", "text/html"),
+            ("logo.PNG", b"\x89PNG\r\n\x1a\n\0\xff", "image/png"),
+            ("data.json", b"{}", "application/json"),
+            ("archive.xyz", b"\0\x01\x02", "application/octet-stream"),
+            ("README", b"read me", "application/octet-stream"),
+        ];
+        for (name, contents, content_type) in files {
+            let path = dir.join(name);
+            std::fs::write(&path, contents).unwrap();
+            assert_eq!(rendered(HttpResponse::file(&path)), (200, Some(content_type.to_string()), contents.to_vec()), "{name}");
+        }
+        assert_eq!(HttpResponse::file(dir.join("missing.html")).status, HttpStatus::NOT_FOUND);
+        assert_eq!(HttpResponse::file(&dir).status, HttpStatus::FORBIDDEN);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
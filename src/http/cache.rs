@@ -0,0 +1,220 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::http::base::{Body, HttpHeader, HttpResponse, HttpStatus};
+
+/// Bounds of `HttpServer::enable_response_cache`.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct CacheConfig {
+    /// How long an entry is served at most, a shorter `max-age` of the handler wins.
+    pub(crate) ttl: Duration,
+    /// The total size of the cached bodies, the least recently used entries are evicted to stay
+    /// below it.
+    pub(crate) max_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Successful GET responses of handlers, keyed by path and query, shared by the workers.
+pub(crate) struct ResponseCache {
+    config: CacheConfig,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// Keys by the tick they were last used at, the first one is evicted next.
+    recency: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+}
+
+struct Entry {
+    response: HttpResponse,
+    stored_at: Instant,
+    expires_at: Instant,
+    size: usize,
+    used: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.used);
+            entry.used = self.tick;
+            self.recency.insert(self.tick, key.to_string());
+        }
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: CacheConfig) -> ResponseCache {
+        ResponseCache {
+            config,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// A copy of the fresh response cached for `key`, with its `Age`.
+    pub(crate) fn get(&self, key: &str) -> Option<HttpResponse> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.get(key)?;
+        let now = Instant::now();
+        if now >= entry.expires_at {
+            state.remove(key);
+            return None;
+        }
+        let mut response = entry.response.try_clone()?;
+        response.set_header(HttpHeader::AGE.to_string(), now.duration_since(entry.stored_at).as_secs().to_string());
+        state.touch(key);
+        Some(response)
+    }
+
+    /// Keeps a copy of `response` for `key` unless it must not be shared: anything but a 200 with
+    /// a body in memory, responses setting cookies and those whose `Cache-Control` has
+    /// `no-store`, `no-cache`, `private` or a `max-age` of 0.
+    pub(crate) fn store(&self, key: &str, response: &HttpResponse) {
+        let Body::Bytes(data) = &response.body else {
+            return;
+        };
+        if response.status != HttpStatus::OK || !response.cookies().is_empty() {
+            return;
+        }
+        let Some(ttl) = self.ttl(response.get_header(HttpHeader::CACHE_CONTROL)) else {
+            return;
+        };
+        let size = data.len();
+        if size > self.config.max_size {
+            return;
+        }
+        let Some(response) = response.try_clone() else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        while state.size + size > self.config.max_size {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        let now = Instant::now();
+        state.entries.insert(key.to_string(), Entry { response, stored_at: now, expires_at: now + ttl, size, used: 0 });
+        state.size += size;
+        state.touch(key);
+    }
+
+    /// Drops the entries of `path` whatever their query, after a request that may change it.
+    pub(crate) fn invalidate(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        let stale: Vec<String> = state.entries.keys()
+            .filter(|key| key.split('?').next() == Some(path))
+            .cloned()
+            .collect();
+        stale.iter().for_each(|key| state.remove(key));
+    }
+
+    /// How long a response with this `Cache-Control` may be cached, `None` if not at all.
+    fn ttl(&self, cache_control: Option<&str>) -> Option<Duration> {
+        let mut ttl = self.config.ttl;
+        for directive in cache_control.unwrap_or_default().split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                Some(("max-age", seconds)) => {
+                    let seconds: u64 = seconds.trim_matches('"').parse().ok()?;
+                    ttl = ttl.min(Duration::from_secs(seconds));
+                }
+                None if matches!(directive.as_str(), "no-store" | "no-cache" | "private") => return None,
+                _ => {}
+            }
+        }
+        if ttl.is_zero() { None } else { Some(ttl) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::http::cookie::Cookie;
+    use super::*;
+
+    fn cached_text(cache: &ResponseCache, key: &str) -> Option<String> {
+        cache.get(key).map(|response| match &response.body {
+            Body::Bytes(data) => String::from_utf8(data.clone()).unwrap(),
+            _ => panic!("not in memory"),
+        })
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = ResponseCache::new(CacheConfig { ttl: Duration::from_secs(60), max_size: 10 });
+        cache.store("/a", &HttpResponse::ok().text("aaaa"));
+        cache.store("/b", &HttpResponse::ok().text("bbbb"));
+        assert!(cache.get("/a").is_some());
+        // /b is the least recently used
+        cache.store("/c", &HttpResponse::ok().text("cccc"));
+        assert_eq!(cached_text(&cache, "/a").as_deref(), Some("aaaa"));
+        assert_eq!(cached_text(&cache, "/b"), None);
+        assert_eq!(cached_text(&cache, "/c").as_deref(), Some("cccc"));
+        // larger than the whole budget
+        cache.store("/big", &HttpResponse::ok().text("0123456789a"));
+        assert!(cache.get("/big").is_none());
+        assert!(cache.get("/a").is_some());
+
+        cache.store("/a?page=2", &HttpResponse::ok().text("a2"));
+        cache.invalidate("/a");
+        assert!(cache.get("/a").is_none() && cache.get("/a?page=2").is_none());
+        assert!(cache.get("/c").is_some());
+    }
+
+    #[test]
+    fn only_shareable_responses_are_stored() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        let uncacheable = [
+            HttpResponse::with_status(HttpStatus::CREATED).text("x"),
+            HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "no-store").text("x"),
+            HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "public, No-Cache").text("x"),
+            HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "private").text("x"),
+            HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "max-age=0").text("x"),
+            HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "max-age=soon").text("x"),
+        ];
+        for (index, response) in uncacheable.iter().enumerate() {
+            let key = format!("/{index}");
+            cache.store(&key, response);
+            assert!(cache.get(&key).is_none(), "{:?}", response.get_header(HttpHeader::CACHE_CONTROL));
+        }
+        let mut with_cookie = HttpResponse::ok().text("x");
+        with_cookie.add_cookie(Cookie::new("a", "b"));
+        cache.store("/cookie", &with_cookie);
+        assert!(cache.get("/cookie").is_none());
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = ResponseCache::new(CacheConfig { ttl: Duration::from_secs(60), max_size: 1024 });
+        cache.store("/short", &HttpResponse::ok().header(HttpHeader::CACHE_CONTROL, "public, max-age=1").text("x"));
+        cache.store("/long", &HttpResponse::ok().text("x"));
+        assert!(cache.get("/short").is_some());
+        thread::sleep(Duration::from_millis(1100));
+        assert!(cache.get("/short").is_none());
+        let long = cache.get("/long").unwrap();
+        assert_eq!(long.get_header(HttpHeader::AGE), Some("1"));
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::Utf8Error;
+use std::time::Duration;
+use crate::http::base::{HttpHeader, HttpMethod, HttpRequest, HttpStatus, HttpVersion, MediaType, RequestLimits};
+use crate::http::headers::HeaderMap;
+use crate::utils::json::{JsonDeserializable, JsonError, JsonParser, JsonSerializable, Serializer};
+
+/// A blocking HTTP/1.1 client for plain `http://` urls, one connection per request, e.g.
+/// `HttpClient::new().post("http://127.0.0.1:7878/users").json(&user).send()`.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    timeout: Option<Duration>,
+    limits: RequestLimits,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient::new()
+    }
+}
+
+impl HttpClient {
+    pub(crate) fn new() -> HttpClient {
+        HttpClient {
+            timeout: Some(Duration::from_secs(30)),
+            limits: RequestLimits { max_header_size: 64 * 1024, max_body_size: 16 * 1024 * 1024 },
+        }
+    }
+
+    /// Bounds connecting and each read or write, 30 seconds by default; `None` waits forever.
+    pub(crate) fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Responses with a larger body fail with `ClientError::InvalidResponse`, defaults to 16 MiB.
+    pub(crate) fn max_response_size(mut self, bytes: u64) -> Self {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    pub(crate) fn get(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::GET, url)
+    }
+
+    pub(crate) fn post(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::POST, url)
+    }
+
+    pub(crate) fn put(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::PUT, url)
+    }
+
+    pub(crate) fn patch(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::PATCH, url)
+    }
+
+    pub(crate) fn delete(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::DELETE, url)
+    }
+
+    pub(crate) fn head(&self, url: &str) -> ClientRequest<'_> {
+        self.request(HttpMethod::HEAD, url)
+    }
+
+    pub(crate) fn request(&self, method: HttpMethod, url: &str) -> ClientRequest<'_> {
+        ClientRequest {
+            client: self,
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+/// A request being built, sent with `send`.
+pub(crate) struct ClientRequest<'c> {
+    client: &'c HttpClient,
+    method: HttpMethod,
+    url: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl<'c> ClientRequest<'c> {
+    /// Adds a header line, several calls with the same name send several lines.
+    pub(crate) fn header(mut self, key: &str, value: &str) -> ClientRequest<'c> {
+        self.headers.append(key, value);
+        self
+    }
+
+    pub(crate) fn body(mut self, data: Vec<u8>) -> ClientRequest<'c> {
+        self.body = data;
+        self
+    }
+
+    pub(crate) fn text(self, text: &str) -> ClientRequest<'c> {
+        self.content_type(MediaType::TEXT_PLAIN).body(text.as_bytes().to_vec())
+    }
+
+    pub(crate) fn json<T: JsonSerializable + ?Sized>(self, value: &T) -> ClientRequest<'c> {
+        let json = Serializer::new().serialize(value);
+        self.content_type(MediaType::APPLICATION_JSON).body(json.into_bytes())
+    }
+
+    fn content_type(mut self, content_type: &str) -> ClientRequest<'c> {
+        self.headers.insert(HttpHeader::CONTENT_TYPE, content_type);
+        self
+    }
+
+    /// Connects, writes the request and reads the whole response. Any status is a response,
+    /// only transport and protocol failures are errors.
+    pub(crate) fn send(self) -> Result<ClientResponse, ClientError> {
+        let url = Url::parse(&self.url)?;
+        let stream = match self.client.timeout {
+            Some(timeout) => {
+                let mut addresses = (url.host.as_str(), url.port).to_socket_addrs()?;
+                let address = addresses.next()
+                    .ok_or_else(|| ClientError::InvalidUrl(format!("`{}` does not resolve", url.host)))?;
+                TcpStream::connect_timeout(&address, timeout)?
+            }
+            None => TcpStream::connect((url.host.as_str(), url.port))?,
+        };
+        stream.set_read_timeout(self.client.timeout)?;
+        stream.set_write_timeout(self.client.timeout)?;
+
+        let mut head = format!("{:?} {} {}\r\n", self.method, url.target, HttpVersion::Http11);
+        let host = if url.port == 80 { url.host.clone() } else { format!("{}:{}", url.host, url.port) };
+        let mut headers = self.headers;
+        if !headers.contains(HttpHeader::HOST) {
+            headers.insert(HttpHeader::HOST, &host);
+        }
+        if !self.body.is_empty() || matches!(self.method, HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH) {
+            headers.insert(HttpHeader::CONTENT_LENGTH, &self.body.len().to_string());
+        }
+        headers.insert(HttpHeader::CONNECTION, "close");
+        headers.iter().for_each(|(name, value)| head.push_str(&format!("{}: {}\r\n", name, value)));
+        head.push_str("\r\n");
+        let mut writer = &stream;
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()?;
+
+        ClientResponse::read(&mut BufReader::new(stream), self.method, &self.client.limits)
+    }
+}
+
+/// The parts of an `http://host[:port][/path][?query]` url a request needs.
+struct Url {
+    host: String,
+    port: u16,
+    /// The path and query, `/` when the url has neither.
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, ClientError> {
+        let invalid = |reason: &str| ClientError::InvalidUrl(format!("`{}`: {}", url, reason));
+        let rest = url.strip_prefix("http://").ok_or_else(|| invalid("only http:// urls are supported"))?;
+        let (authority, target) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "")
+        };
+        let target = match target {
+            "" => "/".to_string(),
+            target if target.starts_with('?') => format!("/{}", target),
+            target => target.to_string()
+        };
+        // an IPv6 host is bracketed, its colons are not the port separator
+        let (host, port) = match authority.rfind(':') {
+            Some(index) if !authority[index..].contains(']') => {
+                let port = authority[index + 1..].parse().map_err(|_| invalid("invalid port"))?;
+                (&authority[..index], port)
+            }
+            _ => (authority, 80)
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+        Ok(Url { host, port, target })
+    }
+}
+
+/// A response as received by `HttpClient`, its body read completely.
+#[derive(Debug)]
+pub(crate) struct ClientResponse {
+    pub(crate) version: HttpVersion,
+    pub(crate) status: u32,
+    pub(crate) reason: String,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: Vec<u8>,
+}
+
+impl ClientResponse {
+    fn read(reader: &mut BufReader<TcpStream>, method: HttpMethod, limits: &RequestLimits) -> Result<ClientResponse, ClientError> {
+        let invalid = |reason: &str| ClientError::InvalidResponse(reason.to_string());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") && head != "\r\n" {
+            if head.len() >= limits.max_header_size {
+                return Err(invalid("the response headers are too large"));
+            }
+            let limit = (limits.max_header_size - head.len()) as u64;
+            if reader.by_ref().take(limit).read_line(&mut head)? == 0 {
+                return Err(invalid("the connection closed before the response was complete"));
+            }
+        }
+        let (status_line, header) = head.split_once("\r\n").unwrap_or((&head, ""));
+        let mut parts = status_line.splitn(3, ' ');
+        let version = match parts.next() {
+            Some("HTTP/1.0") => HttpVersion::Http10,
+            Some("HTTP/1.1") => HttpVersion::Http11,
+            _ => return Err(invalid("malformed status line"))
+        };
+        let status: u32 = parts.next()
+            .filter(|status| status.len() == 3)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))?;
+        let reason = parts.next().unwrap_or_default().to_string();
+        let headers = HttpRequest::parse_header(header).map_err(|_| invalid("malformed header"))?;
+
+        let bodiless = method == HttpMethod::HEAD
+            || status < 200 || status == HttpStatus::NO_CONTENT || status == HttpStatus::NOT_MODIFIED;
+        let delimited = headers.contains(HttpHeader::CONTENT_LENGTH) || headers.contains(HttpHeader::TRANSFER_ENCODING);
+        let body = if bodiless {
+            Vec::new()
+        } else if delimited {
+            HttpRequest::read_body(reader, &headers, limits).map_err(|e| ClientError::InvalidResponse(e.to_string()))?
+        } else {
+            // without either header the body ends with the connection
+            let mut body = Vec::new();
+            reader.take(limits.max_body_size + 1).read_to_end(&mut body)?;
+            if body.len() as u64 > limits.max_body_size {
+                return Err(invalid("the body is too large"));
+            }
+            body
+        };
+        Ok(ClientResponse { version, status, reason, headers, body })
+    }
+
+    pub(crate) fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub(crate) fn text(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    /// The body deserialized into `T`, whatever the `Content-type`.
+    pub(crate) fn json<T: JsonDeserializable>(&self) -> Result<T, JsonError> {
+        T::from_json(&JsonParser::from_slice(&self.body).parse_to_value()?)
+    }
+}
+
+/// Why `ClientRequest::send` did not produce a response.
+#[derive(Debug)]
+pub(crate) enum ClientError {
+    InvalidUrl(String),
+    Io(io::Error),
+    InvalidResponse(String),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(reason) => write!(f, "invalid url {reason}"),
+            ClientError::Io(error) => write!(f, "{error}"),
+            ClientError::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Io(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(error: io::Error) -> Self {
+        ClientError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::http::base::{HttpContext, HttpResponse};
+    use crate::http::http_core::{HttpServer, ServerHandle};
+    use crate::utils::json::JsonObjectExt;
+    use super::*;
+
+    fn server() -> ServerHandle {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(2);
+        server.register_end_point("/users/{id:int}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+            let page = r.get_query_param("page").map_or("1", String::as_str);
+            HttpResponse::ok().header("X-Page", page).text(&format!("user {}", r.path_params["id"]))
+        }));
+        server.register_end_point("/sum", HttpMethod::POST, Arc::new(|r: HttpContext| {
+            let body = match r.request.body_json() {
+                Ok(body) => body,
+                Err(e) => return HttpResponse::bad_request_with_data(e.to_string().into_bytes())
+            };
+            let sum = body.get_i64("a").unwrap_or(0) + body.get_i64("b").unwrap_or(0);
+            let tags = r.request.headers.get_all("X-Tag").join(",");
+            HttpResponse::with_status(HttpStatus::CREATED).text(&format!("{} {} {}", sum, tags, r.request.headers.get(HttpHeader::HOST).unwrap_or("")))
+        }));
+        server.start_in_background().unwrap()
+    }
+
+    /// Answers one connection with `response` once it read the request head.
+    fn canned(response: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let _ = (&stream).write_all(response);
+        });
+        addr
+    }
+
+    #[test]
+    fn requests_against_a_running_server() {
+        let handle = server();
+        let base = format!("http://{}", handle.local_addr());
+        let client = HttpClient::new().timeout(Some(Duration::from_secs(5)));
+
+        let user = client.get(&format!("{base}/users/42?page=3")).send().unwrap();
+        assert_eq!((user.version, user.status, user.reason.as_str()), (HttpVersion::Http11, 200, "OK"));
+        assert!(user.is_success());
+        assert_eq!(user.text().unwrap(), "user 42");
+        assert_eq!(user.header("x-page"), Some("3"));
+        assert_eq!(user.header(HttpHeader::CONTENT_LENGTH), Some("7"));
+
+        let numbers = BTreeMap::from([("a".to_string(), 2), ("b".to_string(), 40)]);
+        let created = client.post(&format!("{base}/sum")).header("X-Tag", "x").header("X-Tag", "y").json(&numbers).send().unwrap();
+        assert_eq!(created.status, 201);
+        assert_eq!(created.text().unwrap(), format!("42 x,y {}", handle.local_addr()));
+
+        // a HEAD response announces a length but has no body
+        let head = client.head(&format!("{base}/users/42")).send().unwrap();
+        assert_eq!((head.status, head.header(HttpHeader::CONTENT_LENGTH)), (200, Some("7")));
+        assert!(head.body.is_empty());
+
+        // error statuses are responses too
+        let missing = client.get(&format!("{base}/users/ann")).send().unwrap();
+        assert_eq!(missing.status, 404);
+        assert!(!missing.is_success());
+        assert_eq!(client.post(&format!("{base}/sum")).text("{").send().unwrap().status, 400);
+
+        let small = HttpClient::new().max_response_size(3);
+        assert!(matches!(small.get(&format!("{base}/users/42")).send(), Err(ClientError::InvalidResponse(_))));
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn bodies_delimited_by_chunks_or_the_connection() {
+        let client = HttpClient::new().timeout(Some(Duration::from_secs(5)));
+        let chunked = canned(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n");
+        assert_eq!(client.get(&format!("http://{chunked}")).send().unwrap().text().unwrap(), "hello world");
+        let until_close = canned(b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil the end");
+        let response = client.get(&format!("http://{until_close}/")).send().unwrap();
+        assert_eq!((response.version, response.text().unwrap()), (HttpVersion::Http10, "until the end"));
+
+        let garbage = canned(b"SMTP ready\r\n\r\n");
+        assert!(matches!(client.get(&format!("http://{garbage}")).send(), Err(ClientError::InvalidResponse(_))));
+        let truncated = canned(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort");
+        assert!(client.get(&format!("http://{truncated}")).send().is_err());
+    }
+
+    #[test]
+    fn urls() {
+        let url = Url::parse("http://example.com").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.target.as_str()), ("example.com", 80, "/"));
+        let url = Url::parse("http://127.0.0.1:8080?q=1").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.target.as_str()), ("127.0.0.1", 8080, "/?q=1"));
+        let url = Url::parse("http://[::1]:9000/a/b?c=d").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.target.as_str()), ("::1", 9000, "/a/b?c=d"));
+        let url = Url::parse("http://[::1]/").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 80));
+
+        for invalid in ["https://example.com", "example.com", "http://", "http://:80/", "http://host:port/", "http://host:70000"] {
+            assert!(matches!(Url::parse(invalid), Err(ClientError::InvalidUrl(_))), "{invalid}");
+        }
+        assert!(matches!(HttpClient::new().get("ftp://x").send(), Err(ClientError::InvalidUrl(_))));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::http::base::{Body, HttpHeader, HttpRequest, HttpResponse, MediaType};
+
+/// A content coding for response bodies, named as in `Accept-Encoding` and `Content-Encoding`.
+pub(crate) trait Encoder: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Leaves the body untouched, what a client gets when it accepts none of the other encoders.
+pub(crate) struct Identity;
+
+impl Encoder for Identity {
+    fn name(&self) -> &'static str {
+        "identity"
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+}
+
+#[cfg(feature = "gzip")]
+pub(crate) struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Encoder for Gzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    /// A single gzip member (RFC 1952) without file name or modification time.
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+        encoded.extend(miniz_oxide::deflate::compress_to_vec(data, 6));
+        encoded.extend(crc32(data).to_le_bytes());
+        encoded.extend((data.len() as u32).to_le_bytes());
+        encoded
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The encoders available in this build: gzip with the `gzip` feature, none otherwise.
+pub(crate) fn default_encoders() -> Vec<Box<dyn Encoder>> {
+    vec![
+        #[cfg(feature = "gzip")]
+        Box::new(Gzip),
+    ]
+}
+
+/// Compresses buffered text-like bodies of at least `min_size` bytes with the encoder the
+/// client's `Accept-Encoding` prefers.
+pub(crate) struct Compression {
+    min_size: usize,
+    encoders: Vec<Box<dyn Encoder>>,
+}
+
+impl Compression {
+    pub(crate) fn new(min_size: usize, encoders: Vec<Box<dyn Encoder>>) -> Compression {
+        Compression { min_size, encoders }
+    }
+
+    /// Streams, small bodies, binary content types, partial responses and bodies a filter already
+    /// encoded are left alone. Everything else gets `Vary: Accept-Encoding`, since its encoding now depends
+    /// on the request, and a strong `ETag` of an encoded body becomes weak.
+    pub(crate) fn apply(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        let Body::Bytes(data) = &response.body else {
+            return;
+        };
+        let length = data.len();
+        let compressible = response.get_header(HttpHeader::CONTENT_TYPE).is_some_and(is_compressible);
+        let encoded = response.get_header(HttpHeader::CONTENT_ENCODING).is_some() || response.get_header(HttpHeader::CONTENT_RANGE).is_some();
+        if length < self.min_size || !compressible || encoded {
+            return;
+        }
+        let vary = match response.get_header(HttpHeader::VARY) {
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".to_string(),
+        };
+        response.set_header(HttpHeader::VARY.to_string(), vary);
+
+        let encoder = self.negotiate(request);
+        if encoder.name() == Identity.name() {
+            return;
+        }
+        if let Body::Bytes(data) = &response.body {
+            response.body = Body::Bytes(encoder.encode(data));
+        }
+        response.set_header(HttpHeader::CONTENT_ENCODING.to_string(), encoder.name().to_string());
+        // the encoded bytes differ from what a strong tag vouches for, only equivalence remains
+        if let Some(tag) = response.get_header(HttpHeader::ETAG).filter(|tag| !tag.starts_with("W/")) {
+            let weak = format!("W/{}", tag);
+            response.set_header(HttpHeader::ETAG.to_string(), weak);
+        }
+    }
+
+    /// The encoder with the highest non-zero weight in `Accept-Encoding`, the earlier registered
+    /// one on a tie. `*` weighs encoders not listed by name; no header at all means identity.
+    fn negotiate(&self, request: &HttpRequest) -> &dyn Encoder {
+        let Some(accept) = request.headers.get(HttpHeader::ACCEPT_ENCODING) else {
+            return &Identity;
+        };
+        let weights: Vec<(String, f32)> = accept.split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let coding = parts.next()?.trim().to_ascii_lowercase();
+                let q = parts
+                    .filter_map(|param| param.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                    .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+                Some((coding, q))
+            })
+            .collect();
+        let weight = |name: &str| {
+            let weight_of = |coding: &str| weights.iter().find(|(c, _)| c == coding).map(|(_, q)| *q);
+            weight_of(name).or_else(|| weight_of("*")).unwrap_or(0.0)
+        };
+
+        let mut best: (&dyn Encoder, f32) = (&Identity, 0.0);
+        for encoder in &self.encoders {
+            let q = weight(encoder.name());
+            if q > best.1 {
+                best = (encoder.as_ref(), q);
+            }
+        }
+        best.0
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+        || [MediaType::APPLICATION_JSON, MediaType::APPLICATION_XML, "application/javascript"].contains(&media_type.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::http::base::{HttpHeader, HttpMethod, HttpResponse};
+    use crate::http::testing::TestServer;
+
+    fn server() -> TestServer {
+        TestServer::start(|server| {
+            server.enable_compression(0);
+            server.register_end_point("/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text(&"compressible ".repeat(100))));
+        })
+    }
+
+    #[test]
+    fn clients_not_accepting_an_encoding_get_identity() {
+        let response = server().get("/");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header(HttpHeader::CONTENT_ENCODING), None);
+        assert_eq!(response.header(HttpHeader::VARY), Some("Accept-Encoding"));
+        assert_eq!(response.text().unwrap(), "compressible ".repeat(100));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn clients_accepting_gzip_get_it() {
+        let response = server().request(HttpMethod::GET, "/", &[(HttpHeader::ACCEPT_ENCODING, "gzip")], &[]);
+        assert_eq!(response.header(HttpHeader::CONTENT_ENCODING), Some("gzip"));
+        assert!(response.body.len() < 1300);
+    }
+}
@@ -1,27 +1,41 @@
-use std::fs::File;
-use std::io::Read;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::Arc;
+use orm_macro_derive::JsonDeserializable;
+use crate::http::access_log::log_to_stderr;
 use crate::http::base::{HttpConnection, HttpContext, HttpMethod, HttpResponse, HttpStatus};
-use crate::http::http_core::HttpServer;
+use crate::http::http_core::{BeforeFilter, HttpServer};
+use crate::utils::json::{DataType, JsonDeserializable, JsonError, JsonObjectExt};
+
+#[derive(Debug, JsonDeserializable)]
+struct CreateUser {
+    name: String,
+    age: u32,
+}
 
 fn main() {
-    let mut server = HttpServer::bind("127.0.0.1", 7878);
-    server.register_end_point("/abc/{username}/{id}", HttpMethod::GET, Box::new(test));
-    server.register_end_point("/images/{image-id}", HttpMethod::GET, Box::new(get_image));
-    server.do_before(Box::new(filter)); // executed before starting process the request
+    let mut server = HttpServer::bind("127.0.0.1", 7878).workers(4);
+    server.register_end_point("/abc/{username}/{id:int}", HttpMethod::GET, Arc::new(test));
+    server.register_end_point("/users", HttpMethod::POST, Arc::new(create_user));
+    server.serve_static("/images", Path::new("images"));
+    server.do_before(allow_only(IpAddr::V4(Ipv4Addr::LOCALHOST))); // executed before starting process the request
     server.do_after(Box::new(do_after)); // executed after the request has been processed
-    server.start()
+    server.on_request_complete(Box::new(log_to_stderr)); // executed once the response has been sent
+    server.on_accept_error(Box::new(|error, pause| eprintln!("cannot accept a connection, retrying in {:?}: {}", pause, error)));
+    if let Err(error) = server.start() {
+        eprintln!("{}", error);
+    }
 }
 
-fn filter(c:&HttpConnection) -> bool {
-    match c.socket_addr {
-        SocketAddr::V4(addr) => {
-            addr.ip().to_string() != "127.0.0.1"
+/// Answers requests from anywhere but `ip` with a 403.
+fn allow_only(ip: IpAddr) -> BeforeFilter {
+    Box::new(move |c: &HttpConnection| {
+        if c.socket_addr.ip() == ip {
+            None
+        } else {
+            Some(HttpResponse::build_response(HttpStatus::FORBIDDEN, Some(b"local requests only".to_vec())))
         }
-        SocketAddr::V6(addr) => {
-            true
-        }
-    }
+    })
 }
 
 fn do_after(response: &mut HttpResponse) {
@@ -30,22 +44,120 @@ fn do_after(response: &mut HttpResponse) {
 
 fn test(r: HttpContext) -> HttpResponse {
     let request = r.request;
+    let id: u64 = match r.get_path_param_as("id") {
+        Ok(id) => id,
+        Err(e) => return e.into()
+    };
+    println!("id: {}", id);
+    println!("page: {}", r.get_query_param_or("page", 1u32));
     println!("path params: {:?}", r.path_params);
     println!("query params: {:?}", r.query_params);
     println!("method: {:?}", request.method);
     println!("version: {:?}", request.version);
     println!("headers: {:?}", request.headers);
-    println!("body: {:?}", request.body);
-    return HttpResponse::ok_with_data(String::from("nb").into_bytes())
+    let body = match request.body_json() {
+        Ok(body) => body,
+        Err(e) => return HttpResponse::bad_request_with_data(e.to_string().into_bytes())
+    };
+    println!("body: {:?}", body);
+    println!("name: {}", body.get_string_or("name", "anonymous"));
+    match body.get_i64("age") {
+        Ok(age) => println!("age: {}", age),
+        Err(e) => return HttpResponse::bad_request_with_data(e.to_string().into_bytes())
+    }
+    HttpResponse::ok().json(body)
 }
 
-fn get_image(r: HttpContext) -> HttpResponse {
-    let image_id = r.get_path_param("image-id").unwrap();
-    let file_path = format!(r"images\{}.jpg", image_id);
-    let mut file = File::open(file_path).unwrap();
+fn create_user(r: HttpContext) -> HttpResponse {
+    let user: CreateUser = match r.body_as() {
+        Ok(user) => user,
+        Err(e) => return e.into()
+    };
+    println!("creating {:?}", user);
+    HttpResponse::with_status(HttpStatus::CREATED).text(&format!("created {}", user.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::base::HttpHeader;
+    use crate::http::testing::TestServer;
+    use super::*;
 
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
+    fn demo_server() -> TestServer {
+        TestServer::start(|server| {
+            server.register_end_point("/abc/{username}/{id:int}", HttpMethod::GET, Arc::new(test));
+            server.register_end_point("/users", HttpMethod::POST, Arc::new(create_user));
+            server.register_end_point("/params/{username}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                let page = r.get_query_param_or("page", 1u32);
+                let tags = r.get_query_params("tag").join(",");
+                HttpResponse::ok().text(&format!("{} {} {}", r.path_params["username"], page, tags))
+            }));
+            server.do_before(allow_only(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            server.do_after(Box::new(do_after));
+        })
+    }
+
+    #[test]
+    fn path_params_are_typed() {
+        let server = demo_server();
+        let body = br#"{"name": "ann", "age": 30}"#;
+        let response = server.request(HttpMethod::GET, "/abc/ann/42", &[(HttpHeader::CONTENT_TYPE, "application/json")], body);
+        assert_eq!(response.status, 200);
+        let echoed: CreateUser = response.json().unwrap();
+        assert_eq!((echoed.name.as_str(), echoed.age), ("ann", 30));
+        // `{id:int}` only matches digits
+        assert_eq!(server.get("/abc/ann/forty-two").status, 404);
+        let missing_age = server.request(HttpMethod::GET, "/abc/ann/42", &[], br#"{"name": "ann"}"#);
+        assert_eq!(missing_age.status, 400);
+    }
 
-    return HttpResponse::build_response(HttpStatus::OK, Some(buffer));
-}
\ No newline at end of file
+    #[test]
+    fn query_params_are_parsed() {
+        let server = demo_server();
+        assert_eq!(server.get("/params/ann?page=3&tag=a&tag=b").text().unwrap(), "ann 3 a,b");
+        assert_eq!(server.get("/params/ann?page=x").text().unwrap(), "ann 1 ");
+        assert_eq!(server.get("/params/b%C3%A9a").text().unwrap(), "béa 1 ");
+    }
+
+    #[test]
+    fn body_deserializes_into_a_struct() {
+        let server = demo_server();
+        let json = [(HttpHeader::CONTENT_TYPE, "application/json")];
+        let created = server.request(HttpMethod::POST, "/users", &json, br#"{"name": "ann", "age": 30}"#);
+        assert_eq!(created.status, 201);
+        assert_eq!(created.text().unwrap(), "created ann");
+        assert_eq!(server.request(HttpMethod::POST, "/users", &json, br#"{"name": "ann"}"#).status, 400);
+        assert_eq!(server.request(HttpMethod::POST, "/users", &[], br#"{"name": "ann", "age": 30}"#).status, 400);
+    }
+
+    #[test]
+    fn filter_rejects_foreign_addresses() {
+        let foreign = TestServer::start(|server| {
+            server.register_end_point("/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hi")));
+            server.do_before(allow_only(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+        });
+        let response = foreign.get("/");
+        assert_eq!(response.status, 403);
+        assert_eq!(response.body, b"local requests only");
+        assert_eq!(demo_server().get("/params/ann").status, 200);
+    }
+
+    #[test]
+    fn do_after_adds_its_header() {
+        let server = demo_server();
+        assert_eq!(server.get("/params/ann").header("Server Name"), Some("yoo"));
+        // also on responses the dispatcher produced itself
+        assert_eq!(server.get("/missing").header("Server Name"), Some("yoo"));
+    }
+
+    #[test]
+    fn json_response_headers() {
+        let server = demo_server();
+        let body = br#"{"name": "ann", "age": 30}"#;
+        let response = server.request(HttpMethod::GET, "/abc/ann/42", &[(HttpHeader::CONTENT_TYPE, "application/json")], body);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header(HttpHeader::CONTENT_TYPE), Some("application/json"));
+        assert_eq!(response.header(HttpHeader::CONTENT_LENGTH), Some(response.body.len().to_string().as_str()));
+        assert_eq!(response.text().unwrap(), r#"{"name": "ann","age": 30}"#);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::http::base::{HttpHeader, HttpMethod, HttpRequest, HttpResponse, HttpStatus};
+
+/// A strong entity tag derived from `data` with 64-bit FNV-1a, stable across restarts.
+pub(crate) fn body_etag(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+/// Quotes `tag` unless it already is a quoted or weak (`W/"..."`) entity tag.
+pub(crate) fn quote(tag: &str) -> String {
+    if tag.starts_with('"') || tag.starts_with("W/\"") {
+        tag.to_string()
+    } else {
+        format!("\"{}\"", tag)
+    }
+}
+
+/// Whether `etag` is in the `If-Match`/`If-None-Match` list `condition`. The weak comparison
+/// ignores `W/` prefixes, the strong one never matches a weak tag. `*` is left to the caller.
+pub(crate) fn matches(condition: &str, etag: &str, weak: bool) -> bool {
+    let opaque = |tag: &str| -> Option<String> {
+        match tag.trim().strip_prefix("W/") {
+            Some(_) if !weak => None,
+            Some(tag) => Some(tag.to_string()),
+            None => Some(tag.trim().to_string()),
+        }
+    };
+    let Some(etag) = opaque(etag) else {
+        return false;
+    };
+    condition.split(',').any(|candidate| opaque(candidate).is_some_and(|candidate| candidate == etag))
+}
+
+/// The 304 sent instead of `response`: no body, but the headers a cache needs to refresh the
+/// copy it already holds.
+pub(crate) fn not_modified(response: &HttpResponse) -> HttpResponse {
+    let mut not_modified = HttpResponse::with_status(HttpStatus::NOT_MODIFIED);
+    for name in [HttpHeader::ETAG, HttpHeader::CACHE_CONTROL, HttpHeader::VARY, HttpHeader::EXPIRES, HttpHeader::CONTENT_LOCATION] {
+        if let Some(value) = response.get_header(name) {
+            not_modified.set_header(name.to_string(), value.to_string());
+        }
+    }
+    not_modified
+}
+
+/// Replaces a successful GET or HEAD response with a 304 when the client's `If-None-Match`
+/// already names its `ETag`, or is `*`. Weak tags count, a cached copy only has to be equivalent.
+pub(crate) fn check_if_none_match(request: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let Some(condition) = request.headers.get(HttpHeader::IF_NONE_MATCH) else {
+        return response;
+    };
+    if !matches!(request.method, HttpMethod::GET | HttpMethod::HEAD) || !(200..300).contains(&response.status) {
+        return response;
+    }
+    let matched = condition.trim() == "*"
+        || response.get_header(HttpHeader::ETAG).is_some_and(|tag| matches(condition, tag, true));
+    if matched { not_modified(&response) } else { response }
+}
+
+/// Whether the current `ETag` of a resource satisfies the `If-Match` of a mutating request: the
+/// resource must exist and, unless the condition is `*`, its tag be one of the listed ones
+/// compared strongly.
+pub(crate) fn satisfies_if_match(condition: &str, current: Option<&str>) -> bool {
+    current.is_some_and(|tag| condition.trim() == "*" || matches(condition, &quote(tag), false))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use crate::http::base::{HttpMethod, HttpResponse, HttpStatus};
+    use crate::http::testing::TestServer;
+    use super::*;
+
+    #[test]
+    fn matches_lists_and_weak_tags() {
+        assert!(matches("\"a\", \"b\"", "\"b\"", false));
+        assert!(!matches("\"a\"", "\"b\"", false));
+        assert!(matches("W/\"a\"", "\"a\"", true));
+        assert!(!matches("W/\"a\"", "\"a\"", false));
+        assert!(satisfies_if_match("*", Some("v1")));
+        assert!(!satisfies_if_match("*", None));
+        assert!(satisfies_if_match("\"v1\"", Some("v1")));
+    }
+
+    #[test]
+    fn conditional_get_gets_not_modified() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/doc", HttpMethod::GET, Arc::new(|_| {
+                HttpResponse::with_status(HttpStatus::OK).header(HttpHeader::CACHE_CONTROL, "max-age=60").text("content")
+            }));
+            server.enable_auto_etag();
+        });
+        let first = server.get("/doc");
+        assert_eq!(first.status, 200);
+        let tag = first.header(HttpHeader::ETAG).expect("an ETag").to_string();
+        let second = server.request(HttpMethod::GET, "/doc", &[(HttpHeader::IF_NONE_MATCH, &tag)], &[]);
+        assert_eq!(second.status, 304);
+        assert!(second.body.is_empty());
+        assert_eq!(second.header(HttpHeader::ETAG), Some(tag.as_str()));
+        assert_eq!(second.header(HttpHeader::CACHE_CONTROL), Some("max-age=60"));
+        let listed = format!("\"other\", {}", tag);
+        assert_eq!(server.request(HttpMethod::GET, "/doc", &[(HttpHeader::IF_NONE_MATCH, &listed)], &[]).status, 304);
+        assert_eq!(server.request(HttpMethod::GET, "/doc", &[(HttpHeader::IF_NONE_MATCH, "*")], &[]).status, 304);
+        assert_eq!(server.request(HttpMethod::GET, "/doc", &[(HttpHeader::IF_NONE_MATCH, "\"other\"")], &[]).status, 200);
+    }
+
+    #[test]
+    fn if_match_uses_the_validator_without_calling_handlers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = TestServer::start(|server| {
+            let counted = Arc::clone(&calls);
+            server.register_end_point("/doc", HttpMethod::PUT, Arc::new(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                HttpResponse::with_status(HttpStatus::NO_CONTENT)
+            })).with_etag_validator(Box::new(|_| Some("v2".to_string())));
+            server.register_end_point("/unversioned", HttpMethod::DELETE, Arc::new(|_| HttpResponse::with_status(HttpStatus::NO_CONTENT)));
+        });
+        let put = |condition: &str| server.request(HttpMethod::PUT, "/doc", &[(HttpHeader::IF_MATCH, condition)], b"new").status;
+        assert_eq!(put("\"v1\""), 412);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(put("\"v1\", \"v2\""), 204);
+        assert_eq!(put("*"), 204);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let delete = |condition: &str| server.request(HttpMethod::DELETE, "/unversioned", &[(HttpHeader::IF_MATCH, condition)], &[]).status;
+        assert_eq!(delete("\"v1\""), 412);
+        assert_eq!(delete("*"), 204);
+        // routing comes first, a method the path does not have is still a 405
+        assert_eq!(server.request(HttpMethod::POST, "/doc", &[(HttpHeader::IF_MATCH, "\"v2\"")], &[]).status, 405);
+    }
+
+    #[test]
+    fn if_match_runs_after_the_filters() {
+        let server = TestServer::start(|server| {
+            server.do_before(Box::new(|connection| {
+                connection.request.headers.get("Authorization").is_none()
+                    .then(|| HttpResponse::with_status(HttpStatus::UNAUTHORIZED))
+            }));
+            server.register_end_point("/doc", HttpMethod::PUT, Arc::new(|_| HttpResponse::with_status(HttpStatus::NO_CONTENT)))
+                .with_etag_validator(Box::new(|_| panic!("the filter rejects the request first")));
+        });
+        assert_eq!(server.request(HttpMethod::PUT, "/doc", &[(HttpHeader::IF_MATCH, "\"v1\"")], &[]).status, 401);
+    }
+}
@@ -0,0 +1,114 @@
+/// Header fields looked up case-insensitively, keeping the casing and order they were added in
+/// for output. A name can carry several values, e.g. repeated `Accept` or `Via` fields.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct HeaderMap {
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl HeaderMap {
+    pub(crate) fn new() -> HeaderMap {
+        HeaderMap { entries: Vec::new() }
+    }
+
+    /// The first value of `name`.
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).first().map(String::as_str)
+    }
+
+    /// Every value of `name` in the order they were added, empty if there is none.
+    pub(crate) fn get_all(&self, name: &str) -> &[String] {
+        self.entry(name).map_or(&[], |(_, values)| values.as_slice())
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.entry(name).is_some()
+    }
+
+    /// Sets `name` to `value` alone, replacing every previous value and the previous casing.
+    pub(crate) fn insert(&mut self, name: &str, value: &str) {
+        match self.entry_mut(name) {
+            Some(entry) => *entry = (name.to_string(), vec![value.to_string()]),
+            None => self.entries.push((name.to_string(), vec![value.to_string()])),
+        }
+    }
+
+    /// Adds `value` after the existing values of `name`.
+    pub(crate) fn append(&mut self, name: &str, value: &str) {
+        match self.entry_mut(name) {
+            Some((_, values)) => values.push(value.to_string()),
+            None => self.entries.push((name.to_string(), vec![value.to_string()])),
+        }
+    }
+
+    /// Removes every value of `name`, returning whether there was any.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.entries.len() != len
+    }
+
+    /// Every name and value pair, a name with several values once per value.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter()
+            .flat_map(|(name, values)| values.iter().map(move |value| (name.as_str(), value.as_str())))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entry(&self, name: &str) -> Option<&(String, Vec<String>)> {
+        self.entries.iter().find(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+
+    fn entry_mut(&mut self, name: &str) -> Option<&mut (String, Vec<String>)> {
+        self.entries.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Appends each pair, so repeated names keep all their values.
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        let mut headers = HeaderMap::new();
+        iter.into_iter().for_each(|(name, value)| headers.append(&name, &value));
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-TYPE", "text/plain");
+        assert_eq!(headers.get("Content-Type"), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert!(headers.contains("content-type"));
+
+        // replacing takes the new casing, without leaving a second entry
+        headers.insert("Content-Type", "application/json");
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [("Content-Type", "application/json")]);
+
+        headers.append("accept", "text/html");
+        headers.append("ACCEPT", "application/json");
+        assert_eq!(headers.get_all("Accept"), ["text/html", "application/json"]);
+        assert_eq!(headers.get("Accept"), Some("text/html"));
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.iter().collect::<Vec<_>>(), [
+            ("Content-Type", "application/json"),
+            ("accept", "text/html"),
+            ("accept", "application/json"),
+        ]);
+
+        assert!(headers.remove("CONTENT-type"));
+        assert!(!headers.remove("Content-Type"));
+        assert_eq!(headers.get("content-type"), None);
+        assert!(headers.get_all("content-type").is_empty());
+    }
+}
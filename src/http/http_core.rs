@@ -1,27 +1,108 @@
-use std::collections::{HashMap, HashSet};
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::net::{TcpListener};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
 use std::string::ToString;
-use std::vec;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::{thread, vec};
 use regex::Regex;
-use crate::http::base::{HttpConnection, HttpMethod, HttpContext, HttpResponse, HttpStatus};
+use std::time::{Duration, Instant, SystemTime};
+use crate::http::base::{invalid_json_response, percent_decode, ConnectionInfo, HttpConnection, HttpHeader, HttpMethod, HttpContext, HttpRequest, HttpResponse, HttpStatus, KeepAlive, RequestLimits};
+use crate::http::access_log::RequestLog;
+use crate::http::cache::{CacheConfig, ResponseCache};
+use crate::http::compression::{default_encoders, Compression, Encoder};
+use crate::http::etag;
+use crate::http::request_id;
+use crate::http::router::{RouteInfo, RouteTrie, Segment};
+use crate::utils::threads::{panic_message, ThreadPool, ThreadPoolBuilder};
+
+/// Handlers and filters run on the server's worker threads, hence the `Send + Sync` bounds.
+pub(crate) type Handler = Arc<dyn Fn(HttpContext) -> HttpResponse + Send + Sync>;
+/// Returns `None` to let the request through, or the response to send instead of calling the handler.
+pub(crate) type BeforeFilter = Box<dyn Fn(&HttpConnection) -> Option<HttpResponse> + Send + Sync>;
+pub(crate) type AfterFilter = Box<dyn Fn(&mut HttpResponse) + Send + Sync>;
+pub(crate) type RequestLogger = Box<dyn Fn(&RequestLog) + Send + Sync>;
+/// Gets an error of `accept` and how long the server pauses before accepting again.
+pub(crate) type AcceptErrorHook = Box<dyn Fn(&io::Error, Duration) + Send + Sync>;
+/// Gets the payload of the panic, as `std::panic::catch_unwind` returns it.
+pub(crate) type PanicHandler = Box<dyn Fn(&(dyn Any + Send)) -> HttpResponse + Send + Sync>;
+/// The current `ETag` of the resource a request targets, `None` if it does not exist.
+pub(crate) type EtagValidator = Box<dyn Fn(&HttpContext) -> Option<String> + Send + Sync>;
 
 struct EndPoint{
     url: String,
-    method: HttpMethod,
-    pub func: Box<dyn Fn(HttpContext) -> HttpResponse>
+    /// `None` for a route answering every method, see `HttpServer::register_any_method`.
+    method: Option<HttpMethod>,
+    pub func: Handler,
+    /// Index of the route's own filters in `RequestDispatcher::route_filters`.
+    filters: usize,
 }
 
 impl EndPoint {
-    fn new(url: &str, method: HttpMethod, func: Box<dyn Fn(HttpContext) -> HttpResponse>) -> Self {
+    fn new(url: &str, method: Option<HttpMethod>, func: Handler, filters: usize) -> Self {
         EndPoint{
             url: url.to_string(),
             method,
-            func
+            func,
+            filters
         }
     }
 }
 
+/// Filters attached to a single registration through [`Route`].
+#[derive(Default)]
+struct RouteFilters {
+    before: Vec<BeforeFilter>,
+    after: Vec<AfterFilter>,
+    validator: Option<EtagValidator>,
+}
+
+/// Returned by the `register_*` methods to attach filters to the routes just registered.
+pub(crate) struct Route<'a> {
+    dispatcher: &'a mut RequestDispatcher,
+    filters: usize,
+}
+
+impl Route<'_> {
+    /// Runs `filter` only for requests this route handles, after the `do_before` filters; the
+    /// first one returning a response short-circuits the handler.
+    pub(crate) fn with_filter(self, filter: BeforeFilter) -> Self {
+        self.dispatcher.route_filters[self.filters].before.push(filter);
+        self
+    }
+
+    /// Answers requests whose body is not a JSON object with a 400 saying where it went wrong,
+    /// see `invalid_json_response`, so the handler can rely on `HttpRequest::body_json`, which
+    /// reuses the parsed body. An empty body passes as an empty object.
+    pub(crate) fn expect_json(self) -> Self {
+        self.with_filter(Box::new(|connection: &HttpConnection| {
+            let request = &connection.request;
+            request.body_json().err().map(|error| invalid_json_response(&request.body, &error))
+        }))
+    }
+
+    /// Runs `filter` only on responses of this route, before the `do_after` filters.
+    pub(crate) fn with_after(self, filter: AfterFilter) -> Self {
+        self.dispatcher.route_filters[self.filters].after.push(filter);
+        self
+    }
+
+    /// Looks up the current `ETag` for the `If-Match` of a POST, PUT, PATCH or DELETE, which
+    /// gets a 412 without calling the handler when it does not match. Without a validator only
+    /// `If-Match: *` passes, the current tag being unknown.
+    pub(crate) fn with_etag_validator(self, validator: EtagValidator) -> Self {
+        self.dispatcher.route_filters[self.filters].validator = Some(validator);
+        self
+    }
+}
+
 impl PartialEq<Self> for EndPoint {
     fn eq(&self, other: &Self) -> bool {
         other.url == self.url && other.method == self.method
@@ -37,67 +118,725 @@ impl Hash for EndPoint {
     }
 }
 
+/// How paths differing only by a trailing slash, such as `/users` and `/users/`, are routed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TrailingSlash {
+    /// They are different paths, each needs its own endpoint.
+    #[default]
+    Strict,
+    /// The trailing slash is dropped from both endpoints and requests, so they are the same path.
+    TreatAsSame,
+    /// Like `TreatAsSame`, but requests for any path that is not canonical, also one with
+    /// repeated slashes or dot segments, get a 301 to the canonical one.
+    RedirectToCanonical,
+}
+
+/// How the listening socket is set up, see the `HttpServer` builder methods.
+#[derive(Debug, Copy, Clone)]
+struct ListenerOptions {
+    reuse_address: bool,
+    nodelay: bool,
+    backlog: i32,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        // address reuse is what `TcpListener::bind` does on unix
+        ListenerOptions { reuse_address: true, nodelay: false, backlog: 128 }
+    }
+}
+
+/// Why the server could not start listening.
+#[derive(Debug)]
+pub(crate) enum ServerError {
+    /// `host:port` is not an address or did not resolve to one.
+    InvalidAddress(String),
+    /// None of the addresses `host:port` resolved to could be bound, with the last error.
+    Bind(String, io::Error),
+    /// The worker threads could not be spawned.
+    Workers(io::Error),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::InvalidAddress(address) => write!(f, "invalid address {address}"),
+            ServerError::Bind(address, error) => write!(f, "cannot bind {address}: {error}"),
+            ServerError::Workers(error) => write!(f, "cannot spawn the workers: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind(_, error) | ServerError::Workers(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
 pub(crate) struct HttpServer {
     host: String,
     port: u32,
     listener: Option<TcpListener>,
-    dispatcher: RequestDispatcher,
-    do_before: Vec<Box<dyn Fn(&HttpConnection) -> bool>>,
-    do_after: Vec<Box<dyn Fn(&mut HttpResponse)>>
+    listener_options: ListenerOptions,
+    workers: usize,
+    keep_alive: KeepAlive,
+    limits: RequestLimits,
+    server_name: Arc<str>,
+    dispatcher: Arc<RequestDispatcher>,
+    on_accept_error: Vec<AcceptErrorHook>,
 }
 
 impl HttpServer {
+    const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+    const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
     pub(crate) fn bind(host: &str, port: u32) -> Self {
         HttpServer {
             host: String::from(host),
             port,
             listener: None,
-            dispatcher: RequestDispatcher::new(),
-            do_before: vec![],
-            do_after: vec![]
+            listener_options: ListenerOptions::default(),
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            keep_alive: KeepAlive::default(),
+            limits: RequestLimits::default(),
+            server_name: Arc::from(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))),
+            dispatcher: Arc::new(RequestDispatcher::new()),
+            on_accept_error: Vec::new(),
         }
     }
 
-    pub(crate) fn do_before(&mut self, filter: Box<dyn Fn(&HttpConnection) -> bool>) {
-        self.do_before.push(filter)
+    /// Number of worker threads handling connections, defaults to the available parallelism.
+    pub(crate) fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Keeps connections open for up to `max_requests` requests, closing them after `idle_timeout`
+    /// without a new request. A `max_requests` of 1 disables keep-alive. The first request of a
+    /// connection, and any read of a request, must also come within `idle_timeout`.
+    pub(crate) fn keep_alive(mut self, idle_timeout: Duration, max_requests: usize) -> Self {
+        self.keep_alive = KeepAlive { idle_timeout, max_requests };
+        self
+    }
+
+    /// Requests with a larger body get a 413, defaults to 1 MiB.
+    pub(crate) fn max_body_size(mut self, bytes: u64) -> Self {
+        self.limits.max_body_size = bytes;
+        self
+    }
+
+    /// Requests whose request line and headers are larger get a 431, defaults to 16 KiB.
+    pub(crate) fn max_header_size(mut self, bytes: usize) -> Self {
+        self.limits.max_header_size = bytes;
+        self
+    }
+
+    /// The `Server` header of responses whose handler did not set one, defaults to this crate's
+    /// name and version. An empty name leaves the header out.
+    pub(crate) fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Arc::from(name);
+        self
+    }
+
+    /// Whether the address can be bound again while connections of a previous server are in
+    /// `TIME_WAIT`, so that a restart does not fail with "address in use". On by default.
+    #[cfg(feature = "socket2")]
+    pub(crate) fn reuse_address(mut self, reuse: bool) -> Self {
+        self.listener_options.reuse_address = reuse;
+        self
+    }
+
+    /// How many connections the system queues before they are accepted, defaults to 128.
+    #[cfg(feature = "socket2")]
+    pub(crate) fn accept_backlog(mut self, backlog: i32) -> Self {
+        self.listener_options.backlog = backlog;
+        self
+    }
+
+    /// Whether accepted connections disable Nagle's algorithm, sending small responses without
+    /// delay. Off by default.
+    pub(crate) fn nodelay(mut self, nodelay: bool) -> Self {
+        self.listener_options.nodelay = nodelay;
+        self
+    }
+
+    /// Whether `HttpContext::client_ip` takes the client address from `X-Forwarded-For` and
+    /// `X-Real-IP`. Only enable it behind a reverse proxy that sets them, off by default.
+    pub(crate) fn trust_proxy(mut self, trust: bool) -> Self {
+        self.dispatcher_mut().trust_proxy = trust;
+        self
     }
 
-    pub(crate) fn do_after(&mut self, filter: Box<dyn Fn(&mut HttpResponse)>) {
-        self.do_after.push(filter)
+    /// Whether `GET /__routes` answers with `routes()` as JSON, off by default. It goes through
+    /// the `do_before` filters like any request, guard it with one outside of development, and
+    /// shadows a route of your own on that path.
+    pub(crate) fn route_listing(mut self, enabled: bool) -> Self {
+        self.dispatcher_mut().route_listing = enabled;
+        self
     }
 
+    /// Defaults to `TrailingSlash::Strict`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if endpoints were already registered, their paths were normalized by the old policy.
+    pub(crate) fn trailing_slash(mut self, policy: TrailingSlash) -> Self {
+        let dispatcher = self.dispatcher_mut();
+        if !dispatcher.endpoints_pure_url.is_empty() || !dispatcher.endpoints_path_param_url.is_empty() {
+            panic!("the trailing slash policy must be set before registering endpoints")
+        }
+        dispatcher.trailing_slash = policy;
+        self
+    }
+
+    pub(crate) fn do_before(&mut self, filter: BeforeFilter) {
+        self.dispatcher_mut().do_before.push((String::new(), filter))
+    }
+
+    pub(crate) fn do_after(&mut self, filter: AfterFilter) {
+        self.dispatcher_mut().do_after.push((String::new(), filter))
+    }
+
+    /// Routes and filters registered through the returned group are scoped to `prefix`.
+    pub(crate) fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup {
+            server: self,
+            prefix: prefix.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// When several routes match a path, an exact one wins over those with parameters, which are
+    /// tried by the number of literal segments they have, most first, then by the number of
+    /// constrained parameters, so `/users/{id:int}` goes before `/users/{name}`, and registration
+    /// order on a tie; wildcards come last.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the route is already registered for `method`, or if it has the same shape and
+    /// constraints as another one with different parameters, e.g. `/users/{id}` and `/users/{name}`.
     pub(crate) fn register_end_point(&mut self,
                                      url: &str,
                                      method: HttpMethod,
-                                     func: Box<dyn Fn(HttpContext) -> HttpResponse>) {
-        let mut dispatcher = &mut self.dispatcher;
-        dispatcher.register_end_point(url, method, func);
+                                     func: Handler) -> Route<'_> {
+        self.register_end_point_methods(url, &[method], func)
     }
 
-    pub(crate) fn start(&mut self) {
-        match self.listener {
-            None => { self.listener = Some(TcpListener::bind(format!("{}:{}", self.host, self.port)).unwrap()) }
-            Some(_) => {}
+    /// Registers `func` for each of `methods`, as `register_end_point` would one by one. Filters
+    /// attached to the returned route apply to all of them.
+    pub(crate) fn register_end_point_methods(&mut self, url: &str, methods: &[HttpMethod], func: Handler) -> Route<'_> {
+        let dispatcher = self.dispatcher_mut();
+        let filters = dispatcher.new_route_filters();
+        for &method in methods {
+            dispatcher.register_end_point(url, Some(method), Arc::clone(&func), filters);
         }
+        Route { dispatcher, filters }
+    }
+
+    /// Registers `func` for every method on `url`. Routes registered for the request's method
+    /// take precedence, also on a less specific path, and so does the GET route for a HEAD request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `url` already has an any-method route, or as `register_end_point` does.
+    pub(crate) fn register_any_method(&mut self, url: &str, func: Handler) -> Route<'_> {
+        let dispatcher = self.dispatcher_mut();
+        let filters = dispatcher.new_route_filters();
+        dispatcher.register_end_point(url, None, func, filters);
+        Route { dispatcher, filters }
+    }
+
+    /// Replaces the empty 404 sent for paths without any endpoint. The handler gets the request's
+    /// query parameters but no path parameters.
+    pub(crate) fn set_not_found_handler(&mut self, func: Handler) {
+        self.dispatcher_mut().not_found_handler = Some(func);
+    }
+
+    /// Replaces the empty 405 sent when the path exists but not for the request's method. The
+    /// handler finds the methods it could have used in `HttpContext::allowed_methods` and is
+    /// responsible for the `Allow` header.
+    pub(crate) fn set_method_not_allowed_handler(&mut self, func: Handler) {
+        self.dispatcher_mut().method_not_allowed_handler = Some(func);
+    }
+
+    /// Replaces the empty 500 sent when a handler or filter panics. The panic is also reported
+    /// to the `on_request_complete` hooks, see `RequestLog::panic`. Catching panics needs them to
+    /// unwind, which the crate's profiles do in debug and release builds; a binary built with
+    /// `panic = "abort"` is still brought down by the first one.
+    pub(crate) fn set_panic_handler(&mut self, func: PanicHandler) {
+        self.dispatcher_mut().panic_handler = Some(func);
+    }
+
+    /// Calls `logger` once for every request after its response was written, whether a handler,
+    /// a `do_before` filter or the dispatcher itself produced it. Requests too malformed to be
+    /// parsed are rejected before dispatching and not logged. See `access_log::log_to_stderr`.
+    pub(crate) fn on_request_complete(&mut self, logger: RequestLogger) {
+        self.dispatcher_mut().on_complete.push(logger);
+    }
+
+    /// Calls `hook` when accepting a connection fails for a reason other than that connection,
+    /// e.g. running out of file descriptors. The server pauses before trying again, see
+    /// `AcceptErrorHook`, since such errors tend to repeat until some connection closes.
+    pub(crate) fn on_accept_error(&mut self, hook: AcceptErrorHook) {
+        self.on_accept_error.push(hook);
+    }
+
+    /// Compresses text, JSON and XML bodies of at least `min_size` bytes for clients that accept
+    /// it. gzip needs the `gzip` feature, without it responses only gain `Vary: Accept-Encoding`.
+    pub(crate) fn enable_compression(&mut self, min_size: usize) {
+        self.enable_compression_with(min_size, default_encoders());
+    }
 
+    /// Like `enable_compression` with your own encoders, tried in order on equal preference.
+    pub(crate) fn enable_compression_with(&mut self, min_size: usize, encoders: Vec<Box<dyn Encoder>>) {
+        self.dispatcher_mut().compression = Some(Compression::new(min_size, encoders));
+    }
+
+    /// Serves repeated GET requests for the same path and query from memory rather than calling
+    /// the handler again, see `ResponseCache::store` for what is cached. Any other request for
+    /// the path, such as a POST, drops its entries. Only suited to handlers whose response
+    /// depends on nothing but the path and query; filters and compression still run on every
+    /// request.
+    pub(crate) fn enable_response_cache(&mut self, config: CacheConfig) {
+        self.dispatcher_mut().response_cache = Some(ResponseCache::new(config));
+    }
+
+    /// Gives every buffered 2xx body without an `ETag` of its own one hashed from its content, see
+    /// `HttpResponse::auto_etag`, so clients can revalidate with `If-None-Match`.
+    pub(crate) fn enable_auto_etag(&mut self) {
+        self.dispatcher_mut().auto_etag = true;
+    }
+
+    /// Tags every request with the `X-Request-Id` it was sent with, or a new random one if it has
+    /// none or an unusable one. Handlers read it with `HttpContext::request_id`, it is echoed in
+    /// the response header and added to the `RequestLog`.
+    pub(crate) fn enable_request_ids(&mut self) {
+        self.dispatcher_mut().request_ids = true;
+    }
+
+    /// Serves the files below `directory` under `url_prefix`, e.g. `/static/css/site.css` from
+    /// `directory/css/site.css`. Paths escaping the directory and directories themselves get a 403,
+    /// missing files a 404; files are sent as by `HttpResponse::file`, range requests included.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `directory` does not exist.
+    pub(crate) fn serve_static(&mut self, url_prefix: &str, directory: &Path) {
+        let root = directory.canonicalize()
+            .unwrap_or_else(|e| panic!("cannot serve `{}`: {}", directory.display(), e));
+        let parser = PathParamParser::catch_all(url_prefix);
+        let url = format!("{}/{{*path}}", url_prefix.trim_end_matches('/'));
+        let func: Handler = Arc::new(move |context| {
+            match context.get_path_param("path") {
+                Some(path) => serve_file(&root, path),
+                None => HttpResponse::build_response(HttpStatus::NOT_FOUND, None)
+            }
+        });
+        let dispatcher = self.dispatcher_mut();
+        let filters = dispatcher.new_route_filters();
+        if !dispatcher.register_parser_end_point(parser, &url, Some(HttpMethod::GET), func, filters) {
+            panic!("`GET {}` is already used by another endpoint", url)
+        }
+    }
+
+    /// Every registered route, one per method, sorted by pattern then method; routes answering
+    /// every method come first. The files of `serve_static` appear as a wildcard route.
+    pub(crate) fn routes(&self) -> Vec<RouteInfo> {
+        self.dispatcher.routes()
+    }
+
+    fn dispatcher_mut(&mut self) -> &mut RequestDispatcher {
+        Arc::get_mut(&mut self.dispatcher).expect("endpoints and filters must be registered before the server starts")
+    }
+
+    /// Binds the listener now rather than when the server starts, returning its address; binding
+    /// port 0 picks a free port. Calling it again returns the address already bound.
+    pub(crate) fn listen(&mut self) -> Result<SocketAddr, ServerError> {
+        let listener = match self.listener.take() {
+            Some(listener) => self.listener.insert(listener),
+            None => self.listener.insert(bind_listener(&self.host, self.port, self.listener_options)?),
+        };
+        listener.local_addr().map_err(|error| ServerError::Bind(format!("{}:{}", self.host, self.port), error))
+    }
+
+    /// The address the listener is bound to, `None` before `listen` or `start`.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Accepts connections forever, reading and dispatching each one on the worker pool so that
+    /// a slow client only occupies a single worker. Only returns if the address cannot be bound or
+    /// the workers cannot be spawned.
+    pub(crate) fn start(&mut self) -> Result<(), ServerError> {
+        self.listen()?;
+        let pool = self.worker_pool()?;
         let listener = self.listener.as_ref().unwrap();
+        let running = ServerState::new();
+        self.accept_loop(listener, &pool, &running);
+        Ok(())
+    }
+
+    /// Binds the listener and runs the accept loop on a separate thread, returning a handle to
+    /// stop it. Binding port 0 picks a free port, see [`ServerHandle::local_addr`].
+    pub(crate) fn start_in_background(mut self) -> Result<ServerHandle, ServerError> {
+        let local_addr = self.listen()?;
+        let pool = self.worker_pool()?;
+        let listener = self.listener.take().unwrap();
+        let state = ServerState::new();
+        let thread_state = Arc::clone(&state);
+        let thread = thread::spawn(move || {
+            self.accept_loop(&listener, &pool, &thread_state);
+            pool
+        });
+        Ok(ServerHandle {
+            local_addr,
+            state,
+            thread,
+        })
+    }
 
+    /// Threads named `http-worker-{id}`.
+    fn worker_pool(&self) -> Result<ThreadPool, ServerError> {
+        ThreadPoolBuilder::new()
+            .name_prefix("http-worker")
+            .num_threads(self.workers)
+            .build()
+            .map_err(ServerError::Workers)
+    }
+
+    /// Errors that only concern the connection being accepted, the next one may well succeed.
+    fn is_connection_error(error: &io::Error) -> bool {
+        matches!(error.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted)
+    }
+
+    /// Other accept errors, e.g. running out of file descriptors, tend to repeat until some
+    /// connection closes, so they go to the `on_accept_error` hooks and are retried after a
+    /// pause that doubles up to a second rather than in a busy loop.
+    fn accept_loop(&self, listener: &TcpListener, pool: &ThreadPool, state: &Arc<ServerState>) {
+        let mut backoff = Duration::ZERO;
         loop {
-            let accepted = listener.accept().unwrap();
-            let mut connection = HttpConnection::new(accepted);
-            if self.do_before.iter().any(|x| x(&connection)) {
-                connection.response(HttpResponse::build_response(HttpStatus::NOT_ALLOWED, None))
-            } else {
-                self.dispatcher.dispatch(connection, &self.do_after)
+            let accepted = listener.accept();
+            if state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            let accepted = match accepted {
+                Ok(accepted) => accepted,
+                Err(error) if Self::is_connection_error(&error) => continue,
+                Err(error) => {
+                    backoff = (backoff * 2).clamp(Self::MIN_ACCEPT_BACKOFF, Self::MAX_ACCEPT_BACKOFF);
+                    self.on_accept_error.iter().for_each(|hook| hook(&error, backoff));
+                    thread::sleep(backoff);
+                    continue;
+                }
+            };
+            backoff = Duration::ZERO;
+            if self.listener_options.nodelay {
+                let _ = accepted.0.set_nodelay(true);
+            }
+            let dispatcher = Arc::clone(&self.dispatcher);
+            let server_name = Arc::clone(&self.server_name);
+            let (keep_alive, limits) = (self.keep_alive, self.limits);
+            // kept to close the connection on shutdown while it waits for a request
+            let Ok(stream) = accepted.0.try_clone() else {
+                continue;
+            };
+            let state = Arc::clone(state);
+            let id = state.connection_opened();
+            pool.execute(move || {
+                let trust_proxy = dispatcher.trust_proxy;
+                let stream = Arc::new(stream);
+                let connection = state.wait_for_request(id, &stream)
+                    .then(|| HttpConnection::new(accepted, keep_alive, limits, server_name, trust_proxy))
+                    .flatten();
+                state.request_arrived(id);
+                if let Some(mut connection) = connection {
+                    let mut queued = ThreadPool::current_context()
+                        .map_or(Duration::ZERO, |context| context.queued_duration());
+                    loop {
+                        dispatcher.dispatch(&mut connection, queued);
+                        queued = Duration::ZERO;
+                        let next = state.wait_for_request(id, &stream) && connection.next_request();
+                        state.request_arrived(id);
+                        if !next {
+                            break;
+                        }
+                    }
+                }
+                state.connection_closed();
+            });
+        }
+    }
+}
+
+/// Binds the first address `host:port` resolves to that can be bound.
+fn bind_listener(host: &str, port: u32, options: ListenerOptions) -> Result<TcpListener, ServerError> {
+    let address = format!("{}:{}", host, port);
+    let candidates = address.to_socket_addrs().map_err(|_| ServerError::InvalidAddress(address.clone()))?;
+    let mut last_error = None;
+    for candidate in candidates {
+        match bind_socket(candidate, options) {
+            Ok(listener) => return Ok(listener),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    match last_error {
+        Some(error) => Err(ServerError::Bind(address, error)),
+        None => Err(ServerError::InvalidAddress(address)),
+    }
+}
+
+#[cfg(feature = "socket2")]
+fn bind_socket(address: SocketAddr, options: ListenerOptions) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(options.reuse_address)?;
+    socket.bind(&address.into())?;
+    socket.listen(options.backlog)?;
+    Ok(socket.into())
+}
+
+/// Without the `socket2` feature only `nodelay` can be configured, the standard library picks
+/// the rest.
+#[cfg(not(feature = "socket2"))]
+fn bind_socket(address: SocketAddr, _options: ListenerOptions) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+}
+
+/// Shared between the accept loop and the workers: the shutdown flag and the connections
+/// currently being served.
+struct ServerState {
+    shutdown: AtomicBool,
+    connections: Mutex<usize>,
+    idle: Condvar,
+    next_id: AtomicU64,
+    /// The connections waiting for their next request by id, which `shutdown` closes rather
+    /// than letting them wait for as long as the idle timeout.
+    waiting: Mutex<HashMap<u64, Arc<TcpStream>>>,
+}
+
+impl ServerState {
+    fn new() -> Arc<ServerState> {
+        Arc::new(ServerState {
+            shutdown: AtomicBool::new(false),
+            connections: Mutex::new(0),
+            idle: Condvar::new(),
+            next_id: AtomicU64::new(0),
+            waiting: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Counts a connection in, returning its id.
+    fn connection_opened(&self) -> u64 {
+        *self.connections.lock().unwrap() += 1;
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lets `shutdown` close `stream` until `request_arrived`, returning `false` if the server is
+    /// already shutting down and no more requests should be read.
+    fn wait_for_request(&self, id: u64, stream: &Arc<TcpStream>) -> bool {
+        self.waiting.lock().unwrap().insert(id, Arc::clone(stream));
+        // read after the insert, so either this sees the flag or `close_waiting` the stream
+        !self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn request_arrived(&self, id: u64) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    /// Ends the reads of the connections waiting for a request, the requests being served
+    /// are answered all the same.
+    fn close_waiting(&self) {
+        for stream in self.waiting.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    fn connection_closed(&self) {
+        let mut connections = self.connections.lock().unwrap();
+        *connections -= 1;
+        if *connections == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    /// Waits until no connection is being served, returning `false` if `timeout` elapsed first.
+    fn wait_idle(&self, timeout: Duration) -> bool {
+        let connections = self.connections.lock().unwrap();
+        let (connections, _) = self.idle.wait_timeout_while(connections, timeout, |c| *c > 0).unwrap();
+        *connections == 0
+    }
+}
+
+/// Returned by [`HttpServer::start_in_background`].
+pub(crate) struct ServerHandle {
+    local_addr: SocketAddr,
+    state: Arc<ServerState>,
+    thread: JoinHandle<ThreadPool>,
+}
+
+impl ServerHandle {
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections, closes those waiting for their next request and waits up to
+    /// `timeout` for the requests being served, returning whether they all finished. Those still
+    /// running after the timeout are left to complete on their own: their workers are detached
+    /// rather than joined, and the pool is leaked.
+    pub(crate) fn shutdown(self, timeout: Duration) -> bool {
+        self.state.shutdown.store(true, Ordering::SeqCst);
+        // the accept loop is blocked in `accept`, a connection of our own wakes it up
+        let mut wake_addr = self.local_addr;
+        if wake_addr.ip().is_unspecified() {
+            wake_addr.set_ip(match wake_addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(wake_addr);
+        let pool = self.thread.join().unwrap();
+
+        self.state.close_waiting();
+        let finished = self.state.wait_idle(timeout);
+        if finished {
+            drop(pool);
+        } else {
+            // dropping the pool would join the busy workers and block past the timeout, so it is
+            // never dropped: the workers exit once their request is served, but the pool's memory
+            // and its idle threads are only reclaimed with the process
+            std::mem::forget(pool);
+        }
+        finished
+    }
+}
+
+/// A set of routes sharing a path prefix, see [`HttpServer::group`]. Groups nest by calling
+/// `group` again, which appends to the prefix.
+pub(crate) struct RouteGroup<'a> {
+    server: &'a mut HttpServer,
+    prefix: String,
+}
+
+impl<'a> RouteGroup<'a> {
+    pub(crate) fn register_end_point(&mut self, url: &str, method: HttpMethod, func: Handler) -> Route<'_> {
+        let url = format!("{}{}", self.prefix, url);
+        self.server.register_end_point(&url, method, func)
+    }
+
+    pub(crate) fn register_end_point_methods(&mut self, url: &str, methods: &[HttpMethod], func: Handler) -> Route<'_> {
+        let url = format!("{}{}", self.prefix, url);
+        self.server.register_end_point_methods(&url, methods, func)
+    }
+
+    pub(crate) fn register_any_method(&mut self, url: &str, func: Handler) -> Route<'_> {
+        let url = format!("{}{}", self.prefix, url);
+        self.server.register_any_method(&url, func)
+    }
+
+    /// Runs `filter` only for requests below this group's prefix.
+    pub(crate) fn do_before(&mut self, filter: BeforeFilter) {
+        let prefix = self.prefix.clone();
+        self.server.dispatcher_mut().do_before.push((prefix, filter))
+    }
+
+    /// Runs `filter` only on responses to requests below this group's prefix.
+    pub(crate) fn do_after(&mut self, filter: AfterFilter) {
+        let prefix = self.prefix.clone();
+        self.server.dispatcher_mut().do_after.push((prefix, filter))
+    }
+
+    pub(crate) fn group(&mut self, prefix: &str) -> RouteGroup<'_> {
+        RouteGroup {
+            prefix: format!("{}{}", self.prefix, prefix.trim_end_matches('/')),
+            server: self.server,
+        }
+    }
+}
+
+/// Whether `path` is `prefix` itself or lies below it; `/api` covers `/api/users` but not `/apis`.
+fn is_under_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false
+    }
+}
+
+/// A 412 for a POST, PUT, PATCH or DELETE whose `If-Match` the route's current `ETag` does not
+/// satisfy, see `Route::with_etag_validator`.
+fn check_if_match(filters: &RouteFilters, path_params: &HashMap<String, String>, request: &HttpRequest, info: ConnectionInfo) -> Option<HttpResponse> {
+    let condition = request.headers.get(HttpHeader::IF_MATCH)?;
+    if !matches!(request.method, HttpMethod::POST | HttpMethod::PUT | HttpMethod::PATCH | HttpMethod::DELETE) {
+        return None;
+    }
+    let satisfied = match &filters.validator {
+        Some(validator) => {
+            let current = validator(&HttpContext::new(path_params.clone(), request, info));
+            etag::satisfies_if_match(condition, current.as_deref())
+        }
+        None => condition.trim() == "*",
+    };
+    if satisfied {
+        None
+    } else {
+        Some(HttpResponse::with_status(HttpStatus::PRECONDITION_FAILED))
+    }
+}
+
+/// Collapses repeated slashes and resolves `.` and `..` segments of an absolute path, keeping a
+/// trailing slash. `None` if a `..` climbs above the root. Other targets, such as the `*` of
+/// `OPTIONS *`, are returned as they are.
+fn normalize_path(path: &str) -> Option<String> {
+    if !path.starts_with('/') {
+        return Some(path.to_string());
+    }
+    let mut segments = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
             }
+            segment => segments.push(segment),
         }
     }
+    // `/a/b/..` names the directory `/a/`, not `/a`
+    let last = path.rsplit('/').next().unwrap_or_default();
+    let trailing = last.is_empty() || last == "." || last == "..";
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+fn serve_file(root: &Path, relative: &str) -> HttpResponse {
+    let path = match root.join(relative).canonicalize() {
+        Ok(path) => path,
+        Err(_) => return HttpResponse::build_response(HttpStatus::NOT_FOUND, None)
+    };
+    // canonicalization resolved `..` and symlinks, so anything outside the root is an escape
+    if !path.starts_with(root) || path.is_dir() {
+        return HttpResponse::build_response(HttpStatus::FORBIDDEN, None);
+    }
+    HttpResponse::file(path)
 }
 
 #[derive(Debug)]
 struct PathParamParser{
     path_param: Vec<String>,
-    url_path_pattern_regex: Regex,
+    /// Whether a parameter spans several segments, such parsers only apply when no other one does.
+    wildcard: bool,
+    /// Segments without any parameter; the more a parser has, the more specific it is.
+    literal_segments: usize,
+    /// Parameters with a constraint other than `path`, which break ties of `literal_segments`.
+    constrained_params: usize,
+    /// The url with each parameter replaced by `{}`, `{constraint}` if it has one, or `{*}` when
+    /// it spans segments. Two parsers of the same shape would match the same paths.
+    shape: String,
+    /// What the route trie indexes the parser by.
+    segments: Vec<Segment>,
     pattern_str: String,
 }
 
@@ -107,46 +846,124 @@ impl PartialEq for PathParamParser {
     }
 }
 impl  PathParamParser  {
-    fn new(path_param: Vec<String>, url: &str) -> PathParamParser {
-        let regex = Regex::new(r"\{([\w-]+)}").unwrap();
-        let mut pattern_str = regex.replace_all(url, "([\\w-]+)").to_string();
-        pattern_str.push('$');
-        let url_path_pattern_regex = Regex::new(pattern_str.as_str()).unwrap();
+    /// Builds the matcher for a url with `{name}` or `{name:constraint}` placeholders, see
+    /// `constraint_pattern` for the constraints. A path that violates a constraint does not match,
+    /// leaving it to the next route.
+    ///
+    /// # Panics
+    ///
+    /// Panics on an unknown constraint, or a `path` parameter that is not the whole last segment.
+    fn new(placeholder: &Regex, url: &str) -> PathParamParser {
+        let mut path_param = vec![];
+        let mut wildcard = false;
+        let mut constrained_params = 0;
+        let mut pattern_str = String::new();
+        let mut shape = String::new();
+        let mut segments = vec![];
+        let raw_segments: Vec<&str> = url.strip_prefix('/').unwrap_or(url).split('/').collect();
+        for (index, segment) in raw_segments.iter().enumerate() {
+            pattern_str.push('/');
+            shape.push('/');
+            if !placeholder.is_match(segment) {
+                pattern_str.push_str(&regex::escape(segment));
+                shape.push_str(segment);
+                segments.push(Segment::Literal(segment.to_string()));
+                continue;
+            }
+            let mut segment_pattern = String::from("^");
+            let mut literal_start = 0;
+            let mut rest = false;
+            for cap in placeholder.captures_iter(segment) {
+                let whole = cap.get(0).unwrap();
+                segment_pattern.push_str(&regex::escape(&segment[literal_start..whole.start()]));
+                shape.push_str(&segment[literal_start..whole.start()]);
+                let constraint = cap.get(2).map_or("", |c| c.as_str());
+                let fragment = Self::constraint_pattern(constraint)
+                    .unwrap_or_else(|| panic!("`{}` uses the unknown path parameter constraint `{}`", url, constraint));
+                if constraint == "path" {
+                    if whole.as_str() != *segment || index + 1 != raw_segments.len() {
+                        panic!("`{}` has a `path` parameter that is not the whole last segment", url)
+                    }
+                    rest = true;
+                }
+                segment_pattern.push_str(&format!("({})", fragment));
+                if rest {
+                    shape.push_str("{*}");
+                } else {
+                    shape.push_str(&format!("{{{}}}", constraint));
+                    constrained_params += usize::from(!constraint.is_empty());
+                }
+                path_param.push(cap[1].to_string());
+                literal_start = whole.end();
+            }
+            segment_pattern.push_str(&regex::escape(&segment[literal_start..]));
+            segment_pattern.push('$');
+            shape.push_str(&segment[literal_start..]);
+            pattern_str.push_str(&segment_pattern);
+            wildcard |= rest;
+            segments.push(if rest { Segment::Rest } else { Segment::Param(Regex::new(&segment_pattern).unwrap()) });
+        }
+        let literal_segments = url.split('/')
+            .filter(|segment| !segment.is_empty() && !placeholder.is_match(segment))
+            .count();
         PathParamParser {
             path_param,
-            url_path_pattern_regex,
+            wildcard,
+            literal_segments,
+            constrained_params,
+            shape,
+            segments,
             pattern_str
         }
     }
 
-    fn is_match(&self, url: &str) -> bool {
-        self.url_path_pattern_regex.is_match(url)
-    }
-
-    pub(crate) fn parse(&self, url: &str) -> (HashMap<String, String>, HashMap<String, String>){
-        let without_query_params:&str = url.split("?").take(1).next().unwrap();
-
-        let mut path_params = HashMap::new();
-        for cap in self.url_path_pattern_regex.captures_iter(without_query_params) {
-            for (i, string) in self.path_param.iter().enumerate() {
-                path_params.insert(string.clone(), cap[i + 1].to_string());
-            }
+    /// `int` is digits only, `alpha` word characters, `uuid` the 8-4-4-4-12 hex shape and `path`
+    /// the rest of the url, slashes included. Without a constraint a parameter is a single segment,
+    /// percent-encoded characters included, so an encoded `%2F` does not split it.
+    fn constraint_pattern(constraint: &str) -> Option<&'static str> {
+        match constraint {
+            "" => Some(r"[\w%-]+"),
+            "int" => Some(r"[0-9]+"),
+            "alpha" => Some(r"\w+"),
+            "uuid" => Some(r"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}"),
+            "path" => Some(r".+"),
+            _ => None
         }
+    }
 
-        let mut query_params = HashMap::new();
-        match url.split_once("?") {
-            None => {}
-            Some((_, queries)) => {
-                queries.split("&")
-                    .map(|x| x.split_once("=").unwrap())
-                    .for_each(|(query, value)| {
-                        query_params.insert(String::from(query), String::from(value));
-                    });
-            }
+    /// Matches every path below `prefix`, capturing the remainder (slashes included) as `path`.
+    fn catch_all(prefix: &str) -> PathParamParser {
+        let prefix = prefix.trim_end_matches('/');
+        let literals: Vec<&str> = prefix.split('/').filter(|segment| !segment.is_empty()).collect();
+        // as `new` builds it for `{prefix}/{path:path}`, so the two are the same route
+        let mut pattern_str: String = literals.iter().map(|literal| format!("/{}", regex::escape(literal))).collect();
+        pattern_str.push_str("/^(.+)$");
+        let mut segments: Vec<Segment> = literals.iter().map(|literal| Segment::Literal(literal.to_string())).collect();
+        segments.push(Segment::Rest);
+        PathParamParser {
+            path_param: vec!["path".to_string()],
+            wildcard: true,
+            literal_segments: literals.len(),
+            constrained_params: 0,
+            shape: format!("{}/{{*}}", prefix),
+            segments,
+            pattern_str,
         }
+    }
 
+    /// Sorts parsers by precedence, most specific first: wildcards after everything else, then
+    /// more literal segments before fewer, then more constrained parameters before fewer.
+    fn precedence(&self) -> (bool, Reverse<usize>, Reverse<usize>) {
+        (self.wildcard, Reverse(self.literal_segments), Reverse(self.constrained_params))
+    }
 
-        (path_params, query_params)
+    /// Names the raw `values` the route trie captured for this parser and decodes them.
+    fn path_params(&self, values: Vec<String>) -> HashMap<String, String> {
+        self.path_param.iter()
+            .zip(values)
+            // the whole path was checked for invalid escapes when the request was read
+            .map(|(name, value)| (name.clone(), percent_decode(&value, false).unwrap_or(value)))
+            .collect()
     }
 
 }
@@ -154,8 +971,27 @@ impl  PathParamParser  {
 
 struct RequestDispatcher {
     endpoints_pure_url: HashMap<String, HashSet<EndPoint>>,
+    /// In registration order, which breaks ties in `PathParamParser::precedence`.
     endpoints_path_param_url: Vec<(PathParamParser, HashSet<EndPoint>)>,
-    path_param_pattern:  Regex
+    /// Indexes `endpoints_path_param_url` by segments.
+    path_param_routes: RouteTrie,
+    path_param_pattern:  Regex,
+    /// Filters with the path prefix they are scoped to, empty for global ones.
+    do_before: Vec<(String, BeforeFilter)>,
+    do_after: Vec<(String, AfterFilter)>,
+    /// Indexed by `EndPoint::filters`.
+    route_filters: Vec<RouteFilters>,
+    not_found_handler: Option<Handler>,
+    method_not_allowed_handler: Option<Handler>,
+    panic_handler: Option<PanicHandler>,
+    compression: Option<Compression>,
+    response_cache: Option<ResponseCache>,
+    auto_etag: bool,
+    trailing_slash: TrailingSlash,
+    trust_proxy: bool,
+    request_ids: bool,
+    route_listing: bool,
+    on_complete: Vec<RequestLogger>,
 }
 
 impl RequestDispatcher {
@@ -163,49 +999,166 @@ impl RequestDispatcher {
         RequestDispatcher {
             endpoints_pure_url: HashMap::new(),
             endpoints_path_param_url: vec![],
-            path_param_pattern:  Regex::new(r"\{([\w-]+)}").unwrap()
+            path_param_routes: RouteTrie::default(),
+            path_param_pattern:  Regex::new(r"\{([\w-]+)(?::(\w*))?}").unwrap(),
+            do_before: vec![],
+            do_after: vec![],
+            route_filters: vec![],
+            not_found_handler: None,
+            method_not_allowed_handler: None,
+            panic_handler: None,
+            compression: None,
+            response_cache: None,
+            auto_etag: false,
+            trailing_slash: TrailingSlash::default(),
+            trust_proxy: false,
+            request_ids: false,
+            route_listing: false,
+            on_complete: vec![],
         }
     }
+    /// A slot for the filters of the endpoints about to be registered.
+    fn new_route_filters(&mut self) -> usize {
+        self.route_filters.push(RouteFilters::default());
+        self.route_filters.len() - 1
+    }
+
     fn register_end_point(&mut self,
                                  url: &str,
-                                 method: HttpMethod,
-                                 func: Box<dyn Fn(HttpContext) -> HttpResponse>) {
+                                 method: Option<HttpMethod>,
+                                 func: Handler,
+                                 filters: usize) {
         match url.split_once("?") {
             Some((_, _)) => {
                 panic!("`{}` has query parameters, they are not allowed when defining the endpoint!", url)
             },
             _ => {}
         }
+        let url = &self.canonical_path(url)
+            .unwrap_or_else(|| panic!("`{}` climbs above the root with `..`", url));
+        let pattern = Self::expand_wildcard(url);
         let mut inserted = false;
-        if self.path_param_pattern.is_match(url) {
-            let path_params: Vec<String> = self.path_param_pattern.captures_iter(url)
-                                                                    .map(|x| x[1].to_string())
-                                                                    .collect();
-            let parser = PathParamParser::new(path_params, url);
-            let exist = self.endpoints_path_param_url.iter_mut()
-                                                        .filter(|(p,hs)| *p == parser)
-                                                        .take(1)
-                                                        .next();
-
-            if let Some((_, endpoints)) = exist {
-                inserted = endpoints.insert(EndPoint::new(url, method, func));
-            } else {
-                let mut set = HashSet::new();
-                set.insert(EndPoint::new(url, method, func));
-                self.endpoints_path_param_url.push((parser, set));
-                inserted = true;
-            }
+        if self.path_param_pattern.is_match(&pattern) {
+            let parser = PathParamParser::new(&self.path_param_pattern, &pattern);
+            inserted = self.register_parser_end_point(parser, url, method, func, filters);
         } else {
             inserted = self.endpoints_pure_url.entry(url.to_string())
                                             .or_insert(HashSet::new())
-                                            .insert(EndPoint::new(url, method, func));
+                                            .insert(EndPoint::new(url, method, func, filters));
         }
 
         if !inserted {
-            panic!("`{:?} {}` is already used by another endpoint", method, url)
+            match method {
+                Some(method) => panic!("`{:?} {}` is already used by another endpoint", method, url),
+                None => panic!("`{}` already has an any-method endpoint", url),
+            }
         }
     }
 
+    /// `path` normalized, without its trailing slash unless the policy is `Strict`.
+    fn canonical_path(&self, path: &str) -> Option<String> {
+        let mut path = normalize_path(path)?;
+        if self.trailing_slash != TrailingSlash::Strict && path.len() > 1 && path.ends_with('/') {
+            path.pop();
+        }
+        Some(path)
+    }
+
+    /// Rewrites the request target to its canonical form for routing, or returns the response
+    /// to send instead: a 400 for paths escaping the root, a 301 under `RedirectToCanonical`.
+    fn canonicalize_target(&self, target: &mut String) -> Option<HttpResponse> {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target.as_str(), None)
+        };
+        let Some(canonical) = self.canonical_path(path) else {
+            return Some(HttpResponse::bad_request().text("the path climbs above the root"));
+        };
+        if canonical == path {
+            return None;
+        }
+        let canonical = match query {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical
+        };
+        if self.trailing_slash == TrailingSlash::RedirectToCanonical {
+            return Some(HttpResponse::with_status(HttpStatus::MOVED_PERMANENTLY).header(HttpHeader::LOCATION, &canonical));
+        }
+        *target = canonical;
+        None
+    }
+
+    /// Rewrites a trailing `{*rest}` or `*rest` segment to `{rest:path}`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a wildcard is not the last segment.
+    fn expand_wildcard(url: &str) -> String {
+        let (head, last) = url.rsplit_once('/').unwrap_or(("", url));
+        if head.contains("{*") || head.contains("/*") {
+            panic!("`{}` has a wildcard before its last segment", url)
+        }
+        let name = last.strip_prefix("{*")
+            .and_then(|name| name.strip_suffix('}'))
+            .or_else(|| last.strip_prefix('*'));
+        match name {
+            Some(name) => format!("{}/{{{}:path}}", head, name),
+            None => url.to_string()
+        }
+    }
+
+    fn register_parser_end_point(&mut self,
+                                 parser: PathParamParser,
+                                 url: &str,
+                                 method: Option<HttpMethod>,
+                                 func: Handler,
+                                 filters: usize) -> bool {
+        let exist = self.endpoints_path_param_url.iter_mut()
+                                                    .find(|(p, _)| p.shape == parser.shape);
+
+        if let Some((existing, endpoints)) = exist {
+            // the same route for another method, unless its parameters differ
+            if *existing != parser || existing.path_param != parser.path_param {
+                let other = endpoints.iter().next().map_or("", |e| e.url.as_str());
+                panic!("`{}` is ambiguous with `{}`, both match the same paths", url, other)
+            }
+            endpoints.insert(EndPoint::new(url, method, func, filters))
+        } else {
+            let mut set = HashSet::new();
+            set.insert(EndPoint::new(url, method, func, filters));
+            self.path_param_routes.insert(&parser.segments, self.endpoints_path_param_url.len());
+            self.endpoints_path_param_url.push((parser, set));
+            true
+        }
+    }
+
+    fn routes(&self) -> Vec<RouteInfo> {
+        let pure = self.endpoints_pure_url.values()
+            .flatten()
+            .map(|endpoint| (endpoint, None));
+        let with_params = self.endpoints_path_param_url.iter()
+            .flat_map(|(parser, endpoints)| endpoints.iter().map(move |endpoint| (endpoint, Some(parser))));
+        let mut routes: Vec<RouteInfo> = pure.chain(with_params)
+            .map(|(endpoint, parser)| RouteInfo {
+                pattern: endpoint.url.clone(),
+                method: endpoint.method,
+                path_params: parser.map_or_else(Vec::new, |parser| parser.path_param.clone()),
+                wildcard: parser.is_some_and(|parser| parser.wildcard),
+            })
+            .collect();
+        routes.sort_by(|a, b| (&a.pattern, a.method).cmp(&(&b.pattern, b.method)));
+        routes
+    }
+
+    /// The `routes` for `GET /__routes` when `HttpServer::route_listing` is on.
+    fn list_routes(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        let path = request.path.split('?').next().unwrap_or_default();
+        if !self.route_listing || path != "/__routes" || !matches!(request.method, HttpMethod::GET | HttpMethod::HEAD) {
+            return None;
+        }
+        Some(HttpResponse::with_status(HttpStatus::OK).json(&self.routes()))
+    }
+
     fn find_possible_endpoints_pure_url(&self, url: &str) -> Option<&HashSet<EndPoint>> {
         let without_query_params = url.split("?").take(1).next()?;
         match self.endpoints_pure_url.get(&without_query_params.to_string()) {
@@ -213,50 +1166,1104 @@ impl RequestDispatcher {
             Some(endpoints) => {Some(endpoints)}
         }
     }
-    fn find_possible_endpoints_path_url(&self, url: &str) -> Option<((HashMap<String, String>, HashMap<String, String>), &HashSet<EndPoint>)> {
-        let without_query_params = url.split("?").take(1).next()?;
-        self.endpoints_path_param_url.iter()
-            .filter(|x| x.0.is_match(without_query_params))
-            .map(|x| (x.0.parse(url), &(x.1)))
-            .next()
+    /// The routes with path parameters matching `url`, the most specific first.
+    fn find_possible_endpoints_path_url(&self, url: &str) -> Vec<(HashMap<String, String>, &HashSet<EndPoint>)> {
+        let without_query_params = url.split('?').next().unwrap_or_default();
+        let mut matches = self.path_param_routes.matches(without_query_params);
+        matches.sort_by_key(|(route, _)| (self.endpoints_path_param_url[*route].0.precedence(), *route));
+        matches.into_iter()
+            .map(|(route, values)| {
+                let (parser, endpoints) = &self.endpoints_path_param_url[route];
+                (parser.path_params(values), endpoints)
+            })
+            .collect()
     }
 
-    fn dispatch(&mut self, mut connection: HttpConnection, do_after: &Vec<Box<dyn Fn(&mut HttpResponse)>>) {
+    /// Normalizes the request path, then runs the `do_before` filters and those of the matched route, the first one returning a
+    /// response short-circuits the handler, as does an `If-Match` the route's `ETag` validator rejects, then the route's and the
+    /// `do_after` filters on whichever response was produced.
+    /// `queued` is the wait for a worker reported in the `RequestLog`.
+    fn dispatch(&self, connection: &mut HttpConnection, queued: Duration) {
+        let started = Instant::now();
+        let received_at = SystemTime::now();
+        let target = connection.request.path.clone();
+        let rejection = self.canonicalize_target(&mut connection.request.path);
+        let info = connection.info();
+        if self.request_ids {
+            let id = connection.request.headers.get(HttpHeader::X_REQUEST_ID)
+                .filter(|id| request_id::is_valid(id))
+                .map_or_else(request_id::generate, str::to_string);
+            connection.request.request_id = Some(id);
+        }
         let request = &connection.request;
-        let endpoints_pure_url = match self.find_possible_endpoints_pure_url(&request.path){
-            None => {None}
-            Some(endpoints) => {
-                endpoints.iter()
-                                .filter(|e| e.method == request.method)
-                                .take(1)
-                                .next()
+        let path = request.path.split('?').next().unwrap_or_default();
+        let mut route_filters = None;
+        // nothing the filters and handlers share with the rest of the server outlives a panic of
+        // theirs, the worker can go on with the next request
+        let produced = panic::catch_unwind(AssertUnwindSafe(|| {
+            let rejection = rejection.or_else(|| {
+                self.do_before.iter()
+                    .filter(|(prefix, _)| is_under_prefix(path, prefix))
+                    .find_map(|(_, x)| x(connection))
+            });
+            rejection
+                .or_else(|| self.list_routes(request))
+                .unwrap_or_else(|| match self.resolve(request, info) {
+                    Ok((endpoint, path_params)) => {
+                        let filters = &self.route_filters[endpoint.filters];
+                        route_filters = Some(filters);
+                        filters.before.iter()
+                            .find_map(|x| x(connection))
+                            .or_else(|| check_if_match(filters, &path_params, request, info))
+                            .unwrap_or_else(|| self.handle(endpoint, path_params, request, info))
+                    }
+                    Err(response) => response,
+                })
+        }));
+        let (mut response, panic) = match produced {
+            Ok(response) => (response, None),
+            Err(payload) => {
+                let response = match &self.panic_handler {
+                    Some(handler) => handler(&*payload),
+                    None => HttpResponse::with_status(HttpStatus::INTERNAL_ERROR),
+                };
+                (response, Some(panic_message(&*payload)))
+            }
+        };
+        if let Some(cache) = &self.response_cache {
+            if !matches!(request.method, HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS | HttpMethod::TRACE) {
+                cache.invalidate(path);
             }
+        }
+        if request.method == HttpMethod::HEAD {
+            response.strip_body();
+        }
+
+        route_filters.into_iter()
+            .flat_map(|filters| &filters.after)
+            .for_each(|x| x(&mut response));
+        self.do_after.iter()
+            .filter(|(prefix, _)| is_under_prefix(path, prefix))
+            .for_each(|(_, x)| x(&mut response));
+        if self.auto_etag {
+            response = response.auto_etag();
+        }
+        let mut response = etag::check_if_none_match(request, response);
+        if let (HttpMethod::GET, Some(range)) = (request.method, request.headers.get(HttpHeader::RANGE)) {
+            response.apply_range(range);
+        }
+        if let Some(compression) = &self.compression {
+            compression.apply(request, &mut response);
+        }
+        if let Some(id) = &request.request_id {
+            response.set_header(HttpHeader::X_REQUEST_ID.to_string(), id.clone());
+        }
+        if self.on_complete.is_empty() {
+            let _ = connection.response(response);
+            return;
+        }
+        let status = response.status;
+        let sent = connection.response(response);
+        let request = &connection.request;
+        let log = RequestLog {
+            remote_addr: connection.socket_addr,
+            method: request.method,
+            path: target,
+            version: request.version,
+            status,
+            size: *sent.as_ref().unwrap_or(&0),
+            received_at,
+            duration: started.elapsed(),
+            queued,
+            request_id: request.request_id.clone(),
+            panic,
+            disconnected: sent.is_err(),
+        };
+        self.on_complete.iter().for_each(|logger| logger(&log));
+    }
+
+    /// Calls the endpoint's handler, unless the response cache has a GET response for the path and
+    /// query; responses to GET requests are offered to the cache. Either way they are marked
+    /// with `X-Cache`.
+    fn handle(&self, endpoint: &EndPoint, path_params: HashMap<String, String>, request: &HttpRequest, info: ConnectionInfo) -> HttpResponse {
+        let call = || (endpoint.func)(HttpContext::new(path_params, request, info));
+        let Some(cache) = self.response_cache.as_ref().filter(|_| matches!(request.method, HttpMethod::GET | HttpMethod::HEAD)) else {
+            return call();
         };
+        if let Some(mut cached) = cache.get(&request.path) {
+            cached.set_header(HttpHeader::X_CACHE.to_string(), "HIT".to_string());
+            return cached;
+        }
+        let mut response = call();
+        if request.method == HttpMethod::GET {
+            cache.store(&request.path, &response);
+        }
+        response.set_header(HttpHeader::X_CACHE.to_string(), "MISS".to_string());
+        response
+    }
 
-        let mut response = match endpoints_pure_url {
+    /// The endpoint for `request` with its path parameters, or the response when there is none:
+    /// the 404, the 405 or the answer to an OPTIONS request.
+    fn resolve(&self, request: &HttpRequest, info: ConnectionInfo) -> Result<(&EndPoint, HashMap<String, String>), HttpResponse> {
+        // the exact path first, then the routes with path parameters from the most specific one,
+        // so that a less specific route still gets the methods a more specific one lacks
+        let candidates: Vec<(HashMap<String, String>, &HashSet<EndPoint>)> = self.find_possible_endpoints_pure_url(&request.path)
+            .map(|endpoints| (HashMap::new(), endpoints))
+            .into_iter()
+            .chain(self.find_possible_endpoints_path_url(&request.path))
+            .collect();
+        let find = |method: Option<HttpMethod>| {
+            candidates.iter().find_map(|(params, endpoints)| {
+                endpoints.iter().find(|e| e.method == method).map(|endpoint| (endpoint, params))
+            })
+        };
+        // HEAD falls back to the GET handler unless it was registered explicitly, any-method
+        // routes only get what no route for a specific method takes
+        let endpoint = find(Some(request.method))
+            .or_else(|| if request.method == HttpMethod::HEAD { find(Some(HttpMethod::GET)) } else { None })
+            .or_else(|| find(None));
+
+        match endpoint {
+            Some((endpoint, path_params)) => Ok((endpoint, path_params.clone())),
             None => {
-                match self.find_possible_endpoints_path_url(&request.path) {
-                    None => {HttpResponse::build_response(HttpStatus::NOT_FOUND, None)}
-                    Some(endpoints) => {
-                        let endpoint = endpoints.1.iter()
-                            .filter(|e| e.method == request.method).take(1).next();
-                        if endpoint.is_none() {
-                            HttpResponse::build_response(HttpStatus::NOT_ALLOWED, None)
-                        }else{
-                            let endpoint: &EndPoint = endpoint.unwrap();
-                            let func = &(*endpoint.func);
-                            func(HttpContext::new(endpoints.0.0, endpoints.0.1, request))
-                        }
+                let mut allowed: BTreeSet<HttpMethod> = candidates.iter()
+                    .flat_map(|(_, endpoints)| endpoints.iter())
+                    .filter_map(|e| e.method)
+                    .collect();
+                if allowed.is_empty() {
+                    return Err(match &self.not_found_handler {
+                        Some(func) => func(HttpContext::new(HashMap::new(), request, info)),
+                        None => HttpResponse::not_found(),
+                    });
+                }
+                if allowed.contains(&HttpMethod::GET) {
+                    allowed.insert(HttpMethod::HEAD);
+                }
+                allowed.insert(HttpMethod::OPTIONS);
+                if request.method != HttpMethod::OPTIONS {
+                    if let Some(func) = &self.method_not_allowed_handler {
+                        let mut context = HttpContext::new(HashMap::new(), request, info);
+                        context.allowed_methods = allowed.into_iter().collect();
+                        return Err(func(context));
                     }
                 }
+                let status = if request.method == HttpMethod::OPTIONS { HttpStatus::NO_CONTENT } else { HttpStatus::NOT_ALLOWED };
+                let mut response = HttpResponse::build_response(status, None);
+                let allow: Vec<String> = allowed.iter().map(|m| format!("{:?}", m)).collect();
+                response.set_header(HttpHeader::ALLOW.to_string(), allow.join(", "));
+                Err(response)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use crate::http::base::MediaType;
+    use crate::http::testing::{raw_status, TestServer};
+    use crate::utils::json::{JsonError, JsonParser, Serializer};
+    use super::*;
+
+    #[test]
+    fn panicking_handler_gets_a_500_and_the_server_goes_on() {
+        let (sender, logs) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let server = TestServer::start(|server| {
+            server.register_end_point("/panic", HttpMethod::GET, Arc::new(|_| panic!("no such file")));
+            server.register_end_point("/ok", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("fine")));
+            server.set_panic_handler(Box::new(|payload| {
+                HttpResponse::with_status(HttpStatus::INTERNAL_ERROR).text(&format!("oops: {}", panic_message(payload)))
+            }));
+            server.on_request_complete(Box::new(move |log| {
+                let _ = sender.lock().unwrap().send((log.status, log.panic.clone()));
+            }));
+        });
+        // more requests than workers, none of them may be lost to a panic
+        for _ in 0..4 {
+            let response = server.get("/panic");
+            assert_eq!(response.status, 500);
+            assert_eq!(response.text().unwrap(), "oops: no such file");
+        }
+        assert_eq!(server.get("/ok").text().unwrap(), "fine");
+        // the hooks run after the response is sent, possibly out of order
+        let logged: Vec<_> = logs.iter().take(5).collect();
+        assert_eq!(logged.iter().filter(|log| **log == (500, Some("no such file".to_string()))).count(), 4);
+        assert!(logged.contains(&(200, None)));
+    }
+
+    #[test]
+    fn idle_connections_do_not_hold_the_workers() {
+        let mut server = HttpServer::bind("127.0.0.1", 0)
+            .workers(2)
+            .keep_alive(Duration::from_millis(200), 10);
+        server.register_end_point("/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hi")));
+        let handle = server.start_in_background().unwrap();
+        // as many silent clients as workers, each one sending nothing or only part of its head
+        let mut silent = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut partial = TcpStream::connect(handle.local_addr()).unwrap();
+        io::Write::write_all(&mut partial, b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let client = crate::http::client::HttpClient::new().timeout(Some(Duration::from_secs(5)));
+        let response = client.get(&format!("http://{}/", handle.local_addr())).send().unwrap();
+        assert_eq!(response.status, 200);
+        // both were closed without a response
+        for stream in [&mut silent, &mut partial] {
+            assert_eq!(io::Read::read(stream, &mut [0; 16]).unwrap(), 0);
+        }
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    /// Reads one response of `length` body bytes off a connection that stays open.
+    fn read_response(stream: &mut TcpStream, length: usize) -> String {
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+        let complete = |response: &[u8]| response.windows(4).position(|w| w == b"\r\n\r\n")
+            .is_some_and(|end| response.len() >= end + 4 + length);
+        while !complete(&response) {
+            let read = io::Read::read(stream, &mut buffer).unwrap();
+            assert!(read > 0, "closed after {:?}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buffer[..read]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn shutdown_closes_idle_keep_alive_connections() {
+        let mut server = HttpServer::bind("127.0.0.1", 0)
+            .workers(2)
+            .keep_alive(Duration::from_secs(30), 10);
+        server.register_end_point("/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hi")));
+        let handle = server.start_in_background().unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        io::Write::write_all(&mut stream, b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert!(read_response(&mut stream, 2).ends_with("hi"));
+        // a connection that has not sent its first request yet is idle too
+        let silent = TcpStream::connect(handle.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert!(handle.shutdown(Duration::from_secs(2)));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(io::Read::read(&mut stream, &mut [0; 16]).unwrap(), 0);
+        assert_eq!(io::Read::read(&mut &silent, &mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn kept_alive_connection_serves_sequential_requests() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/count", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&r.request_number.to_string())
+            }));
+        });
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for expected in ["1", "2"] {
+            io::Write::write_all(&mut stream, b"GET /count HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let response = read_response(&mut stream, 1);
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+            assert!(!response.to_ascii_lowercase().contains("connection: close"));
+            assert!(response.ends_with(expected), "{response}");
+        }
+    }
+
+    #[test]
+    fn one_handler_for_several_methods() {
+        let server = TestServer::start(|server| {
+            let handler: Handler = Arc::new(|r: HttpContext| HttpResponse::ok().text(&format!("{:?}", r.request.method)));
+            server.register_end_point("/items/{id}", HttpMethod::GET, Arc::clone(&handler));
+            server.register_end_point("/items/{id}", HttpMethod::POST, handler);
+        });
+        assert_eq!(server.get("/items/1").text().unwrap(), "GET");
+        assert_eq!(server.request(HttpMethod::POST, "/items/1", &[], b"").text().unwrap(), "POST");
+        let response = server.request(HttpMethod::PUT, "/items/1", &[], b"");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header(HttpHeader::ALLOW), Some("GET, POST, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn any_method_route_is_not_shadowed_by_a_more_specific_one() {
+        let server = TestServer::start(|server| {
+            server.register_any_method("/api/{*rest}", Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("any {:?} {}", r.request.method, r.path_params["rest"]))
+            }));
+            server.register_end_point("/api/users/{id}", HttpMethod::DELETE, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("delete {}", r.path_params["id"]))
+            }));
+        });
+        assert_eq!(server.request(HttpMethod::DELETE, "/api/users/5", &[], b"").text().unwrap(), "delete 5");
+        assert_eq!(server.get("/api/users/5").text().unwrap(), "any GET users/5");
+        assert_eq!(server.request(HttpMethod::PUT, "/api/other", &[], b"").text().unwrap(), "any PUT other");
+    }
+
+    fn head(server: &TestServer, path: &str) -> String {
+        server.send_raw(format!("HEAD {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").as_bytes())
+    }
+
+    #[test]
+    fn head_and_options_are_answered_automatically() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/items/{id}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hello")));
+            server.register_end_point("/items/{id}", HttpMethod::DELETE, Arc::new(|_| HttpResponse::ok()));
+        });
+        let response = head(&server, "/items/1");
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.to_ascii_lowercase().contains("\r\ncontent-length:5\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+        let options = server.request(HttpMethod::OPTIONS, "/items/1", &[], b"");
+        assert_eq!(options.status, 204);
+        assert_eq!(options.header(HttpHeader::ALLOW), Some("GET, DELETE, HEAD, OPTIONS"));
+        assert!(options.body.is_empty());
+        assert_eq!(server.request(HttpMethod::OPTIONS, "/other", &[], b"").status, 404);
+    }
+
+    #[test]
+    fn explicit_head_and_options_handlers_take_precedence() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/items/{id}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hello")));
+            server.register_end_point("/items/{id}", HttpMethod::HEAD, Arc::new(|_| {
+                HttpResponse::ok().header("X-Handler", "head").text("ignored body")
+            }));
+            server.register_end_point("/items/{id}", HttpMethod::OPTIONS, Arc::new(|_| {
+                HttpResponse::ok().header(HttpHeader::ALLOW, "GET").text("custom")
+            }));
+        });
+        let response = head(&server, "/items/1");
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("X-Handler:head"), "{response}");
+        // the body is still left out, its length kept
+        assert!(response.to_ascii_lowercase().contains("\r\ncontent-length:12\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+        let options = server.request(HttpMethod::OPTIONS, "/items/1", &[], b"");
+        assert_eq!(options.status, 200);
+        assert_eq!(options.header(HttpHeader::ALLOW), Some("GET"));
+        assert_eq!(options.text().unwrap(), "custom");
+    }
+
+    #[test]
+    fn serve_static_stays_inside_its_directory() {
+        let root = std::env::temp_dir().join(format!("serve_static_{}", std::process::id()));
+        let public = root.join("public");
+        std::fs::create_dir_all(public.join("css/nested")).unwrap();
+        std::fs::write(public.join("css/nested/site.css"), "body {}").unwrap();
+        std::fs::write(public.join("data.unknown"), [0, 1, 2]).unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+        let server = TestServer::start(|server| server.serve_static("/static", &public));
+
+        let css = server.get("/static/css/nested/site.css");
+        assert_eq!(css.status, 200);
+        assert_eq!(css.header(HttpHeader::CONTENT_TYPE), Some("text/css"));
+        assert_eq!(css.text().unwrap(), "body {}");
+        let unknown = server.get("/static/data.unknown");
+        assert_eq!(unknown.header(HttpHeader::CONTENT_TYPE), Some(MediaType::APPLICATION_OCTET_STREAM));
+        assert_eq!(unknown.body, [0, 1, 2]);
+
+        assert_eq!(server.get("/static/css").status, 403);
+        assert_eq!(server.get("/static/missing.css").status, 404);
+        // an encoded slash decodes into the parameter, the resolved path is outside the directory
+        assert_eq!(server.get("/static/..%2Fsecret.txt").status, 403);
+        assert_eq!(server.get("/static/css/..%2F..%2Fsecret.txt").status, 403);
+        let raw = server.send_raw(b"GET /static/css/../../secret.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+        assert!(matches!(raw_status(&raw), 403 | 404), "{raw}");
+        assert!(!raw.contains("secret"), "{raw}");
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn group_filters_only_run_inside_the_group() {
+        let api_calls = Arc::new(AtomicUsize::new(0));
+        let admin_calls = Arc::new(AtomicUsize::new(0));
+        let (api, admin) = (Arc::clone(&api_calls), Arc::clone(&admin_calls));
+        let server = TestServer::start(move |server| {
+            let ok = |text: &'static str| -> Handler { Arc::new(move |_| HttpResponse::ok().text(text)) };
+            server.register_end_point("/health", HttpMethod::GET, ok("health"));
+            server.register_end_point("/apis/users", HttpMethod::GET, ok("apis"));
+            let mut group = server.group("/api/v1/");
+            group.register_end_point("/users", HttpMethod::GET, ok("users"));
+            group.do_before(Box::new(move |_| {
+                api.fetch_add(1, Ordering::SeqCst);
+                None
+            }));
+            group.do_after(Box::new(|response| response.set_header("X-Group".to_string(), "api".to_string())));
+            let mut nested = group.group("/admin");
+            nested.register_end_point("/stats", HttpMethod::GET, ok("stats"));
+            nested.do_before(Box::new(move |c| {
+                admin.fetch_add(1, Ordering::SeqCst);
+                let allowed = c.request.header("X-Admin").is_some();
+                if allowed { None } else { Some(HttpResponse::with_status(HttpStatus::FORBIDDEN)) }
+            }));
+        });
+
+        for (path, text) in [("/health", "health"), ("/apis/users", "apis")] {
+            let response = server.get(path);
+            assert_eq!(response.text().unwrap(), text);
+            assert_eq!(response.header("X-Group"), None);
+        }
+        assert_eq!(server.get("/missing").status, 404);
+        assert_eq!((api_calls.load(Ordering::SeqCst), admin_calls.load(Ordering::SeqCst)), (0, 0));
+
+        let users = server.get("/api/v1/users");
+        assert_eq!(users.text().unwrap(), "users");
+        assert_eq!(users.header("X-Group"), Some("api"));
+        assert_eq!((api_calls.load(Ordering::SeqCst), admin_calls.load(Ordering::SeqCst)), (1, 0));
+
+        // the outer group's filters also run for the nested one
+        assert_eq!(server.get("/api/v1/admin/stats").status, 403);
+        let stats = server.request(HttpMethod::GET, "/api/v1/admin/stats", &[("X-Admin", "1")], b"");
+        assert_eq!(stats.text().unwrap(), "stats");
+        assert_eq!(stats.header("X-Group"), Some("api"));
+        assert_eq!((api_calls.load(Ordering::SeqCst), admin_calls.load(Ordering::SeqCst)), (3, 2));
+    }
+
+    #[test]
+    fn custom_not_found_and_method_not_allowed_handlers() {
+        let routes = |server: &mut HttpServer| {
+            server.register_end_point("/users/{id}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("user")));
+            server.register_end_point("/users/{id}", HttpMethod::PUT, Arc::new(|_| HttpResponse::ok().text("updated")));
+        };
+        let server = TestServer::start(|server| {
+            routes(server);
+            server.set_not_found_handler(Arc::new(|r: HttpContext| {
+                let lang = r.get_query_param("lang").cloned().unwrap_or_default();
+                HttpResponse::not_found().json(&format!("no such page ({lang})"))
+            }));
+            server.set_method_not_allowed_handler(Arc::new(|r: HttpContext| {
+                let allowed: Vec<String> = r.allowed_methods.iter().map(|m| format!("{:?}", m)).collect();
+                HttpResponse::with_status(HttpStatus::NOT_ALLOWED)
+                    .header(HttpHeader::ALLOW, &allowed.join(","))
+                    .text(&format!("use one of {}", allowed.join(",")))
+            }));
+        });
+        let missing = server.get("/nowhere?lang=en");
+        assert_eq!(missing.status, 404);
+        assert_eq!(missing.header(HttpHeader::CONTENT_TYPE), Some("application/json"));
+        assert_eq!(missing.text().unwrap(), r#""no such page (en)""#);
+        let wrong_method = server.request(HttpMethod::DELETE, "/users/1", &[], b"");
+        assert_eq!(wrong_method.status, 405);
+        assert_eq!(wrong_method.header(HttpHeader::ALLOW), Some("GET,PUT,HEAD,OPTIONS"));
+        assert_eq!(wrong_method.text().unwrap(), "use one of GET,PUT,HEAD,OPTIONS");
+        assert_eq!(server.get("/users/1").text().unwrap(), "user");
+
+        // without custom handlers the defaults are empty
+        let server = TestServer::start(routes);
+        let missing = server.get("/nowhere");
+        assert_eq!((missing.status, missing.body.len()), (404, 0));
+        let wrong_method = server.request(HttpMethod::DELETE, "/users/1", &[], b"");
+        assert_eq!((wrong_method.status, wrong_method.body.len()), (405, 0));
+        assert_eq!(wrong_method.header(HttpHeader::ALLOW), Some("GET, PUT, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn constrained_parameters_go_before_unconstrained_ones() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/users/{name}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("name {}", r.path_params["name"]))
+            }));
+            server.register_end_point("/users/{id:int}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                let id: u64 = r.get_path_param_as("id").unwrap();
+                HttpResponse::ok().text(&format!("id {}", id))
+            }));
+            server.register_end_point("/files/{id:uuid}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("uuid")));
+        });
+        assert_eq!(server.get("/users/123").text().unwrap(), "id 123");
+        assert_eq!(server.get("/users/abc").text().unwrap(), "name abc");
+        assert_eq!(server.get("/users/12a").text().unwrap(), "name 12a");
+        assert_eq!(server.get("/files/123e4567-e89b-12d3-a456-426614174000").text().unwrap(), "uuid");
+        assert_eq!(server.get("/files/123e4567").status, 404);
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown path parameter constraint `number`")]
+    fn unknown_constraints_are_refused() {
+        HttpServer::bind("127.0.0.1", 0)
+            .register_end_point("/users/{id:number}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok()));
+    }
+
+    #[test]
+    #[should_panic(expected = "is ambiguous with")]
+    fn routes_differing_only_in_parameter_names_are_refused() {
+        let mut server = HttpServer::bind("127.0.0.1", 0);
+        server.register_end_point("/users/{id}", HttpMethod::GET, Arc::new(|_| HttpResponse::ok()));
+        server.register_end_point("/users/{name}", HttpMethod::POST, Arc::new(|_| HttpResponse::ok()));
+    }
+
+    #[test]
+    fn wildcards_do_not_shadow_other_routes() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/static/{*path}", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("file {}", r.get_path_param("path").unwrap()))
+            }));
+            server.register_end_point("/static/health", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("healthy")));
+            server.register_end_point("/static/{name}/info", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("info {}", r.path_params["name"]))
+            }));
+            server.register_end_point("/spa/*rest", HttpMethod::GET, Arc::new(|r: HttpContext| {
+                HttpResponse::ok().text(&format!("spa {}", r.path_params["rest"]))
+            }));
+        });
+        assert_eq!(server.get("/static/css/site.css").text().unwrap(), "file css/site.css");
+        assert_eq!(server.get("/static/site.css").text().unwrap(), "file site.css");
+        assert_eq!(server.get("/static/health").text().unwrap(), "healthy");
+        assert_eq!(server.get("/static/logo/info").text().unwrap(), "info logo");
+        assert_eq!(server.get("/static/a/b/info").text().unwrap(), "file a/b/info");
+        assert_eq!(server.get("/spa/users/1/edit").text().unwrap(), "spa users/1/edit");
+        // the wildcard needs at least one segment
+        assert_eq!(server.get("/static/").status, 404);
+        assert_eq!(server.get("/static").status, 404);
+    }
+
+    #[test]
+    fn over_limit_requests_are_refused_without_reading_them() {
+        let mut server = HttpServer::bind("127.0.0.1", 0)
+            .workers(2)
+            .max_body_size(1000)
+            .max_header_size(1024);
+        server.register_end_point("/upload", HttpMethod::POST, Arc::new(|r: HttpContext| {
+            HttpResponse::ok().text(&r.request.body_bytes().len().to_string())
+        }));
+        let handle = server.start_in_background().unwrap();
+        let send = |raw: &[u8]| {
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            io::Write::write_all(&mut stream, raw).unwrap();
+            let mut response = Vec::new();
+            let _ = io::Read::read_to_end(&mut stream, &mut response);
+            String::from_utf8_lossy(&response).into_owned()
+        };
+        let accepted = send(&[b"POST /upload HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: 1000\r\n\r\n".as_slice(), &[b'a'; 1000]].concat());
+        assert!(accepted.starts_with("HTTP/1.1 200") && accepted.ends_with("1000"), "{accepted}");
+        // the declared size alone is refused, the body is never sent
+        let huge = send(b"POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999999\r\n\r\n");
+        assert!(huge.starts_with("HTTP/1.1 413 "), "{huge}");
+
+        // a client sending header lines forever is cut off after the limit and a short drain
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        let mut reader = stream.try_clone().unwrap();
+        reader.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let writer = thread::spawn(move || {
+            let mut sent = io::Write::write_all(&mut stream, b"GET / HTTP/1.1\r\n").map(|_| 16).unwrap_or(0);
+            let line = b"X-Filler: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n";
+            // the server stops reading long before this much, the writes then fail
+            while sent < 256 * 1024 * 1024 && io::Write::write_all(&mut stream, line).is_ok() {
+                sent += line.len();
+            }
+            sent
+        });
+        let mut response = Vec::new();
+        let _ = io::Read::read_to_end(&mut reader, &mut response);
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 431 "), "{response}");
+        assert!(writer.join().unwrap() < 64 * 1024 * 1024);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn connection_header_follows_the_version_and_the_request() {
+        let server = TestServer::start(|server| {
+            server.register_end_point("/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hi")));
+        });
+        let cases = [
+            ("HTTP/1.0", "", "close"),
+            ("HTTP/1.0", "Connection: keep-alive\r\n", "keep-alive"),
+            ("HTTP/1.1", "", "keep-alive"),
+            ("HTTP/1.1", "Connection: close\r\n", "close"),
+        ];
+        for (version, connection, expected) in cases {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            io::Write::write_all(&mut stream, format!("GET / {version}\r\nHost: x\r\n{connection}\r\n").as_bytes()).unwrap();
+            let response = read_response(&mut stream, 2);
+            assert!(response.starts_with(&format!("{version} 200 OK\r\n")), "{response}");
+            let header = response.lines().find(|line| line.to_ascii_lowercase().starts_with("connection:"));
+            assert_eq!(header.map(|line| line[11..].trim()), Some(expected), "{version} {connection:?}");
+            // the server acts on its decision
+            let mut rest = [0; 16];
+            match expected {
+                "close" => assert_eq!(io::Read::read(&mut stream, &mut rest).unwrap(), 0),
+                _ => {
+                    io::Write::write_all(&mut stream, format!("GET / {version}\r\nHost: x\r\n{connection}\r\n").as_bytes()).unwrap();
+                    assert!(read_response(&mut stream, 2).ends_with("hi"));
+                }
+            }
+        }
+    }
+
+    /// A GET on a connection of its own, everything up to the server closing it.
+    fn raw_get(addr: SocketAddr, target: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        io::Write::write_all(&mut stream, format!("GET {target} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").as_bytes()).unwrap();
+        let mut response = String::new();
+        io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        response
+    }
+
+    fn slash_server(policy: TrailingSlash) -> ServerHandle {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(2).trailing_slash(policy);
+        server.register_end_point("/users", HttpMethod::GET, Arc::new(|r: HttpContext| HttpResponse::ok().text(&r.request.path)));
+        server.register_end_point("/docs/", HttpMethod::GET, Arc::new(|r: HttpContext| HttpResponse::ok().text(&r.request.path)));
+        server.start_in_background().unwrap()
+    }
+
+    #[test]
+    fn strict_trailing_slashes() {
+        let handle = slash_server(TrailingSlash::Strict);
+        let addr = handle.local_addr();
+        assert!(raw_get(addr, "/users").ends_with("\r\n\r\n/users"));
+        assert_eq!(raw_status(&raw_get(addr, "/users/")), 404);
+        assert!(raw_get(addr, "/docs/").ends_with("\r\n\r\n/docs/"));
+        assert_eq!(raw_status(&raw_get(addr, "/docs")), 404);
+        // duplicate slashes and dot segments are still resolved
+        assert!(raw_get(addr, "//users?x=1").ends_with("\r\n\r\n/users?x=1"));
+        assert!(raw_get(addr, "/a/../users").ends_with("\r\n\r\n/users"));
+        assert!(raw_get(addr, "/./docs/").ends_with("\r\n\r\n/docs/"));
+        assert_eq!(raw_status(&raw_get(addr, "/../users")), 400);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn trailing_slashes_treated_as_the_same_path() {
+        let handle = slash_server(TrailingSlash::TreatAsSame);
+        let addr = handle.local_addr();
+        for target in ["/users", "/users/", "//users//", "/docs/../users/"] {
+            assert!(raw_get(addr, target).ends_with("\r\n\r\n/users"), "{target}");
+        }
+        assert!(raw_get(addr, "/docs").ends_with("\r\n\r\n/docs"));
+        assert!(raw_get(addr, "/docs/?page=2").ends_with("\r\n\r\n/docs?page=2"));
+        assert_eq!(raw_status(&raw_get(addr, "/users/../../x")), 400);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    #[should_panic(expected = "already")]
+    fn both_slash_variants_cannot_be_registered_when_treated_as_the_same() {
+        let mut server = HttpServer::bind("127.0.0.1", 0).trailing_slash(TrailingSlash::TreatAsSame);
+        server.register_end_point("/users", HttpMethod::GET, Arc::new(|_| HttpResponse::ok()));
+        server.register_end_point("/users/", HttpMethod::GET, Arc::new(|_| HttpResponse::ok()));
+    }
+
+    #[test]
+    fn non_canonical_paths_are_redirected() {
+        let handle = slash_server(TrailingSlash::RedirectToCanonical);
+        let addr = handle.local_addr();
+        assert!(raw_get(addr, "/users").ends_with("\r\n\r\n/users"));
+        assert!(raw_get(addr, "/docs").ends_with("\r\n\r\n/docs"));
+        for (target, location) in [("/users/", "/users"), ("//users", "/users"), ("/a/./../docs/?q=1", "/docs?q=1")] {
+            let response = raw_get(addr, target);
+            assert_eq!(raw_status(&response), 301, "{target}");
+            assert!(response.contains(&format!("\r\nLocation:{location}\r\n")), "{response}");
+        }
+        assert_eq!(raw_status(&raw_get(addr, "/..")), 400);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn the_most_specific_route_wins_whatever_the_registration_order() {
+        let server = TestServer::start(|server| {
+            let named = |name: &'static str| -> Handler { Arc::new(move |_| HttpResponse::ok().text(name)) };
+            // from the least to the most specific
+            server.register_end_point("/{*rest}", HttpMethod::GET, named("root wildcard"));
+            server.register_end_point("/files/{*rest}", HttpMethod::GET, named("files wildcard"));
+            server.register_end_point("/{kind}/{name}", HttpMethod::GET, named("two params"));
+            server.register_end_point("/files/{name}", HttpMethod::GET, named("file"));
+            server.register_end_point("/files/{name}/meta", HttpMethod::GET, named("meta"));
+            server.register_end_point("/files/readme", HttpMethod::GET, named("readme"));
+        });
+        let cases = [
+            ("/files/readme", "readme"),
+            ("/files/report/meta", "meta"),
+            ("/files/report", "file"),
+            ("/users/ann", "two params"),
+            ("/files/a/b/c", "files wildcard"),
+            ("/users/ann/more", "root wildcard"),
+            ("/x", "root wildcard"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(server.get(path).text().unwrap(), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn date_and_server_are_set_unless_the_handler_did() {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(1).server_name("myapp/1.0");
+        server.register_end_point("/default", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("hi")));
+        server.register_end_point("/custom", HttpMethod::GET, Arc::new(|_| HttpResponse::ok()
+            .header(HttpHeader::DATE, "Sun, 06 Nov 1994 08:49:37 GMT")
+            .header(HttpHeader::SERVER, "handler/2.0")
+            .text("hi")));
+        let handle = server.start_in_background().unwrap();
+
+        let default = raw_get(handle.local_addr(), "/default");
+        assert!(default.contains("\r\nServer:myapp/1.0\r\n"), "{default}");
+        let date = default.lines().find_map(|line| line.strip_prefix("Date:")).unwrap();
+        // IMF-fixdate: `Sun, 06 Nov 1994 08:49:37 GMT`
+        assert_eq!(date.len(), 29, "{date}");
+        assert!(date.ends_with(" GMT") && date.as_bytes()[3] == b',', "{date}");
+
+        let custom = raw_get(handle.local_addr(), "/custom");
+        assert!(custom.contains("\r\nDate:Sun, 06 Nov 1994 08:49:37 GMT\r\n"), "{custom}");
+        assert!(custom.contains("\r\nServer:handler/2.0\r\n"), "{custom}");
+        assert_eq!(custom.matches("Date:").count(), 1);
+        assert_eq!(custom.matches("Server:").count(), 1);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+
+        let handle = HttpServer::bind("127.0.0.1", 0).workers(1).server_name("").start_in_background().unwrap();
+        assert!(!raw_get(handle.local_addr(), "/missing").contains("Server:"));
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    fn client_ip_server(trust_proxy: bool) -> ServerHandle {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(1).trust_proxy(trust_proxy);
+        server.register_end_point("/ip", HttpMethod::GET, Arc::new(|r: HttpContext| {
+            HttpResponse::ok().text(&format!("{} {} {} {:?}", r.client_ip(), r.remote_addr.ip(), r.request_number, r.request.version))
+        }));
+        server.start_in_background().unwrap()
+    }
+
+    fn get_ip(addr: SocketAddr, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        io::Write::write_all(&mut stream, format!("GET /ip HTTP/1.1\r\nHost: x\r\nConnection: close\r\n{headers}\r\n").as_bytes()).unwrap();
+        let mut response = String::new();
+        io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        response.split("\r\n\r\n").nth(1).unwrap().to_string()
+    }
+
+    #[test]
+    fn forwarded_addresses_are_only_believed_from_a_trusted_proxy() {
+        let trusted = client_ip_server(true);
+        let addr = trusted.local_addr();
+        assert_eq!(get_ip(addr, ""), "127.0.0.1 127.0.0.1 1 Http11");
+        assert_eq!(get_ip(addr, "X-Forwarded-For: 203.0.113.7, 10.0.0.1\r\n"), "203.0.113.7 127.0.0.1 1 Http11");
+        assert_eq!(get_ip(addr, "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-For: 10.0.0.1\r\n"), "203.0.113.7 127.0.0.1 1 Http11");
+        assert_eq!(get_ip(addr, "X-Real-IP: 2001:db8::1\r\n"), "2001:db8::1 127.0.0.1 1 Http11");
+        assert_eq!(get_ip(addr, "X-Forwarded-For: 198.51.100.2\r\nX-Real-IP: 2001:db8::1\r\n"), "198.51.100.2 127.0.0.1 1 Http11");
+        // not an address, the peer it is
+        assert_eq!(get_ip(addr, "X-Forwarded-For: unknown\r\n"), "127.0.0.1 127.0.0.1 1 Http11");
+        assert!(trusted.shutdown(Duration::from_secs(5)));
+
+        let untrusted = client_ip_server(false);
+        let addr = untrusted.local_addr();
+        assert_eq!(get_ip(addr, "X-Forwarded-For: 203.0.113.7\r\n"), "127.0.0.1 127.0.0.1 1 Http11");
+        assert_eq!(get_ip(addr, "X-Real-IP: 2001:db8::1\r\n"), "127.0.0.1 127.0.0.1 1 Http11");
+        assert!(untrusted.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn the_request_number_counts_up_on_a_persistent_connection() {
+        let server = client_ip_server(false);
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        for number in 1..=3 {
+            io::Write::write_all(&mut stream, b"GET /ip HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let expected = format!("127.0.0.1 127.0.0.1 {number} Http11");
+            assert_eq!(read_response(&mut stream, expected.len()).split("\r\n\r\n").nth(1), Some(expected.as_str()));
+        }
+        assert!(server.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn exact_routes_see_the_query_parameters() {
+        let server = TestServer::start(|server| {
+            let echo: Handler = Arc::new(|r: HttpContext| HttpResponse::ok().text(&format!("{:?} {:?} {:?}",
+                r.get_query_param("q"), r.get_query_param_as::<u32>("page").ok(), r.get_query_param("missing"))));
+            server.register_end_point("/search", HttpMethod::GET, echo.clone());
+            server.register_end_point("/search/{kind}", HttpMethod::GET, echo);
+        });
+        let expected = r#"Some("rust") Some(2) None"#;
+        assert_eq!(server.get("/search?q=rust&page=2").text().unwrap(), expected);
+        assert_eq!(server.get("/search/books?q=rust&page=2").text().unwrap(), expected);
+        assert_eq!(server.get("/search").text().unwrap(), "None None None");
+    }
+
+    #[test]
+    fn request_ids_are_unique_and_echoed() {
+        let (sender, logs) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(4);
+        server.register_end_point("/id", HttpMethod::GET, Arc::new(|r: HttpContext| HttpResponse::ok().text(r.request_id().unwrap_or("none"))));
+        server.enable_request_ids();
+        server.on_request_complete(Box::new(move |log| {
+            let _ = sender.lock().unwrap().send(log.request_id.clone());
+        }));
+        let handle = server.start_in_background().unwrap();
+        let addr = handle.local_addr();
+        let id_of = |response: &str| -> (String, String) {
+            let header = response.lines().find_map(|line| line.strip_prefix("X-Request-Id:")).unwrap();
+            (header.to_string(), response.split("\r\n\r\n").nth(1).unwrap().to_string())
+        };
+
+        let ids: Vec<String> = thread::scope(|scope| {
+            let requests: Vec<_> = (0..64).map(|_| scope.spawn(|| raw_get(addr, "/id"))).collect();
+            requests.into_iter().map(|request| {
+                let (header, body) = id_of(&request.join().unwrap());
+                assert_eq!(header, body);
+                assert!(header.len() == 32 && header.bytes().all(|b| b.is_ascii_hexdigit()), "{header}");
+                header
+            }).collect()
+        });
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), ids.len());
+        let logged: HashSet<String> = (0..ids.len()).map(|_| logs.recv_timeout(Duration::from_secs(5)).unwrap().unwrap()).collect();
+        assert_eq!(logged, ids.iter().cloned().collect());
+
+        let get_with = |id: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let request = format!("GET /id HTTP/1.1\r\nHost: x\r\nX-Request-Id: {id}\r\nConnection: close\r\n\r\n");
+            io::Write::write_all(&mut stream, request.as_bytes()).unwrap();
+            let mut response = String::new();
+            io::Read::read_to_string(&mut stream, &mut response).unwrap();
+            id_of(&response)
+        };
+        let provided = "upstream-7f3a.b:2";
+        assert_eq!(get_with(provided), (provided.to_string(), provided.to_string()));
+        assert_eq!(logs.recv_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some(provided));
+        // one that could break a log line is replaced
+        let (replaced, _) = get_with("bad id\"");
+        assert_eq!(replaced.len(), 32);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn many_overlapping_routes() {
+        let server = TestServer::start(|server| {
+            let mut patterns = vec!["/api/{*rest}".to_string(), "/api/v1/users/me".to_string(), "/{a}/{b}/{c}".to_string()];
+            for version in 0..30 {
+                patterns.push(format!("/api/v{version}/users"));
+                patterns.push(format!("/api/v{version}/users/{{id:int}}"));
+                patterns.push(format!("/api/v{version}/users/{{name}}"));
+                patterns.push(format!("/api/v{version}/users/{{id:int}}/posts/{{post}}"));
             }
-            Some(endpoint) => {
-                let func = &(*endpoint.func);
-                func(HttpContext::new(HashMap::new(), HashMap::new(), request))
+            assert!(patterns.len() > 100);
+            for pattern in patterns {
+                let echoed = pattern.clone();
+                server.register_end_point(&pattern, HttpMethod::GET, Arc::new(move |r: HttpContext| {
+                    let mut params: Vec<String> = r.path_params.iter().map(|(name, value)| format!("{name}={value}")).collect();
+                    params.sort();
+                    HttpResponse::ok().text(&format!("{} {}", echoed, params.join(",")))
+                }));
             }
+        });
+        let cases = [
+            ("/api/v0/users", "/api/v0/users "),
+            ("/api/v29/users", "/api/v29/users "),
+            ("/api/v1/users/me", "/api/v1/users/me "),
+            ("/api/v2/users/me", "/api/v2/users/{name} name=me"),
+            ("/api/v7/users/42", "/api/v7/users/{id:int} id=42"),
+            ("/api/v7/users/ann", "/api/v7/users/{name} name=ann"),
+            ("/api/v13/users/42/posts/hello", "/api/v13/users/{id:int}/posts/{post} id=42,post=hello"),
+            // the posts route needs an integer id
+            ("/api/v13/users/ann/posts/hello", "/api/{*rest} rest=v13/users/ann/posts/hello"),
+            ("/api/v30/users", "/{a}/{b}/{c} a=api,b=v30,c=users"),
+            ("/api/v30/users/42", "/api/{*rest} rest=v30/users/42"),
+            ("/web/v1/users", "/{a}/{b}/{c} a=web,b=v1,c=users"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(server.get(path).text().unwrap(), expected, "{path}");
+        }
+        assert_eq!(server.get("/web/v1/users/42").status, 404);
+        assert_eq!(server.get("/api").status, 404);
+    }
+
+    #[test]
+    fn route_filters_only_guard_their_route() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let server = TestServer::start(|server| {
+            let record = |name: &'static str, reject: Option<u32>| -> BeforeFilter {
+                let calls = Arc::clone(&calls);
+                Box::new(move |c: &HttpConnection| {
+                    calls.lock().unwrap().push(name);
+                    let authenticated = c.request.headers.contains(HttpHeader::AUTHORIZATION);
+                    reject.filter(|_| !authenticated).map(|status| HttpResponse::with_status(status).text(name))
+                })
+            };
+            let ok: Handler = Arc::new(|_| HttpResponse::ok().text("ok"));
+            server.register_end_point("/public", HttpMethod::GET, ok.clone());
+            server.register_end_point("/admin/stats", HttpMethod::GET, ok.clone())
+                .with_filter(record("admin", Some(HttpStatus::UNAUTHORIZED)))
+                .with_filter(record("never reached", Some(HttpStatus::INTERNAL_ERROR)))
+                .with_after(Box::new(|response: &mut HttpResponse| response.set_header("X-Admin".to_string(), "yes".to_string())));
+            server.register_end_point("/admin/users/{id:int}", HttpMethod::GET, ok)
+                .with_filter(record("admin user", Some(HttpStatus::FORBIDDEN)));
+            server.do_before(record("global", None));
+        });
+        let take_calls = || std::mem::take(&mut *calls.lock().unwrap());
+
+        let public = server.get("/public");
+        assert_eq!((public.status, public.header("X-Admin")), (200, None));
+        assert_eq!(take_calls(), ["global"]);
+
+        let rejected = server.get("/admin/stats");
+        assert_eq!((rejected.status, rejected.text().unwrap()), (401, "admin"));
+        // route after filters also apply to the response the filter produced
+        assert_eq!(rejected.header("X-Admin"), Some("yes"));
+        assert_eq!(take_calls(), ["global", "admin"]);
+
+        let rejected = server.get("/admin/users/7");
+        assert_eq!((rejected.status, rejected.text().unwrap()), (403, "admin user"));
+        assert_eq!(take_calls(), ["global", "admin user"]);
+
+        let authorized = server.request(HttpMethod::GET, "/admin/stats", &[(HttpHeader::AUTHORIZATION, "Bearer t")], b"");
+        assert_eq!((authorized.status, authorized.text().unwrap()), (200, "ok"));
+        assert_eq!(take_calls(), ["global", "admin", "never reached"]);
+
+        assert_eq!(server.get("/admin/users/ann").status, 404);
+        assert_eq!(server.get("/admin/missing").status, 404);
+        assert_eq!(take_calls(), ["global", "global"]);
+    }
+
+    #[test]
+    fn response_cache_hits_invalidation_and_no_store() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = TestServer::start(|server| {
+            let counted = |cache_control: Option<&'static str>| -> Handler {
+                let calls = Arc::clone(&calls);
+                Arc::new(move |_| {
+                    let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    let response = HttpResponse::ok().text(&format!("call {call}"));
+                    match cache_control {
+                        Some(value) => response.header(HttpHeader::CACHE_CONTROL, value),
+                        None => response,
+                    }
+                })
+            };
+            server.register_end_point("/report", HttpMethod::GET, counted(None));
+            server.register_end_point("/report", HttpMethod::POST, Arc::new(|_| HttpResponse::ok().text("updated")));
+            server.register_end_point("/live", HttpMethod::GET, counted(Some("no-store")));
+            server.enable_response_cache(CacheConfig::default());
+        });
+        let get = |path: &str| {
+            let response = server.get(path);
+            (response.text().unwrap().to_string(), response.header(HttpHeader::X_CACHE).map(str::to_string), response.header(HttpHeader::AGE).map(str::to_string))
+        };
+        let miss = |text: &str| (text.to_string(), Some("MISS".to_string()), None);
+        let hit = |text: &str| (text.to_string(), Some("HIT".to_string()), Some("0".to_string()));
+
+        assert_eq!(get("/report"), miss("call 1"));
+        assert_eq!(get("/report"), hit("call 1"));
+        // the query is part of the key
+        assert_eq!(get("/report?page=2"), miss("call 2"));
+        assert_eq!(get("/report?page=2"), hit("call 2"));
+
+        assert_eq!(server.request(HttpMethod::POST, "/report", &[], b"x").text().unwrap(), "updated");
+        assert_eq!(get("/report"), miss("call 3"));
+        assert_eq!(get("/report?page=2"), miss("call 4"));
+        assert_eq!(get("/report"), hit("call 3"));
+
+        assert_eq!(get("/live"), miss("call 5"));
+        assert_eq!(get("/live"), miss("call 6"));
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn truncated_json_is_refused_only_where_expected() {
+        let server = TestServer::start(|server| {
+            let handler: Handler = Arc::new(|r: HttpContext| HttpResponse::ok().text(&format!("handled {} bytes", r.request.body.len())));
+            server.register_end_point("/strict", HttpMethod::POST, handler.clone()).expect_json();
+            server.register_end_point("/plain", HttpMethod::POST, handler);
+        });
+        let json = [(HttpHeader::CONTENT_TYPE, MediaType::APPLICATION_JSON)];
+        let truncated = "{\n  \"name\": \"ann\",\n  \"age\": 3";
+
+        let refused = server.request(HttpMethod::POST, "/strict", &json, truncated.as_bytes());
+        assert_eq!(refused.status, 400);
+        assert_eq!(refused.header(HttpHeader::CONTENT_TYPE), Some(MediaType::APPLICATION_JSON));
+        let details = JsonParser::from_slice(&refused.body).parse_to_value().unwrap();
+        assert_eq!((details.get_i64("line"), details.get_i64("column")), (Some(3), Some(11)));
+        assert_eq!(details.get_str("error"), Some(JsonError::UnexpectedEof.to_string().as_str()));
+
+        let misplaced = server.request(HttpMethod::POST, "/strict", &json, b"{\"a\": 1,\n \"b\" 2}");
+        let details = JsonParser::from_slice(&misplaced.body).parse_to_value().unwrap();
+        assert_eq!((misplaced.status, details.get_i64("line"), details.get_i64("column")), (400, Some(2), Some(6)));
+
+        let plain = server.request(HttpMethod::POST, "/plain", &json, truncated.as_bytes());
+        assert_eq!((plain.status, plain.text().unwrap()), (200, format!("handled {} bytes", truncated.len()).as_str()));
+        let not_json = server.request(HttpMethod::POST, "/plain", &[(HttpHeader::CONTENT_TYPE, "text/csv")], b"a,b");
+        assert_eq!(not_json.status, 200);
+        let valid = server.request(HttpMethod::POST, "/strict", &json, b"{\"name\": \"ann\"}");
+        assert_eq!(valid.text().unwrap(), "handled 15 bytes");
+        assert_eq!(server.request(HttpMethod::POST, "/strict", &[], b"").status, 200);
+    }
+
+    #[test]
+    fn clients_leaving_mid_response_do_not_harm_the_server() {
+        let (sender, logs) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let server = TestServer::start(|server| {
+            server.register_end_point("/big", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().body(vec![b'x'; 16 * 1024 * 1024])));
+            server.register_end_point("/small", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("still here")));
+            server.on_request_complete(Box::new(move |log| {
+                let _ = sender.lock().unwrap().send(log.clone());
+            }));
+        });
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            io::Write::write_all(&mut stream, b"GET /big HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let mut start = [0; 1024];
+            io::Read::read_exact(&mut stream, &mut start).unwrap();
+            // unread data makes the close a reset
+            drop(stream);
+            let log = logs.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!((log.path.as_str(), log.status), ("/big", 200));
+            assert!(log.disconnected);
+            assert!(log.size < 16 * 1024 * 1024, "{}", log.size);
+            assert!(log.to_string().ends_with(" client disconnected"), "{log}");
+
+            // a client that leaves before the response
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            io::Write::write_all(&mut stream, b"GET /big HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            drop(stream);
+            assert!(logs.recv_timeout(Duration::from_secs(10)).unwrap().disconnected);
+
+            let response = server.get("/small");
+            assert_eq!((response.status, response.text().unwrap()), (200, "still here"));
+            assert!(!logs.recv_timeout(Duration::from_secs(5)).unwrap().disconnected);
+        }
+    }
+
+    #[test]
+    fn routes_are_listed() {
+        let ok: Handler = Arc::new(|_| HttpResponse::ok());
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(1).route_listing(true);
+        server.register_end_point("/users", HttpMethod::POST, ok.clone());
+        server.register_end_point("/users", HttpMethod::GET, ok.clone());
+        server.register_end_point("/users/{id:int}/posts/{post}", HttpMethod::GET, ok.clone());
+        server.register_end_point("/files/{*rest}", HttpMethod::GET, ok.clone());
+        server.register_any_method("/health", ok);
+        server.serve_static("/static", &std::env::temp_dir());
+
+        let route = |pattern: &str, method: Option<HttpMethod>, path_params: &[&str], wildcard: bool| RouteInfo {
+            pattern: pattern.to_string(),
+            method,
+            path_params: path_params.iter().map(|name| name.to_string()).collect(),
+            wildcard,
         };
+        let routes = server.routes();
+        assert_eq!(routes, [
+            route("/files/{*rest}", Some(HttpMethod::GET), &["rest"], true),
+            route("/health", None, &[], false),
+            route("/static/{*path}", Some(HttpMethod::GET), &["path"], true),
+            route("/users", Some(HttpMethod::GET), &[], false),
+            route("/users", Some(HttpMethod::POST), &[], false),
+            route("/users/{id:int}/posts/{post}", Some(HttpMethod::GET), &["id", "post"], false),
+        ]);
+        assert!(!routes[1].has_path_params() && routes[5].has_path_params());
+
+        let handle = server.start_in_background().unwrap();
+        let listing = raw_get(handle.local_addr(), "/__routes");
+        let body = listing.split("\r\n\r\n").nth(1).unwrap();
+        assert!(listing.starts_with("HTTP/1.1 200"), "{listing}");
+        assert_eq!(body, Serializer::new().serialize(&routes));
+        assert!(body.starts_with(r#"[{"pattern": "/files/{*rest}","method": "GET","path_params": ["rest"],"wildcard": true},{"pattern": "/health","method": null,"#), "{body}");
+        assert!(handle.shutdown(Duration::from_secs(5)));
 
-        do_after.iter().for_each(|x| x(&mut response));
-        connection.response(response);
+        let handle = HttpServer::bind("127.0.0.1", 0).workers(1).start_in_background().unwrap();
+        assert_eq!(raw_status(&raw_get(handle.local_addr(), "/__routes")), 404);
+        assert!(handle.shutdown(Duration::from_secs(5)));
     }
-}
\ No newline at end of file
+}
@@ -1,3 +1,16 @@
+pub(crate) mod access_log;
+pub(crate) mod auth;
 pub(crate) mod http_core;
 pub(crate) mod base;
+pub(crate) mod cache;
+pub(crate) mod client;
+pub(crate) mod compression;
+pub(crate) mod cookie;
+pub(crate) mod etag;
+pub(crate) mod headers;
+pub(crate) mod rate_limit;
+pub(crate) mod request_id;
+pub(crate) mod router;
+pub(crate) mod sse;
+pub(crate) mod testing;
 mod demo;
\ No newline at end of file
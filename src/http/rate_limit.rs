@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::http::base::{HttpConnection, HttpHeader, HttpResponse, HttpStatus, MediaType};
+use crate::http::http_core::BeforeFilter;
+
+/// Allows each client IP `requests` requests per `window`, as a token bucket: a client may spend
+/// the whole allowance at once, then regains it gradually over the window. Clients are told
+/// apart by `HttpConnection::client_ip`, so behind a proxy enable `HttpServer::trust_proxy`.
+pub(crate) struct RateLimiter {
+    requests: u32,
+    window: Duration,
+    state: Mutex<Buckets>,
+}
+
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// # Panics
+    ///
+    /// Panics if `requests` or `window` is zero.
+    pub(crate) fn new(requests: u32, window: Duration) -> RateLimiter {
+        if requests == 0 || window.is_zero() {
+            panic!("a rate limit needs at least one request per non-empty window")
+        }
+        RateLimiter {
+            requests,
+            window,
+            state: Mutex::new(Buckets { buckets: HashMap::new(), last_sweep: Instant::now() }),
+        }
+    }
+
+    /// Spends one of the requests `ip` has left, or tells how long until it has one again.
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = self.requests as f64;
+        let mut state = self.state.lock().unwrap();
+        // a bucket untouched for a whole window is full again, the same as no bucket at all
+        if now.duration_since(state.last_sweep) >= self.window {
+            state.buckets.retain(|_, bucket| now.duration_since(bucket.updated) < self.window);
+            state.last_sweep = now;
+        }
+        let bucket = state.buckets.entry(ip).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.refill_rate()).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_rate()))
+        }
+    }
+
+    /// Tokens regained per second.
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.window.as_secs_f64()
+    }
+
+    /// A `do_before` filter, or a route filter, answering clients over the limit with a 429 whose
+    /// `Retry-After` says when to come back.
+    pub(crate) fn filter(self) -> BeforeFilter {
+        Box::new(move |connection: &HttpConnection| {
+            let wait = self.acquire(connection.client_ip()).err()?;
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let body = format!("{{\"error\":\"too many requests\",\"retry_after\":{}}}", retry_after);
+            let response = HttpResponse::with_status(HttpStatus::TOO_MANY_REQUESTS)
+                .header(HttpHeader::RETRY_AFTER, &retry_after.to_string())
+                .content_type(MediaType::APPLICATION_JSON)
+                .body(body.into_bytes());
+            Some(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use crate::http::base::HttpMethod;
+    use crate::http::client::HttpClient;
+    use crate::http::http_core::HttpServer;
+    use super::*;
+
+    #[test]
+    fn one_request_over_the_limit_is_refused() {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(4).trust_proxy(true);
+        server.register_end_point("/search", HttpMethod::GET, Arc::new(|_| HttpResponse::ok().text("results")));
+        server.do_before(RateLimiter::new(5, Duration::from_secs(60)).filter());
+        let handle = server.start_in_background().unwrap();
+        let client = HttpClient::new();
+        let url = format!("http://{}/search", handle.local_addr());
+        let from = |ip: &str| client.get(&url).header(HttpHeader::X_FORWARDED_FOR, ip).send().unwrap();
+
+        let statuses: Vec<u32> = thread::scope(|scope| {
+            let requests: Vec<_> = (0..6).map(|_| scope.spawn(|| from("203.0.113.7"))).collect();
+            requests.into_iter().map(|request| {
+                let response = request.join().unwrap();
+                if response.status == HttpStatus::TOO_MANY_REQUESTS {
+                    // a token comes back every 12 seconds
+                    assert_eq!(response.header(HttpHeader::RETRY_AFTER), Some("12"));
+                    assert_eq!(response.header(HttpHeader::CONTENT_TYPE), Some(MediaType::APPLICATION_JSON));
+                    assert_eq!(response.text().unwrap(), r#"{"error":"too many requests","retry_after":12}"#);
+                }
+                response.status
+            }).collect()
+        });
+        assert_eq!(statuses.iter().filter(|&&status| status == HttpStatus::TOO_MANY_REQUESTS).count(), 1, "{statuses:?}");
+        assert_eq!(statuses.iter().filter(|&&status| status == HttpStatus::OK).count(), 5);
+
+        let other = from("198.51.100.2");
+        assert_eq!((other.status, other.text().unwrap()), (200, "results"));
+        assert_eq!(from("203.0.113.7").status, 429);
+        assert!(handle.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn tokens_come_back_and_idle_clients_are_forgotten() {
+        let limiter = RateLimiter::new(2, Duration::from_millis(100));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(limiter.acquire(ip).is_ok());
+        assert!(limiter.acquire(ip).is_ok());
+        let wait = limiter.acquire(ip).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50), "{wait:?}");
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiter.acquire(ip).is_ok());
+
+        for last in 0..100u8 {
+            let _ = limiter.acquire(IpAddr::from([10, 0, 0, last]));
+        }
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), 101);
+        thread::sleep(Duration::from_millis(120));
+        assert!(limiter.acquire(ip).is_ok());
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), 1);
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SEED: OnceLock<(u64, u64)> = OnceLock::new();
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A new 128-bit request ID as 32 hex digits. Both halves are bijections of a process-wide
+/// counter, so IDs never repeat within a process; the random seed keeps them apart across
+/// processes and restarts.
+pub(crate) fn generate() -> String {
+    let (high, low) = *SEED.get_or_init(seed);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:016x}", mix(high.wrapping_add(n.wrapping_mul(0x9e37_79b9_7f4a_7c15))), mix(low ^ n))
+}
+
+/// Whether a client-sent `X-Request-Id` can be reused as is: short and made of characters that
+/// cannot break a log line.
+pub(crate) fn is_valid(id: &str) -> bool {
+    (1..=128).contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// The std hasher keys are random per process; the time and process ID add to them.
+fn seed() -> (u64, u64) {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(nanos);
+    hasher.write_u32(process::id());
+    let high = hasher.finish();
+    hasher.write_usize(&COUNTER as *const AtomicU64 as usize);
+    (mix(high), mix(hasher.finish()))
+}
+
+/// The SplitMix64 finalizer, a bijection on `u64` spreading every input bit over the output.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
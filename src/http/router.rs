@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use regex::Regex;
+use crate::http::base::HttpMethod;
+use crate::utils::json::{JsonSerializable, Serializer};
+
+/// A registered route as `HttpServer::routes` lists it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RouteInfo {
+    /// The url it was registered with, after the trailing slash policy and `..` were applied.
+    pub(crate) pattern: String,
+    /// `None` for a route answering every method.
+    pub(crate) method: Option<HttpMethod>,
+    /// The names of its parameters in the order they appear, empty for an exact path.
+    pub(crate) path_params: Vec<String>,
+    /// Whether its last parameter spans several segments, as `{*rest}` or `{rest:path}` do.
+    pub(crate) wildcard: bool,
+}
+
+impl RouteInfo {
+    pub(crate) fn has_path_params(&self) -> bool {
+        !self.path_params.is_empty()
+    }
+}
+
+impl JsonSerializable for RouteInfo {
+    fn serialize(&self, serializer: &mut Serializer) {
+        let method = self.method.map(|method| format!("{:?}", method));
+        let mut fields = serializer.serialize_struct();
+        fields.serialize_field("pattern", &self.pattern);
+        fields.serialize_field("method", &method);
+        fields.serialize_field("path_params", &self.path_params);
+        fields.serialize_field("wildcard", &self.wildcard);
+        fields.end()
+    }
+}
+
+/// One `/`-separated segment of a route with path parameters.
+#[derive(Debug, Clone)]
+pub(crate) enum Segment {
+    Literal(String),
+    /// A segment holding parameters, possibly among literal text as in `{name}.txt`. The regex
+    /// matches the whole segment and captures the parameters in order.
+    Param(Regex),
+    /// A `path` parameter capturing the rest of the path, slashes included; always the last segment.
+    Rest,
+}
+
+/// Indexes routes by their segments so that a lookup only visits routes sharing the request's
+/// leading segments, however many are registered. Routes are referred to by their index in the
+/// dispatcher's registration-ordered list.
+#[derive(Debug, Default)]
+pub(crate) struct RouteTrie {
+    literals: HashMap<String, RouteTrie>,
+    params: Vec<(Regex, RouteTrie)>,
+    /// Routes ending at this node.
+    routes: Vec<usize>,
+    /// Routes whose `Rest` segment starts at this node.
+    rest: Vec<usize>,
+}
+
+impl RouteTrie {
+    pub(crate) fn insert(&mut self, segments: &[Segment], route: usize) {
+        match segments.split_first() {
+            None => self.routes.push(route),
+            Some((Segment::Rest, _)) => self.rest.push(route),
+            Some((Segment::Literal(literal), tail)) => {
+                self.literals.entry(literal.clone()).or_default().insert(tail, route)
+            }
+            Some((Segment::Param(regex), tail)) => {
+                let index = match self.params.iter().position(|(r, _)| r.as_str() == regex.as_str()) {
+                    Some(index) => index,
+                    None => {
+                        self.params.push((regex.clone(), RouteTrie::default()));
+                        self.params.len() - 1
+                    }
+                };
+                self.params[index].1.insert(tail, route)
+            }
+        }
+    }
+
+    /// Every route matching `path`, with the raw values its parameters captured, in no
+    /// particular order; the caller picks among them by precedence.
+    pub(crate) fn matches(&self, path: &str) -> Vec<(usize, Vec<String>)> {
+        let mut found = vec![];
+        if let Some(path) = path.strip_prefix('/') {
+            self.collect(Some(path), &mut vec![], &mut found);
+        }
+        found
+    }
+
+    /// `remaining` is the path after the segments leading to this node, `None` once all of it
+    /// was consumed.
+    fn collect(&self, remaining: Option<&str>, captures: &mut Vec<String>, found: &mut Vec<(usize, Vec<String>)>) {
+        let Some(path) = remaining else {
+            found.extend(self.routes.iter().map(|&route| (route, captures.clone())));
+            return;
+        };
+        if !path.is_empty() {
+            for &route in &self.rest {
+                let mut captures = captures.clone();
+                captures.push(path.to_string());
+                found.push((route, captures));
+            }
+        }
+        let (segment, tail) = match path.split_once('/') {
+            Some((segment, tail)) => (segment, Some(tail)),
+            None => (path, None)
+        };
+        if let Some(child) = self.literals.get(segment) {
+            child.collect(tail, captures, found);
+        }
+        for (regex, child) in &self.params {
+            let Some(values) = regex.captures(segment) else {
+                continue;
+            };
+            let len = captures.len();
+            captures.extend(values.iter().skip(1).map(|value| value.map_or("", |v| v.as_str()).to_string()));
+            child.collect(tail, captures, found);
+            captures.truncate(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{}` is a parameter segment and `{*}` the rest of the path.
+    fn segments(pattern: &str) -> Vec<Segment> {
+        pattern.trim_start_matches('/').split('/').map(|segment| match segment {
+            "{}" => Segment::Param(Regex::new(r"^([\w%-]+)$").unwrap()),
+            "{}.txt" => Segment::Param(Regex::new(r"^([\w%-]+)\.txt$").unwrap()),
+            "{*}" => Segment::Rest,
+            literal => Segment::Literal(literal.to_string()),
+        }).collect()
+    }
+
+    fn trie(patterns: &[&str]) -> RouteTrie {
+        let mut trie = RouteTrie::default();
+        patterns.iter().enumerate().for_each(|(route, pattern)| trie.insert(&segments(pattern), route));
+        trie
+    }
+
+    fn sorted_matches(trie: &RouteTrie, path: &str) -> Vec<(usize, Vec<String>)> {
+        let mut found = trie.matches(path);
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn every_matching_route_is_found_with_its_captures() {
+        let trie = trie(&["/users", "/users/{}", "/users/me", "/users/{}/posts/{}", "/{}/{}", "/users/{*}", "/files/{}.txt"]);
+        let found = |path| sorted_matches(&trie, path);
+        let values = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        assert_eq!(found("/users"), [(0, values(&[]))]);
+        assert_eq!(found("/users/me"), [(1, values(&["me"])), (2, values(&[])), (4, values(&["users", "me"])), (5, values(&["me"]))]);
+        assert_eq!(found("/users/42/posts/7"), [(3, values(&["42", "7"])), (5, values(&["42/posts/7"]))]);
+        assert_eq!(found("/files/notes.txt"), [(6, values(&["notes"]))]);
+        assert_eq!(found("/files/notes"), [(4, values(&["files", "notes"]))]);
+        assert!(found("/files/notes.md").is_empty());
+        assert!(found("/").is_empty());
+        assert!(found("/orders/1/2").is_empty());
+        // a rest segment needs something to capture, an empty segment no parameter matches
+        assert_eq!(found("/users/"), []);
+        assert!(found("users").is_empty());
+    }
+
+    #[test]
+    fn routes_sharing_a_parameter_pattern_share_its_node() {
+        let patterns: Vec<String> = (0..150).map(|route| format!("/items/{{}}/v{route}")).collect();
+        let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+        let trie = trie(&patterns);
+        assert_eq!(trie.literals["items"].params.len(), 1);
+        for route in [0, 99, 149] {
+            assert_eq!(trie.matches(&format!("/items/x/v{route}")), [(route, vec!["x".to_string()])]);
+        }
+        assert!(trie.matches("/items/x/v150").is_empty());
+    }
+}
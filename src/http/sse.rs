@@ -0,0 +1,116 @@
+use std::io;
+use std::io::Write;
+
+/// Writes server-sent events to the client of an `HttpResponse::sse` response. Every event is
+/// flushed right away; once a write fails the client is gone and further sends fail too.
+pub(crate) struct SseSink<'w> {
+    writer: &'w mut dyn Write,
+    closed: bool,
+    sent: u64,
+}
+
+impl<'w> SseSink<'w> {
+    pub(crate) fn new(writer: &'w mut dyn Write) -> SseSink<'w> {
+        SseSink { writer, closed: false, sent: 0 }
+    }
+
+    /// Sends `data` as an event named `name`, one `data:` field per line of `data`.
+    pub(crate) fn send_event(&mut self, name: &str, data: &str) -> io::Result<()> {
+        let mut block = String::new();
+        if !name.is_empty() {
+            block.push_str(&format!("event: {}\n", name));
+        }
+        data.split('\n').for_each(|line| block.push_str(&format!("data: {}\n", line)));
+        block.push('\n');
+        self.send(&block)
+    }
+
+    /// Sends a comment, which clients ignore; handy as a heartbeat to keep proxies from timing out.
+    pub(crate) fn send_comment(&mut self, text: &str) -> io::Result<()> {
+        let mut block = String::new();
+        text.split('\n').for_each(|line| block.push_str(&format!(": {}\n", line)));
+        block.push('\n');
+        self.send(&block)
+    }
+
+    /// Whether a previous send failed because the client disconnected.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub(crate) fn bytes_sent(&self) -> u64 {
+        self.sent
+    }
+
+    fn send(&mut self, block: &str) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the client disconnected"));
+        }
+        let result = self.writer.write_all(block.as_bytes()).and_then(|_| self.writer.flush());
+        self.closed = result.is_err();
+        if result.is_ok() {
+            self.sent += block.len() as u64;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+    use crate::http::base::{HttpMethod, HttpResponse};
+    use crate::http::testing::TestServer;
+
+    /// Reads lines up to the blank one ending a block of the head or of an event.
+    fn read_block(reader: &mut BufReader<TcpStream>) -> Vec<String> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0, "closed after {lines:?}");
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                return lines;
+            }
+            lines.push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn events_arrive_while_the_stream_is_open() {
+        let (done, finished) = mpsc::channel::<()>();
+        let finished = Arc::new(Mutex::new(finished));
+        let server = TestServer::start(move |server| {
+            server.register_end_point("/events", HttpMethod::GET, Arc::new(move |_| {
+                let finished = Arc::clone(&finished);
+                HttpResponse::sse(move |sink| {
+                    sink.send_event("greeting", "hello").unwrap();
+                    sink.send_comment("keep-alive").unwrap();
+                    sink.send_event("", "line 1\nline 2").unwrap();
+                    // holds the connection until the client read both events
+                    let _ = finished.lock().unwrap().recv_timeout(Duration::from_secs(10));
+                })
+            }));
+        });
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        std::io::Write::write_all(&mut stream, b"GET /events HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let head = read_block(&mut reader);
+        assert_eq!(head[0], "HTTP/1.1 200 OK");
+        let has_header = |expected: &str| head.iter().any(|line| line.eq_ignore_ascii_case(expected));
+        assert!(has_header("content-type:text/event-stream"), "{head:?}");
+        assert!(has_header("cache-control:no-cache"), "{head:?}");
+        assert!(!head.iter().any(|line| line.to_ascii_lowercase().starts_with("content-length")), "{head:?}");
+
+        assert_eq!(read_block(&mut reader), ["event: greeting", "data: hello"]);
+        assert_eq!(read_block(&mut reader), [": keep-alive"]);
+        assert_eq!(read_block(&mut reader), ["data: line 1", "data: line 2"]);
+        done.send(()).unwrap();
+        // the stream ends with the connection
+        assert_eq!(reader.read_line(&mut String::new()).unwrap(), 0);
+    }
+}
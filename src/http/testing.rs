@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::time::Duration;
+use crate::http::base::HttpMethod;
+use crate::http::client::{ClientResponse, HttpClient};
+use crate::http::http_core::{HttpServer, ServerHandle};
+
+/// A server on a free port of 127.0.0.1 for tests, stopped when dropped, e.g.
+/// `TestServer::start(|server| server.register_end_point("/", HttpMethod::GET, handler)).get("/")`.
+pub(crate) struct TestServer {
+    handle: Option<ServerHandle>,
+    client: HttpClient,
+}
+
+impl TestServer {
+    /// Starts the server once `setup` registered its routes and filters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no port of 127.0.0.1 can be bound.
+    pub(crate) fn start(setup: impl FnOnce(&mut HttpServer)) -> TestServer {
+        let mut server = HttpServer::bind("127.0.0.1", 0).workers(2);
+        setup(&mut server);
+        let handle = server.start_in_background()
+            .unwrap_or_else(|e| panic!("cannot start the test server: {}", e));
+        TestServer {
+            handle: Some(handle),
+            client: HttpClient::new().timeout(Some(Duration::from_secs(10))),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.handle.as_ref().unwrap().local_addr()
+    }
+
+    /// The absolute url of `path` on this server, `path` starting with a `/`.
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.local_addr(), path)
+    }
+
+    pub(crate) fn get(&self, path: &str) -> ClientResponse {
+        self.request(HttpMethod::GET, path, &[], &[])
+    }
+
+    /// Sends a request with `headers` and `body`, a `Content-length` is added for the latter.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no valid response comes back, any status is a response.
+    pub(crate) fn request(&self, method: HttpMethod, path: &str, headers: &[(&str, &str)], body: &[u8]) -> ClientResponse {
+        let url = self.url(path);
+        let request = headers.iter()
+            .fold(self.client.request(method, &url), |request, (name, value)| request.header(name, value));
+        request.body(body.to_vec())
+            .send()
+            .unwrap_or_else(|e| panic!("`{:?} {}` failed: {}", method, url, e))
+    }
+
+    /// Writes `bytes` as they are on a new connection, for requests the client would not send,
+    /// then reads until the server closes it. Everything the server wrote is returned, several
+    /// responses if `bytes` held several requests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server cannot be reached or does not close within 10 seconds.
+    pub(crate) fn send_raw(&self, bytes: &[u8]) -> String {
+        let mut stream = TcpStream::connect(self.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        // the server may answer and close before reading everything, e.g. for a too large head
+        let _ = stream.write_all(bytes);
+        let _ = stream.shutdown(Shutdown::Write);
+        let mut response = Vec::new();
+        if let Err(e) = stream.read_to_end(&mut response) {
+            assert!(!response.is_empty(), "no response to {:?}: {}", String::from_utf8_lossy(bytes), e);
+        }
+        String::from_utf8_lossy(&response).into_owned()
+    }
+}
+
+/// The status code of the first response in what `TestServer::send_raw` returned.
+pub(crate) fn raw_status(response: &str) -> u32 {
+    response.split(' ').nth(1).and_then(|status| status.parse().ok())
+        .unwrap_or_else(|| panic!("not a response: {:?}", response))
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown(Duration::from_secs(5));
+        }
+    }
+}
@@ -1,13 +1,44 @@
+use std::fmt::{self, Display, Formatter};
+use std::marker::PhantomData;
+use std::ptr;
+
+type Link<T> = *mut Node<T>;
+
 struct Node<T> {
     data: T,
-    next: Option<Box<Node<T>>>,
+    next: Link<T>,
 }
 
+/// Nodes are allocated with `Box::into_raw` and only ever reached through raw pointers until they
+/// are turned back into a `Box` to be freed, so that `tail` can point at the last one without
+/// aliasing a `Box` that owns it.
 pub(crate) struct List<T> {
-    head: Option<Box<Node<T>>>,
-    pub(crate) size: u32
+    head: Link<T>,
+    tail: Link<T>,
+    /// Vouches for the nodes that indexed accesses dereference, hence private.
+    size: u32,
+    _owns: PhantomData<Box<Node<T>>>,
+}
+
+// SAFETY: the list owns its nodes like a `Box` would, the pointers are never shared
+unsafe impl<T: Send> Send for List<T> {}
+unsafe impl<T: Sync> Sync for List<T> {}
+
+/// Returned by `List::insert` for an index past the end of the list.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct IndexOutOfRange {
+    pub(crate) index: u32,
+    pub(crate) size: u32,
+}
+
+impl Display for IndexOutOfRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "index {} is out of range for a list of {} elements", self.index, self.size)
+    }
 }
 
+impl std::error::Error for IndexOutOfRange {}
+
 pub struct IntoIter<T>(List<T>);
 
 impl<T> Iterator for IntoIter<T> {
@@ -26,7 +57,8 @@ impl<'a, T> Iterator for Iter<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.next.map(|node| {
-            self.next = node.next.as_deref();
+            // SAFETY: the nodes live as long as the list borrowed for 'a
+            self.next = unsafe { node.next.as_ref() };
             &node.data
         })
     }
@@ -41,7 +73,8 @@ impl<'a, T> Iterator for IterMut<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.next.take().map(|node| {
-            self.next = node.next.as_deref_mut();
+            // SAFETY: the nodes live as long as the list borrowed for 'a, each is handed out once
+            self.next = unsafe { node.next.as_mut() };
             &mut node.data
         })
     }
@@ -51,26 +84,134 @@ impl<'a, T> Iterator for IterMut<'a, T> {
 impl<T> List<T> {
     pub(crate) fn new() -> Self {
         List {
-            head: None,
-            size: 0
+            head: ptr::null_mut(),
+            tail: ptr::null_mut(),
+            size: 0,
+            _owns: PhantomData,
         }
     }
 
+    pub(crate) fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
     pub(crate) fn push(&mut self, data: T) {
-        self.head = Some(Box::new(Node { data, next: self.head.take()}));
+        let node = Box::into_raw(Box::new(Node { data, next: self.head }));
+        if self.tail.is_null() {
+            self.tail = node;
+        }
+        self.head = node;
+        self.size += 1;
+    }
+
+    /// Appends `data` in constant time.
+    pub(crate) fn push_back(&mut self, data: T) {
+        let node = Box::into_raw(Box::new(Node { data, next: ptr::null_mut() }));
+        // SAFETY: `tail` is null or points at the last node, which is owned by the list
+        match unsafe { self.tail.as_mut() } {
+            Some(tail) => tail.next = node,
+            None => self.head = node,
+        }
+        self.tail = node;
         self.size += 1;
     }
 
+    /// Inserts `data` so that it ends up at `index`, `len()` appending it.
+    pub(crate) fn insert(&mut self, index: u32, data: T) -> Result<(), IndexOutOfRange> {
+        if index > self.size {
+            return Err(IndexOutOfRange { index, size: self.size });
+        }
+        if index == 0 {
+            self.push(data);
+        } else if index == self.size {
+            self.push_back(data);
+        } else {
+            let previous = self.node_at(index - 1);
+            // SAFETY: `index - 1 < size`, so `previous` is a node of the list
+            unsafe {
+                let node = Box::into_raw(Box::new(Node { data, next: (*previous).next }));
+                (*previous).next = node;
+            }
+            self.size += 1;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, index: u32) -> Option<&T> {
+        if index >= self.size {
+            return None;
+        }
+        // SAFETY: `index < size`, so the node exists
+        unsafe { self.node_at(index).as_ref() }.map(|x| &x.data)
+    }
+
+    pub(crate) fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        if index >= self.size {
+            return None;
+        }
+        // SAFETY: as for `get`, borrowed mutably through `self`
+        unsafe { self.node_at(index).as_mut() }.map(|x| &mut x.data)
+    }
+
+    /// The node at `index`, which must be less than `size`.
+    fn node_at(&self, index: u32) -> Link<T> {
+        let mut node = self.head;
+        for _ in 0..index {
+            // SAFETY: `index < size`, so the nodes up to `index` exist
+            node = unsafe { (*node).next };
+        }
+        node
+    }
+
     pub(crate) fn peek(&self) -> Option<&T> {
-        self.head.as_ref().map(|x| &x.data)
+        self.front()
+    }
+
+    pub(crate) fn front(&self) -> Option<&T> {
+        // SAFETY: `head` is null or points at a node owned by the list
+        unsafe { self.head.as_ref() }.map(|x| &x.data)
+    }
+
+    pub(crate) fn front_mut(&mut self) -> Option<&mut T> {
+        // SAFETY: as for `front`, borrowed mutably through `self`
+        unsafe { self.head.as_mut() }.map(|x| &mut x.data)
+    }
+
+    pub(crate) fn back(&self) -> Option<&T> {
+        // SAFETY: `tail` is null or points at a node owned by the list
+        unsafe { self.tail.as_ref() }.map(|x| &x.data)
+    }
+
+    pub(crate) fn back_mut(&mut self) -> Option<&mut T> {
+        // SAFETY: as for `back`, borrowed mutably through `self`
+        unsafe { self.tail.as_mut() }.map(|x| &mut x.data)
     }
 
     pub(crate) fn pop(&mut self) -> Option<T> {
-        self.head.take().map(|x| {
-            self.head = x.next;
-            self.size  -= 1;
-            x.data
-        })
+        if self.head.is_null() {
+            return None;
+        }
+        // SAFETY: a non-null `head` came from `Box::into_raw` and is unlinked right away
+        let node = unsafe { Box::from_raw(self.head) };
+        self.head = node.next;
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        self.size -= 1;
+        Some(node.data)
+    }
+
+    /// Removes the last element. Unlike `push_back` this takes linear time: the list is singly
+    /// linked, so the new last node is found by walking from the head, as `remove` does.
+    pub(crate) fn pop_back(&mut self) -> Option<T> {
+        if self.size < 2 {
+            return self.pop();
+        }
+        self.remove(self.size - 1)
     }
 
     pub(crate) fn remove(&mut self, index: u32) -> Option<T> {
@@ -79,42 +220,224 @@ impl<T> List<T> {
         }
 
         if index == 0 {
-            return self.head.take().map(|x| {
-                self.head = x.next;
-                self.size -= 1;
-                x.data
-            })
+            return self.pop();
         }
 
-        let mut current_node = self.head.as_deref_mut();
-        let mut current_index = 0;
+        let previous = self.node_at(index - 1);
 
-        while current_index < index - 1 {
-            if let Some(node) = current_node{
-                current_node = node.next.as_deref_mut();
-            }
-            current_index += 1;
+        // `previous` is the node before `index`, the removed node's successor takes its place
+        // SAFETY: both nodes exist since `index < size`, the removed one came from `Box::into_raw`
+        let removed = unsafe { Box::from_raw((*previous).next) };
+        unsafe { (*previous).next = removed.next };
+        if removed.next.is_null() {
+            self.tail = previous;
         }
-
-        current_node.take().map(|x| {
-            return x.next.take().map(|next| {
-                let result = x.next.take().map(|x| x.data);
-                x.next = next.next;
-                self.size -= 1;
-                result
-            }).unwrap_or(None);
-        }).unwrap_or(None)
-     }
+        self.size -= 1;
+        Some(removed.data)
+    }
 
     pub fn into_iter(self) -> IntoIter<T> {
         IntoIter(self)
     }
 
     pub fn iter(&self) -> Iter<'_, T> {
-        Iter { next: self.head.as_deref() }
+        // SAFETY: as for `front`
+        Iter { next: unsafe { self.head.as_ref() } }
     }
 
     pub fn iter_mut(&mut self) -> IterMut<'_, T> {
-        IterMut { next: self.head.as_deref_mut() }
+        // SAFETY: as for `front_mut`
+        IterMut { next: unsafe { self.head.as_mut() } }
+    }
+}
+
+impl<T> Drop for List<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list_of(values: &[i32]) -> List<i32> {
+        let mut list = List::new();
+        for value in values.iter().rev() {
+            list.push(*value);
+        }
+        list
+    }
+
+    fn values(list: &List<i32>) -> Vec<i32> {
+        list.iter().copied().collect()
+    }
+
+    #[test]
+    fn remove_unlinks_the_node_at_index() {
+        let mut list = list_of(&[1, 2, 3, 4]);
+        assert_eq!(list.remove(1), Some(2));
+        assert_eq!(values(&list), [1, 3, 4]);
+        assert_eq!(list.remove(1), Some(3));
+        assert_eq!(values(&list), [1, 4]);
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn remove_last_moves_the_tail() {
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.remove(2), Some(3));
+        assert_eq!(values(&list), [1, 2]);
+        assert_eq!(list.back(), Some(&2));
+        list.push_back(5);
+        assert_eq!(values(&list), [1, 2, 5]);
+        assert_eq!(list.remove(0), Some(1));
+        assert_eq!(list.remove(1), Some(5));
+        assert_eq!(list.remove(0), Some(2));
+        assert_eq!((list.front(), list.back(), list.len()), (None, None, 0));
     }
- }
\ No newline at end of file
+
+    #[test]
+    fn remove_out_of_range_changes_nothing() {
+        let mut list = list_of(&[1, 2]);
+        assert_eq!(list.remove(2), None);
+        assert_eq!(List::<i32>::new().remove(0), None);
+        assert_eq!(values(&list), [1, 2]);
+    }
+
+    #[test]
+    fn push_back_and_pop_back_through_empty_one_and_many() {
+        let mut list = List::new();
+        assert_eq!((list.pop_back(), list.front(), list.back()), (None, None, None));
+        list.push_back(1);
+        assert_eq!((list.front(), list.back()), (Some(&1), Some(&1)));
+        assert_eq!(list.pop_back(), Some(1));
+        assert_eq!((list.front(), list.back(), list.len()), (None, None, 0));
+        list.push_back(2);
+        list.push_back(3);
+        list.push(1);
+        assert_eq!(values(&list), [1, 2, 3]);
+        assert_eq!(list.pop_back(), Some(3));
+        assert_eq!(list.back(), Some(&2));
+        assert_eq!(list.pop_back(), Some(2));
+        assert_eq!((list.front(), list.back()), (Some(&1), Some(&1)));
+        assert_eq!(list.pop_back(), Some(1));
+        assert_eq!((list.pop_back(), list.len()), (None, 0));
+    }
+
+    #[test]
+    fn push_and_pop_keep_the_tail() {
+        let mut list = List::new();
+        list.push(2);
+        assert_eq!(list.back(), Some(&2));
+        list.push(1);
+        assert_eq!(list.back(), Some(&2));
+        assert_eq!(list.pop(), Some(1));
+        assert_eq!(list.pop(), Some(2));
+        assert_eq!(list.back(), None);
+        // a tail left dangling by `pop` would be written through here
+        list.push_back(3);
+        list.push_back(4);
+        assert_eq!(values(&list), [3, 4]);
+    }
+
+    #[test]
+    fn accessors_reach_both_ends() {
+        let mut list = list_of(&[1, 2, 3]);
+        *list.front_mut().unwrap() = 10;
+        *list.back_mut().unwrap() = 30;
+        assert_eq!(values(&list), [10, 2, 30]);
+        assert_eq!(list.peek(), Some(&10));
+        for value in list.iter_mut() {
+            *value += 1;
+        }
+        assert_eq!(list.into_iter().collect::<Vec<_>>(), [11, 3, 31]);
+    }
+
+    /// Writes through the tail after it moved, so that Miri reports it if the pointer no longer
+    /// points into a live node.
+    #[test]
+    fn tail_stays_valid_after_removing_the_last_element() {
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.remove(list.len() - 1), Some(3));
+        *list.back_mut().unwrap() += 20;
+        list.push_back(4);
+        *list.back_mut().unwrap() += 40;
+        assert_eq!(values(&list), [1, 22, 44]);
+        while !list.is_empty() {
+            list.remove(list.len() - 1);
+            if let Some(back) = list.back_mut() {
+                *back += 1;
+            }
+        }
+        assert_eq!((list.front(), list.back()), (None, None));
+        list.push_back(5);
+        assert_eq!((list.front(), list.back()), (Some(&5), Some(&5)));
+    }
+
+    #[test]
+    fn every_node_is_dropped_once() {
+        let counter = std::rc::Rc::new(());
+        let mut list = List::new();
+        for _ in 0..4 {
+            list.push_back(std::rc::Rc::clone(&counter));
+        }
+        list.push(std::rc::Rc::clone(&counter));
+        drop(list.pop_back());
+        drop(list.remove(1));
+        assert_eq!(std::rc::Rc::strong_count(&counter), 4);
+        drop(list);
+        assert_eq!(std::rc::Rc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn insert_at_head_middle_and_end() {
+        let mut list = List::new();
+        assert_eq!(list.insert(0, 2), Ok(()));
+        assert_eq!(list.back(), Some(&2));
+        assert_eq!(list.insert(0, 1), Ok(()));
+        assert_eq!(list.insert(2, 4), Ok(()));
+        assert_eq!(list.back(), Some(&4));
+        assert_eq!(list.insert(2, 3), Ok(()));
+        assert_eq!(values(&list), [1, 2, 3, 4]);
+        assert_eq!(list.len(), 4);
+        list.push_back(5);
+        assert_eq!(values(&list), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn insert_at_zero_is_push() {
+        let mut inserted = list_of(&[2, 3]);
+        let mut pushed = list_of(&[2, 3]);
+        inserted.insert(0, 1).unwrap();
+        pushed.push(1);
+        assert_eq!(values(&inserted), values(&pushed));
+        assert_eq!((inserted.len(), inserted.back()), (pushed.len(), pushed.back()));
+    }
+
+    #[test]
+    fn insert_past_the_end_fails() {
+        let mut list = list_of(&[1, 2]);
+        assert_eq!(list.insert(3, 9), Err(IndexOutOfRange { index: 3, size: 2 }));
+        assert_eq!(List::new().insert(1, 9), Err(IndexOutOfRange { index: 1, size: 0 }));
+        assert_eq!(values(&list), [1, 2]);
+        assert_eq!(IndexOutOfRange { index: 3, size: 2 }.to_string(), "index 3 is out of range for a list of 2 elements");
+    }
+
+    #[test]
+    fn get_and_get_mut_by_index() {
+        let mut empty: List<i32> = List::new();
+        assert_eq!(empty.get(0), None);
+        assert_eq!(empty.get_mut(0), None);
+
+        let mut list = list_of(&[1, 2, 3]);
+        assert_eq!(list.get(0), Some(&1));
+        assert_eq!(list.get(2), Some(&3));
+        assert_eq!(list.get(3), None);
+        *list.get_mut(2).unwrap() = 30;
+        *list.get_mut(1).unwrap() = 20;
+        assert_eq!(list.get_mut(3), None);
+        assert_eq!(values(&list), [1, 20, 30]);
+        assert_eq!(list.back(), Some(&30));
+    }
+}
@@ -141,4 +141,151 @@ fn get_fields_map(s: &DataStruct, types_map: &HashMap<&str, String>) -> Vec<(Str
         }
     }
     fields_map
-}
\ No newline at end of file
+}
+#[proc_macro_derive(JsonSerializable, attributes(json))]
+pub fn json_serializable(input: TokenStream) -> TokenStream {
+    let ast: DeriveInput = syn::parse(input).unwrap();
+    let id = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let body = match &ast.data {
+        Data::Struct(s) => {
+            let Fields::Named(fields) = &s.fields else {
+                panic!("JsonSerializable derive macro only supports structs with named fields");
+            };
+            let mut names = vec![];
+            let mut idents = vec![];
+            for field in &fields.named {
+                let ident = field.ident.clone().unwrap();
+                let attributes = get_json_field_attributes(&field.attrs);
+                if attributes.skip {
+                    continue;
+                }
+                names.push(attributes.rename.unwrap_or_else(|| ident.to_string()));
+                idents.push(ident);
+            }
+            quote! {
+                let mut serializer_struct = serializer.serialize_struct();
+                #(serializer_struct.serialize_field(#names, &self.#idents);)*
+                serializer_struct.end()
+            }
+        }
+        Data::Enum(e) => {
+            let variants: Vec<&Ident> = e.variants.iter().map(|v| {
+                if !matches!(v.fields, Fields::Unit) {
+                    panic!("JsonSerializable derive macro only supports enums with unit variants");
+                }
+                &v.ident
+            }).collect();
+            let names: Vec<String> = variants.iter().map(|v| v.to_string()).collect();
+            quote! {
+                match self {
+                    #(Self::#variants => serializer.serialize_string(#names),)*
+                }
+            }
+        }
+        Data::Union(_) => panic!("JsonSerializable derive macro must use in struct or enum")
+    };
+
+    let gen = quote! {
+        impl #impl_generics JsonSerializable for #id #ty_generics #where_clause {
+            fn serialize(&self, serializer: &mut Serializer) {
+                #body
+            }
+        }
+    };
+    gen.into()
+}
+
+#[derive(Default)]
+struct JsonFieldAttributes {
+    rename: Option<String>,
+    skip: bool,
+}
+
+fn get_json_field_attributes(attrs: &[syn::Attribute]) -> JsonFieldAttributes {
+    let mut attributes = JsonFieldAttributes::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("json")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                attributes.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                let name: syn::LitStr = meta.value()?.parse()?;
+                attributes.rename = Some(name.value());
+                Ok(())
+            } else {
+                Err(meta.error("unsupported json attribute, expected `rename` or `skip`"))
+            }
+        }).unwrap();
+    }
+    attributes
+}
+
+#[proc_macro_derive(JsonDeserializable, attributes(json))]
+pub fn json_deserializable(input: TokenStream) -> TokenStream {
+    let ast: DeriveInput = syn::parse(input).unwrap();
+    let id = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let body = match &ast.data {
+        Data::Struct(s) => {
+            let Fields::Named(fields) = &s.fields else {
+                panic!("JsonDeserializable derive macro only supports structs with named fields");
+            };
+            let mut names = vec![];
+            let mut idents = vec![];
+            let mut skipped = vec![];
+            for field in &fields.named {
+                let ident = field.ident.clone().unwrap();
+                let attributes = get_json_field_attributes(&field.attrs);
+                if attributes.skip {
+                    skipped.push(ident);
+                    continue;
+                }
+                names.push(attributes.rename.unwrap_or_else(|| ident.to_string()));
+                idents.push(ident);
+            }
+            quote! {
+                let DataType::Object(map) = value else {
+                    return Err(JsonError::InvalidType { path: String::new(), expected: "an object" });
+                };
+                Ok(Self {
+                    #(#idents: match map.get(#names) {
+                        // a missing key is only acceptable for fields accepting `null`, e.g. `Option<T>`
+                        None => JsonDeserializable::from_json(&DataType::Null)
+                            .map_err(|_| JsonError::MissingField { field: #names.to_string() })?,
+                        Some(v) => JsonDeserializable::from_json(v).map_err(|e: JsonError| e.at_field(#names))?,
+                    },)*
+                    #(#skipped: Default::default(),)*
+                })
+            }
+        }
+        Data::Enum(e) => {
+            let variants: Vec<&Ident> = e.variants.iter().map(|v| {
+                if !matches!(v.fields, Fields::Unit) {
+                    panic!("JsonDeserializable derive macro only supports enums with unit variants");
+                }
+                &v.ident
+            }).collect();
+            let names: Vec<String> = variants.iter().map(|v| v.to_string()).collect();
+            let expected = format!("one of {}", names.join(", "));
+            quote! {
+                match value {
+                    #(DataType::String(s) if s == #names => Ok(Self::#variants),)*
+                    _ => Err(JsonError::InvalidType { path: String::new(), expected: #expected }),
+                }
+            }
+        }
+        Data::Union(_) => panic!("JsonDeserializable derive macro must use in struct or enum")
+    };
+
+    let gen = quote! {
+        impl #impl_generics JsonDeserializable for #id #ty_generics #where_clause {
+            fn from_json(value: &DataType) -> Result<Self, JsonError> {
+                #body
+            }
+        }
+    };
+    gen.into()
+}
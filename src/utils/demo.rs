@@ -1,36 +1,20 @@
 use std::collections::HashMap;
-use crate::utils::json::{JsonSerializable, Serializer};
+use orm_macro_derive::{JsonDeserializable, JsonSerializable};
+use crate::utils::json::{DataType, JsonDeserializable, JsonError, JsonParser, JsonSerializable, Serializer};
 
+#[derive(Debug, JsonSerializable, JsonDeserializable)]
 struct Test {
     name: String,
     value: HashMap<String, Vec<String>>
 }
 
-impl JsonSerializable for Test {
-    fn serialize(&self, serializer: Serializer) -> String {
-        let mut serializer_struct = serializer.serialize_struct();
-        serializer_struct.serialize_field("name", &self.name);
-        serializer_struct.serialize_field("value", &self.value);
-        serializer_struct.end()
-    }
-}
-
+#[derive(Debug, JsonSerializable, JsonDeserializable)]
 struct User {
     name: String,
     age: i32,
     test: Test
 }
 
-impl JsonSerializable for User {
-    fn serialize(&self, serializer: Serializer) -> String {
-        let mut serializer_struct = serializer.serialize_struct();
-        serializer_struct.serialize_field("name", &self.name);
-        serializer_struct.serialize_field("age", &self.age);
-        serializer_struct.serialize_field("test", &self.test);
-        serializer_struct.end()
-    }
-}
-
 fn main() {
     let serializer = Serializer::new();
     let mut map = HashMap::new();
@@ -46,6 +30,74 @@ fn main() {
         test: t
     };
 
-    let string = u.serialize(serializer);
-    println!("{}", string)
+    let string = serializer.serialize(&u);
+    println!("{}", string);
+
+    let value = JsonParser::new(&string).parse_to_value().unwrap();
+    println!("age: {:?}", value["age"].as_i64());
+    println!("test name: {:?}", value["test"]["name"].as_str());
+    println!("first value: {:?}", value["test"]["value"]["123123"][0].as_str());
+    println!("missing: {:?}", value["nothing"]["here"]);
+    let u = User::from_json(&value).unwrap();
+    println!("{:?}", u)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Test` serialized the way it was written by hand before the derive.
+    struct HandWrittenTest<'a>(&'a Test);
+
+    impl JsonSerializable for HandWrittenTest<'_> {
+        fn serialize(&self, serializer: &mut Serializer) {
+            let mut serializer_struct = serializer.serialize_struct();
+            serializer_struct.serialize_field("name", &self.0.name);
+            serializer_struct.serialize_field("value", &self.0.value);
+            serializer_struct.end()
+        }
+    }
+
+    struct HandWrittenUser<'a>(&'a User);
+
+    impl JsonSerializable for HandWrittenUser<'_> {
+        fn serialize(&self, serializer: &mut Serializer) {
+            let mut serializer_struct = serializer.serialize_struct();
+            serializer_struct.serialize_field("name", &self.0.name);
+            serializer_struct.serialize_field("age", &self.0.age);
+            serializer_struct.serialize_field("test", &HandWrittenTest(&self.0.test));
+            serializer_struct.end()
+        }
+    }
+
+    #[derive(JsonSerializable)]
+    struct Renamed {
+        #[json(rename = "userName")]
+        name: String,
+        #[json(skip)]
+        password: String,
+        role: Role,
+    }
+
+    #[derive(JsonSerializable)]
+    enum Role {
+        Admin,
+    }
+
+    #[test]
+    fn derived_output_matches_the_hand_written_impls() {
+        let value = HashMap::from([
+            ("a".to_string(), vec!["1".to_string()]),
+            ("b".to_string(), vec![]),
+        ]);
+        let user = User { name: "ann".to_string(), age: 30, test: Test { name: "t".to_string(), value } };
+        for serializer in [Serializer::new, Serializer::canonical] {
+            assert_eq!(serializer().serialize(&user), serializer().serialize(&HandWrittenUser(&user)));
+        }
+        assert_eq!(Serializer::canonical().serialize(&user.test), r#"{"name":"t","value":{"a":["1"],"b":[]}}"#);
+
+        let renamed = Renamed { name: "ann".to_string(), password: "secret".to_string(), role: Role::Admin };
+        assert_eq!(Serializer::new().serialize(&renamed), r#"{"userName": "ann","role": "Admin"}"#);
+        assert_eq!(renamed.password, "secret");
+    }
 }
@@ -1,20 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::{Display, Formatter, LowerExp};
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::{BufRead, Write};
+use std::ops::Index;
 use crate::utils::json::DataType::{Array, Boolean, Float, Int, Null, Object};
 
-pub(crate) struct JsonParser {
-    data: Vec<char>,
-    len: usize,
+pub(crate) mod patch;
+mod sqlite;
+
+pub(crate) struct JsonParser<S: JsonSource> {
+    source: S,
     position: usize,
+    duplicate_key_policy: DuplicateKeyPolicy,
+    lone_surrogate_policy: LoneSurrogatePolicy,
+}
+
+/// What to do when an object contains the same key more than once.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    #[default]
+    LastWins,
+    FirstWins,
+    Error,
+}
+
+/// What to do with a `\u` escape encoding half of a surrogate pair without its other half.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LoneSurrogatePolicy {
+    #[default]
+    Reject,
+    /// Decode it as U+FFFD REPLACEMENT CHARACTER.
+    Replace,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DataType {
     String(String),
     Float(f64),
     Int(i32),
     Array(Vec<DataType>),
     Boolean(bool),
-    Object(HashMap<String, DataType>),
+    Object(JsonMap),
     Null,
 }
 
@@ -54,7 +82,7 @@ impl DataType {
         }
     }
 
-    pub(crate) fn unwrap_as_object(&self) -> Result<&HashMap<String, DataType>, &str> {
+    pub(crate) fn unwrap_as_object(&self) -> Result<&JsonMap, &str> {
         match self {
             Object(data) => { Ok(data) }
             _ => Err("this is not an Object")
@@ -67,344 +95,2518 @@ impl DataType {
             _ => false
         }
     }
-}
 
-impl JsonParser {
-    pub fn new(str: &str) -> Self {
-        let chars: Vec<char> = str.chars().collect();
-        let len = chars.len();
-        JsonParser {
-            data: chars,
-            len,
-            position: 0,
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            DataType::String(data) => Some(data),
+            _ => None
         }
     }
 
-    pub fn parse_to_map(mut self) -> HashMap<String, DataType> {
-        let Object(map) = self.parse_object() else {
-            panic!("parse json failed")
-        };
-        map
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Int(data) => Some(*data as i64),
+            _ => None
+        }
     }
 
-    fn parse(&mut self) -> DataType {
-        self.skip_white_spaces();
-        let result = match self.current_token() {
-            '{' => self.parse_object(),
-            '"' => self.parse_string(),
-            '[' => self.parse_array(),
-            '+' | '-' | '0'..='9' => self.parse_number(),
-            't' | 'f' => self.parse_boolean(),
-            'n' => self.parse_null(),
-            _ => { panic!("wtf??") }
-        };
-        self.skip_white_spaces();
-        result
+    /// Integers are widened, so any number can be read as a float.
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match self {
+            Float(data) => Some(*data),
+            Int(data) => Some(*data as f64),
+            _ => None
+        }
     }
 
-    fn parse_object(&mut self) -> DataType {
-        self.consume_token(); // skip '{'
-        let mut result: HashMap<String, DataType> = HashMap::new();
-        while !self.is_end() && self.current_token() != '}' {
-            if let DataType::String(key) = self.parse_string() {
-                if self.current_token() != ':' {
-                    panic!("':' is expected")
-                }
-                self.consume_token(); // skip ':'
-                let value = self.parse();
-                result.insert(key, value);
-                if !self.is_end() && self.current_token() == ',' {
-                    self.consume_token();
-                } else if !self.is_end() && self.current_token() != '}' {
-                    panic!("object parse failed");
-                }
-            }
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Boolean(data) => Some(*data),
+            _ => None
         }
-        self.consume_token();
-        Object(result)
     }
 
-    fn parse_string(&mut self) -> DataType {
-        self.skip_white_spaces();
-        self.consume_token(); // skip '"'
-        let mut result = String::new();
+    pub(crate) fn as_array(&self) -> Option<&Vec<DataType>> {
+        match self {
+            Array(data) => Some(data),
+            _ => None
+        }
+    }
 
-        while !self.is_end() {
-            if self.current_token() != '"' {
-                result.push(self.current_token());
-                self.consume_token();
-            } else {
-                self.consume_token(); // skip '"'
-                self.skip_white_spaces();
-                return DataType::String(result);
-            }
+    pub(crate) fn as_object(&self) -> Option<&JsonMap> {
+        match self {
+            Object(data) => Some(data),
+            _ => None
         }
-        panic!("string parse failed")
     }
 
-    fn parse_array(&mut self) -> DataType {
-        self.consume_token(); // skip '['
-        let mut array: Vec<DataType> = Vec::new();
+    /// Looks up a nested value by a path made of dotted keys and bracket indices,
+    /// e.g. `user.addresses[0].zip`. Keys containing dots can be quoted: `a."b.c"` or `a["b.c"]`.
+    ///
+    /// Returns `None` if the path is malformed, a key is missing, an index is out of bounds
+    /// or an intermediate value has the wrong type.
+    pub(crate) fn get_path(&self, path: &str) -> Option<&DataType> {
+        PathSegment::parse(path)?
+            .iter()
+            .try_fold(self, |current, segment| match (segment, current) {
+                (PathSegment::Key(key), Object(map)) => map.get(key),
+                (PathSegment::Index(index), Array(array)) => array.get(*index),
+                _ => None
+            })
+    }
 
-        while !self.is_end() {
-            array.push(self.parse());
-            let current = self.current_token();
-            self.consume_token();
-            match current {
-                ']' => break,
-                ',' => continue,
-                _ => { panic!("array parse failed") }
-            }
+    pub(crate) fn get_str(&self, path: &str) -> Option<&str> {
+        match self.get_path(path)? {
+            DataType::String(s) => Some(s),
+            _ => None
         }
-        Array(array)
     }
 
-    fn parse_null(&mut self) -> DataType {
-        self.position += 4;
-        Null
+    pub(crate) fn get_i64(&self, path: &str) -> Option<i64> {
+        match self.get_path(path)? {
+            Int(i) => Some(*i as i64),
+            _ => None
+        }
     }
 
-    fn parse_boolean(&mut self) -> DataType {
-        self.skip_white_spaces();
-        let mut read_str = String::new();
+    pub(crate) fn get_f64(&self, path: &str) -> Option<f64> {
+        match self.get_path(path)? {
+            Float(f) => Some(*f),
+            Int(i) => Some(*i as f64),
+            _ => None
+        }
+    }
 
-        self.data[self.position..(self.position + 4)].iter().for_each(|c| read_str.push(*c));
-        self.position += 4;
+    pub(crate) fn get_bool(&self, path: &str) -> Option<bool> {
+        match self.get_path(path)? {
+            Boolean(b) => Some(*b),
+            _ => None
+        }
+    }
 
-        let result: bool;
+    /// Resolves a JSON Pointer (RFC 6901) such as `/foo/0/bar`. An empty pointer refers to the whole document.
+    pub(crate) fn pointer(&self, ptr: &str) -> Option<&DataType> {
+        Self::pointer_tokens(ptr)?
+            .iter()
+            .try_fold(self, |current, token| match current {
+                Object(map) => map.get(token),
+                Array(array) => array.get(Self::pointer_index(token)?),
+                _ => None
+            })
+    }
 
-        if read_str == "true" {
-            result = true
-        } else if read_str == "fals" && self.current_token() == 'e' {
-            result = false;
-            self.consume_token(); // skip 'e'
-        } else {
-            panic!("boolean parse failed");
+    /// Mutable counterpart of `pointer`. The special `-` index refers to the element past the end
+    /// of an array: as the last token, a `Null` is appended and a reference to it is returned.
+    /// Elsewhere it resolves to nothing, and the document is left as it was whenever the pointer
+    /// does not resolve.
+    pub(crate) fn pointer_mut(&mut self, ptr: &str) -> Option<&mut DataType> {
+        let mut tokens = Self::pointer_tokens(ptr)?;
+        let Some(last) = tokens.pop() else {
+            return Some(self);
+        };
+        let parent = tokens.iter()
+            .try_fold(self, |current, token| match current {
+                Object(map) => map.get_mut(token),
+                Array(array) => array.get_mut(Self::pointer_index(token)?),
+                _ => None
+            })?;
+        match parent {
+            Object(map) => map.get_mut(&last),
+            Array(array) => {
+                if last == "-" {
+                    array.push(Null);
+                    array.last_mut()
+                } else {
+                    array.get_mut(Self::pointer_index(&last)?)
+                }
+            }
+            _ => None
         }
-        Boolean(result)
     }
 
-    fn parse_number(&mut self) -> DataType {
-        let negative = self.current_token() == '-';
-        let sign: i32 = if negative { -1 } else { 1 };
-        if negative || self.current_token() == '+' {
-            self.consume_token();
+    /// Applies `patch` following the JSON Merge Patch rules (RFC 7386): objects are merged recursively,
+    /// a `Null` in the patch removes the key and any other value replaces the target wholesale.
+    pub(crate) fn merge(&mut self, patch: &DataType) {
+        let Object(patch) = patch else {
+            *self = patch.clone();
+            return;
+        };
+        if !matches!(self, Object(_)) {
+            *self = Object(JsonMap::new());
         }
-        let mut result: DataType = self.parse_int();
-        if let Int(first_part) = result {
-            if !self.is_end() && self.current_token() == '.' {
-                self.consume_token();
-                let mut base = 1.0;
-                if let Int(nb) = self.parse_int() {
-                    let second_part = nb as f64;
-                    while second_part / base > 0.0 {
-                        base *= 10.0
-                    }
-                    result = Float(sign as f64 * (first_part as f64 + second_part / base))
-                }
+        let Object(target) = self else { unreachable!() };
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
             } else {
-                result = Int(sign * first_part)
+                target.get_or_insert(key.clone(), Null).merge(value);
             }
         }
+    }
+
+    /// Non-mutating variant of `merge`.
+    pub(crate) fn merged(&self, patch: &DataType) -> DataType {
+        let mut result = self.clone();
+        result.merge(patch);
         result
     }
 
-    fn parse_int(&mut self) -> DataType {
-        match self.current_token() {
-            '0'..='9' => {
-                let mut result = 0;
-                while !self.is_end() && ('0'..='9').contains(&self.current_token()) {
-                    result = result * 10 + JsonParser::char_to_integer(self.current_token());
-                    self.consume_token();
-                }
-                return Int(result);
+    /// Sorts the keys of every object in the tree alphabetically, giving a deterministic
+    /// serialization (e.g. for hashing) regardless of the original key order.
+    pub(crate) fn sort_keys(&mut self) {
+        match self {
+            Object(map) => {
+                map.sort_keys();
+                map.values_mut().for_each(|v| v.sort_keys());
             }
-            _ => panic!("parse int failed")
+            Array(array) => array.iter_mut().for_each(|v| v.sort_keys()),
+            _ => {}
         }
     }
 
-    fn current_token(&self) -> char {
-        return self.data[self.position];
+    pub(crate) fn to_json_string(&self) -> String {
+        Serializer::new().serialize(self)
     }
 
-    fn consume_token(&mut self) {
-        self.position += 1;
+    /// Byte-stable serialization, see [`Serializer::canonical`].
+    pub(crate) fn to_canonical_json_string(&self) -> String {
+        Serializer::canonical().serialize(self)
     }
 
-    fn skip_white_spaces(&mut self) {
-        let white_space = " \t\r\n";
-        while !self.is_end() && white_space.contains(self.current_token()) {
-            self.position += 1
+    fn pointer_tokens(ptr: &str) -> Option<Vec<String>> {
+        if ptr.is_empty() {
+            return Some(vec![]);
         }
+        let ptr = ptr.strip_prefix('/')?;
+        ptr.split('/').map(Self::unescape_pointer_token).collect()
     }
 
-    fn is_end(&self) -> bool {
-        self.position >= self.len
+    /// Undoes the `~1` (`/`) and `~0` (`~`) escapes; any other `~` makes the pointer invalid.
+    fn unescape_pointer_token(token: &str) -> Option<String> {
+        let mut unescaped = String::with_capacity(token.len());
+        let mut chars = token.chars();
+        while let Some(c) = chars.next() {
+            unescaped.push(match c {
+                '~' => match chars.next()? {
+                    '0' => '~',
+                    '1' => '/',
+                    _ => return None
+                },
+                c => c
+            });
+        }
+        Some(unescaped)
     }
 
-    fn char_to_integer(c: char) -> i32 {
-        c as i32 - 0x30
+    fn pointer_index(token: &str) -> Option<usize> {
+        // array indices are plain decimal numbers without leading zeros
+        if token.is_empty() || !token.bytes().all(|b| b.is_ascii_digit()) || (token.len() > 1 && token.starts_with('0')) {
+            return None;
+        }
+        token.parse().ok()
     }
 }
 
-pub(crate) trait JsonSerializable {
-    fn serialize(&self, serializer: Serializer) -> String;
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonFieldError {
+    Missing { key: String },
+    WrongType { key: String, expected: &'static str },
 }
 
-impl JsonSerializable for String {
-    fn serialize(&self, serializer: Serializer) -> String {
-        serializer.serialize_string(&self[..])
+impl Display for JsonFieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonFieldError::Missing { key } => write!(f, "field `{key}` is missing"),
+            JsonFieldError::WrongType { key, expected } => write!(f, "field `{key}` is not {expected}"),
+        }
     }
 }
 
-impl JsonSerializable for f64
-{
-    fn serialize(&self, serializer: Serializer) -> String {
-        serializer.serialize_f64(*self)
+impl std::error::Error for JsonFieldError {}
+
+/// Typed lookups on parsed objects, e.g. `request.body_json()?.get_i64("age")?`.
+///
+/// The `_or` variants return the default when the key is missing, null or of the wrong type.
+pub(crate) trait JsonObjectExt {
+    fn get_value(&self, key: &str) -> Option<&DataType>;
+
+    fn get_typed<'a, T>(&'a self, key: &str, expected: &'static str, f: impl FnOnce(&'a DataType) -> Option<T>) -> Result<T, JsonFieldError> {
+        let value = self.get_value(key).ok_or_else(|| JsonFieldError::Missing { key: key.to_string() })?;
+        f(value).ok_or_else(|| JsonFieldError::WrongType { key: key.to_string(), expected })
+    }
+
+    fn get_string(&self, key: &str) -> Result<&str, JsonFieldError> {
+        self.get_typed(key, "a string", DataType::as_str)
+    }
+
+    fn get_i64(&self, key: &str) -> Result<i64, JsonFieldError> {
+        self.get_typed(key, "an integer", DataType::as_i64)
+    }
+
+    fn get_f64(&self, key: &str) -> Result<f64, JsonFieldError> {
+        self.get_typed(key, "a number", DataType::as_f64)
+    }
+
+    fn get_bool(&self, key: &str) -> Result<bool, JsonFieldError> {
+        self.get_typed(key, "a boolean", DataType::as_bool)
+    }
+
+    fn get_array(&self, key: &str) -> Result<&Vec<DataType>, JsonFieldError> {
+        self.get_typed(key, "an array", DataType::as_array)
+    }
+
+    fn get_object(&self, key: &str) -> Result<&JsonMap, JsonFieldError> {
+        self.get_typed(key, "an object", DataType::as_object)
+    }
+
+    fn get_string_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.get_string(key).unwrap_or(default)
+    }
+
+    fn get_i64_or(&self, key: &str, default: i64) -> i64 {
+        self.get_i64(key).unwrap_or(default)
+    }
+
+    fn get_f64_or(&self, key: &str, default: f64) -> f64 {
+        self.get_f64(key).unwrap_or(default)
+    }
+
+    fn get_bool_or(&self, key: &str, default: bool) -> bool {
+        self.get_bool(key).unwrap_or(default)
+    }
+
+    fn get_array_or<'a>(&'a self, key: &str, default: &'a Vec<DataType>) -> &'a Vec<DataType> {
+        self.get_array(key).unwrap_or(default)
+    }
+
+    fn get_object_or<'a>(&'a self, key: &str, default: &'a JsonMap) -> &'a JsonMap {
+        self.get_object(key).unwrap_or(default)
     }
 }
 
-impl JsonSerializable for i32
-{
-    fn serialize(&self, serializer: Serializer) -> String {
-        serializer.serialize_i32(*self)
+impl JsonObjectExt for JsonMap {
+    fn get_value(&self, key: &str) -> Option<&DataType> {
+        self.get(key)
     }
 }
 
-impl<T> JsonSerializable for Vec<T>
-    where T: JsonSerializable
-{
-    fn serialize(&self, serializer: Serializer) -> String {
-        let mut seq = serializer.serialize_seq();
-        for e in self {
-            seq.serialize_element(e);
+impl JsonObjectExt for HashMap<String, DataType> {
+    fn get_value(&self, key: &str) -> Option<&DataType> {
+        self.get(key)
+    }
+}
+
+static NULL: DataType = Null;
+
+/// Numbers compare by value, so `Int(1) == Float(1.0)`; floats follow IEEE 754 (`NaN` is never equal).
+/// Objects compare regardless of key order.
+impl PartialEq for DataType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DataType::String(a), DataType::String(b)) => a == b,
+            (Int(a), Int(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            (Int(a), Float(b)) | (Float(b), Int(a)) => *a as f64 == *b,
+            (Array(a), Array(b)) => a == b,
+            (Boolean(a), Boolean(b)) => a == b,
+            (Object(a), Object(b)) => a == b,
+            (Null, Null) => true,
+            _ => false
         }
-        seq.end()
     }
 }
 
-impl<T> JsonSerializable for HashMap<String, T>
-    where T: JsonSerializable
-{
-    fn serialize(&self, serializer: Serializer) -> String {
-        let mut seq = serializer.serialize_struct();
-        for e in self {
-            seq.serialize_field(e.0, e.1);
+/// Missing keys, out of bounds indices and non-container values all yield `Null`,
+/// so lookups can be chained: `value["user"]["age"].as_i64()`.
+impl Index<&str> for DataType {
+    type Output = DataType;
+
+    fn index(&self, key: &str) -> &Self::Output {
+        match self {
+            Object(map) => map.get(key).unwrap_or(&NULL),
+            _ => &NULL
         }
-        seq.end()
     }
 }
 
-struct JsonEntry<'a, T>
-    where T: JsonSerializable
-{
-    key: String,
-    value: &'a T
+impl Index<usize> for DataType {
+    type Output = DataType;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        match self {
+            Array(array) => array.get(index).unwrap_or(&NULL),
+            _ => &NULL
+        }
+    }
 }
 
-impl<'a, T> JsonEntry<'a, T>
-    where T: JsonSerializable
-{
-    fn new(key: String, value: &'a T) -> JsonEntry<'a, T>
-    {
-        JsonEntry {
-            key,
-            value
+/// A map keeping its keys in insertion order, backing `DataType::Object` so that
+/// a parsed document is serialized back with the same key order.
+#[derive(Clone, Default)]
+pub struct JsonMap {
+    entries: Vec<(String, DataType)>,
+    indices: HashMap<String, usize>,
+}
+
+impl JsonMap {
+    pub fn new() -> Self {
+        JsonMap::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&DataType> {
+        self.indices.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut DataType> {
+        self.indices.get(key).map(|&i| &mut self.entries[i].1)
+    }
+
+    /// Inserts a value, an existing key keeps its position and the old value is returned.
+    pub fn insert(&mut self, key: String, value: DataType) -> Option<DataType> {
+        match self.indices.get(&key) {
+            Some(&i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.indices.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
         }
     }
+
+    /// Returns the value of `key`, inserting `default` first if the key is absent.
+    pub fn get_or_insert(&mut self, key: String, default: DataType) -> &mut DataType {
+        let i = match self.indices.get(&key) {
+            Some(&i) => i,
+            None => {
+                self.insert(key, default);
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[i].1
+    }
+
+    /// Removes a key, the following entries keep their relative order.
+    pub fn remove(&mut self, key: &str) -> Option<DataType> {
+        let index = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(index);
+        self.indices.values_mut().filter(|i| **i > index).for_each(|i| *i -= 1);
+        Some(value)
+    }
+
+    pub fn sort_keys(&mut self) {
+        self.entries.sort_by(|a, b| a.0.cmp(&b.0));
+        self.reindex();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&String, &DataType)> {
+        self.entries.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(&String, &mut DataType)> {
+        self.entries.iter_mut().map(|(k, v)| (&*k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&String> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item=&DataType> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item=&mut DataType> {
+        self.entries.iter_mut().map(|(_, v)| v)
+    }
+
+    fn reindex(&mut self) {
+        self.indices = self.entries.iter().enumerate().map(|(i, (k, _))| (k.clone(), i)).collect();
+    }
 }
 
-pub(crate) struct Serializer {
+impl PartialEq for JsonMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
 }
 
-impl Serializer {
-    pub fn new() -> Serializer {
-        Serializer{}
+impl fmt::Debug for JsonMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
     }
-    pub fn serialize_string(&self, str: &str) -> String {
-        format!("\"{str}\"")
+}
+
+impl Index<&str> for JsonMap {
+    type Output = DataType;
+
+    fn index(&self, key: &str) -> &Self::Output {
+        self.get(key).expect("no entry found for key")
     }
+}
 
-    pub fn serialize_bool(&self, b: bool) -> String {
-        b.to_string()
+impl FromIterator<(String, DataType)> for JsonMap {
+    fn from_iter<I: IntoIterator<Item=(String, DataType)>>(iter: I) -> Self {
+        let mut map = JsonMap::new();
+        iter.into_iter().for_each(|(k, v)| {
+            map.insert(k, v);
+        });
+        map
     }
+}
 
-    pub fn serialize_i32(&self, i: i32) -> String {
-        i.to_string()
+impl IntoIterator for JsonMap {
+    type Item = (String, DataType);
+    type IntoIter = std::vec::IntoIter<(String, DataType)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
     }
+}
 
-    pub fn serialize_f64(&self, f: f64) -> String {
-        f.to_string()
+impl<'a> IntoIterator for &'a JsonMap {
+    type Item = (&'a String, &'a DataType);
+    type IntoIter = std::iter::Map<std::slice::Iter<'a, (String, DataType)>, fn(&'a (String, DataType)) -> (&'a String, &'a DataType)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter().map(|(k, v)| (k, v))
     }
+}
 
-    pub fn serialize_struct(&self) -> SerializerStruct
-    {
-        SerializerStruct::new()
+#[derive(Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl PathSegment {
+    fn parse(path: &str) -> Option<Vec<PathSegment>> {
+        let mut segments = vec![];
+        let mut chars = path.chars().peekable();
+        let mut expect_key = true;
+
+        while let Some(&c) = chars.peek() {
+            match c {
+                '[' => {
+                    chars.next();
+                    if chars.peek() == Some(&'"') {
+                        segments.push(PathSegment::Key(Self::parse_quoted(&mut chars)?));
+                    } else {
+                        let mut digits = String::new();
+                        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+                            digits.push(d);
+                        }
+                        segments.push(PathSegment::Index(digits.parse().ok()?));
+                    }
+                    if chars.next() != Some(']') {
+                        return None;
+                    }
+                    expect_key = false;
+                }
+                '.' if !expect_key => {
+                    chars.next();
+                    expect_key = true;
+                }
+                '"' if expect_key => {
+                    segments.push(PathSegment::Key(Self::parse_quoted(&mut chars)?));
+                    expect_key = false;
+                }
+                _ if expect_key => {
+                    let mut key = String::new();
+                    while let Some(k) = chars.next_if(|k| *k != '.' && *k != '[') {
+                        key.push(k);
+                    }
+                    if key.is_empty() {
+                        return None;
+                    }
+                    segments.push(PathSegment::Key(key));
+                    expect_key = false;
+                }
+                _ => return None
+            }
+        }
+
+        // a trailing '.' is not a valid path
+        if expect_key && !segments.is_empty() {
+            return None;
+        }
+        Some(segments)
     }
 
-    pub fn serialize_seq(&self) -> SerializerSeq
-    {
-        SerializerSeq::new()
+    fn parse_quoted(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+        chars.next(); // skip '"'
+        let mut key = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(key),
+                '\\' => key.push(chars.next()?),
+                c => key.push(c)
+            }
+        }
     }
 }
 
-pub(crate) struct SerializerStruct
-{
-    fields: String
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    UnexpectedEof,
+    UnexpectedToken { expected: String, found: char, position: usize },
+    InvalidLiteral { position: usize },
+    TrailingCharacters { position: usize },
+    NotAnObject,
+    MissingField { field: String },
+    InvalidType { path: String, expected: &'static str },
+    InvalidUtf8 { position: usize },
+    DuplicateKey { key: String, position: usize },
+    InvalidEscape { position: usize },
+    InvalidNumber { position: usize },
+    LoneSurrogate { position: usize },
+    /// The path given to `JsonReader::stream_array_elements` is malformed, missing from the
+    /// document, or leads to a value other than an array.
+    PathNotFound { path: String },
+    Io(String),
 }
 
-impl SerializerStruct
-{
-    fn new() -> SerializerStruct {
-        SerializerStruct {
-            fields: String::from("{")
+impl JsonError {
+    /// Prefixes the path carried by deserialization errors with the given object key,
+    /// so nested failures read like `user.addresses[0].zip`.
+    pub fn at_field(self, name: &str) -> JsonError {
+        match self {
+            JsonError::MissingField { field } => JsonError::MissingField { field: Self::join_path(name, &field) },
+            JsonError::InvalidType { path, expected } => JsonError::InvalidType { path: Self::join_path(name, &path), expected },
+            other => other
         }
     }
 
-    pub fn serialize_field<T>(&mut self, name: &str, value: &T)
-        where T: JsonSerializable
-    {
-        self.fields.push_str("\"");
-        self.fields.push_str(name);
-        self.fields.push_str("\": ");
-        self.fields.push_str(value.serialize(Serializer{}).as_str());
-        self.fields.push(',');
+    /// The byte offset in the document the error was found at, if it is a syntax error.
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            JsonError::UnexpectedToken { position, .. }
+            | JsonError::InvalidLiteral { position }
+            | JsonError::TrailingCharacters { position }
+            | JsonError::InvalidUtf8 { position }
+            | JsonError::DuplicateKey { position, .. }
+            | JsonError::InvalidEscape { position }
+            | JsonError::InvalidNumber { position }
+            | JsonError::LoneSurrogate { position } => Some(*position),
+            _ => None
+        }
     }
 
-    pub fn end(mut self) -> String {
-        if self.fields.len() > 1 {
-            self.fields.remove(self.fields.len() - 1);
+    /// Same as `at_field` but for an array index.
+    pub fn at_index(self, index: usize) -> JsonError {
+        self.at_field(&format!("[{index}]"))
+    }
+
+    fn join_path(prefix: &str, path: &str) -> String {
+        if path.is_empty() || path.starts_with('[') {
+            format!("{prefix}{path}")
+        } else {
+            format!("{prefix}.{path}")
         }
-        self.fields.push('}');
-        self.fields
     }
 }
 
-pub(crate) struct SerializerSeq
-{
-    seq: String
+impl Display for JsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnexpectedEof => write!(f, "unexpected end of input"),
+            JsonError::UnexpectedToken { expected, found, position } => {
+                write!(f, "expected {expected} but found '{found}' at position {position}")
+            }
+            JsonError::InvalidLiteral { position } => write!(f, "invalid literal at position {position}"),
+            JsonError::TrailingCharacters { position } => {
+                write!(f, "trailing characters after the document at position {position}")
+            }
+            JsonError::NotAnObject => write!(f, "the document is not a json object"),
+            JsonError::MissingField { field } => write!(f, "missing field `{field}`"),
+            JsonError::InvalidType { path, expected } if path.is_empty() => write!(f, "invalid type, expected {expected}"),
+            JsonError::InvalidType { path, expected } => write!(f, "invalid type at `{path}`, expected {expected}"),
+            JsonError::InvalidUtf8 { position } => write!(f, "invalid UTF-8 at position {position}"),
+            JsonError::DuplicateKey { key, position } => write!(f, "duplicate key `{key}` at position {position}"),
+            JsonError::InvalidNumber { position } => write!(f, "invalid number at position {position}"),
+            JsonError::InvalidEscape { position } => write!(f, "invalid escape sequence at position {position}"),
+            JsonError::LoneSurrogate { position } => write!(f, "lone surrogate escape at position {position}"),
+            JsonError::PathNotFound { path } => write!(f, "path `{path}` is malformed or does not lead to an array"),
+            JsonError::Io(message) => write!(f, "failed to read the document: {message}"),
+        }
+    }
 }
 
-impl SerializerSeq
-{
-    fn new() -> SerializerSeq{
-        SerializerSeq {
-            seq: String::from("[")
+impl std::error::Error for JsonError {}
+
+/// Where the parser pulls its bytes from.
+pub(crate) trait JsonSource {
+    /// Returns the next byte without consuming it, `None` at the end of the input.
+    fn peek(&mut self) -> Result<Option<u8>, JsonError>;
+
+    /// Consumes the byte returned by the last `peek`.
+    fn advance(&mut self);
+}
+
+/// An in-memory document.
+pub(crate) struct SliceSource<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> JsonSource for SliceSource<'a> {
+    fn peek(&mut self) -> Result<Option<u8>, JsonError> {
+        Ok(self.data.get(self.index).copied())
+    }
+
+    fn advance(&mut self) {
+        self.index += 1;
+    }
+}
+
+/// A document read incrementally, the lookahead is the reader's own buffer.
+pub(crate) struct ReaderSource<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> JsonSource for ReaderSource<R> {
+    fn peek(&mut self) -> Result<Option<u8>, JsonError> {
+        let buffer = self.reader.fill_buf().map_err(|e| JsonError::Io(e.to_string()))?;
+        Ok(buffer.first().copied())
+    }
+
+    fn advance(&mut self) {
+        self.reader.consume(1);
+    }
+}
+
+impl<'a> JsonParser<SliceSource<'a>> {
+    pub fn new(str: &'a str) -> Self {
+        Self::from_slice(str.as_bytes())
+    }
+
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        JsonParser {
+            source: SliceSource {
+                data,
+                index: 0,
+            },
+            position: 0,
+            duplicate_key_policy: DuplicateKeyPolicy::default(),
+            lone_surrogate_policy: LoneSurrogatePolicy::default(),
         }
     }
+}
 
-    fn serialize_element<T>(&mut self, elem: &T)
-        where T: JsonSerializable
-    {
-        self.seq.push_str(elem.serialize(Serializer{}).as_str());
-        self.seq.push(',');
+impl<R: BufRead> JsonParser<ReaderSource<R>> {
+    /// Parses straight from a reader without materializing the whole document.
+    pub fn from_reader(reader: R) -> Self {
+        JsonParser {
+            source: ReaderSource {
+                reader,
+            },
+            position: 0,
+            duplicate_key_policy: DuplicateKeyPolicy::default(),
+            lone_surrogate_policy: LoneSurrogatePolicy::default(),
+        }
+    }
+}
+
+impl<S: JsonSource> JsonParser<S> {
+    pub fn duplicate_key_policy(mut self, policy: DuplicateKeyPolicy) -> Self {
+        self.duplicate_key_policy = policy;
+        self
+    }
+
+    pub fn lone_surrogate_policy(mut self, policy: LoneSurrogatePolicy) -> Self {
+        self.lone_surrogate_policy = policy;
+        self
     }
 
-    fn end(mut self) -> String {
-        if self.seq.len() > 1 {
-            self.seq.remove(self.seq.len() - 1);
+    /// Parses the whole document and returns its top-level value, whatever it is
+    /// (object, array, string, number, boolean or null).
+    ///
+    /// Anything but whitespace after the value (e.g. `{}junk`) is rejected with
+    /// `JsonError::TrailingCharacters`.
+    pub fn parse_to_value(mut self) -> Result<DataType, JsonError> {
+        self.skip_bom()?;
+        let value = self.parse()?;
+        if !self.is_end()? {
+            return Err(JsonError::TrailingCharacters { position: self.position });
         }
-        self.seq.push(']');
-        self.seq
+        Ok(value)
     }
-}
\ No newline at end of file
+
+    /// Parses a document whose top-level value must be an object. Trailing characters are
+    /// rejected just like in `parse_to_value`.
+    pub fn parse_to_map(self) -> Result<JsonMap, JsonError> {
+        match self.parse_to_value()? {
+            Object(map) => Ok(map),
+            _ => Err(JsonError::NotAnObject)
+        }
+    }
+
+    fn parse(&mut self) -> Result<DataType, JsonError> {
+        self.skip_white_spaces()?;
+        let result = match self.current_token()? {
+            b'{' => self.parse_object(),
+            b'"' => self.parse_string(),
+            b'[' => self.parse_array(),
+            b'+' | b'-' | b'.' | b'0'..=b'9' => self.parse_number(),
+            b't' | b'f' => self.parse_boolean(),
+            b'n' => self.parse_null(),
+            c => Err(self.unexpected("a value", c))
+        }?;
+        self.skip_white_spaces()?;
+        Ok(result)
+    }
+
+    fn parse_object(&mut self) -> Result<DataType, JsonError> {
+        self.consume_token(); // skip '{'
+        self.skip_white_spaces()?;
+        let mut result = JsonMap::new();
+        if self.current_token()? == b'}' {
+            self.consume_token();
+            return Ok(Object(result));
+        }
+        loop {
+            self.skip_white_spaces()?;
+            let key_position = self.position;
+            let key = self.parse_raw_string()?;
+            self.expect_token(b':')?;
+            let value = self.parse()?;
+            if !result.contains_key(&key) {
+                result.insert(key, value);
+            } else {
+                match self.duplicate_key_policy {
+                    DuplicateKeyPolicy::LastWins => {
+                        result.insert(key, value);
+                    }
+                    DuplicateKeyPolicy::FirstWins => {}
+                    DuplicateKeyPolicy::Error => return Err(JsonError::DuplicateKey { key, position: key_position })
+                }
+            }
+            match self.current_token()? {
+                b',' => self.consume_token(),
+                b'}' => break,
+                c => return Err(self.unexpected("',' or '}'", c))
+            }
+        }
+        self.consume_token(); // skip '}'
+        Ok(Object(result))
+    }
+
+    fn parse_string(&mut self) -> Result<DataType, JsonError> {
+        Ok(DataType::String(self.parse_raw_string()?))
+    }
+
+    fn parse_raw_string(&mut self) -> Result<String, JsonError> {
+        self.skip_white_spaces()?;
+        let start = self.position;
+        self.expect_token(b'"')?;
+        // '"' and '\\' never occur inside a multi-byte UTF-8 sequence, so the raw bytes can be
+        // collected as they are and validated once the literal is complete
+        let mut result = Vec::new();
+
+        while !self.is_end()? {
+            let token = self.current_token()?;
+            if token == b'\\' {
+                self.parse_escape(&mut result)?;
+            } else if token != b'"' {
+                result.push(token);
+                self.consume_token();
+            } else {
+                self.consume_token(); // skip '"'
+                self.skip_white_spaces()?;
+                return String::from_utf8(result).map_err(|_| JsonError::InvalidUtf8 { position: start });
+            }
+        }
+        Err(JsonError::UnexpectedEof)
+    }
+
+    fn parse_escape(&mut self, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let start = self.position;
+        self.consume_token(); // skip '\\'
+        if self.current_token()? == b'u' {
+            let code = self.parse_hex_code(start)?;
+            return self.push_code_point(code, start, out);
+        }
+        self.push_simple_escape(start, out)
+    }
+
+    fn push_simple_escape(&mut self, start: usize, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        let c = match self.current_token()? {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            _ => return Err(JsonError::InvalidEscape { position: start })
+        };
+        self.consume_token();
+        Self::push_char(c, out);
+        Ok(())
+    }
+
+    /// Pushes the code point of a `\uXXXX` escape, combining a high surrogate with the
+    /// low surrogate escape following it (`\uD83D\uDE00`) into a single char.
+    fn push_code_point(&mut self, code: u32, start: usize, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        if !(0xD800..=0xDFFF).contains(&code) {
+            Self::push_char(char::from_u32(code).unwrap(), out);
+            return Ok(());
+        }
+        if code <= 0xDBFF && self.source.peek()? == Some(b'\\') {
+            let next_start = self.position;
+            self.consume_token(); // skip '\\'
+            if self.current_token()? != b'u' {
+                self.push_lone_surrogate(start, out)?;
+                return self.push_simple_escape(next_start, out);
+            }
+            let low = self.parse_hex_code(next_start)?;
+            if (0xDC00..=0xDFFF).contains(&low) {
+                let c = char::from_u32(0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)).unwrap();
+                Self::push_char(c, out);
+                return Ok(());
+            }
+            self.push_lone_surrogate(start, out)?;
+            return self.push_code_point(low, next_start, out);
+        }
+        self.push_lone_surrogate(start, out)
+    }
+
+    fn push_lone_surrogate(&self, position: usize, out: &mut Vec<u8>) -> Result<(), JsonError> {
+        match self.lone_surrogate_policy {
+            LoneSurrogatePolicy::Reject => Err(JsonError::LoneSurrogate { position }),
+            LoneSurrogatePolicy::Replace => {
+                Self::push_char(char::REPLACEMENT_CHARACTER, out);
+                Ok(())
+            }
+        }
+    }
+
+    fn push_char(c: char, out: &mut Vec<u8>) {
+        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+
+    /// Reads the four hex digits following `\u`.
+    fn parse_hex_code(&mut self, start: usize) -> Result<u32, JsonError> {
+        self.consume_token(); // skip 'u'
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.current_token()? as char).to_digit(16)
+                .ok_or(JsonError::InvalidEscape { position: start })?;
+            code = code * 16 + digit;
+            self.consume_token();
+        }
+        Ok(code)
+    }
+
+    fn parse_array(&mut self) -> Result<DataType, JsonError> {
+        self.consume_token(); // skip '['
+        self.skip_white_spaces()?;
+        let mut array: Vec<DataType> = Vec::new();
+        if self.current_token()? == b']' {
+            self.consume_token();
+            return Ok(Array(array));
+        }
+
+        loop {
+            array.push(self.parse()?);
+            match self.current_token()? {
+                b',' => self.consume_token(),
+                b']' => break,
+                c => return Err(self.unexpected("',' or ']'", c))
+            }
+        }
+        self.consume_token(); // skip ']'
+        Ok(Array(array))
+    }
+
+    fn parse_null(&mut self) -> Result<DataType, JsonError> {
+        self.parse_literal("null", Null)
+    }
+
+    fn parse_boolean(&mut self) -> Result<DataType, JsonError> {
+        match self.current_token()? {
+            b't' => self.parse_literal("true", Boolean(true)),
+            _ => self.parse_literal("false", Boolean(false))
+        }
+    }
+
+    /// Consumes exactly `literal`, which must be followed by a delimiter or the end of input.
+    fn parse_literal(&mut self, literal: &str, value: DataType) -> Result<DataType, JsonError> {
+        let start = self.position;
+        for expected in literal.bytes() {
+            if self.source.peek()? != Some(expected) {
+                return Err(JsonError::InvalidLiteral { position: start });
+            }
+            self.consume_token();
+        }
+        match self.source.peek()? {
+            None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n') => Ok(value),
+            Some(_) => Err(JsonError::InvalidLiteral { position: start })
+        }
+    }
+
+    /// Scans the whole literal `[-]digits[.digits][(e|E)[+-]digits]` before converting it once.
+    /// Every part that is present needs at least one digit, so `-`, `.5`, `12.` and `1e` are all
+    /// `InvalidNumber` errors, as are a leading `+` and leading zeros such as `012`, which RFC 8259
+    /// does not allow either. Numbers without fraction or exponent that fit in an `i32` become
+    /// `Int`, all others `Float`.
+    fn parse_number(&mut self) -> Result<DataType, JsonError> {
+        let start = self.position;
+        let mut literal = String::new();
+        if self.source.peek()? == Some(b'-') {
+            literal.push('-');
+            self.consume_token();
+        }
+        let digits = self.read_digits(&mut literal)?;
+        let mut valid = digits == 1 || digits > 1 && !literal.trim_start_matches('-').starts_with('0');
+
+        let mut is_float = false;
+        if self.source.peek()? == Some(b'.') {
+            literal.push('.');
+            self.consume_token();
+            valid &= self.read_digits(&mut literal)? > 0;
+            is_float = true;
+        }
+        if let Some(b'e' | b'E') = self.source.peek()? {
+            literal.push('e');
+            self.consume_token();
+            if let Some(sign @ (b'+' | b'-')) = self.source.peek()? {
+                literal.push(sign as char);
+                self.consume_token();
+            }
+            valid &= self.read_digits(&mut literal)? > 0;
+            is_float = true;
+        }
+        if !valid {
+            return Err(JsonError::InvalidNumber { position: start });
+        }
+
+        // `-0` stays a float so that the sign of negative zero survives a round trip
+        if !is_float && literal != "-0" {
+            if let Ok(i) = literal.parse::<i32>() {
+                return Ok(Int(i));
+            }
+        }
+        literal.parse::<f64>()
+            .map(Float)
+            .map_err(|_| JsonError::InvalidNumber { position: start })
+    }
+
+    /// Reads a run of digits, returning how many there were.
+    fn read_digits(&mut self, literal: &mut String) -> Result<usize, JsonError> {
+        let mut count = 0;
+        while let Some(digit) = self.source.peek()?.filter(u8::is_ascii_digit) {
+            literal.push(digit as char);
+            self.consume_token();
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn current_token(&mut self) -> Result<u8, JsonError> {
+        self.source.peek()?.ok_or(JsonError::UnexpectedEof)
+    }
+
+    fn consume_token(&mut self) {
+        self.source.advance();
+        self.position += 1;
+    }
+
+    fn expect_token(&mut self, expected: u8) -> Result<(), JsonError> {
+        match self.current_token()? {
+            c if c == expected => {
+                self.consume_token();
+                Ok(())
+            }
+            c => Err(JsonError::UnexpectedToken {
+                expected: format!("'{}'", expected as char),
+                found: c as char,
+                position: self.position,
+            })
+        }
+    }
+
+    fn unexpected(&self, expected: &str, found: u8) -> JsonError {
+        JsonError::UnexpectedToken {
+            expected: expected.to_string(),
+            found: found as char,
+            position: self.position,
+        }
+    }
+
+    fn skip_white_spaces(&mut self) -> Result<(), JsonError> {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.source.peek()? {
+            self.consume_token();
+        }
+        Ok(())
+    }
+
+    /// A leading UTF-8 byte order mark is skipped, any other use of its first byte is invalid anyway.
+    fn skip_bom(&mut self) -> Result<(), JsonError> {
+        if self.source.peek()? != Some(0xEF) {
+            return Ok(());
+        }
+        for expected in [0xEF, 0xBB, 0xBF] {
+            if self.source.peek()? != Some(expected) {
+                return Err(JsonError::InvalidUtf8 { position: self.position });
+            }
+            self.consume_token();
+        }
+        Ok(())
+    }
+
+    fn is_end(&mut self) -> Result<bool, JsonError> {
+        Ok(self.source.peek()?.is_none())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonEvent {
+    StartObject,
+    Key(String),
+    EndObject,
+    StartArray,
+    EndArray,
+    /// A string, number, boolean or null.
+    Value(DataType),
+}
+
+enum ReaderFrame {
+    Object { first: bool, key: Option<String>, expect_value: bool },
+    Array { count: usize },
+}
+
+/// Pull-based reader yielding one `JsonEvent` at a time, so documents can be processed
+/// without holding the whole tree in memory.
+pub(crate) struct JsonReader<S: JsonSource> {
+    parser: JsonParser<S>,
+    frames: Vec<ReaderFrame>,
+    started: bool,
+    done: bool,
+}
+
+impl<S: JsonSource> JsonReader<S> {
+    pub fn new(parser: JsonParser<S>) -> Self {
+        JsonReader {
+            parser,
+            frames: vec![],
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Iterates over the elements of the array found at `path` (same syntax as `DataType::get_path`),
+    /// parsing one element at a time. Reading stops at the end of that array.
+    pub fn stream_array_elements(self, path: &str) -> ArrayElements<S> {
+        ArrayElements {
+            target: PathSegment::parse(path),
+            path: path.to_string(),
+            reader: self,
+            streaming: None,
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Option<JsonEvent>, JsonError> {
+        self.parser.skip_white_spaces()?;
+        let event = match self.frames.last_mut() {
+            None if self.started => {
+                if !self.parser.is_end()? {
+                    return Err(JsonError::TrailingCharacters { position: self.parser.position });
+                }
+                return Ok(None);
+            }
+            None => {
+                self.started = true;
+                self.parser.skip_bom()?;
+                self.read_value()?
+            }
+            Some(ReaderFrame::Array { count }) => {
+                if self.parser.current_token()? == b']' {
+                    self.parser.consume_token();
+                    self.frames.pop();
+                    JsonEvent::EndArray
+                } else {
+                    if *count > 0 {
+                        self.parser.expect_token(b',')?;
+                    }
+                    *count += 1;
+                    self.read_value()?
+                }
+            }
+            Some(ReaderFrame::Object { first, key, expect_value }) => {
+                if *expect_value {
+                    *expect_value = false;
+                    self.read_value()?
+                } else if self.parser.current_token()? == b'}' {
+                    self.parser.consume_token();
+                    self.frames.pop();
+                    JsonEvent::EndObject
+                } else {
+                    if !*first {
+                        self.parser.expect_token(b',')?;
+                    }
+                    *first = false;
+                    let name = self.parser.parse_raw_string()?;
+                    self.parser.expect_token(b':')?;
+                    *key = Some(name.clone());
+                    *expect_value = true;
+                    JsonEvent::Key(name)
+                }
+            }
+        };
+        Ok(Some(event))
+    }
+
+    fn read_value(&mut self) -> Result<JsonEvent, JsonError> {
+        self.parser.skip_white_spaces()?;
+        match self.parser.current_token()? {
+            b'{' => {
+                self.parser.consume_token();
+                self.frames.push(ReaderFrame::Object { first: true, key: None, expect_value: false });
+                Ok(JsonEvent::StartObject)
+            }
+            b'[' => {
+                self.parser.consume_token();
+                self.frames.push(ReaderFrame::Array { count: 0 });
+                Ok(JsonEvent::StartArray)
+            }
+            _ => self.parser.parse().map(JsonEvent::Value)
+        }
+    }
+
+    /// Path of the container on top of the stack, i.e. the one whose start event was just read.
+    fn current_container_path(&self) -> Vec<PathSegment> {
+        self.frames[..self.frames.len().saturating_sub(1)].iter()
+            .map(|frame| match frame {
+                ReaderFrame::Object { key, .. } => PathSegment::Key(key.clone().unwrap_or_default()),
+                ReaderFrame::Array { count } => PathSegment::Index(count.saturating_sub(1)),
+            })
+            .collect()
+    }
+}
+
+impl<S: JsonSource> Iterator for JsonReader<S> {
+    type Item = Result<JsonEvent, JsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_event().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Iterator returned by `JsonReader::stream_array_elements`.
+pub(crate) struct ArrayElements<S: JsonSource> {
+    reader: JsonReader<S>,
+    target: Option<Vec<PathSegment>>,
+    path: String,
+    /// `Some(count)` once the target array has been entered.
+    streaming: Option<usize>,
+}
+
+impl<S: JsonSource> ArrayElements<S> {
+    fn find_array(&mut self) -> Result<bool, JsonError> {
+        let Some(target) = &self.target else {
+            return Ok(false);
+        };
+        while let Some(event) = self.reader.next_event()? {
+            if event == JsonEvent::StartArray && self.reader.current_container_path() == *target {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn next_element(&mut self) -> Result<Option<DataType>, JsonError> {
+        let count = match self.streaming {
+            Some(count) => count,
+            None => {
+                if !self.find_array()? {
+                    return Err(JsonError::PathNotFound { path: self.path.clone() });
+                }
+                0
+            }
+        };
+        let parser = &mut self.reader.parser;
+        parser.skip_white_spaces()?;
+        if parser.current_token()? == b']' {
+            parser.consume_token();
+            return Ok(None);
+        }
+        if count > 0 {
+            parser.expect_token(b',')?;
+        }
+        self.streaming = Some(count + 1);
+        parser.parse().map(Some)
+    }
+}
+
+impl<S: JsonSource> Iterator for ArrayElements<S> {
+    type Item = Result<DataType, JsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reader.done {
+            return None;
+        }
+        let result = self.next_element().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.reader.done = true;
+        }
+        result
+    }
+}
+
+pub(crate) trait JsonDeserializable: Sized {
+    fn from_json(value: &DataType) -> Result<Self, JsonError>;
+}
+
+fn invalid_type<T>(expected: &'static str) -> Result<T, JsonError> {
+    Err(JsonError::InvalidType { path: String::new(), expected })
+}
+
+impl JsonDeserializable for String {
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        match value {
+            DataType::String(s) => Ok(s.clone()),
+            _ => invalid_type("a string")
+        }
+    }
+}
+
+impl JsonDeserializable for char {
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        let mut chars = match value {
+            DataType::String(s) => s.chars(),
+            _ => return invalid_type("a single character string")
+        };
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => invalid_type("a single character string")
+        }
+    }
+}
+
+impl JsonDeserializable for bool {
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        match value {
+            Boolean(b) => Ok(*b),
+            _ => invalid_type("a boolean")
+        }
+    }
+}
+
+impl JsonDeserializable for f64 {
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        match value {
+            Float(f) => Ok(*f),
+            Int(i) => Ok(*i as f64),
+            _ => invalid_type("a number")
+        }
+    }
+}
+
+impl JsonDeserializable for f32 {
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        f64::from_json(value).map(|f| f as f32)
+    }
+}
+
+/// The integer a `Float` stands for, which is how the parser keeps integers beyond the `i32`
+/// range. Only below 2^53 in magnitude: from there on neighbouring integers round to the same
+/// float, so the document may have held a different integer than the one the float stands for.
+fn exact_integer(f: f64) -> Option<i128> {
+    const EXACT_LIMIT: f64 = 9_007_199_254_740_992.0;
+    (f.fract() == 0.0 && f.abs() < EXACT_LIMIT).then_some(f as i128)
+}
+
+macro_rules! impl_json_deserializable_for_integers {
+    ($($t:ty),+) => {
+        $(
+            impl JsonDeserializable for $t {
+                fn from_json(value: &DataType) -> Result<Self, JsonError> {
+                    let out_of_range = || invalid_type(concat!("an integer in the range of ", stringify!($t)));
+                    match value {
+                        Int(i) => <$t>::try_from(*i).or_else(|_| out_of_range()),
+                        Float(f) => match exact_integer(*f) {
+                            Some(i) => <$t>::try_from(i).or_else(|_| out_of_range()),
+                            None if f.fract() == 0.0 => invalid_type("an integer below 2^53 in magnitude"),
+                            None => invalid_type("an integer"),
+                        },
+                        _ => invalid_type("an integer")
+                    }
+                }
+            }
+        )+
+    };
+}
+
+impl_json_deserializable_for_integers!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl<T> JsonDeserializable for Option<T>
+    where T: JsonDeserializable
+{
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        match value {
+            Null => Ok(None),
+            _ => T::from_json(value).map(Some)
+        }
+    }
+}
+
+impl<T> JsonDeserializable for Vec<T>
+    where T: JsonDeserializable
+{
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        let Array(array) = value else {
+            return invalid_type("an array");
+        };
+        array.iter()
+            .enumerate()
+            .map(|(i, e)| T::from_json(e).map_err(|err| err.at_index(i)))
+            .collect()
+    }
+}
+
+impl<T> JsonDeserializable for HashMap<String, T>
+    where T: JsonDeserializable
+{
+    fn from_json(value: &DataType) -> Result<Self, JsonError> {
+        let Object(map) = value else {
+            return invalid_type("an object");
+        };
+        map.iter()
+            .map(|(k, v)| T::from_json(v).map(|v| (k.clone(), v)).map_err(|err| err.at_field(k)))
+            .collect()
+    }
+}
+
+pub(crate) trait JsonSerializable {
+    fn serialize(&self, serializer: &mut Serializer);
+}
+
+impl JsonSerializable for String {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_string(&self[..])
+    }
+}
+
+impl JsonSerializable for f64
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_f64(*self)
+    }
+}
+
+impl JsonSerializable for i32
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_i32(*self)
+    }
+}
+
+impl<T> JsonSerializable for Vec<T>
+    where T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        let mut seq = serializer.serialize_seq();
+        for e in self {
+            seq.serialize_element(e);
+        }
+        seq.end()
+    }
+}
+
+impl<K, T> JsonSerializable for HashMap<K, T>
+    where K: Display, T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_map(self)
+    }
+}
+
+impl JsonSerializable for JsonMap {
+    fn serialize(&self, serializer: &mut Serializer) {
+        let mut seq = serializer.serialize_struct();
+        for e in self {
+            seq.serialize_field(e.0, e.1);
+        }
+        seq.end()
+    }
+}
+
+impl JsonSerializable for DataType {
+    fn serialize(&self, serializer: &mut Serializer) {
+        match self {
+            DataType::String(s) => serializer.serialize_string(s),
+            Float(f) => serializer.serialize_f64(*f),
+            Int(i) => serializer.serialize_i32(*i),
+            Array(array) => array.serialize(serializer),
+            Boolean(b) => serializer.serialize_bool(*b),
+            Object(map) => map.serialize(serializer),
+            Null => serializer.serialize_null()
+        }
+    }
+}
+
+impl<K, T> JsonSerializable for BTreeMap<K, T>
+    where K: Display, T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_map(self)
+    }
+}
+
+impl JsonSerializable for str {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_string(self)
+    }
+}
+
+impl JsonSerializable for char {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_string(self.encode_utf8(&mut [0; 4]))
+    }
+}
+
+impl JsonSerializable for bool {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_bool(*self)
+    }
+}
+
+impl JsonSerializable for f32 {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.serialize_f32(*self)
+    }
+}
+
+macro_rules! impl_json_serializable_for_integers {
+    ($method:ident, $target:ty, $($t:ty),+) => {
+        $(
+            impl JsonSerializable for $t {
+                fn serialize(&self, serializer: &mut Serializer) {
+                    serializer.$method(*self as $target)
+                }
+            }
+        )+
+    };
+}
+
+impl_json_serializable_for_integers!(serialize_i64, i64, i8, i16, i64, isize);
+impl_json_serializable_for_integers!(serialize_u64, u64, u8, u16, u32, u64, usize);
+impl_json_serializable_for_integers!(serialize_i128, i128, i128);
+impl_json_serializable_for_integers!(serialize_u128, u128, u128);
+
+impl<T> JsonSerializable for Option<T>
+    where T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        match self {
+            Some(value) => value.serialize(serializer),
+            None => serializer.serialize_null()
+        }
+    }
+}
+
+impl<T> JsonSerializable for &T
+    where T: JsonSerializable + ?Sized
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<T> JsonSerializable for [T]
+    where T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        let mut seq = serializer.serialize_seq();
+        for e in self {
+            seq.serialize_element(e);
+        }
+        seq.end()
+    }
+}
+
+impl<T, const N: usize> JsonSerializable for [T; N]
+    where T: JsonSerializable
+{
+    fn serialize(&self, serializer: &mut Serializer) {
+        self[..].serialize(serializer)
+    }
+}
+
+macro_rules! impl_json_serializable_for_tuples {
+    ($(($($name:ident: $index:tt),+)),+) => {
+        $(
+            impl<$($name),+> JsonSerializable for ($($name,)+)
+                where $($name: JsonSerializable),+
+            {
+                fn serialize(&self, serializer: &mut Serializer) {
+                    let mut seq = serializer.serialize_seq();
+                    $(seq.serialize_element(&self.$index);)+
+                    seq.end()
+                }
+            }
+        )+
+    };
+}
+
+// tuples are serialized as heterogeneous arrays
+impl_json_serializable_for_tuples!(
+    (A: 0),
+    (A: 0, B: 1),
+    (A: 0, B: 1, C: 2),
+    (A: 0, B: 1, C: 2, D: 3),
+    (A: 0, B: 1, C: 2, D: 3, E: 4),
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5),
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6),
+    (A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7)
+);
+
+struct JsonEntry<'a, T>
+    where T: JsonSerializable
+{
+    key: String,
+    value: &'a T
+}
+
+impl<'a, T> JsonEntry<'a, T>
+    where T: JsonSerializable
+{
+    fn new(key: String, value: &'a T) -> JsonEntry<'a, T>
+    {
+        JsonEntry {
+            key,
+            value
+        }
+    }
+}
+
+/// Serializes values into a single output shared by all nested struct and sequence serializers.
+#[derive(Default)]
+pub(crate) struct Serializer<'w> {
+    output: Output<'w>,
+    escape_non_ascii: bool,
+    non_finite_policy: NonFinitePolicy,
+    canonical: bool,
+}
+
+/// How `NaN` and infinities, which have no JSON representation, are serialized.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum NonFinitePolicy {
+    /// Emit `null`.
+    #[default]
+    Null,
+    /// Emit the strings `"NaN"`, `"Infinity"` and `"-Infinity"`.
+    String,
+}
+
+/// Where a `Serializer` puts the document: a `String` it returns, or a writer it streams into.
+enum Output<'w> {
+    String(String),
+    /// Collected in `buffer`, which is written out whenever it fills up. After a failed write the
+    /// rest is dropped, `finish` returns the error.
+    Writer { writer: &'w mut dyn Write, buffer: String, error: Option<io::Error> },
+}
+
+impl Default for Output<'_> {
+    fn default() -> Self {
+        Output::String(String::new())
+    }
+}
+
+impl Output<'_> {
+    const WRITE_BUFFER_SIZE: usize = 8 * 1024;
+
+    fn push(&mut self, c: char) {
+        match self {
+            Output::String(output) => output.push(c),
+            Output::Writer { .. } => self.push_str(c.encode_utf8(&mut [0; 4])),
+        }
+    }
+
+    fn push_str(&mut self, str: &str) {
+        match self {
+            Output::String(output) => output.push_str(str),
+            Output::Writer { buffer, .. } => {
+                buffer.push_str(str);
+                if buffer.len() >= Self::WRITE_BUFFER_SIZE {
+                    self.flush();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Output::Writer { writer, buffer, error } = self {
+            if error.is_none() {
+                *error = writer.write_all(buffer.as_bytes()).err();
+            }
+            buffer.clear();
+        }
+    }
+
+    /// Writes out what is left in the buffer, returning the first write error.
+    fn finish(mut self) -> io::Result<()> {
+        self.flush();
+        match self {
+            Output::Writer { error: Some(error), .. } => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FmtWrite for Output<'_> {
+    fn write_str(&mut self, str: &str) -> fmt::Result {
+        self.push_str(str);
+        Ok(())
+    }
+}
+
+impl<'w> Serializer<'w> {
+    pub fn new() -> Serializer<'static> {
+        Serializer::default()
+    }
+
+    /// Escapes every character above U+007F as `\uXXXX` (surrogate pairs beyond the BMP),
+    /// producing pure ASCII output.
+    pub fn escape_non_ascii(mut self, escape_non_ascii: bool) -> Self {
+        self.escape_non_ascii = escape_non_ascii;
+        self
+    }
+
+    pub fn non_finite_policy(mut self, policy: NonFinitePolicy) -> Self {
+        self.non_finite_policy = policy;
+        self
+    }
+
+    /// A serializer producing byte-stable output suitable for hashing and signing: object keys
+    /// are sorted at every level, no whitespace is emitted and only mandatory escapes are used.
+    pub fn canonical() -> Serializer<'static> {
+        Serializer {
+            canonical: true,
+            ..Serializer::default()
+        }
+    }
+
+    /// Serializes `value` and returns the resulting document.
+    pub fn serialize<T>(mut self, value: &T) -> String
+        where T: JsonSerializable + ?Sized
+    {
+        value.serialize(&mut self);
+        match self.output {
+            Output::String(output) => output,
+            Output::Writer { .. } => unreachable!("only `serialize_to_writer` writes to a writer"),
+        }
+    }
+
+    /// Serializes `value` straight into `writer`, e.g. a `TcpStream`, a few kilobytes at a time
+    /// rather than as a whole document. Canonical objects are still put together before they are
+    /// written, to sort their keys. `writer` is not flushed.
+    pub fn serialize_to_writer<T, W>(&self, value: &T, writer: &mut W) -> io::Result<()>
+        where T: JsonSerializable + ?Sized,
+              W: Write
+    {
+        let buffer = String::with_capacity(Output::WRITE_BUFFER_SIZE);
+        let mut serializer = self.with_output(Output::Writer { writer, buffer, error: None });
+        value.serialize(&mut serializer);
+        serializer.output.finish()
+    }
+
+    pub fn serialize_string(&mut self, str: &str) {
+        self.output.push('"');
+        self.write_escaped(str);
+        self.output.push('"');
+    }
+
+    pub fn serialize_bool(&mut self, b: bool) {
+        self.output.push_str(if b { "true" } else { "false" });
+    }
+
+    pub fn serialize_i32(&mut self, i: i32) {
+        self.write_display(i);
+    }
+
+    pub fn serialize_i64(&mut self, i: i64) {
+        self.write_display(i);
+    }
+
+    pub fn serialize_u64(&mut self, u: u64) {
+        self.write_display(u);
+    }
+
+    pub fn serialize_i128(&mut self, i: i128) {
+        self.write_display(i);
+    }
+
+    pub fn serialize_u128(&mut self, u: u128) {
+        self.write_display(u);
+    }
+
+    pub fn serialize_f32(&mut self, f: f32) {
+        self.write_float(f, f.is_finite(), f.abs() as f64);
+    }
+
+    pub fn serialize_f64(&mut self, f: f64) {
+        self.write_float(f, f.is_finite(), f.abs());
+    }
+
+    pub fn serialize_null(&mut self) {
+        self.output.push_str("null");
+    }
+
+    pub fn serialize_struct(&mut self) -> SerializerStruct<'_, 'w>
+    {
+        SerializerStruct::new(self)
+    }
+
+    pub fn serialize_seq(&mut self) -> SerializerSeq<'_, 'w>
+    {
+        SerializerSeq::new(self)
+    }
+
+    /// Writes the entries as an object, stringifying keys with `Display` so that maps keyed by
+    /// e.g. `i32` come out as `{"1": ...}`.
+    pub fn serialize_map<I, K, V>(&mut self, entries: I)
+        where I: IntoIterator<Item = (K, V)>, K: Display, V: JsonSerializable
+    {
+        let mut map = self.serialize_struct();
+        let mut key = String::new();
+        for (k, v) in entries {
+            key.clear();
+            let _ = write!(key, "{k}");
+            map.serialize_field(&key, &v);
+        }
+        map.end()
+    }
+
+    fn with_output<'o>(&self, output: Output<'o>) -> Serializer<'o> {
+        Serializer {
+            output,
+            escape_non_ascii: self.escape_non_ascii,
+            non_finite_policy: self.non_finite_policy,
+            canonical: self.canonical,
+        }
+    }
+
+    fn write_escaped(&mut self, str: &str) {
+        for c in str.chars() {
+            match c {
+                '"' => self.output.push_str("\\\""),
+                '\\' => self.output.push_str("\\\\"),
+                '\n' => self.output.push_str("\\n"),
+                '\r' => self.output.push_str("\\r"),
+                '\t' => self.output.push_str("\\t"),
+                '\u{8}' => self.output.push_str("\\b"),
+                '\u{c}' => self.output.push_str("\\f"),
+                c if (c as u32) < 0x20 || (self.escape_non_ascii && !c.is_ascii()) => {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        let _ = write!(self.output, "\\u{:04x}", unit);
+                    }
+                }
+                c => self.output.push(c)
+            }
+        }
+    }
+
+    /// Writes the shortest decimal that parses back to the same value, using an exponent
+    /// only for magnitudes where plain notation gets unreasonably long.
+    fn write_float<F: Display + LowerExp>(&mut self, f: F, finite: bool, abs: f64) {
+        if !finite {
+            match self.non_finite_policy {
+                NonFinitePolicy::Null => self.serialize_null(),
+                NonFinitePolicy::String => {
+                    let name = f.to_string().replace("inf", "Infinity");
+                    self.serialize_string(&name)
+                }
+            }
+        } else if abs != 0.0 && !(1e-7..1e21).contains(&abs) {
+            let _ = write!(self.output, "{f:e}");
+        } else {
+            self.write_display(f);
+        }
+    }
+
+    fn write_display<D: Display>(&mut self, value: D) {
+        // writing into a String never fails
+        let _ = write!(self.output, "{value}");
+    }
+}
+
+pub(crate) struct SerializerStruct<'a, 'w>
+{
+    serializer: &'a mut Serializer<'w>,
+    empty: bool,
+    /// In canonical mode fields are buffered as `(name, serialized value)` and sorted on `end`.
+    sorted_fields: Option<Vec<(String, String)>>,
+}
+
+impl<'a, 'w> SerializerStruct<'a, 'w>
+{
+    fn new(serializer: &'a mut Serializer<'w>) -> SerializerStruct<'a, 'w> {
+        serializer.output.push('{');
+        let sorted_fields = if serializer.canonical { Some(Vec::new()) } else { None };
+        SerializerStruct {
+            serializer,
+            empty: true,
+            sorted_fields,
+        }
+    }
+
+    pub fn serialize_field<T>(&mut self, name: &str, value: &T)
+        where T: JsonSerializable + ?Sized
+    {
+        if let Some(fields) = &mut self.sorted_fields {
+            let outer = std::mem::take(&mut self.serializer.output);
+            value.serialize(self.serializer);
+            let Output::String(value) = std::mem::replace(&mut self.serializer.output, outer) else {
+                unreachable!("fields are buffered into a string");
+            };
+            fields.push((name.to_string(), value));
+            return;
+        }
+        self.write_field(name, value);
+    }
+
+    fn write_field<T>(&mut self, name: &str, value: &T)
+        where T: JsonSerializable + ?Sized
+    {
+        if !self.empty {
+            self.serializer.output.push(',');
+        }
+        self.empty = false;
+        self.serializer.output.push('"');
+        self.serializer.write_escaped(name);
+        self.serializer.output.push_str(if self.serializer.canonical { "\":" } else { "\": " });
+        value.serialize(self.serializer);
+    }
+
+    /// Emits `"name": null` for `None`, or omits the field entirely when `skip_if_none` is set.
+    pub fn serialize_optional_field<T>(&mut self, name: &str, value: Option<&T>, skip_if_none: bool)
+        where T: JsonSerializable
+    {
+        match value {
+            Some(value) => self.serialize_field(name, value),
+            None if skip_if_none => {}
+            None => self.serialize_field(name, &None::<&T>)
+        }
+    }
+
+    pub fn end(mut self) {
+        if let Some(mut fields) = self.sorted_fields.take() {
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            for (name, value) in fields {
+                self.write_field(&name, &RawJson(&value));
+            }
+        }
+        self.serializer.output.push('}');
+    }
+}
+
+/// Already serialized JSON text, copied to the output verbatim.
+struct RawJson<'a>(&'a str);
+
+impl JsonSerializable for RawJson<'_> {
+    fn serialize(&self, serializer: &mut Serializer) {
+        serializer.output.push_str(self.0);
+    }
+}
+
+pub(crate) struct SerializerSeq<'a, 'w>
+{
+    serializer: &'a mut Serializer<'w>,
+    empty: bool,
+}
+
+impl<'a, 'w> SerializerSeq<'a, 'w>
+{
+    fn new(serializer: &'a mut Serializer<'w>) -> SerializerSeq<'a, 'w> {
+        serializer.output.push('[');
+        SerializerSeq {
+            serializer,
+            empty: true,
+        }
+    }
+
+    pub fn serialize_element<T>(&mut self, elem: &T)
+        where T: JsonSerializable + ?Sized
+    {
+        if !self.empty {
+            self.serializer.output.push(',');
+        }
+        self.empty = false;
+        elem.serialize(self.serializer);
+    }
+
+    pub fn end(self) {
+        self.serializer.output.push(']');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<DataType, JsonError> {
+        JsonParser::new(text).parse_to_value()
+    }
+
+    #[test]
+    fn numbers_follow_the_rfc_grammar() {
+        assert_eq!(parse("0").unwrap(), Int(0));
+        assert_eq!(parse("-12").unwrap(), Int(-12));
+        assert_eq!(parse("1.5e3").unwrap(), Float(1500.0));
+        assert_eq!(parse("-0.25E-2").unwrap(), Float(-0.0025));
+        assert_eq!(parse("1e+2").unwrap(), Float(100.0));
+        assert_eq!(parse("0.5").unwrap(), Float(0.5));
+        assert_eq!(parse("2147483648").unwrap(), Float(2147483648.0));
+    }
+
+    #[test]
+    fn invalid_numbers_are_rejected() {
+        for text in ["+1", "+0.5", "-", "--1", "-+1", ".5", "12.", "1e", "1e+", "01", "-01", "00", "1.2.3", "0x10", "1_000", "- 1"] {
+            assert!(parse(text).is_err(), "`{}` was accepted", text);
+        }
+        assert_eq!(parse("+1"), Err(JsonError::InvalidNumber { position: 0 }));
+        assert_eq!(parse("[1, +2]"), Err(JsonError::InvalidNumber { position: 4 }));
+        assert_eq!(parse("01"), Err(JsonError::InvalidNumber { position: 0 }));
+    }
+
+    #[test]
+    fn wide_integers_deserialize_from_floats() {
+        assert_eq!(u32::from_json(&parse("4294967295").unwrap()), Ok(u32::MAX));
+        assert_eq!(i64::from_json(&parse("-3000000000").unwrap()), Ok(-3_000_000_000));
+        assert_eq!(u64::from_json(&parse("9007199254740991").unwrap()), Ok((1 << 53) - 1));
+        assert_eq!(u64::from_json(&parse("1e3").unwrap()), Ok(1000));
+        assert_eq!(i32::from_json(&parse("-7").unwrap()), Ok(-7));
+        let invalid = |expected| JsonError::InvalidType { path: String::new(), expected };
+        assert_eq!(u32::from_json(&parse("4294967296").unwrap()), Err(invalid("an integer in the range of u32")));
+        assert_eq!(u8::from_json(&parse("-1").unwrap()), Err(invalid("an integer in the range of u8")));
+        // beyond 2^53 the parsed float may already be a neighbour of the written integer
+        assert_eq!(u64::from_json(&parse("9007199254740993").unwrap()), Err(invalid("an integer below 2^53 in magnitude")));
+        assert_eq!(i64::from_json(&parse("1.5").unwrap()), Err(invalid("an integer")));
+        assert_eq!(i64::from_json(&parse("\"1\"").unwrap()), Err(invalid("an integer")));
+    }
+
+    #[test]
+    fn pointer_mut_appends_only_for_a_final_dash() {
+        let mut doc = parse(r#"{"a": [1, [2]], "b": {"c": 3}}"#).unwrap();
+        let original = doc.clone();
+        assert!(doc.pointer_mut("/a/-/0").is_none());
+        assert!(doc.pointer_mut("/a/-/x").is_none());
+        assert!(doc.pointer_mut("/a/1/-/0").is_none());
+        assert!(doc.pointer_mut("/b/-").is_none());
+        assert!(doc.pointer_mut("/missing/-").is_none());
+        assert!(doc.pointer_mut("/a/7").is_none());
+        assert_eq!(doc, original);
+
+        *doc.pointer_mut("/a/1/-").unwrap() = Int(4);
+        *doc.pointer_mut("/b/c").unwrap() = Int(5);
+        assert_eq!(doc, parse(r#"{"a": [1, [2, 4]], "b": {"c": 5}}"#).unwrap());
+        assert_eq!(doc.pointer_mut("").map(|root| root.clone()), Some(doc.clone()));
+    }
+
+    /// Records the size of every write, failing once `limit` bytes were written.
+    struct ChunkWriter {
+        written: Vec<u8>,
+        writes: Vec<usize>,
+        limit: usize,
+    }
+
+    impl Write for ChunkWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+            }
+            self.written.extend_from_slice(buf);
+            self.writes.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serialize_to_writer_streams_in_chunks() {
+        let rows: Vec<BTreeMap<String, String>> = (0..2000)
+            .map(|i| BTreeMap::from([("name".to_string(), format!("row \"{}\"", i)), ("b".to_string(), "é".to_string())]))
+            .collect();
+        for serializer in [Serializer::new(), Serializer::canonical().escape_non_ascii(true)] {
+            let mut writer = ChunkWriter { written: vec![], writes: vec![], limit: usize::MAX };
+            serializer.serialize_to_writer(&rows, &mut writer).unwrap();
+            assert_eq!(String::from_utf8(writer.written).unwrap(), serializer.serialize(&rows));
+            assert!(writer.writes.len() > 1);
+            assert!(writer.writes.iter().all(|size| *size < 2 * Output::WRITE_BUFFER_SIZE));
+        }
+    }
+
+    #[test]
+    fn serialize_to_writer_reports_write_errors() {
+        let rows: Vec<String> = (0..5000).map(|i| i.to_string()).collect();
+        let mut writer = ChunkWriter { written: vec![], writes: vec![], limit: 10_000 };
+        let error = Serializer::new().serialize_to_writer(&rows, &mut writer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+        // nothing more was attempted after the failure
+        assert_eq!(writer.writes.len(), 1);
+    }
+
+    #[test]
+    fn stream_a_large_array() {
+        let items: Vec<String> = (0..10_000).map(|i| format!(r#"{{"id": {i}}}"#)).collect();
+        let text = format!(r#"{{"meta": [1, 2], "data": {{"items": [{}]}}, "after": true}}"#, items.join(","));
+        let elements = JsonReader::new(JsonParser::new(&text)).stream_array_elements("data.items");
+        let mut count = 0;
+        for element in elements {
+            assert_eq!(element.unwrap()["id"], Int(count));
+            count += 1;
+        }
+        assert_eq!(count, 10_000);
+
+        for path in ["data.missing", "data", "data..items"] {
+            let mut elements = JsonReader::new(JsonParser::new(&text)).stream_array_elements(path);
+            assert!(matches!(elements.next(), Some(Err(JsonError::PathNotFound { .. }))), "{path}");
+            assert!(elements.next().is_none());
+        }
+    }
+
+    struct Primitives<'a> {
+        flag: bool,
+        small: i8,
+        unsigned: u32,
+        size: usize,
+        wide: i128,
+        single: f32,
+        letter: char,
+        name: &'a str,
+        present: Option<u16>,
+        absent: Option<i64>,
+        slice: &'a [u8],
+        array: [i16; 3],
+        pair: (String, bool),
+        triple: (i32, char, Option<&'a str>),
+        sorted: BTreeMap<String, u64>,
+    }
+
+    impl JsonSerializable for Primitives<'_> {
+        fn serialize(&self, serializer: &mut Serializer) {
+            let mut fields = serializer.serialize_struct();
+            fields.serialize_field("flag", &self.flag);
+            fields.serialize_field("small", &self.small);
+            fields.serialize_field("unsigned", &self.unsigned);
+            fields.serialize_field("size", &self.size);
+            fields.serialize_field("wide", &self.wide);
+            fields.serialize_field("single", &self.single);
+            fields.serialize_field("letter", &self.letter);
+            fields.serialize_field("name", &self.name);
+            fields.serialize_field("present", &self.present);
+            fields.serialize_field("absent", &self.absent);
+            fields.serialize_field("slice", &self.slice);
+            fields.serialize_field("array", &self.array);
+            fields.serialize_field("pair", &self.pair);
+            fields.serialize_field("triple", &self.triple);
+            fields.serialize_field("sorted", &self.sorted);
+            fields.end()
+        }
+    }
+
+    #[test]
+    fn primitive_and_std_impls() {
+        let value = Primitives {
+            flag: true,
+            small: -8,
+            unsigned: u32::MAX,
+            size: 7,
+            wide: -(1 << 100),
+            single: 1.5,
+            letter: '"',
+            name: "ann",
+            present: Some(3),
+            absent: None,
+            slice: &[1, 2],
+            array: [-1, 0, 1],
+            pair: ("a".to_string(), false),
+            triple: (1, 'x', None),
+            sorted: BTreeMap::from([("b".to_string(), 2), ("a".to_string(), 1)]),
+        };
+        let expected = concat!(
+            r#"{"flag": true,"small": -8,"unsigned": 4294967295,"size": 7,"wide": -1267650600228229401496703205376,"#,
+            r#""single": 1.5,"letter": "\"","name": "ann","present": 3,"absent": null,"slice": [1,2],"array": [-1,0,1],"#,
+            r#""pair": ["a",false],"triple": [1,"x",null],"sorted": {"a": 1,"b": 2}}"#,
+        );
+        assert_eq!(Serializer::new().serialize(&value), expected);
+    }
+
+    #[test]
+    fn get_path_finds_nested_values() {
+        let doc = parse(r#"{"user": {"name": "ann", "age": 30, "admin": false, "score": 1.5,
+            "addresses": [{"zip": "1000"}, {"zip": "2000"}], "a.b": {"c": 1}}}"#).unwrap();
+        assert_eq!(doc.get_str("user.addresses[1].zip"), Some("2000"));
+        assert_eq!(doc.get_i64("user.age"), Some(30));
+        assert_eq!(doc.get_f64("user.score"), Some(1.5));
+        assert_eq!(doc.get_f64("user.age"), Some(30.0));
+        assert_eq!(doc.get_bool("user.admin"), Some(false));
+        assert_eq!(doc.get_i64(r#"user."a.b".c"#), Some(1));
+        assert_eq!(doc.get_i64(r#"user["a.b"].c"#), Some(1));
+        assert_eq!(doc.get_path(""), Some(&doc));
+    }
+
+    #[test]
+    fn get_path_returns_none_when_nothing_matches() {
+        let doc = parse(r#"{"user": {"name": "ann", "addresses": [{"zip": "1000"}]}}"#).unwrap();
+        // missing intermediate key
+        assert_eq!(doc.get_path("user.profile.zip"), None);
+        assert_eq!(doc.get_path("account.name"), None);
+        // out-of-bounds index
+        assert_eq!(doc.get_path("user.addresses[1].zip"), None);
+        assert_eq!(doc.get_path("user.addresses[99]"), None);
+        // type mismatches
+        assert_eq!(doc.get_path("user[0]"), None);
+        assert_eq!(doc.get_path("user.addresses.zip"), None);
+        assert_eq!(doc.get_path("user.name.first"), None);
+        assert_eq!(doc.get_i64("user.name"), None);
+        assert_eq!(doc.get_str("user.addresses"), None);
+        assert_eq!(doc.get_bool("user.addresses[0].zip"), None);
+        // malformed paths
+        assert_eq!(doc.get_path("user..name"), None);
+        assert_eq!(doc.get_path("user.addresses[x]"), None);
+    }
+
+    #[test]
+    fn pointer_resolves_the_rfc_6901_examples() {
+        let doc = parse(r#"{"foo": ["bar", "baz"], "": 0, "a/b": 1, "c%d": 2, "e^f": 3, "g|h": 4,
+            "i\\j": 5, "k\"l": 6, " ": 7, "m~n": 8}"#).unwrap();
+        assert_eq!(doc.pointer(""), Some(&doc));
+        assert_eq!(doc.pointer("/foo"), Some(&parse(r#"["bar", "baz"]"#).unwrap()));
+        assert_eq!(doc.pointer("/foo/0"), Some(&DataType::String("bar".to_string())));
+        let expected = [("/", 0), ("/a~1b", 1), ("/c%d", 2), ("/e^f", 3), ("/g|h", 4),
+            (r"/i\j", 5), ("/k\"l", 6), ("/ ", 7), ("/m~0n", 8)];
+        for (ptr, value) in expected {
+            assert_eq!(doc.pointer(ptr), Some(&Int(value)), "{ptr}");
+        }
+
+        assert_eq!(doc.pointer("/foo/2"), None);
+        assert_eq!(doc.pointer("/foo/01"), None);
+        assert_eq!(doc.pointer("/foo/-"), None);
+        // a `~` must start one of the two escapes
+        assert_eq!(doc.pointer("/m~n"), None);
+        assert_eq!(doc.pointer("/m~2n"), None);
+        assert_eq!(doc.pointer("/m~"), None);
+        assert_eq!(doc.pointer("/a/b"), None);
+        assert_eq!(doc.pointer("foo"), None);
+        // `~01` unescapes to `~1`, not to `/`
+        let tilde = parse(r#"{"~1": 1, "/": 2}"#).unwrap();
+        assert_eq!(tilde.pointer("/~01"), Some(&Int(1)));
+    }
+
+    #[test]
+    fn merge_follows_rfc_7386() {
+        let merged = |target: &str, patch: &str| parse(target).unwrap().merged(&parse(patch).unwrap());
+        // nested deletion keeps the siblings of the removed key
+        assert_eq!(merged(r#"{"a": {"b": 1, "c": {"d": 2, "e": 3}}, "f": 4}"#, r#"{"a": {"c": {"d": null}}}"#),
+                   parse(r#"{"a": {"b": 1, "c": {"e": 3}}, "f": 4}"#).unwrap());
+        // deleting a key that is not there changes nothing
+        assert_eq!(merged(r#"{"a": 1}"#, r#"{"b": null}"#), parse(r#"{"a": 1}"#).unwrap());
+        // arrays are replaced wholesale, never merged element by element
+        assert_eq!(merged(r#"{"a": [1, 2, 3]}"#, r#"{"a": [4]}"#), parse(r#"{"a": [4]}"#).unwrap());
+        assert_eq!(merged(r#"{"a": [{"b": 1}]}"#, r#"{"a": [{"c": 2}]}"#), parse(r#"{"a": [{"c": 2}]}"#).unwrap());
+        // a non-object target is replaced by an object built from the patch, without its nulls
+        assert_eq!(merged(r#"["a", "b"]"#, r#"{"a": "c", "b": null}"#), parse(r#"{"a": "c"}"#).unwrap());
+        assert_eq!(merged(r#"{"a": "foo"}"#, r#"{"a": {"bb": {"ccc": null}}}"#), parse(r#"{"a": {"bb": {}}}"#).unwrap());
+        assert_eq!(merged("12", r#"{"a": 1}"#), parse(r#"{"a": 1}"#).unwrap());
+        // a non-object patch replaces the whole target
+        assert_eq!(merged(r#"{"a": "b"}"#, r#"["c"]"#), parse(r#"["c"]"#).unwrap());
+        assert_eq!(merged(r#"{"a": "b"}"#, "null"), Null);
+
+        let target = parse(r#"{"a": 1}"#).unwrap();
+        let _ = target.merged(&parse(r#"{"a": null}"#).unwrap());
+        assert_eq!(target, parse(r#"{"a": 1}"#).unwrap());
+    }
+
+    #[test]
+    fn multi_byte_utf8_keys_and_values() {
+        let text = r#"{"名前": "ünïcødé 日本語", "emoji 🎉": ["€", "𝄞"], "ß": {"ключ": "значение"}}"#;
+        let doc = parse(text).unwrap();
+        assert_eq!(doc["名前"], DataType::String("ünïcødé 日本語".to_string()));
+        assert_eq!(doc["emoji 🎉"][1], DataType::String("𝄞".to_string()));
+        assert_eq!(doc["ß"]["ключ"], DataType::String("значение".to_string()));
+        assert_eq!(JsonParser::from_reader(text.as_bytes()).parse_to_value().unwrap(), doc);
+        assert_eq!(parse(&doc.to_json_string()).unwrap(), doc);
+        // positions are byte offsets, not character counts
+        assert_eq!(parse(r#"["é", +1]"#), Err(JsonError::InvalidNumber { position: 7 }));
+        // invalid sequences inside strings are reported at the opening quote of the literal
+        assert_eq!(JsonParser::from_slice(b"[\"a\xff\"]").parse_to_value(), Err(JsonError::InvalidUtf8 { position: 1 }));
+        assert!(JsonParser::from_slice(b"[\"\xe2\x82\"]").parse_to_value().is_err());
+    }
+
+    #[test]
+    fn a_leading_bom_is_skipped() {
+        let expected = parse(r#"{"a": "é"}"#).unwrap();
+        let with_bom = "\u{feff}{\"a\": \"é\"}";
+        assert_eq!(parse(with_bom).unwrap(), expected);
+        assert_eq!(JsonParser::from_reader(with_bom.as_bytes()).parse_to_value().unwrap(), expected);
+        assert_eq!(JsonParser::from_slice(b"\xef\xbb\xbf[]").parse_to_value().unwrap(), Array(vec![]));
+        // only one BOM, and only at the very start
+        assert!(parse("\u{feff}\u{feff}{}").is_err());
+        assert!(parse(" \u{feff}{}").is_err());
+        assert!(parse("[\u{feff}]").is_err());
+        assert_eq!(JsonParser::from_slice(b"\xef\xbb{}").parse_to_value(), Err(JsonError::InvalidUtf8 { position: 2 }));
+    }
+
+    #[test]
+    fn literals_must_match_exactly() {
+        assert_eq!(parse(r#"{"a": null, "b": [true, false]}"#).unwrap(), parse(r#"{"b": [true,false],"a":null}"#).unwrap());
+        assert_eq!(parse("true\n").unwrap(), Boolean(true));
+        for (text, position) in [("nul", 0), ("truee", 0), ("falsey", 0), ("nope", 0), ("fals", 0), ("nulll", 0),
+                                 (r#"{"a": nul}"#, 6), (r#"{"a": truee}"#, 6), (r#"{"a": falsey}"#, 6), (r#"[null, nul]"#, 7),
+                                 (r#"[true1]"#, 1), (r#"[false"x"]"#, 1), (r#"{"a": nullnull}"#, 6)] {
+            assert_eq!(parse(text), Err(JsonError::InvalidLiteral { position }), "{text}");
+        }
+        assert!(matches!(parse("True"), Err(JsonError::UnexpectedToken { found: 'T', .. })));
+    }
+
+    #[test]
+    fn duplicate_keys_follow_the_policy() {
+        let text = r#"{"a": 1, "b": {"c": [{"d": {"e": "first", "f": 0, "e": "second"}}]}, "a": 2}"#;
+        let with = |policy| JsonParser::new(text).duplicate_key_policy(policy).parse_to_value();
+
+        let last = with(DuplicateKeyPolicy::LastWins).unwrap();
+        assert_eq!(last, parse(text).unwrap());
+        assert_eq!(last.get_i64("a"), Some(2));
+        assert_eq!(last.get_str("b.c[0].d.e"), Some("second"));
+
+        let first = with(DuplicateKeyPolicy::FirstWins).unwrap();
+        assert_eq!(first.get_i64("a"), Some(1));
+        assert_eq!(first.get_str("b.c[0].d.e"), Some("first"));
+        assert_eq!(first.get_path("b.c[0].d").unwrap().as_object().unwrap().len(), 2);
+
+        // the deepest duplicate is found first, reported with the position of its repeated key
+        let position = text.rfind(r#""e""#).unwrap();
+        assert_eq!(with(DuplicateKeyPolicy::Error), Err(JsonError::DuplicateKey { key: "e".to_string(), position }));
+        let top_level = r#"{"a": 1, "b": {"a": 3}, "a": 2}"#;
+        assert_eq!(JsonParser::new(top_level).duplicate_key_policy(DuplicateKeyPolicy::Error).parse_to_value(),
+                   Err(JsonError::DuplicateKey { key: "a".to_string(), position: 24 }));
+        // equal keys in different objects are not duplicates
+        assert!(JsonParser::new(r#"{"a": {"a": {"a": 1}}, "b": [{"a": 1}, {"a": 2}]}"#)
+            .duplicate_key_policy(DuplicateKeyPolicy::Error).parse_to_value().is_ok());
+    }
+
+    struct Profile {
+        name: String,
+        nickname: Option<String>,
+        age: Option<u32>,
+        skip_if_none: bool,
+    }
+
+    impl JsonSerializable for Profile {
+        fn serialize(&self, serializer: &mut Serializer) {
+            let mut fields = serializer.serialize_struct();
+            fields.serialize_optional_field("nickname", self.nickname.as_ref(), self.skip_if_none);
+            fields.serialize_field("name", &self.name);
+            fields.serialize_optional_field("age", self.age.as_ref(), self.skip_if_none);
+            fields.end()
+        }
+    }
+
+    #[test]
+    fn options_serialize_as_null_or_are_skipped() {
+        let profile = |nickname: Option<&str>, age, skip_if_none| Profile {
+            name: "ann".to_string(),
+            nickname: nickname.map(str::to_string),
+            age,
+            skip_if_none,
+        };
+        let serialize = |value: &Profile| Serializer::new().serialize(value);
+
+        assert_eq!(serialize(&profile(None, None, false)), r#"{"nickname": null,"name": "ann","age": null}"#);
+        assert_eq!(serialize(&profile(None, None, true)), r#"{"name": "ann"}"#);
+        assert_eq!(serialize(&profile(None, Some(30), true)), r#"{"name": "ann","age": 30}"#);
+        // present values are written the same way in both modes
+        for skip_if_none in [false, true] {
+            assert_eq!(serialize(&profile(Some("an"), Some(30), skip_if_none)), r#"{"nickname": "an","name": "ann","age": 30}"#);
+        }
+        assert_eq!(Serializer::canonical().serialize(&profile(None, Some(30), false)), r#"{"age":30,"name":"ann","nickname":null}"#);
+        assert_eq!(Serializer::canonical().serialize(&profile(None, Some(30), true)), r#"{"age":30,"name":"ann"}"#);
+
+        assert_eq!(Serializer::new().serialize(&vec![Some(1), None, Some(3)]), "[1,null,3]");
+        assert_eq!(Serializer::new().serialize(&None::<String>), "null");
+        assert_eq!(Serializer::new().serialize(&Some(Some(true))), "true");
+    }
+
+    #[test]
+    fn emoji_round_trip_through_escapes() {
+        let text = "smile 😀, clef 𝄞, é";
+        let value = DataType::String(text.to_string());
+        let escaped = Serializer::new().escape_non_ascii(true).serialize(&value);
+        assert_eq!(escaped, r#""smile \ud83d\ude00, clef \ud834\udd1e, \u00e9""#);
+        assert!(escaped.is_ascii());
+        assert_eq!(parse(&escaped).unwrap(), value);
+        let raw = Serializer::new().serialize(&value);
+        assert_eq!(raw, format!("\"{text}\""));
+        assert_eq!(parse(&raw).unwrap(), value);
+        assert_eq!(parse(r#""😀""#).unwrap(), DataType::String("😀".to_string()));
+    }
+
+    #[test]
+    fn lone_surrogates_follow_the_policy() {
+        let with = |text: &str, policy| JsonParser::new(text).lone_surrogate_policy(policy).parse_to_value();
+        let replaced = |text: &str| with(text, LoneSurrogatePolicy::Replace).unwrap();
+        let string = |s: &str| DataType::String(s.to_string());
+        // a high surrogate at the end, before a simple escape or before a non-surrogate,
+        // a low surrogate on its own and two high surrogates in a row
+        let cases = [
+            (r#"["\ud83d"]"#, 2, "\u{fffd}"),
+            (r#"["a\ud83d\n"]"#, 3, "a\u{fffd}\n"),
+            (r#"["\ud83dA"]"#, 2, "\u{fffd}A"),
+            (r#"["\ude00x"]"#, 2, "\u{fffd}x"),
+            (r#"["\ud83d😀"]"#, 2, "\u{fffd}😀"),
+        ];
+        for (text, position, expected) in cases {
+            assert_eq!(parse(text), Err(JsonError::LoneSurrogate { position }), "{text}");
+            assert_eq!(with(text, LoneSurrogatePolicy::Reject), Err(JsonError::LoneSurrogate { position }), "{text}");
+            assert_eq!(replaced(text), Array(vec![string(expected)]), "{text}");
+        }
+    }
+
+    #[test]
+    fn floats_round_trip_exactly() {
+        let cases = [
+            (0.1, "0.1"),
+            (1e-7, "0.0000001"),
+            (1e20, "100000000000000000000"),
+            (-0.0, "-0"),
+            (2.5, "2.5"),
+            (1e21, "1e21"),
+            (1.5e-8, "1.5e-8"),
+            (f64::MAX, "1.7976931348623157e308"),
+            (f64::MIN_POSITIVE, "2.2250738585072014e-308"),
+        ];
+        for (value, text) in cases {
+            let serialized = Serializer::new().serialize(&value);
+            assert_eq!(serialized, text);
+            let parsed = f64::from_json(&parse(&serialized).unwrap()).unwrap();
+            assert_eq!(parsed.to_bits(), value.to_bits(), "{text}");
+        }
+        assert_eq!(Serializer::new().serialize(&0.1f32), "0.1");
+    }
+
+    #[test]
+    fn non_finite_floats_follow_the_policy() {
+        let values = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+        let with = |policy| Serializer::new().non_finite_policy(policy).serialize(&values);
+        assert_eq!(Serializer::new().serialize(&values), "[null,null,null]");
+        assert_eq!(with(NonFinitePolicy::Null), "[null,null,null]");
+        assert_eq!(with(NonFinitePolicy::String), r#"["NaN","Infinity","-Infinity"]"#);
+        assert_eq!(Serializer::new().non_finite_policy(NonFinitePolicy::String).serialize(&f32::NEG_INFINITY), r#""-Infinity""#);
+        // either way the output is valid JSON
+        assert!(parse(&with(NonFinitePolicy::Null)).is_ok());
+        assert!(parse(&with(NonFinitePolicy::String)).is_ok());
+    }
+
+    #[test]
+    fn empty_input_is_an_error() {
+        for text in ["", "   \n\t", "\u{feff}", "\u{feff} \r\n"] {
+            assert_eq!(parse(text), Err(JsonError::UnexpectedEof), "{text:?}");
+            assert_eq!(JsonParser::new(text).parse_to_map(), Err(JsonError::UnexpectedEof), "{text:?}");
+            assert_eq!(JsonParser::from_reader(text.as_bytes()).parse_to_value(), Err(JsonError::UnexpectedEof), "{text:?}");
+        }
+    }
+
+    /// A row type building its own sequence through the public `SerializerSeq` methods.
+    struct Row(Vec<i32>);
+
+    impl JsonSerializable for Row {
+        fn serialize(&self, serializer: &mut Serializer) {
+            let mut seq = serializer.serialize_seq();
+            for value in &self.0 {
+                seq.serialize_element(&(value * 2));
+            }
+            seq.end()
+        }
+    }
+
+    #[test]
+    fn nested_sequences_and_tuples() {
+        let rows: Vec<(String, Vec<i32>)> = vec![
+            ("a".to_string(), vec![1, 2, 3]),
+            ("b".to_string(), vec![]),
+            ("c\"".to_string(), vec![-4]),
+        ];
+        let expected = r#"[["a",[1,2,3]],["b",[]],["c\"",[-4]]]"#;
+        assert_eq!(Serializer::new().serialize(&rows), expected);
+        assert_eq!(Serializer::canonical().serialize(&rows), expected);
+        assert_eq!(parse(expected).unwrap()[2][1][0], Int(-4));
+
+        assert_eq!(Serializer::new().serialize(&vec![vec![vec![1], vec![]], vec![]]), "[[[1],[]],[]]");
+        assert_eq!(Serializer::new().serialize(&(1, ("x", [true]), None::<i32>)), r#"[1,["x",[true]],null]"#);
+        assert_eq!(Serializer::new().serialize(&vec![Row(vec![1, 2]), Row(vec![])]), "[[2,4],[]]");
+        let by_id = HashMap::from([(7, vec![(1, 'x')])]);
+        assert_eq!(Serializer::new().serialize(&by_id), r#"{"7": [[1,"x"]]}"#);
+    }
+
+    #[test]
+    fn canonical_output_ignores_insertion_order() {
+        let entries = [("zeta", 1), ("alpha", 2), ("Beta", 3), ("é", 4), ("a", 5), ("b", 6), ("c", 7), ("d", 8)];
+        let nested = |entries: &[(&str, i32)]| -> HashMap<String, HashMap<String, i32>> {
+            let inner: HashMap<String, i32> = entries.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+            entries.iter().map(|(k, _)| (k.to_string(), inner.clone())).collect()
+        };
+        let forward = nested(&entries);
+        let mut reversed_entries = entries;
+        reversed_entries.reverse();
+        let reversed = nested(&reversed_entries);
+        let canonical = Serializer::canonical().serialize(&forward);
+        assert_eq!(canonical, Serializer::canonical().serialize(&reversed));
+        assert!(canonical.starts_with(r#"{"Beta":{"Beta":3,"a":5,"alpha":2,"b":6,"#), "{canonical}");
+        assert!(canonical.ends_with(r#""zeta":1,"é":4}}"#), "{canonical}");
+
+        let mut changed = reversed.clone();
+        changed.get_mut("alpha").unwrap().insert("new".to_string(), 0);
+        assert_ne!(Serializer::canonical().serialize(&changed), canonical);
+
+        // parsed documents come out the same way
+        let left = parse(r#"{"b": {"y": [1, {"q": 1, "p": 2}], "x": 0.5}, "a": "é"}"#).unwrap();
+        let right = parse(r#"{"a":"é","b":{"x":0.5,"y":[1,{"p":2,"q":1}]}}"#).unwrap();
+        assert_eq!(left.to_canonical_json_string(), r#"{"a":"é","b":{"x":0.5,"y":[1,{"p":2,"q":1}]}}"#);
+        assert_eq!(left.to_canonical_json_string(), right.to_canonical_json_string());
+        let mut deeper = left.clone();
+        *deeper.pointer_mut("/b/y/1").unwrap() = parse(r#"{"q": 1, "p": 2, "o": null}"#).unwrap();
+        assert_ne!(deeper.to_canonical_json_string(), right.to_canonical_json_string());
+    }
+
+    #[test]
+    fn trailing_characters_are_rejected() {
+        let cases = [(r#"{"a":1}}"#, 7), (r#"{"a":1},"#, 7), (r#"{"a":1} {"b":2}"#, 8), ("{}junk", 2), ("[1,2]]", 5), ("1 2", 2), ("null\n,", 5)];
+        for (text, position) in cases {
+            let expected = Err(JsonError::TrailingCharacters { position });
+            assert_eq!(parse(text), expected, "{text}");
+            assert_eq!(JsonParser::from_reader(text.as_bytes()).parse_to_value(), expected, "{text}");
+            let events: Vec<_> = JsonReader::new(JsonParser::new(text)).collect();
+            assert_eq!(events.last(), Some(&Err(JsonError::TrailingCharacters { position })), "{text}");
+        }
+        for text in [r#"{"a":1}}"#, r#"{"a":1} {"b":2}"#, "{}junk"] {
+            assert!(matches!(JsonParser::new(text).parse_to_map(), Err(JsonError::TrailingCharacters { .. })), "{text}");
+        }
+        // trailing whitespace is fine
+        assert_eq!(JsonParser::new("{\"a\": 1} \r\n\t").parse_to_map().unwrap()["a"], Int(1));
+        assert!(JsonReader::new(JsonParser::new("[1]\n")).all(|event| event.is_ok()));
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use crate::utils::json::DataType;
+use crate::utils::json::DataType::{Array, Object};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchError {
+    /// The patch document itself is malformed, e.g. an unknown `op` or a missing `value`.
+    InvalidOperation { index: usize, reason: String },
+    PathNotFound { index: usize, path: String },
+    TestFailed { index: usize, path: String },
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::InvalidOperation { index, reason } => write!(f, "invalid patch operation {index}: {reason}"),
+            PatchError::PathNotFound { index, path } => write!(f, "patch operation {index}: path `{path}` not found"),
+            PatchError::TestFailed { index, path } => write!(f, "patch operation {index}: test failed at `{path}`"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+enum Operation<'a> {
+    Add { path: &'a str, value: &'a DataType },
+    Remove { path: &'a str },
+    Replace { path: &'a str, value: &'a DataType },
+    Move { from: &'a str, path: &'a str },
+    Copy { from: &'a str, path: &'a str },
+    Test { path: &'a str, value: &'a DataType },
+}
+
+impl<'a> Operation<'a> {
+    fn parse(index: usize, op: &'a DataType) -> Result<Operation<'a>, PatchError> {
+        let invalid = |reason: &str| PatchError::InvalidOperation { index, reason: reason.to_string() };
+        let Object(op) = op else {
+            return Err(invalid("an operation must be an object"));
+        };
+        let member = |name: &str| op.get(name).ok_or_else(|| invalid(&format!("missing `{name}`")));
+        let string_member = |name: &str| member(name)?.as_str().ok_or_else(|| invalid(&format!("`{name}` must be a string")));
+
+        let path = string_member("path")?;
+        let operation = match string_member("op")? {
+            "add" => Operation::Add { path, value: member("value")? },
+            "remove" => Operation::Remove { path },
+            "replace" => Operation::Replace { path, value: member("value")? },
+            "move" => Operation::Move { from: string_member("from")?, path },
+            "copy" => Operation::Copy { from: string_member("from")?, path },
+            "test" => Operation::Test { path, value: member("value")? },
+            other => return Err(invalid(&format!("unknown op `{other}`"))),
+        };
+        Ok(operation)
+    }
+
+    fn apply(&self, index: usize, doc: &mut DataType) -> Result<(), PatchError> {
+        let not_found = |path: &str| PatchError::PathNotFound { index, path: path.to_string() };
+        match *self {
+            Operation::Add { path, value } => add(doc, path, value.clone()).ok_or_else(|| not_found(path)),
+            Operation::Remove { path } => remove(doc, path).map(|_| ()).ok_or_else(|| not_found(path)),
+            Operation::Replace { path, value } => {
+                // `pointer_mut` would append for `-`, which must not count as an existing location
+                if doc.pointer(path).is_none() {
+                    return Err(not_found(path));
+                }
+                *doc.pointer_mut(path).ok_or_else(|| not_found(path))? = value.clone();
+                Ok(())
+            }
+            Operation::Move { from, path } => {
+                if path.starts_with(from) && path[from.len()..].starts_with('/') {
+                    return Err(PatchError::InvalidOperation { index, reason: format!("cannot move `{from}` into its own child") });
+                }
+                let value = remove(doc, from).ok_or_else(|| not_found(from))?;
+                add(doc, path, value).ok_or_else(|| not_found(path))
+            }
+            Operation::Copy { from, path } => {
+                let value = doc.pointer(from).ok_or_else(|| not_found(from))?.clone();
+                add(doc, path, value).ok_or_else(|| not_found(path))
+            }
+            Operation::Test { path, value } => match doc.pointer(path) {
+                Some(actual) if actual == value => Ok(()),
+                _ => Err(PatchError::TestFailed { index, path: path.to_string() }),
+            }
+        }
+    }
+}
+
+impl DataType {
+    /// Applies a JSON Patch (RFC 6902). Operations run against a copy of the document, which only
+    /// replaces `self` once every operation succeeded, so a failing patch leaves `self` untouched.
+    pub(crate) fn apply_patch(&mut self, patch: &DataType) -> Result<(), PatchError> {
+        let Array(ops) = patch else {
+            return Err(PatchError::InvalidOperation { index: 0, reason: "a patch must be an array".to_string() });
+        };
+        let ops = ops.iter()
+            .enumerate()
+            .map(|(index, op)| Operation::parse(index, op))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut doc = self.clone();
+        for (index, op) in ops.iter().enumerate() {
+            op.apply(index, &mut doc)?;
+        }
+        *self = doc;
+        Ok(())
+    }
+}
+
+/// Splits a pointer into its parent pointer and its unescaped last token, `None` for the root
+/// or an invalid escape.
+fn split_last(path: &str) -> Option<(&str, String)> {
+    let slash = path.rfind('/')?;
+    let last = DataType::unescape_pointer_token(&path[slash + 1..])?;
+    Some((&path[..slash], last))
+}
+
+fn add(doc: &mut DataType, path: &str, value: DataType) -> Option<()> {
+    let Some((parent, last)) = split_last(path) else {
+        if !path.is_empty() {
+            return None;
+        }
+        *doc = value;
+        return Some(());
+    };
+    match doc.pointer_mut(parent)? {
+        Object(map) => {
+            map.insert(last, value);
+        }
+        Array(array) if last == "-" => array.push(value),
+        Array(array) => {
+            let i = DataType::pointer_index(&last).filter(|i| *i <= array.len())?;
+            array.insert(i, value);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn remove(doc: &mut DataType, path: &str) -> Option<DataType> {
+    let (parent, last) = split_last(path)?;
+    match doc.pointer_mut(parent)? {
+        Object(map) => map.remove(&last),
+        Array(array) => {
+            let i = DataType::pointer_index(&last).filter(|i| *i < array.len())?;
+            Some(array.remove(i))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PatchError;
+    use crate::utils::json::{DataType, DuplicateKeyPolicy, JsonParser};
+
+    fn parse(text: &str) -> DataType {
+        JsonParser::new(text).parse_to_value().unwrap()
+    }
+
+    fn patched(doc: &str, patch: &str) -> Result<DataType, PatchError> {
+        let mut doc = parse(doc);
+        doc.apply_patch(&parse(patch)).map(|_| doc)
+    }
+
+    // the examples of RFC 6902, appendix A
+
+    #[test]
+    fn adding_and_removing() {
+        let cases = [
+            (r#"{"foo": "bar"}"#, r#"[{"op": "add", "path": "/baz", "value": "qux"}]"#, r#"{"baz": "qux", "foo": "bar"}"#),
+            (r#"{"foo": ["bar", "baz"]}"#, r#"[{"op": "add", "path": "/foo/1", "value": "qux"}]"#, r#"{"foo": ["bar", "qux", "baz"]}"#),
+            (r#"{"baz": "qux", "foo": "bar"}"#, r#"[{"op": "remove", "path": "/baz"}]"#, r#"{"foo": "bar"}"#),
+            (r#"{"foo": ["bar", "qux", "baz"]}"#, r#"[{"op": "remove", "path": "/foo/1"}]"#, r#"{"foo": ["bar", "baz"]}"#),
+            (r#"{"foo": "bar"}"#, r#"[{"op": "add", "path": "/child", "value": {"grandchild": {}}}]"#, r#"{"foo": "bar", "child": {"grandchild": {}}}"#),
+            (r#"{"foo": "bar"}"#, r#"[{"op": "add", "path": "/baz", "value": "qux", "xyz": 123}]"#, r#"{"foo": "bar", "baz": "qux"}"#),
+            (r#"{"foo": ["bar"]}"#, r#"[{"op": "add", "path": "/foo/-", "value": ["abc", "def"]}]"#, r#"{"foo": ["bar", ["abc", "def"]]}"#),
+        ];
+        for (doc, patch, expected) in cases {
+            assert_eq!(patched(doc, patch), Ok(parse(expected)), "{patch}");
+        }
+    }
+
+    #[test]
+    fn replacing_and_moving() {
+        let cases = [
+            (r#"{"baz": "qux", "foo": "bar"}"#, r#"[{"op": "replace", "path": "/baz", "value": "boo"}]"#, r#"{"baz": "boo", "foo": "bar"}"#),
+            (
+                r#"{"foo": {"bar": "baz", "waldo": "fred"}, "qux": {"corge": "grault"}}"#,
+                r#"[{"op": "move", "from": "/foo/waldo", "path": "/qux/thud"}]"#,
+                r#"{"foo": {"bar": "baz"}, "qux": {"corge": "grault", "thud": "fred"}}"#,
+            ),
+            (r#"{"foo": ["all", "grass", "cows", "eat"]}"#, r#"[{"op": "move", "from": "/foo/1", "path": "/foo/3"}]"#, r#"{"foo": ["all", "cows", "eat", "grass"]}"#),
+        ];
+        for (doc, patch, expected) in cases {
+            assert_eq!(patched(doc, patch), Ok(parse(expected)), "{patch}");
+        }
+    }
+
+    #[test]
+    fn testing() {
+        let doc = r#"{"baz": "qux", "foo": ["a", 2, "c"]}"#;
+        let patch = r#"[{"op": "test", "path": "/baz", "value": "qux"}, {"op": "test", "path": "/foo/1", "value": 2}]"#;
+        assert_eq!(patched(doc, patch), Ok(parse(doc)));
+
+        let patch = r#"[{"op": "test", "path": "/baz", "value": "bar"}]"#;
+        assert_eq!(patched(r#"{"baz": "qux"}"#, patch), Err(PatchError::TestFailed { index: 0, path: "/baz".to_string() }));
+
+        // `~01` unescapes to `~1`, not to `/`
+        let doc = r#"{"/": 9, "~1": 10}"#;
+        assert_eq!(patched(doc, r#"[{"op": "test", "path": "/~01", "value": 10}]"#), Ok(parse(doc)));
+        assert!(matches!(patched(doc, r#"[{"op": "test", "path": "/~01", "value": "10"}]"#), Err(PatchError::TestFailed { .. })));
+    }
+
+    #[test]
+    fn invalid_patches() {
+        let patch = r#"[{"op": "add", "path": "/baz/bat", "value": "qux"}]"#;
+        assert_eq!(patched(r#"{"foo": "bar"}"#, patch), Err(PatchError::PathNotFound { index: 0, path: "/baz/bat".to_string() }));
+        let patch = r#"[{"op": "add", "path": "/m~n", "value": 1}]"#;
+        assert_eq!(patched(r#"{}"#, patch), Err(PatchError::PathNotFound { index: 0, path: "/m~n".to_string() }));
+
+        // a repeated `op` makes the patch invalid, which only a parser rejecting duplicates notices
+        let patch = r#"[{"op": "add", "path": "/baz", "value": "qux", "op": "remove"}]"#;
+        assert!(JsonParser::new(patch).duplicate_key_policy(DuplicateKeyPolicy::Error).parse_to_value().is_err());
+    }
+
+    #[test]
+    fn failing_patch_leaves_the_document_untouched() {
+        let original = parse(r#"{"foo": ["bar"], "baz": "qux"}"#);
+        let mut doc = original.clone();
+        let patch = parse(r#"[
+            {"op": "add", "path": "/foo/-", "value": "new"},
+            {"op": "remove", "path": "/baz"},
+            {"op": "test", "path": "/foo/0", "value": "other"}
+        ]"#);
+        assert_eq!(doc.apply_patch(&patch), Err(PatchError::TestFailed { index: 2, path: "/foo/0".to_string() }));
+        assert_eq!(doc, original);
+    }
+}
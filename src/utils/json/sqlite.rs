@@ -0,0 +1,90 @@
+use rusqlite::types::{Value, ValueRef};
+use crate::utils::json::{DataType, JsonError, Serializer};
+
+/// Booleans are stored as 0/1, objects and arrays as serialized JSON text.
+impl TryFrom<&DataType> for Value {
+    type Error = JsonError;
+
+    fn try_from(value: &DataType) -> Result<Self, Self::Error> {
+        let value = match value {
+            DataType::String(s) => Value::Text(s.clone()),
+            DataType::Float(f) if !f.is_finite() => {
+                return Err(JsonError::InvalidType { path: String::new(), expected: "a finite number" });
+            }
+            DataType::Float(f) => Value::Real(*f),
+            DataType::Int(i) => Value::Integer(*i as i64),
+            DataType::Boolean(b) => Value::Integer(*b as i64),
+            DataType::Array(_) | DataType::Object(_) => Value::Text(Serializer::new().serialize(value)),
+            DataType::Null => Value::Null,
+        };
+        Ok(value)
+    }
+}
+
+/// SQLite has no JSON type, so TEXT always comes back as a `DataType::String`, even if it was
+/// written from an object or array; parse it with `JsonParser` where a document is expected.
+/// Integers outside the `i32` range become floats and blobs become arrays of bytes.
+impl From<ValueRef<'_>> for DataType {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => DataType::Null,
+            ValueRef::Integer(i) => match i32::try_from(i) {
+                Ok(i) => DataType::Int(i),
+                Err(_) => DataType::Float(i as f64)
+            },
+            ValueRef::Real(f) => DataType::Float(f),
+            ValueRef::Text(text) => DataType::String(String::from_utf8_lossy(text).into_owned()),
+            ValueRef::Blob(blob) => DataType::Array(blob.iter().map(|b| DataType::Int(*b as i32)).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+    use rusqlite::types::Value;
+    use crate::utils::json::{DataType, JsonParser, Serializer};
+
+    fn round_trip(value: &DataType) -> DataType {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute("CREATE TABLE t (v)", ()).unwrap();
+        connection.execute("INSERT INTO t (v) VALUES (?1)", [Value::try_from(value).unwrap()]).unwrap();
+        connection.query_row("SELECT v FROM t", (), |row| Ok(DataType::from(row.get_ref(0)?))).unwrap()
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        for value in [
+            DataType::Null,
+            DataType::Int(-42),
+            DataType::Int(i32::MAX),
+            DataType::Float(1.5),
+            DataType::String("héllo".to_string()),
+        ] {
+            assert_eq!(round_trip(&value), value);
+        }
+        assert_eq!(round_trip(&DataType::Boolean(true)), DataType::Int(1));
+        assert_eq!(round_trip(&DataType::Boolean(false)), DataType::Int(0));
+    }
+
+    #[test]
+    fn documents_come_back_as_text() {
+        let document = JsonParser::new(r#"{"a": [1, 2.5, null], "b": {"c": "d"}}"#).parse_to_value().unwrap();
+        let DataType::String(text) = round_trip(&document) else {
+            panic!("expected the document as text");
+        };
+        assert_eq!(text, Serializer::new().serialize(&document));
+        assert_eq!(JsonParser::new(&text).parse_to_value().unwrap(), document);
+    }
+
+    #[test]
+    fn wide_integers_blobs_and_non_finite_floats() {
+        let connection = Connection::open_in_memory().unwrap();
+        let (wide, blob) = connection
+            .query_row("SELECT 5000000000, x'00ff'", (), |row| Ok((DataType::from(row.get_ref(0)?), DataType::from(row.get_ref(1)?))))
+            .unwrap();
+        assert_eq!(wide, DataType::Float(5e9));
+        assert_eq!(blob, DataType::Array(vec![DataType::Int(0), DataType::Int(255)]));
+        assert!(Value::try_from(&DataType::Float(f64::NAN)).is_err());
+    }
+}
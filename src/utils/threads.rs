@@ -3,43 +3,388 @@ use std::{
     sync::{mpsc, Arc, Mutex},
     thread,
 };
-use std::sync::Condvar;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+use queue::{JobQueue, LocalQueue};
+use schedule::Timer;
+
+mod queue;
+mod schedule;
+mod scope;
+
+pub(crate) use schedule::ScheduledHandle;
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    /// Ids of the workers that took a `Message::Terminate`, see `resize`.
+    retired: Mutex<mpsc::Receiver<usize>>,
+    next_id: usize,
+    /// Spawned by the first `schedule`.
+    timer: Mutex<Option<Timer>>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct QueuedJob {
+    job: Job,
+    /// When it was handed to the pool, for `WorkerContext::queued_duration`.
+    submitted: Instant,
+}
+
+enum Message {
+    Job(QueuedJob),
+    /// Stops the worker taking it, see `ThreadPool::resize`.
+    Terminate,
+}
 type Result<T> = anyhow::Result<T>;
+/// Gets the id of the worker whose job panicked and the panic's payload.
+pub(crate) type PanicHook = Box<dyn Fn(usize, Box<dyn Any + Send>) + Send + Sync>;
+/// Runs on a worker thread as it starts or stops, with the worker's id.
+pub(crate) type WorkerHook = Box<dyn Fn(usize) + Send + Sync>;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<WorkerContext>> = const { RefCell::new(None) };
+}
+
+/// About the worker running the current thread and its job, see `ThreadPool::current_context`.
+#[derive(Debug, Clone)]
+pub(crate) struct WorkerContext {
+    worker_id: usize,
+    pool_name: Arc<str>,
+    queued_duration: Duration,
+}
+
+impl WorkerContext {
+    pub(crate) fn worker_id(&self) -> usize {
+        self.worker_id
+    }
+
+    /// The `ThreadPoolBuilder::name_prefix` of the pool.
+    pub(crate) fn pool_name(&self) -> &str {
+        &self.pool_name
+    }
+
+    /// How long the running job waited between being submitted and a worker taking it, a wait
+    /// for room in a bounded queue included.
+    pub(crate) fn queued_duration(&self) -> Duration {
+        self.queued_duration
+    }
+}
+
+/// The order in which queued jobs are taken, see `ThreadPool::execute_with_priority`.
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// What the workers share with the pool.
+struct Shared {
+    queue: JobQueue,
+    retired: Mutex<mpsc::Sender<usize>>,
+    panic_hook: RwLock<Option<PanicHook>>,
+    /// Jobs submitted but not yet picked up by a worker.
+    queued: AtomicUsize,
+    max_queued: AtomicUsize,
+    submitted: AtomicU64,
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    on_worker_start: Option<WorkerHook>,
+    on_worker_stop: Option<WorkerHook>,
+}
+
+impl Shared {
+    fn enqueued(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_queued.fetch_max(queued, Ordering::SeqCst);
+        self.submitted.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A snapshot of `ThreadPool::metrics`. The counters are read one by one while the workers go
+/// on, so they may be off by a job or two relative to each other.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct PoolMetrics {
+    pub(crate) workers: usize,
+    /// Jobs accepted since the pool was built, including those still queued.
+    pub(crate) submitted: u64,
+    /// Jobs that ran to the end or panicked.
+    pub(crate) completed: u64,
+    /// The part of `completed` that panicked.
+    pub(crate) panicked: u64,
+    /// Jobs running right now.
+    pub(crate) active: usize,
+    /// Jobs waiting for a worker.
+    pub(crate) queued: usize,
+    /// The most jobs ever waiting at once.
+    pub(crate) max_queued: usize,
+}
+
+/// Configures a `ThreadPool` before its workers are spawned, e.g.
+/// `ThreadPoolBuilder::new().name_prefix("http-worker").num_threads(8).build()`.
+pub(crate) struct ThreadPoolBuilder {
+    num_threads: usize,
+    max_queued: Option<usize>,
+    name_prefix: String,
+    stack_size: Option<usize>,
+    on_worker_start: Option<WorkerHook>,
+    on_worker_stop: Option<WorkerHook>,
+}
+
+impl ThreadPoolBuilder {
+    /// One thread per available CPU, an unbounded queue and threads named `worker-{id}`.
+    pub(crate) fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            num_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_queued: None,
+            name_prefix: "worker".to_string(),
+            stack_size: None,
+            on_worker_start: None,
+            on_worker_stop: None,
+        }
+    }
+
+    pub(crate) fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Bounds the queue as `ThreadPool::with_capacity` does.
+    pub(crate) fn max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = Some(max_queued);
+        self
+    }
+
+    /// Workers are named `{prefix}-{id}`, ids counting from 0.
+    pub(crate) fn name_prefix(mut self, prefix: &str) -> Self {
+        self.name_prefix = prefix.to_string();
+        self
+    }
+
+    /// The stack size of the workers in bytes, the standard library's default otherwise.
+    pub(crate) fn stack_size(mut self, bytes: usize) -> Self {
+        self.stack_size = Some(bytes);
+        self
+    }
+
+    /// Runs on each worker before its first job, e.g. to open a thread-local connection.
+    pub(crate) fn on_worker_start(mut self, hook: WorkerHook) -> Self {
+        self.on_worker_start = Some(hook);
+        self
+    }
+
+    /// Runs on each worker once the pool is dropped and no job is left for it.
+    pub(crate) fn on_worker_stop(mut self, hook: WorkerHook) -> Self {
+        self.on_worker_stop = Some(hook);
+        self
+    }
+
+    /// Spawns the workers. If one of them cannot be, those already spawned are stopped and the
+    /// error returned; a pool without threads is an `InvalidInput` error.
+    pub(crate) fn build(self) -> io::Result<ThreadPool> {
+        if self.num_threads == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "a thread pool needs at least one thread"));
+        }
+        let (retired_sender, retired) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(self.max_queued),
+            retired: Mutex::new(retired_sender),
+            panic_hook: RwLock::new(None),
+            queued: AtomicUsize::new(0),
+            max_queued: AtomicUsize::new(0),
+            submitted: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            name_prefix: self.name_prefix,
+            stack_size: self.stack_size,
+            on_worker_start: self.on_worker_start,
+            on_worker_stop: self.on_worker_stop,
+        });
+
+        let mut pool = ThreadPool {
+            workers: Vec::with_capacity(self.num_threads),
+            shared,
+            retired: Mutex::new(retired),
+            next_id: 0,
+            timer: Mutex::new(None),
+        };
+        // dropping the pool on error joins the workers spawned so far
+        pool.grow(self.num_threads)?;
+        Ok(pool)
+    }
+}
+
+impl Default for ThreadPoolBuilder {
+    fn default() -> Self {
+        ThreadPoolBuilder::new()
+    }
+}
+
+/// Returned by `ThreadPool::try_execute` when the queue of a pool made by `with_capacity` is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct QueueFullError;
+
+impl Display for QueueFullError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "the job queue is full")
+    }
+}
+
+impl std::error::Error for QueueFullError {}
 
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool. See `ThreadPoolBuilder` for the other
+    /// settings.
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero, or if a thread cannot be spawned.
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
+        ThreadPoolBuilder::new().num_threads(size).build()
+            .unwrap_or_else(|e| panic!("cannot spawn the workers: {}", e))
+    }
 
-        let (sender, receiver) = mpsc::channel();
+    /// A pool whose queue holds at most `max_queued` jobs waiting for a worker, so that producers
+    /// outrunning the workers are held back: `execute` then blocks until a worker takes a job,
+    /// and `try_execute` fails. With 0, jobs are only handed to a worker ready to take them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is zero, or if a thread cannot be spawned.
+    pub fn with_capacity(size: usize, max_queued: usize) -> ThreadPool {
+        assert!(size > 0);
+        ThreadPoolBuilder::new().num_threads(size).max_queued(max_queued).build()
+            .unwrap_or_else(|e| panic!("cannot spawn the workers: {}", e))
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Grows or shrinks the pool to `new_size` workers. New workers get the next unused ids.
+    /// Shrinking stops the workers there are too many of as soon as they finish their current
+    /// job, leaving the queued jobs to the others, and waits for them to exit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_size` is zero, or if a thread cannot be spawned.
+    pub(crate) fn resize(&mut self, new_size: usize) {
+        assert!(new_size > 0);
+        let current = self.workers.len();
+        if new_size > current {
+            self.grow(new_size - current)
+                .unwrap_or_else(|e| panic!("cannot spawn the workers: {}", e));
+        }
+        self.shared.queue.terminate(current.saturating_sub(new_size));
+        for _ in new_size..current {
+            let id = self.retired.get_mut().unwrap().recv().expect("the pool holds a worker alive");
+            let index = self.workers.iter().position(|worker| worker.id == id).unwrap();
+            let mut worker = self.workers.remove(index);
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
 
-        let mut workers = Vec::with_capacity(size);
+    /// The number of workers, as last set by `resize`.
+    pub(crate) fn current_size(&self) -> usize {
+        self.workers.len()
+    }
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    fn grow(&mut self, count: usize) -> io::Result<()> {
+        for _ in 0..count {
+            let worker = Worker::new(self.next_id, Arc::clone(&self.shared))?;
+            self.workers.push(worker);
+            self.next_id += 1;
         }
+        Ok(())
+    }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+    pub(crate) fn metrics(&self) -> PoolMetrics {
+        let shared = &self.shared;
+        PoolMetrics {
+            workers: self.workers.len(),
+            submitted: shared.submitted.load(Ordering::SeqCst),
+            completed: shared.completed.load(Ordering::SeqCst),
+            panicked: shared.panicked.load(Ordering::SeqCst),
+            active: shared.active.load(Ordering::SeqCst),
+            queued: shared.queued.load(Ordering::SeqCst),
+            max_queued: shared.max_queued.load(Ordering::SeqCst),
         }
     }
 
+    /// Runs `f` on a worker once `delay` has passed, or a little later if the queue is busy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timer thread cannot be spawned.
+    pub(crate) fn schedule<F>(&self, delay: Duration, f: F) -> ScheduledHandle
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.with_timer(|timer| timer.once(delay, Box::new(f)))
+    }
+
+    /// Runs `f` on a worker after `initial_delay`, then every `period` until cancelled or the
+    /// pool is dropped. Runs never overlap: a run late because the previous one took longer than
+    /// `period` waits for it, and ticks missed meanwhile are skipped rather than caught up on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero, or if the timer thread cannot be spawned.
+    pub(crate) fn schedule_periodic<F>(&self, initial_delay: Duration, period: Duration, f: F) -> ScheduledHandle
+        where
+            F: FnMut() + Send + 'static,
+    {
+        assert!(!period.is_zero(), "a periodic task needs a non-zero period");
+        self.with_timer(|timer| timer.periodic(initial_delay, period, Arc::new(Mutex::new(f))))
+    }
+
+    fn with_timer<T>(&self, f: impl FnOnce(&Timer) -> T) -> T {
+        let mut timer = self.timer.lock().unwrap();
+        let timer = match timer.take() {
+            Some(existing) => timer.insert(existing),
+            None => {
+                let spawned = Timer::spawn(Arc::clone(&self.shared))
+                    .unwrap_or_else(|e| panic!("cannot spawn the timer: {}", e));
+                timer.insert(spawned)
+            }
+        };
+        f(timer)
+    }
+
+    /// The context of the job running on this thread, `None` outside of a pool's workers.
+    pub(crate) fn current_context() -> Option<WorkerContext> {
+        CONTEXT.with(|context| context.borrow().clone())
+    }
+
+    /// How many jobs wait for a worker, counting those of `execute` calls blocked on a full queue.
+    pub(crate) fn queued_len(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Called on the worker thread with every panic a job lets escape, after which the worker
+    /// goes on with the next job. Without a hook the panic is only printed by the standard panic
+    /// hook.
+    pub(crate) fn set_panic_hook(&self, hook: PanicHook) {
+        *self.shared.panic_hook.write().unwrap() = Some(hook);
+    }
+
+    /// Runs `f` on the pool, its result or error is handed over through the returned future. A
+    /// panic of `f` resolves the future with an error, and still reaches the panic hook.
     pub fn execute_as_future<T, F>(&self, f: F) -> Future<T>
         where F: FnOnce() -> Result<T> + Send + 'static,
               T: Send + 'static
@@ -51,23 +396,76 @@ impl ThreadPool {
         let future = Future::new(future_clone);
 
         let f = move || {
-            let result = f();
+            let (result, payload) = catch_job_panic(f);
             let mut data = thread_clone.0.lock().unwrap();
             data.replace(result);
             thread_clone.1.notify_all();
+            drop(data);
+            if let Some(payload) = payload {
+                panic::resume_unwind(payload);
+            }
         };
         self.execute(f);
 
         future
     }
 
+    /// Runs `f` on the pool unless `token` is cancelled before a worker gets to it. A long job
+    /// should check the token it is given now and then, and stop early once it is cancelled.
+    pub(crate) fn execute_cancellable<F>(&self, token: CancellationToken, f: F)
+        where
+            F: FnOnce(&CancellationToken) + Send + 'static,
+    {
+        self.execute(move || {
+            if !token.is_cancelled() {
+                f(&token)
+            }
+        })
+    }
+
+    /// Like `execute_as_future`, the future resolving with a `Cancelled` error if `token` is
+    /// cancelled before a worker gets to `f`. What `f` returns once it started is up to it.
+    pub(crate) fn execute_as_future_cancellable<T, F>(&self, token: CancellationToken, f: F) -> Future<T>
+        where F: FnOnce(&CancellationToken) -> Result<T> + Send + 'static,
+              T: Send + 'static
+    {
+        self.execute_as_future(move || {
+            if token.is_cancelled() {
+                return Err(Cancelled.into());
+            }
+            f(&token)
+        })
+    }
+
+    /// Queues `f` for the next free worker. On a pool made by `with_capacity` this blocks while
+    /// the queue is full.
     pub fn execute<F>(&self, f: F)
         where
             F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.execute_with_priority(Priority::Normal, f)
+    }
+
+    /// Like `execute`, the workers taking the jobs of higher priority first, see `JobQueue` for
+    /// how jobs are spread over the workers. A job still goes ahead of those of higher priority
+    /// once a few of them were taken while it waited, so that none waits forever.
+    pub(crate) fn execute_with_priority<F>(&self, priority: Priority, f: F)
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.shared.enqueued();
+        self.shared.queue.push(priority, Box::new(f));
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// Like `execute`, but fails rather than waiting when the queue is full. Never fails on a
+    /// pool made by `new`.
+    pub(crate) fn try_execute<F>(&self, f: F) -> std::result::Result<(), QueueFullError>
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        // counted in only once there is room, a rejected job must not show in the metrics
+        self.shared.queue.try_push(Priority::Normal, Box::new(f), || self.shared.enqueued())
+            .map_err(|_| QueueFullError)
     }
 
     pub fn execute_all_and_await<F>(&self, fs: Vec<F>) where
@@ -77,17 +475,48 @@ impl ThreadPool {
         for f in fs {
             let cd_clone = Arc::clone(&cd);
             self.execute(move || {
-                f();
-                cd_clone.count_down()
+                // counted down even if `f` panics, or the wait would never end
+                let _count_down = cd_clone.count_down_on_drop();
+                f()
             })
         }
         cd.await_complete()
     }
+
+    /// Like `execute_all_and_await`, returning what each closure returned in the order they were
+    /// given. A closure that panics gets an error in its place, and still reaches the panic hook.
+    pub fn map_all_and_await<T, F>(&self, fs: Vec<F>) -> Vec<Result<T>>
+        where F: FnOnce() -> Result<T> + Send + 'static,
+              T: Send + 'static
+    {
+        let cd = Arc::new(CountDownLatch::new(fs.len()));
+        let slots: Arc<Mutex<Vec<Option<Result<T>>>>> = Arc::new(Mutex::new((0..fs.len()).map(|_| None).collect()));
+        for (index, f) in fs.into_iter().enumerate() {
+            let cd_clone = Arc::clone(&cd);
+            let slots_clone = Arc::clone(&slots);
+            self.execute(move || {
+                let _count_down = cd_clone.count_down_on_drop();
+                let (result, payload) = catch_job_panic(f);
+                slots_clone.lock().unwrap()[index] = Some(result);
+                if let Some(payload) = payload {
+                    panic::resume_unwind(payload);
+                }
+            })
+        }
+        cd.await_complete();
+        let mut slots = slots.lock().unwrap();
+        slots.iter_mut()
+            .map(|slot| slot.take().expect("every job fills its slot before counting down"))
+            .collect()
+    }
 }
 
 impl Drop for ThreadPool {
+    /// Scheduled tasks that are not due yet never run, queued jobs do.
     fn drop(&mut self) {
-        drop(self.sender.take());
+        // the timer may still be queueing a job
+        drop(self.timer.get_mut().unwrap().take());
+        self.shared.queue.close();
 
         for worker in &mut self.workers {
             if let Some(thread) = worker.thread.take() {
@@ -103,25 +532,146 @@ struct Worker {
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
-
-            match message {
-                Ok(job) => job(),
-                Err(_) => break
+    fn new(id: usize, shared: Arc<Shared>) -> io::Result<Worker> {
+        let mut builder = thread::Builder::new().name(format!("{}-{}", shared.name_prefix, id));
+        if let Some(stack_size) = shared.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let local = shared.queue.register(id);
+        let thread_shared = Arc::clone(&shared);
+        let spawned = builder.spawn(move || {
+            let shared = thread_shared;
+            shared.queue.enter(&local);
+            CONTEXT.with(|context| *context.borrow_mut() = Some(WorkerContext {
+                worker_id: id,
+                pool_name: Arc::from(shared.name_prefix.as_str()),
+                queued_duration: Duration::ZERO,
+            }));
+            if let Some(hook) = &shared.on_worker_start {
+                hook(id);
+            }
+            Self::run(id, &shared, &local);
+            if let Some(hook) = &shared.on_worker_stop {
+                hook(id);
             }
         });
+        let thread = spawned.inspect_err(|_| shared.queue.unregister(id))?;
 
-        Worker {
+        Ok(Worker {
             id,
             thread: Some(thread),
+        })
+    }
+
+    /// Runs jobs until the pool is dropped.
+    fn run(id: usize, shared: &Shared, local: &LocalQueue) {
+        loop {
+            let message = shared.queue.pop(local);
+
+            match message {
+                // a panicking job must not take the worker down with it, the pool would shrink
+                // a thread at a time
+                Some(Message::Job(QueuedJob { job, submitted })) => {
+                    CONTEXT.with(|context| {
+                        if let Some(context) = context.borrow_mut().as_mut() {
+                            context.queued_duration = submitted.elapsed();
+                        }
+                    });
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
+                    shared.active.fetch_add(1, Ordering::SeqCst);
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    shared.active.fetch_sub(1, Ordering::SeqCst);
+                    shared.completed.fetch_add(1, Ordering::SeqCst);
+                    if let Err(payload) = result {
+                        shared.panicked.fetch_add(1, Ordering::SeqCst);
+                        if let Some(hook) = &*shared.panic_hook.read().unwrap() {
+                            hook(id, payload);
+                        }
+                    }
+                },
+                Some(Message::Terminate) => {
+                    shared.queue.unregister(id);
+                    let _ = shared.retired.lock().unwrap().send(id);
+                    break
+                }
+                None => {
+                    shared.queue.unregister(id);
+                    break
+                }
+            }
         }
     }
 }
 
+/// Runs `f`, turning a panic into an error result along with the payload to resume it with.
+fn catch_job_panic<T>(f: impl FnOnce() -> Result<T>) -> (Result<T>, Option<Box<dyn Any + Send>>) {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => (result, None),
+        Err(payload) => (Err(anyhow!("the job panicked: {}", panic_message(&*payload))), Some(payload)),
+    }
+}
+
+/// The message `panic!` was called with, or a placeholder for other payloads.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload.downcast_ref::<String>().cloned().unwrap_or_else(|| "Box<dyn Any>".to_string()),
+    }
+}
+
+/// Tells jobs to stop, see `ThreadPool::execute_cancellable`. Clones share the same state, so
+/// that whoever holds one can cancel the job holding another.
+#[derive(Clone, Default)]
+pub(crate) struct CancellationToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+impl CancellationToken {
+    pub(crate) fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancels the token for good, waking those waiting in `wait_cancelled_timeout`.
+    pub(crate) fn cancel(&self) {
+        let _guard = self.state.lock.lock().unwrap();
+        self.state.cancelled.store(true, Ordering::SeqCst);
+        self.state.condvar.notify_all();
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Sleeps for `timeout` unless cancelled meanwhile, returns whether the token is cancelled.
+    pub(crate) fn wait_cancelled_timeout(&self, timeout: Duration) -> bool {
+        let guard = self.state.lock.lock().unwrap();
+        let (_guard, _) = self.state.condvar
+            .wait_timeout_while(guard, timeout, |_| !self.is_cancelled())
+            .unwrap();
+        self.is_cancelled()
+    }
+}
+
+/// The error of a future whose job was cancelled before it started.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "the job was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
 pub(crate) struct CountDownLatch {
-    count: usize,
     condvar: Arc<(Mutex<usize>, Condvar)>,
 }
 
@@ -130,11 +680,16 @@ impl CountDownLatch {
         let mutex = Mutex::new(count);
         let condvar = Condvar::new();
         CountDownLatch {
-            count,
             condvar: Arc::new((mutex, condvar)),
         }
     }
 
+    /// How many `count_down` calls are still awaited.
+    pub(crate) fn current_count(&self) -> usize {
+        *self.condvar.0.lock().unwrap()
+    }
+
+    /// Once the count is 0, further calls do nothing.
     pub(crate) fn count_down(&self) {
         let (mutex, condvar) = &*self.condvar;
         let mut count = mutex.lock().unwrap();
@@ -154,42 +709,600 @@ impl CountDownLatch {
             count = condvar.wait(count).unwrap();
         }
     }
+
+    /// Counts down when the returned guard is dropped, also while unwinding from a panic.
+    pub(crate) fn count_down_on_drop(&self) -> CountDownGuard<'_> {
+        CountDownGuard { latch: self }
+    }
+
+    /// Like `await_complete`, giving up after `timeout`. Returns whether the count reached 0.
+    pub(crate) fn await_timeout(&self, timeout: Duration) -> bool {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            self.await_complete();
+            return true;
+        };
+        let (mutex, condvar) = &*self.condvar;
+        let mut count = mutex.lock().unwrap();
+        while *count > 0 {
+            // woken early, spuriously or by another count down, only the rest is left to wait
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            count = condvar.wait_timeout(count, deadline - now).unwrap().0;
+        }
+        true
+    }
 }
 
+/// See `CountDownLatch::count_down_on_drop`.
+pub(crate) struct CountDownGuard<'a> {
+    latch: &'a CountDownLatch,
+}
+
+impl Drop for CountDownGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.count_down();
+    }
+}
+
+/// Makes `parties` threads wait for each other, then lets them all go and starts over for the
+/// next round, unlike `CountDownLatch`.
+pub(crate) struct CyclicBarrier {
+    parties: usize,
+    state: Mutex<BarrierState>,
+    condvar: Condvar,
+    /// Run by the last thread to arrive, before the others are released.
+    action: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+struct BarrierState {
+    /// Threads waiting in the current round.
+    arrived: usize,
+    /// Counts the rounds, so that a thread only leaves once its own round is over, not when a
+    /// later round reaches the same number of arrivals.
+    generation: u64,
+}
+
+/// What `CyclicBarrier::wait` tells each thread of a round.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct BarrierWaitResult {
+    leader: bool,
+    generation: u64,
+}
+
+impl BarrierWaitResult {
+    /// Whether this thread arrived last and ran the action, exactly one per round is.
+    pub(crate) fn is_leader(&self) -> bool {
+        self.leader
+    }
+
+    /// The round, counting from 0.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl CyclicBarrier {
+    /// # Panics
+    ///
+    /// Panics if `parties` is zero.
+    pub(crate) fn new(parties: usize) -> CyclicBarrier {
+        assert!(parties > 0);
+        CyclicBarrier {
+            parties,
+            state: Mutex::new(BarrierState { arrived: 0, generation: 0 }),
+            condvar: Condvar::new(),
+            action: None,
+        }
+    }
+
+    /// Runs `action` once per round, on the last thread to arrive, before the others go on. If it
+    /// panics, the round ends all the same and the panic goes on in the last thread only.
+    pub(crate) fn with_action(parties: usize, action: impl Fn() + Send + Sync + 'static) -> CyclicBarrier {
+        CyclicBarrier {
+            action: Some(Box::new(action)),
+            ..CyclicBarrier::new(parties)
+        }
+    }
+
+    pub(crate) fn parties(&self) -> usize {
+        self.parties
+    }
+
+    /// Blocks until `parties` threads called it in this round.
+    pub(crate) fn wait(&self) -> BarrierWaitResult {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.generation;
+        state.arrived += 1;
+        if state.arrived < self.parties {
+            while state.generation == generation {
+                state = self.condvar.wait(state).unwrap();
+            }
+            return BarrierWaitResult { leader: false, generation };
+        }
+        // caught so that the lock is not poisoned and the others are released
+        let result = match &self.action {
+            Some(action) => panic::catch_unwind(AssertUnwindSafe(action)),
+            None => Ok(()),
+        };
+        state.arrived = 0;
+        state.generation += 1;
+        self.condvar.notify_all();
+        drop(state);
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+        BarrierWaitResult { leader: true, generation }
+    }
+}
+
+/// The result of a job of `ThreadPool::execute_as_future`. Getting it consumes the future, so
+/// that it can only be retrieved once.
 pub(crate) struct Future<T> {
     condvar: Arc<(Mutex<Option<Result<T>>>, Condvar)>,
-    is_done: bool
 }
 
 impl<T> Future<T> {
     fn new(condvar: Arc<(Mutex<Option<Result<T>>>, Condvar)>) -> Future<T> {
         Future {
             condvar,
-            is_done: false
         }
     }
 
-    pub(crate) fn is_done(&self) -> bool{
-        self.is_done
+    /// Whether the job finished, so that `get` would not block.
+    pub(crate) fn is_done(&self) -> bool {
+        self.condvar.0.lock().unwrap().is_some()
     }
 
-    pub(crate) fn try_get(&mut self) -> Option<Result<T>> {
-        let (mutex, _) = &*self.condvar;
-        let mut data = mutex.lock().unwrap();
-        match data.take() {
-            None => {None}
-            Some(data) => {
-                self.is_done = true;
-                Some(data)}
-        }
+    /// The result if the job finished, the future back otherwise.
+    pub(crate) fn try_get(self) -> std::result::Result<Result<T>, Future<T>> {
+        let data = self.condvar.0.lock().unwrap().take();
+        data.ok_or(self)
     }
 
-    pub(crate) fn get(& self) -> Result<T> {
+    /// Blocks until the job finished.
+    pub(crate) fn get(self) -> Result<T> {
         let (mutex, condvar) = &*self.condvar;
         let mut data = mutex.lock().unwrap();
-        while let None = *data {
+        while data.is_none() {
             data = condvar.wait(data).unwrap();
         }
         data.take().unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use super::*;
+
+    /// Runs a job that blocks its worker until the returned sender is used or dropped.
+    fn block_a_worker(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started_sender, started) = mpsc::channel();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        started.recv().unwrap();
+        release
+    }
+
+    #[test]
+    fn try_execute_fails_on_a_full_queue() {
+        let pool = ThreadPool::with_capacity(1, 2);
+        let release = block_a_worker(&pool);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let ran = Arc::clone(&ran);
+            let slow = move || {
+                thread::sleep(Duration::from_millis(10));
+                ran.fetch_add(1, Ordering::SeqCst);
+            };
+            assert_eq!(pool.try_execute(slow), Ok(()));
+        }
+        assert_eq!(pool.try_execute(|| {}), Err(QueueFullError));
+        // the rejected job is not counted
+        let metrics = pool.metrics();
+        assert_eq!((metrics.submitted, metrics.queued, metrics.max_queued), (3, 2, 2));
+
+        drop(release);
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn barrier_phases_wait_for_every_party() {
+        const THREADS: usize = 4;
+        const PHASES: usize = 3;
+        let done = AtomicUsize::new(0);
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let rounds_in_action = Arc::clone(&rounds);
+        let barrier = CyclicBarrier::with_action(THREADS, move || {
+            rounds_in_action.fetch_add(1, Ordering::SeqCst);
+        });
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for phase in 0..PHASES {
+                        done.fetch_add(1, Ordering::SeqCst);
+                        let result = barrier.wait();
+                        // nobody leaves a phase before all arrived, nor starts the next one early
+                        let seen = done.load(Ordering::SeqCst);
+                        assert!(seen >= (phase + 1) * THREADS && seen <= (phase + 2) * THREADS);
+                        assert_eq!(result.generation(), phase as u64);
+                        if result.is_leader() {
+                            leaders.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(rounds.load(Ordering::SeqCst), PHASES);
+        assert_eq!(leaders.into_inner(), PHASES);
+    }
+
+    #[test]
+    fn panicking_barrier_action_releases_the_others() {
+        let barrier = CyclicBarrier::with_action(3, || panic!("action failed"));
+        let results: Vec<_> = thread::scope(|scope| {
+            let waiters: Vec<_> = (0..3).map(|_| scope.spawn(|| barrier.wait())).collect();
+            waiters.into_iter().map(|waiter| waiter.join()).collect()
+        });
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert!(results.iter().flatten().all(|result| !result.is_leader() && result.generation() == 0));
+        assert!(!barrier.state.is_poisoned());
+        assert_eq!(barrier.state.lock().unwrap().generation, 1);
+    }
+
+    #[test]
+    fn a_panicking_job_leaves_the_worker_running() {
+        let pool = ThreadPool::new(1);
+        let (panics_sender, panics) = mpsc::channel();
+        let panics_sender = Mutex::new(panics_sender);
+        pool.set_panic_hook(Box::new(move |worker, payload| {
+            let _ = panics_sender.lock().unwrap().send((worker, panic_message(&*payload)));
+        }));
+
+        let failed: Future<u32> = pool.execute_as_future(|| panic!("job failed"));
+        let (ran_sender, ran) = mpsc::channel();
+        pool.execute(move || ran_sender.send(thread::current().id()).unwrap());
+        let answer = pool.execute_as_future(|| Ok(42));
+
+        assert!(failed.get().is_err());
+        let worker_thread = ran.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(answer.get().unwrap(), 42);
+        assert_eq!(panics.recv_timeout(Duration::from_secs(5)).unwrap(), (0, "job failed".to_string()));
+
+        // the same single worker ran everything
+        let (id_sender, id) = mpsc::channel();
+        pool.execute(move || id_sender.send(thread::current().id()).unwrap());
+        assert_eq!(id.recv_timeout(Duration::from_secs(5)).unwrap(), worker_thread);
+        let metrics = pool.metrics();
+        assert_eq!((metrics.workers, metrics.panicked), (1, 1));
+        // dropping joins the worker without propagating the panic
+        drop(pool);
+    }
+
+    #[test]
+    fn map_all_and_await_keeps_the_order_with_failures_and_a_panic() {
+        let pool = ThreadPool::new(3);
+        let jobs: Vec<Box<dyn FnOnce() -> Result<u64> + Send>> = (0..12u64).map(|i| -> Box<dyn FnOnce() -> Result<u64> + Send> {
+            Box::new(move || {
+                // later jobs finish first
+                thread::sleep(Duration::from_millis(12 - i));
+                match i {
+                    5 => panic!("job {} panicked", i),
+                    i if i % 4 == 0 => Err(anyhow!("job {} failed", i)),
+                    i => Ok(i * i),
+                }
+            })
+        }).collect();
+        let results = pool.map_all_and_await(jobs);
+        assert_eq!(results.len(), 12);
+        for (i, result) in results.iter().enumerate() {
+            match (i, result) {
+                (5, Err(error)) => assert_eq!(error.to_string(), "the job panicked: job 5 panicked"),
+                (i, Err(error)) if i % 4 == 0 => assert_eq!(error.to_string(), format!("job {} failed", i)),
+                (i, Ok(square)) => assert_eq!(*square, (i * i) as u64),
+                (i, other) => panic!("job {}: {:?}", i, other),
+            }
+        }
+        assert!(pool.map_all_and_await(Vec::<fn() -> Result<()>>::new()).is_empty());
+        // the pool is intact
+        assert_eq!(pool.map_all_and_await(vec![|| Ok(1), || Ok(2)]).into_iter().map(Result::unwrap).collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn execute_all_and_await_returns_despite_a_panic() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let jobs: Vec<Box<dyn FnOnce() + Send>> = (0..6).map(|i| -> Box<dyn FnOnce() + Send> {
+            let ran = Arc::clone(&ran);
+            Box::new(move || {
+                if i == 2 {
+                    panic!("side effect failed");
+                }
+                ran.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect();
+        pool.execute_all_and_await(jobs);
+        assert_eq!(ran.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn workers_are_named_and_hooked() {
+        let (events_sender, events) = mpsc::channel();
+        let start_sender = Mutex::new(events_sender.clone());
+        let stop_sender = Mutex::new(events_sender);
+        let pool = ThreadPoolBuilder::new()
+            .name_prefix("http-worker")
+            .num_threads(3)
+            .stack_size(256 * 1024)
+            .on_worker_start(Box::new(move |id| start_sender.lock().unwrap().send(format!("start {id} {:?}", thread::current().name())).unwrap()))
+            .on_worker_stop(Box::new(move |id| stop_sender.lock().unwrap().send(format!("stop {id}")).unwrap()))
+            .build()
+            .unwrap();
+        let names = pool.map_all_and_await((0..9).map(|_| || Ok(thread::current().name().map(str::to_string))).collect());
+        for name in names {
+            let name = name.unwrap().unwrap();
+            assert!(["http-worker-0", "http-worker-1", "http-worker-2"].contains(&name.as_str()), "{name}");
+        }
+        drop(pool);
+        let mut events: Vec<String> = events.try_iter().collect();
+        events.sort();
+        assert_eq!(events, [
+            "start 0 Some(\"http-worker-0\")", "start 1 Some(\"http-worker-1\")", "start 2 Some(\"http-worker-2\")",
+            "stop 0", "stop 1", "stop 2",
+        ]);
+
+        let pool = ThreadPool::new(1);
+        assert_eq!(pool.execute_as_future(|| Ok(thread::current().name().map(str::to_string))).get().unwrap().as_deref(), Some("worker-0"));
+        assert_eq!(ThreadPoolBuilder::new().num_threads(0).build().err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        // too large a stack to be allocated is an error rather than a panic
+        assert!(ThreadPoolBuilder::new().num_threads(2).stack_size(1 << 50).build().is_err());
+    }
+
+    #[test]
+    fn resize_grows_and_shrinks_the_pool() {
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let stopped_in_hook = Arc::clone(&stopped);
+        let mut pool = ThreadPoolBuilder::new().num_threads(2)
+            .on_worker_stop(Box::new(move |_| { stopped_in_hook.fetch_add(1, Ordering::SeqCst); }))
+            .build()
+            .unwrap();
+        pool.resize(4);
+        assert_eq!(pool.current_size(), 4);
+
+        // each job waits for the other three, which only works with four of them running at once
+        let all_running = Arc::new(CountDownLatch::new(4));
+        let jobs: Vec<_> = (0..4).map(|_| {
+            let all_running = Arc::clone(&all_running);
+            move || {
+                all_running.count_down();
+                Ok((all_running.await_timeout(Duration::from_secs(5)), ThreadPool::current_context().unwrap().worker_id()))
+            }
+        }).collect();
+        let mut workers: Vec<usize> = pool.map_all_and_await(jobs).into_iter().map(|result| {
+            let (together, worker) = result.unwrap();
+            assert!(together);
+            worker
+        }).collect();
+        workers.sort();
+        assert_eq!(workers, [0, 1, 2, 3]);
+
+        // shrinking lets the queued jobs finish on the workers that are left
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..8 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(5));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pool.resize(1);
+        assert_eq!((pool.current_size(), pool.metrics().workers), (1, 1));
+        assert_eq!(stopped.load(Ordering::SeqCst), 3);
+        let worker = pool.execute_as_future(|| Ok(ThreadPool::current_context().unwrap().worker_id())).get().unwrap();
+        let results = pool.map_all_and_await((0..5).map(|_| || Ok(ThreadPool::current_context().unwrap().worker_id())).collect());
+        assert!(results.into_iter().all(|id| id.unwrap() == worker));
+
+        pool.resize(2);
+        let ids = pool.map_all_and_await((0..20).map(|_| || Ok(ThreadPool::current_context().unwrap().worker_id())).collect());
+        // the new worker gets the next unused id
+        assert!(ids.into_iter().all(|id| [worker, 4].contains(&id.unwrap())));
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 8);
+        assert_eq!(stopped.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn metrics_follow_a_burst_of_jobs() {
+        let pool = ThreadPool::new(2);
+        assert_eq!(pool.metrics(), PoolMetrics { workers: 2, ..PoolMetrics::default() });
+        let releases = [block_a_worker(&pool), block_a_worker(&pool)];
+        for i in 0..6 {
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(10));
+                if i == 3 {
+                    panic!("one of the burst failed");
+                }
+            });
+        }
+        let metrics = pool.metrics();
+        assert_eq!(metrics, PoolMetrics { workers: 2, submitted: 8, completed: 0, panicked: 0, active: 2, queued: 6, max_queued: 6 });
+        assert_eq!(pool.queued_len(), 6);
+
+        drop(releases);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut in_between = false;
+        while pool.metrics().completed < 8 {
+            let metrics = pool.metrics();
+            in_between |= metrics.completed > 0 && metrics.queued > 0;
+            assert!(metrics.active <= 2 && metrics.queued <= 6, "{metrics:?}");
+            assert!(Instant::now() < deadline, "{metrics:?}");
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert!(in_between);
+        assert_eq!(pool.metrics(), PoolMetrics { workers: 2, submitted: 8, completed: 8, panicked: 1, active: 0, queued: 0, max_queued: 6 });
+    }
+
+    #[test]
+    fn a_job_cancelled_while_queued_never_starts() {
+        let pool = ThreadPool::new(1);
+        let release = block_a_worker(&pool);
+        let started = Arc::new(AtomicUsize::new(0));
+        let token = CancellationToken::new();
+        let started_in_job = Arc::clone(&started);
+        pool.execute_cancellable(token.clone(), move |_| { started_in_job.fetch_add(1, Ordering::SeqCst); });
+        let started_in_future = Arc::clone(&started);
+        let future = pool.execute_as_future_cancellable(token.clone(), move |_| Ok(started_in_future.fetch_add(1, Ordering::SeqCst)));
+        let kept = pool.execute_as_future_cancellable(CancellationToken::new(), |_| Ok("kept"));
+
+        token.cancel();
+        drop(release);
+        let error = future.get().unwrap_err();
+        assert_eq!(error.downcast_ref::<Cancelled>(), Some(&Cancelled));
+        assert_eq!(error.to_string(), "the job was cancelled");
+        assert_eq!(kept.get().unwrap(), "kept");
+        drop(pool);
+        assert_eq!(started.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn a_running_loop_stops_once_cancelled() {
+        let pool = ThreadPool::new(1);
+        let token = CancellationToken::new();
+        let (started_sender, started) = mpsc::channel();
+        let rounds = pool.execute_as_future_cancellable(token.clone(), move |token| {
+            started_sender.send(()).unwrap();
+            let mut rounds = 0;
+            while !token.wait_cancelled_timeout(Duration::from_millis(5)) {
+                rounds += 1;
+            }
+            Ok(rounds)
+        });
+        started.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        assert!(!rounds.is_done());
+        let cancelled_at = Instant::now();
+        token.cancel();
+        assert!(rounds.get().unwrap() >= 1);
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+
+        // a cancelled token does not wait at all
+        let waited = Instant::now();
+        assert!(token.wait_cancelled_timeout(Duration::from_secs(10)));
+        assert!(waited.elapsed() < Duration::from_secs(1));
+        assert!(!CancellationToken::new().wait_cancelled_timeout(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn count_down_latch_timeouts_and_counts() {
+        let latch = Arc::new(CountDownLatch::new(3));
+        assert_eq!(latch.current_count(), 3);
+
+        // nobody counts down, the timeout expires
+        let waited = Instant::now();
+        assert!(!latch.await_timeout(Duration::from_millis(50)));
+        assert!(waited.elapsed() >= Duration::from_millis(50));
+
+        // the last count down comes shortly before the deadline, after an earlier wake-up
+        latch.count_down();
+        assert_eq!(latch.current_count(), 2);
+        let counting = Arc::clone(&latch);
+        let counter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            counting.count_down();
+            thread::sleep(Duration::from_millis(180));
+            counting.count_down();
+        });
+        let waited = Instant::now();
+        assert!(latch.await_timeout(Duration::from_millis(400)));
+        assert!(waited.elapsed() >= Duration::from_millis(200) && waited.elapsed() < Duration::from_millis(400));
+        assert_eq!(latch.current_count(), 0);
+        counter.join().unwrap();
+
+        // past zero, counting down does nothing and waiting returns at once
+        latch.count_down();
+        drop(latch.count_down_on_drop());
+        assert_eq!(latch.current_count(), 0);
+        assert!(latch.await_timeout(Duration::ZERO));
+        latch.await_complete();
+
+        // an unrepresentable deadline waits without one
+        let latch = Arc::new(CountDownLatch::new(1));
+        let counting = Arc::clone(&latch);
+        let counter = thread::spawn(move || counting.count_down());
+        assert!(latch.await_timeout(Duration::MAX));
+        counter.join().unwrap();
+    }
+
+    #[test]
+    fn a_future_is_done_for_every_thread_before_it_is_got() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let future = pool.execute_as_future(move || {
+            let _ = blocked.recv();
+            Ok(42)
+        });
+        assert!(!future.is_done());
+        // not done yet, the future comes back to be asked again
+        let future = future.try_get().unwrap_err();
+
+        thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                while !future.is_done() {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            release.send(()).unwrap();
+            watcher.join().unwrap();
+        });
+        // done before anyone took the value, and still there for `get`
+        assert!(future.is_done());
+        assert_eq!(future.get().unwrap(), 42);
+
+        let future = pool.execute_as_future(|| Ok("done"));
+        while !future.is_done() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(future.try_get().map(Result::unwrap).ok(), Some("done"));
+    }
+
+    #[test]
+    fn jobs_see_their_worker_context() {
+        const THREADS: usize = 3;
+        assert!(ThreadPool::current_context().is_none());
+        let pool = ThreadPoolBuilder::new().name_prefix("ctx").num_threads(THREADS).build().unwrap();
+        // one worker busy, so that some of the jobs queue
+        let release = block_a_worker(&pool);
+        let futures: Vec<_> = (0..20).map(|_| pool.execute_as_future(|| {
+            let context = ThreadPool::current_context().unwrap();
+            Ok((context.worker_id(), context.pool_name().to_string(), context.queued_duration()))
+        })).collect();
+        for future in futures {
+            let (id, name, queued) = future.get().unwrap();
+            assert!(id < THREADS, "worker id {}", id);
+            assert_eq!(name, "ctx");
+            assert!(queued < Duration::from_secs(10));
+        }
+        drop(release);
+        assert!(ThreadPool::current_context().is_none());
+    }
+
+    #[test]
+    fn a_panicking_future_resolves_with_the_message() {
+        let pool = ThreadPool::new(1);
+        let future = pool.execute_as_future::<(), _>(|| panic!("no {} here", "answer"));
+        let (sender, got) = mpsc::channel();
+        thread::spawn(move || sender.send(future.get()).unwrap());
+        let error = got.recv_timeout(Duration::from_secs(5)).expect("get() never returned").unwrap_err();
+        assert!(error.to_string().contains("no answer here"), "{}", error);
+
+        // the worker survived it
+        assert_eq!(pool.execute_as_future(|| Ok(1)).get().unwrap(), 1);
+    }
+}
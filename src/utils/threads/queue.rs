@@ -0,0 +1,458 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+use super::{Job, Message, Priority, QueuedJob};
+
+/// How many jobs may be taken ahead of a waiting one of lower priority before it goes first.
+const MAX_SKIPPED: usize = 8;
+
+thread_local! {
+    /// The queue of the worker running on this thread, with the address of its `JobQueue`.
+    static LOCAL: RefCell<Option<(usize, Arc<LocalQueue>)>> = const { RefCell::new(None) };
+}
+
+/// The jobs waiting for a worker. Each worker has a queue of its own, so that submitting and
+/// taking jobs rarely contend on the same lock: jobs are spread over them in turn, or go to the
+/// submitting worker's own queue, and a worker whose queue is empty steals from the others
+/// before going to sleep. Priorities still hold across the pool: a worker whose own queue has
+/// nothing as urgent as the most urgent job waiting anywhere steals that job instead.
+pub(super) struct JobQueue {
+    /// By worker id, in spawning order.
+    locals: RwLock<Vec<(usize, Arc<LocalQueue>)>>,
+    /// Picks the queue of the next job submitted from outside the pool.
+    next: AtomicUsize,
+    /// Jobs in all queues, counted before they are pushed and after they are taken.
+    len: AtomicUsize,
+    /// `len` by `Priority`, to find the most urgent level without locking every queue.
+    waiting: [AtomicUsize; 3],
+    /// Workers sleeping, or about to, on `available`.
+    idle: AtomicUsize,
+    /// `Message::Terminate`s not taken yet.
+    terminate: AtomicUsize,
+    closed: AtomicBool,
+    /// Guards going to sleep and waiting for room, so that no notification is missed.
+    sleep: Mutex<()>,
+    /// Signalled when a job or a stop is pushed, or the queue closes.
+    available: Condvar,
+    /// Signalled when a bounded queue may have room again.
+    room: Condvar,
+    /// Jobs that may wait besides those an idle worker is about to take, `None` if unbounded.
+    capacity: Option<usize>,
+}
+
+/// The queue of a single worker.
+#[derive(Default)]
+pub(super) struct LocalQueue {
+    deques: Mutex<Deques>,
+}
+
+#[derive(Default)]
+struct Deques {
+    /// Indexed by `Priority`.
+    jobs: [VecDeque<QueuedJob>; 3],
+    /// How many jobs were taken ahead of the oldest job of each priority.
+    skipped: [usize; 3],
+}
+
+impl Deques {
+    /// The level of the job `take` would return, and whether it goes first for having been
+    /// skipped too often.
+    fn next_level(&self) -> Option<(usize, bool)> {
+        let waiting = |level: &usize| !self.jobs[*level].is_empty();
+        let starving = (0..self.jobs.len()).filter(waiting).find(|level| self.skipped[*level] >= MAX_SKIPPED);
+        starving.map(|level| (level, true))
+            .or_else(|| (0..self.jobs.len()).find(waiting).map(|level| (level, false)))
+    }
+
+    /// The highest priority job, unless one of lower priority was skipped too often.
+    fn take(&mut self) -> Option<(usize, QueuedJob)> {
+        let (level, _) = self.next_level()?;
+        self.skip_below(level);
+        self.skipped[level] = 0;
+        Some((level, self.jobs[level].pop_front()?))
+    }
+
+    /// Counts a job of `level` taken ahead of the waiting ones of lower priority.
+    fn skip_below(&mut self, level: usize) {
+        for lower in level + 1..self.jobs.len() {
+            if !self.jobs[lower].is_empty() {
+                self.skipped[lower] += 1;
+            }
+        }
+    }
+
+    /// The newest job of `level`, leaving the owner the jobs it would take next.
+    fn steal(&mut self, level: usize) -> Option<QueuedJob> {
+        self.jobs[level].pop_back()
+    }
+}
+
+impl JobQueue {
+    pub(super) fn new(capacity: Option<usize>) -> JobQueue {
+        JobQueue {
+            locals: RwLock::new(vec![]),
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            waiting: Default::default(),
+            idle: AtomicUsize::new(0),
+            terminate: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            available: Condvar::new(),
+            room: Condvar::new(),
+            capacity,
+        }
+    }
+
+    /// Adds the queue of a new worker.
+    pub(super) fn register(&self, id: usize) -> Arc<LocalQueue> {
+        let local = Arc::new(LocalQueue::default());
+        self.locals.write().unwrap().push((id, Arc::clone(&local)));
+        local
+    }
+
+    /// Removes the queue of a stopping worker, handing the jobs left in it to another one.
+    pub(super) fn unregister(&self, id: usize) {
+        let mut locals = self.locals.write().unwrap();
+        let Some(index) = locals.iter().position(|(worker, _)| *worker == id) else {
+            return;
+        };
+        let (_, local) = locals.remove(index);
+        let left = std::mem::take(&mut local.deques.lock().unwrap().jobs);
+        if let Some((_, heir)) = locals.first() {
+            let mut heir = heir.deques.lock().unwrap();
+            for (level, jobs) in left.into_iter().enumerate() {
+                heir.jobs[level].extend(jobs);
+            }
+        }
+        drop(locals);
+        let _guard = self.sleep.lock().unwrap();
+        self.available.notify_all();
+    }
+
+    /// Makes this thread push to `local` first, see `push`.
+    pub(super) fn enter(&self, local: &Arc<LocalQueue>) {
+        LOCAL.with(|current| *current.borrow_mut() = Some((self.address(), Arc::clone(local))));
+    }
+
+    fn address(&self) -> usize {
+        self as *const JobQueue as usize
+    }
+
+    /// Waits for room on a bounded queue.
+    pub(super) fn push(&self, priority: Priority, job: Job) {
+        let job = QueuedJob { job, submitted: Instant::now() };
+        if let Some(capacity) = self.capacity {
+            let mut guard = self.sleep.lock().unwrap();
+            while !self.reserve(capacity) {
+                guard = self.room.wait(guard).unwrap();
+            }
+        } else {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+        self.push_reserved(priority, job);
+    }
+
+    /// Gives the job back if a bounded queue is full. Otherwise `queued` runs once there is room
+    /// for the job, before any worker can take it.
+    pub(super) fn try_push(&self, priority: Priority, job: Job, queued: impl FnOnce()) -> Result<(), Job> {
+        let job = QueuedJob { job, submitted: Instant::now() };
+        match self.capacity {
+            Some(capacity) => {
+                let reserved = {
+                    let _guard = self.sleep.lock().unwrap();
+                    self.reserve(capacity)
+                };
+                if !reserved {
+                    return Err(job.job);
+                }
+            }
+            None => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        queued();
+        self.push_reserved(priority, job);
+        Ok(())
+    }
+
+    /// Counts a job in if there is room for it, under the `sleep` lock.
+    fn reserve(&self, capacity: usize) -> bool {
+        let len = self.len.load(Ordering::SeqCst);
+        if len >= capacity + self.idle.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.len.store(len + 1, Ordering::SeqCst);
+        true
+    }
+
+    fn push_reserved(&self, priority: Priority, job: QueuedJob) {
+        let own = LOCAL.with(|current| match &*current.borrow() {
+            Some((address, local)) if *address == self.address() => Some(Arc::clone(local)),
+            _ => None,
+        });
+        let target = own.unwrap_or_else(|| {
+            let locals = self.locals.read().unwrap();
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % locals.len();
+            Arc::clone(&locals[index].1)
+        });
+        self.waiting[priority as usize].fetch_add(1, Ordering::SeqCst);
+        target.deques.lock().unwrap().jobs[priority as usize].push_back(job);
+        // `len` was raised before `idle` is read here, and a worker raises `idle` before reading
+        // `len`, so either this sees the sleeper or the sleeper sees the job
+        if self.idle.load(Ordering::SeqCst) > 0 {
+            let _guard = self.sleep.lock().unwrap();
+            self.available.notify_one();
+        }
+    }
+
+    /// Makes `count` workers stop once they finish their current job, ahead of any queued job.
+    pub(super) fn terminate(&self, count: usize) {
+        self.terminate.fetch_add(count, Ordering::SeqCst);
+        let _guard = self.sleep.lock().unwrap();
+        self.available.notify_all();
+    }
+
+    /// Lets the workers stop once the queue is drained.
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = self.sleep.lock().unwrap();
+        self.available.notify_all();
+    }
+
+    /// Waits for the next message of the worker owning `local`, `None` once the queue is closed
+    /// and empty.
+    pub(super) fn pop(&self, local: &LocalQueue) -> Option<Message> {
+        loop {
+            if self.take_terminate() {
+                return Some(Message::Terminate);
+            }
+            if let Some(job) = self.take(local) {
+                return Some(Message::Job(job));
+            }
+            let guard = self.sleep.lock().unwrap();
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            if self.capacity.is_some() {
+                // an idle worker is room for one more job
+                self.room.notify_one();
+            }
+            let pending = self.len.load(Ordering::SeqCst) > 0 || self.terminate.load(Ordering::SeqCst) > 0;
+            if !pending && self.closed.load(Ordering::SeqCst) {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            if pending {
+                // a job counted in but not pushed yet, or one taken by another worker meanwhile
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                drop(guard);
+                thread::yield_now();
+                continue;
+            }
+            let _guard = self.available.wait(guard).unwrap();
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn steal(&self, local: &LocalQueue, level: usize) -> Option<QueuedJob> {
+        let locals = self.locals.read().unwrap();
+        locals.iter()
+            .filter(|(_, other)| !std::ptr::eq(&**other, local))
+            .find_map(|(_, other)| other.deques.lock().unwrap().steal(level))
+    }
+
+    fn take_terminate(&self) -> bool {
+        self.terminate.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| count.checked_sub(1)).is_ok()
+    }
+
+    /// A job of `local`, or one stolen from another worker if that one is more urgent than any
+    /// of `local` or `local` is empty.
+    fn take(&self, local: &LocalQueue) -> Option<QueuedJob> {
+        // only a hint, a job may be counted in and not pushed yet
+        let urgent = self.waiting.iter().position(|waiting| waiting.load(Ordering::SeqCst) > 0);
+        // the own queue must be unlocked before stealing, two workers stealing from each other
+        // would otherwise wait for each other forever
+        let own = {
+            let mut deques = local.deques.lock().unwrap();
+            match (deques.next_level(), urgent) {
+                // stealing only pays off for jobs more urgent than the own ones
+                (Some((level, false)), Some(urgent)) if level > urgent => Err(level),
+                (None, _) => Err(self.waiting.len()),
+                _ => Ok(deques.take()),
+            }
+        };
+        let (level, job) = match own {
+            Ok(own) => own?,
+            Err(below) => {
+                let stolen = urgent.into_iter().chain(0..below)
+                    .find_map(|level| self.steal(local, level).map(|job| (level, job)));
+                let mut deques = local.deques.lock().unwrap();
+                match stolen {
+                    Some((level, job)) => {
+                        deques.skip_below(level);
+                        (level, job)
+                    }
+                    // the urgent job was taken by another worker meanwhile
+                    None => deques.take()?,
+                }
+            }
+        };
+        self.waiting[level].fetch_sub(1, Ordering::SeqCst);
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if self.capacity.is_some() {
+            let _guard = self.sleep.lock().unwrap();
+            self.room.notify_one();
+        }
+        Some(job)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use super::{JobQueue, LocalQueue, MAX_SKIPPED};
+    use crate::utils::threads::{CountDownLatch, Message, Priority, QueuedJob, ThreadPool};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    fn push(queue: &JobQueue, log: &Log, priority: Priority, label: &str) {
+        let (log, label) = (Arc::clone(log), label.to_string());
+        queue.push(priority, Box::new(move || log.lock().unwrap().push(label)));
+    }
+
+    /// Runs the next job `local` gets, false once the queue is closed and drained.
+    fn run_next(queue: &JobQueue, local: &LocalQueue) -> bool {
+        match queue.pop(local) {
+            Some(Message::Job(QueuedJob { job, .. })) => {
+                job();
+                true
+            }
+            Some(Message::Terminate) => panic!("no worker was terminated"),
+            None => false,
+        }
+    }
+
+    #[test]
+    fn higher_priorities_first_without_starving_the_others() {
+        let queue = JobQueue::new(None);
+        let local = queue.register(0);
+        let log = Log::default();
+        push(&queue, &log, Priority::Low, "low");
+        push(&queue, &log, Priority::Normal, "normal");
+        push(&queue, &log, Priority::High, "high");
+        push(&queue, &log, Priority::Normal, "normal 2");
+        for _ in 0..4 {
+            run_next(&queue, &local);
+        }
+        assert_eq!(*log.lock().unwrap(), ["high", "normal", "normal 2", "low"]);
+
+        log.lock().unwrap().clear();
+        push(&queue, &log, Priority::Low, "low");
+        for i in 0..MAX_SKIPPED + 2 {
+            push(&queue, &log, Priority::High, &i.to_string());
+        }
+        while queue.len.load(Ordering::SeqCst) > 0 {
+            run_next(&queue, &local);
+        }
+        let log = log.lock().unwrap();
+        assert_eq!(log.iter().position(|label| label == "low"), Some(MAX_SKIPPED));
+        assert_eq!(log.len(), MAX_SKIPPED + 3);
+    }
+
+    #[test]
+    fn an_idle_worker_steals_the_newest_job() {
+        let queue = JobQueue::new(None);
+        let (busy, idle) = (queue.register(0), queue.register(1));
+        // pushed from the busy worker, all jobs land in its own queue
+        queue.enter(&busy);
+        let log = Log::default();
+        for label in ["1", "2", "3"] {
+            push(&queue, &log, Priority::Normal, label);
+        }
+        run_next(&queue, &idle);
+        run_next(&queue, &busy);
+        run_next(&queue, &idle);
+        assert_eq!(*log.lock().unwrap(), ["3", "1", "2"]);
+    }
+
+    #[test]
+    fn a_more_urgent_job_of_another_worker_goes_first() {
+        let queue = JobQueue::new(None);
+        let (first, second) = (queue.register(0), queue.register(1));
+        let log = Log::default();
+        queue.enter(&first);
+        push(&queue, &log, Priority::Low, "low");
+        push(&queue, &log, Priority::Normal, "normal");
+        queue.enter(&second);
+        push(&queue, &log, Priority::High, "high");
+        for _ in 0..3 {
+            run_next(&queue, &first);
+        }
+        assert_eq!(*log.lock().unwrap(), ["high", "normal", "low"]);
+    }
+
+    #[test]
+    fn jobs_queued_by_a_blocked_worker_run_elsewhere() {
+        let pool = ThreadPool::new(2);
+        let latch = Arc::new(CountDownLatch::new(4));
+        pool.scope(|scope| scope.spawn(|| {
+            for _ in 0..4 {
+                let latch = Arc::clone(&latch);
+                pool.execute(move || latch.count_down());
+            }
+            // the jobs are in this worker's queue, only the other worker can take them
+            assert!(latch.await_timeout(Duration::from_secs(5)));
+        }));
+    }
+
+    #[test]
+    fn closing_drains_the_queued_jobs() {
+        let queue = JobQueue::new(None);
+        let local = queue.register(0);
+        let log = Log::default();
+        push(&queue, &log, Priority::Normal, "1");
+        push(&queue, &log, Priority::Low, "2");
+        queue.close();
+        while run_next(&queue, &local) {}
+        assert_eq!(*log.lock().unwrap(), ["1", "2"]);
+
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..50 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn every_job_of_many_producers_runs() {
+        let pool = ThreadPool::new(4);
+        let ran = Arc::new(AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for producer in 0..8 {
+                let (pool, ran) = (&pool, &ran);
+                scope.spawn(move || {
+                    let priorities = [Priority::High, Priority::Normal, Priority::Low];
+                    for i in 0..12_500 {
+                        let ran = Arc::clone(ran);
+                        pool.execute_with_priority(priorities[(producer + i) % 3], move || {
+                            ran.fetch_add(1, Ordering::Relaxed);
+                        });
+                    }
+                });
+            }
+        });
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 100_000);
+    }
+}
@@ -0,0 +1,283 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::{atomic, Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{Job, Priority, Shared};
+
+/// Returned by `ThreadPool::schedule` and `schedule_periodic` to call the task off.
+#[derive(Debug, Clone)]
+pub(crate) struct ScheduledHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl ScheduledHandle {
+    /// Prevents the runs that have not started yet; a run already under way finishes.
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, atomic::Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(atomic::Ordering::SeqCst)
+    }
+}
+
+enum Task {
+    Once(Job),
+    /// Shared by the runs, which only overlap if one takes longer than the period; the lock
+    /// then makes the next one wait.
+    Periodic(Arc<Mutex<dyn FnMut() + Send>>, Duration),
+}
+
+struct Entry {
+    deadline: Instant,
+    /// Breaks ties between equal deadlines in scheduling order.
+    seq: u64,
+    task: Task,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that `BinaryHeap` pops the earliest deadline first.
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    entries: BinaryHeap<Entry>,
+    seq: u64,
+    stopped: bool,
+}
+
+/// The thread of a pool holding its scheduled tasks until they are due, then queueing them for
+/// the workers like any other job.
+pub(super) struct Timer {
+    state: Arc<(Mutex<TimerState>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    pub(super) fn spawn(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state: Arc<(Mutex<TimerState>, Condvar)> = Arc::default();
+        let thread_state = Arc::clone(&state);
+        let thread = thread::Builder::new()
+            .name(format!("{}-timer", shared.name_prefix))
+            .spawn(move || run(&thread_state, &shared))?;
+        Ok(Timer {
+            state,
+            thread: Some(thread),
+        })
+    }
+
+    pub(super) fn once(&self, delay: Duration, job: Job) -> ScheduledHandle {
+        self.insert(Instant::now() + delay, Task::Once(job))
+    }
+
+    pub(super) fn periodic(&self, initial_delay: Duration, period: Duration, f: Arc<Mutex<dyn FnMut() + Send>>) -> ScheduledHandle {
+        self.insert(Instant::now() + initial_delay, Task::Periodic(f, period))
+    }
+
+    fn insert(&self, deadline: Instant, task: Task) -> ScheduledHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (mutex, condvar) = &*self.state;
+        let mut state = mutex.lock().unwrap();
+        state.seq += 1;
+        let seq = state.seq;
+        state.entries.push(Entry { deadline, seq, task, cancelled: Arc::clone(&cancelled) });
+        condvar.notify_one();
+        ScheduledHandle { cancelled }
+    }
+}
+
+impl Drop for Timer {
+    /// Tasks not yet due are dropped without running.
+    fn drop(&mut self) {
+        let (mutex, condvar) = &*self.state;
+        mutex.lock().unwrap().stopped = true;
+        condvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn run(state: &(Mutex<TimerState>, Condvar), shared: &Shared) {
+    let (mutex, condvar) = state;
+    let mut guard = mutex.lock().unwrap();
+    loop {
+        if guard.stopped {
+            return;
+        }
+        let now = Instant::now();
+        let wait = match guard.entries.peek() {
+            None => None,
+            Some(entry) if entry.deadline <= now => {
+                let entry = guard.entries.pop().unwrap();
+                if entry.cancelled.load(atomic::Ordering::SeqCst) {
+                    continue;
+                }
+                if let Task::Periodic(f, period) = &entry.task {
+                    // a late tick is not made up for by a burst of them
+                    let deadline = (entry.deadline + *period).max(now);
+                    guard.seq += 1;
+                    let seq = guard.seq;
+                    guard.entries.push(Entry {
+                        deadline,
+                        seq,
+                        task: Task::Periodic(Arc::clone(f), *period),
+                        cancelled: Arc::clone(&entry.cancelled),
+                    });
+                }
+                // queueing may wait for room, the lock must not be held meanwhile
+                drop(guard);
+                queue(entry, shared);
+                guard = mutex.lock().unwrap();
+                continue;
+            }
+            Some(entry) => Some(entry.deadline - now),
+        };
+        guard = match wait {
+            None => condvar.wait(guard).unwrap(),
+            Some(wait) => condvar.wait_timeout(guard, wait).unwrap().0,
+        };
+    }
+}
+
+/// Hands a due task to the workers, which skip it if it is cancelled before it starts.
+fn queue(entry: Entry, shared: &Shared) {
+    let cancelled = entry.cancelled;
+    let job: Job = match entry.task {
+        Task::Once(f) => Box::new(move || {
+            if !cancelled.load(atomic::Ordering::SeqCst) {
+                f()
+            }
+        }),
+        Task::Periodic(f, _) => Box::new(move || {
+            // a panicking run poisons the lock, which must not stop the next ones
+            let mut f = f.lock().unwrap_or_else(PoisonError::into_inner);
+            if !cancelled.load(atomic::Ordering::SeqCst) {
+                f()
+            }
+        }),
+    };
+    shared.enqueued();
+    shared.queue.push(Priority::Normal, job);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use crate::utils::threads::ThreadPool;
+    use super::*;
+
+    fn counter() -> (Arc<AtomicUsize>, impl FnMut() + Send + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&count);
+        (count, move || { counted.fetch_add(1, atomic::Ordering::SeqCst); })
+    }
+
+    #[test]
+    fn a_cancelled_task_never_runs() {
+        let pool = ThreadPool::new(2);
+        let (ran_sender, ran) = mpsc::channel();
+        let cancelled_sender = ran_sender.clone();
+        let started = Instant::now();
+        let cancelled = pool.schedule(Duration::from_millis(50), move || cancelled_sender.send("cancelled").unwrap());
+        pool.schedule(Duration::from_millis(100), move || ran_sender.send("kept").unwrap());
+        cancelled.cancel();
+        assert!(cancelled.is_cancelled());
+
+        assert_eq!(ran.recv_timeout(Duration::from_secs(5)), Ok("kept"));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        // every sender is gone once the cancelled task was dropped without running
+        assert_eq!(ran.recv_timeout(Duration::from_secs(5)), Err(mpsc::RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn a_due_task_cancelled_before_a_worker_took_it_never_runs() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || { let _ = blocked.recv(); });
+        let (count, task) = counter();
+        let handle = pool.schedule(Duration::ZERO, task);
+        // queued behind the blocked job by now
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.queued_len() == 0 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.cancel();
+        drop(release);
+        drop(pool);
+        assert_eq!(count.load(atomic::Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn a_periodic_task_ticks_until_cancelled() {
+        let pool = ThreadPool::new(2);
+        let (count, task) = counter();
+        let handle = pool.schedule_periodic(Duration::from_millis(10), Duration::from_millis(20), task);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while count.load(atomic::Ordering::SeqCst) < 2 {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        }
+        handle.cancel();
+        // a run already queued may still finish
+        thread::sleep(Duration::from_millis(50));
+        let ticks = count.load(atomic::Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(count.load(atomic::Ordering::SeqCst), ticks);
+    }
+
+    #[test]
+    fn a_panicking_run_does_not_stop_the_next_ones() {
+        let pool = ThreadPool::new(1);
+        let (ticks_sender, ticks) = mpsc::channel();
+        let mut run = 0;
+        let handle = pool.schedule_periodic(Duration::ZERO, Duration::from_millis(10), move || {
+            run += 1;
+            let _ = ticks_sender.send(run);
+            if run == 1 {
+                panic!("first run failed");
+            }
+        });
+        assert_eq!(ticks.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(ticks.recv_timeout(Duration::from_secs(5)), Ok(2));
+        handle.cancel();
+    }
+
+    #[test]
+    fn dropping_the_pool_stops_the_timer() {
+        let pool = ThreadPool::new(1);
+        let (count, task) = counter();
+        pool.schedule_periodic(Duration::from_secs(3600), Duration::from_secs(3600), task);
+        let (once_count, once) = counter();
+        pool.schedule(Duration::from_secs(3600), once);
+        let started = Instant::now();
+        drop(pool);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // the tasks were dropped along with the timer, not kept for later
+        assert_eq!(Arc::strong_count(&count), 1);
+        assert_eq!(Arc::strong_count(&once_count), 1);
+    }
+}
@@ -0,0 +1,215 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use super::{catch_job_panic, Result, ThreadPool};
+
+/// Spawns tasks borrowing from outside of `ThreadPool::scope`, which waits for them all.
+pub(crate) struct PoolScope<'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    /// Invariant in `'scope`, so that it cannot be shortened to accept shorter-lived borrows.
+    _scope: PhantomData<fn(&'scope ()) -> &'scope ()>,
+}
+
+#[derive(Default)]
+struct ScopeState {
+    /// Tasks spawned and not finished yet.
+    pending: Mutex<usize>,
+    finished: Condvar,
+    panics: Mutex<Vec<Box<dyn Any + Send>>>,
+}
+
+impl ScopeState {
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.finished.wait(pending).unwrap();
+        }
+    }
+}
+
+impl<'scope> PoolScope<'scope> {
+    /// Queues `f` on the pool, it may borrow anything that outlives the scope.
+    pub(crate) fn spawn<F>(&self, f: F)
+        where
+            F: FnOnce() + Send + 'scope,
+    {
+        *self.state.pending.lock().unwrap() += 1;
+        let state = Arc::clone(&self.state);
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                state.panics.lock().unwrap().push(payload);
+            }
+            let mut pending = state.pending.lock().unwrap();
+            *pending -= 1;
+            if *pending == 0 {
+                state.finished.notify_all();
+            }
+        });
+        // SAFETY: `ThreadPool::scope` does not return before `pending` is back to 0, which the
+        // task only does once `f` and all it borrows were consumed, so nothing it borrows for
+        // `'scope` is used after `'scope` ends.
+        let task: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(task) };
+        self.pool.execute(task);
+    }
+}
+
+impl ThreadPool {
+    /// Runs `f`, which may `spawn` tasks borrowing from the caller, then waits for all of them
+    /// before returning what `f` returned. If `f` or a task panicked, the first panic is
+    /// resumed once all tasks finished.
+    ///
+    /// Waiting ties up the calling thread: called from a job of the same pool, the tasks may
+    /// never get a worker.
+    pub(crate) fn scope<'scope, R>(&'scope self, f: impl FnOnce(&PoolScope<'scope>) -> R) -> R {
+        let scope = PoolScope {
+            pool: self,
+            state: Arc::default(),
+            _scope: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.wait();
+        let mut panics = mem::take(&mut *scope.state.panics.lock().unwrap());
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if !panics.is_empty() => panic::resume_unwind(panics.swap_remove(0)),
+            Ok(result) => result,
+        }
+    }
+
+    /// Applies `f` to every item on the pool, returning the results in the order of the items.
+    /// The items are split in one contiguous chunk per worker, see `par_map_chunked`.
+    pub(crate) fn par_map<T, U, F>(&self, items: Vec<T>, f: F) -> Vec<Result<U>>
+        where T: Send,
+              U: Send,
+              F: Fn(T) -> Result<U> + Send + Sync
+    {
+        let chunk_size = items.len().div_ceil(self.workers.len()).max(1);
+        self.par_map_chunked(items, chunk_size, f)
+    }
+
+    /// Like `par_map`, each job mapping `chunk_size` items in a row; smaller chunks balance
+    /// uneven items better at the cost of more jobs. An item whose `f` fails or panics gets an
+    /// error in its place, the others are mapped all the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub(crate) fn par_map_chunked<T, U, F>(&self, items: Vec<T>, chunk_size: usize, f: F) -> Vec<Result<U>>
+        where T: Send,
+              U: Send,
+              F: Fn(T) -> Result<U> + Send + Sync
+    {
+        assert!(chunk_size > 0, "chunks need at least one item");
+        let mut results: Vec<Option<Result<U>>> = (0..items.len()).map(|_| None).collect();
+        let mut items = items.into_iter();
+        let f = &f;
+        self.scope(|scope| {
+            for slots in results.chunks_mut(chunk_size) {
+                let chunk: Vec<T> = items.by_ref().take(slots.len()).collect();
+                scope.spawn(move || {
+                    for (slot, item) in slots.iter_mut().zip(chunk) {
+                        *slot = Some(catch_job_panic(|| f(item)).0);
+                    }
+                });
+            }
+        });
+        results.into_iter()
+            .map(|result| result.expect("the scope waits for every chunk"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use anyhow::anyhow;
+    use crate::utils::threads::{panic_message, ThreadPool};
+
+    #[test]
+    fn tasks_borrow_from_the_callers_stack() {
+        let pool = ThreadPool::new(3);
+        let words = ["a", "bb", "ccc", "dddd"];
+        let mut lengths = vec![0; words.len()];
+        let sum = AtomicUsize::new(0);
+        let returned = pool.scope(|scope| {
+            for (word, length) in words.iter().zip(lengths.iter_mut()) {
+                let sum = &sum;
+                scope.spawn(move || {
+                    *length = word.len();
+                    sum.fetch_add(word.len(), Ordering::SeqCst);
+                });
+            }
+            "done"
+        });
+        assert_eq!(returned, "done");
+        assert_eq!(lengths, [1, 2, 3, 4]);
+        assert_eq!(sum.into_inner(), 10);
+    }
+
+    #[test]
+    fn a_panic_is_resumed_once_every_task_finished() {
+        let pool = ThreadPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.scope(|scope| {
+            scope.spawn(|| panic!("task failed"));
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        })));
+        assert_eq!(panic_message(&*result.unwrap_err()), "task failed");
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+
+        // a panic of the scope's closure waits for the tasks it spawned all the same
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+            panic!("closure failed");
+        })));
+        assert_eq!(panic_message(&*result.unwrap_err()), "closure failed");
+        assert_eq!(finished.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn par_map_keeps_the_order_of_the_items() {
+        let pool = ThreadPool::new(4);
+        let items: Vec<u64> = (0..100).collect();
+        // later items finish first
+        let results = pool.par_map_chunked(items, 3, |i| {
+            thread::sleep(Duration::from_micros(100 - i));
+            Ok(i * 2)
+        });
+        let results: Vec<u64> = results.into_iter().map(|result| result.unwrap()).collect();
+        assert_eq!(results, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+
+        let results = pool.par_map(vec![1, 0, 2, 3], |i| match i {
+            0 => Err(anyhow!("zero")),
+            3 => panic!("three"),
+            i => Ok(10 / i),
+        });
+        let results: Vec<_> = results.into_iter().map(|result| result.map_err(|e| e.to_string())).collect();
+        assert_eq!(results, [Ok(10), Err("zero".to_string()), Ok(5), Err("the job panicked: three".to_string())]);
+    }
+
+    #[test]
+    fn par_map_agrees_with_a_sequential_map() {
+        let pool = ThreadPool::new(4);
+        let f = |i: u64| if i % 7 == 3 { Err(anyhow!("{i} fails")) } else { Ok(i * i) };
+        let items: Vec<u64> = (0..10_000).collect();
+        let expected: Vec<_> = items.iter().map(|i| f(*i).map_err(|e| e.to_string())).collect();
+        for results in [pool.par_map(items.clone(), f), pool.par_map_chunked(items, 7, f)] {
+            let results: Vec<_> = results.into_iter().map(|result| result.map_err(|e| e.to_string())).collect();
+            assert_eq!(results, expected);
+        }
+    }
+}